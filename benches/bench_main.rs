@@ -4,6 +4,7 @@ use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::thread::JoinHandle;
 use tocket::in_memory::InMemoryStorage;
+use tocket::in_memory_atomic::AtomicInMemoryStorage;
 use tocket::{Storage, TokenBucket};
 
 #[cfg(feature = "redis-impl")]
@@ -39,6 +40,22 @@ fn bench_in_memory_mt(b: &mut Bencher, rps: u32, target_rps: u32, threads_num: u
     );
 }
 
+fn bench_atomic_in_memory_mt(b: &mut Bencher, rps: u32, target_rps: u32, threads_num: u32) {
+    b.iter_batched(
+        || {
+            let rl = TokenBucket::new(AtomicInMemoryStorage::new(rps));
+            let rl = Arc::new(rl);
+            let (starter, waiter) = make_threads(rl, target_rps, threads_num);
+            (starter, waiter)
+        },
+        |(starter, waiter)| {
+            starter.start();
+            waiter.wait();
+        },
+        BatchSize::SmallInput,
+    );
+}
+
 #[cfg(feature = "redis-impl")]
 fn bench_redis(b: &mut Bencher, rps: u32, target_rps: u32) {
     b.iter_batched(
@@ -193,26 +210,41 @@ fn bench_within_limit_mt(c: &mut Criterion) {
 
     // 2 threads
     g.bench_function("in_memory_mt_2", |b| bench_in_memory_mt(b, 1000, 500, 2));
+    g.bench_function("atomic_in_memory_mt_2", |b| {
+        bench_atomic_in_memory_mt(b, 1000, 500, 2)
+    });
     #[cfg(feature = "redis-impl")]
     g.bench_function("redis_mt_2", |b| bench_redis_mt(b, 1000, 500, 2));
 
     // 4 threads
     g.bench_function("in_memory_mt_4", |b| bench_in_memory_mt(b, 1000, 500, 4));
+    g.bench_function("atomic_in_memory_mt_4", |b| {
+        bench_atomic_in_memory_mt(b, 1000, 500, 4)
+    });
     #[cfg(feature = "redis-impl")]
     g.bench_function("redis_mt_4", |b| bench_redis_mt(b, 1000, 500, 4));
 
     // 8 threads
     g.bench_function("in_memory_mt_8", |b| bench_in_memory_mt(b, 1000, 500, 8));
+    g.bench_function("atomic_in_memory_mt_8", |b| {
+        bench_atomic_in_memory_mt(b, 1000, 500, 8)
+    });
     #[cfg(feature = "redis-impl")]
     g.bench_function("redis_mt_8", |b| bench_redis_mt(b, 1000, 500, 8));
 
     // 16 threads
     g.bench_function("in_memory_mt_16", |b| bench_in_memory_mt(b, 1000, 500, 16));
+    g.bench_function("atomic_in_memory_mt_16", |b| {
+        bench_atomic_in_memory_mt(b, 1000, 500, 16)
+    });
     #[cfg(feature = "redis-impl")]
     g.bench_function("redis_mt_16", |b| bench_redis_mt(b, 1000, 500, 16));
 
     // 32 threads
     g.bench_function("in_memory_mt_32", |b| bench_in_memory_mt(b, 1000, 500, 32));
+    g.bench_function("atomic_in_memory_mt_32", |b| {
+        bench_atomic_in_memory_mt(b, 1000, 500, 32)
+    });
     #[cfg(feature = "redis-impl")]
     g.bench_function("redis_mt_32", |b| bench_redis_mt(b, 1000, 500, 32));
 
@@ -224,26 +256,41 @@ fn bench_on_limit_mt(c: &mut Criterion) {
 
     // 2 threads
     g.bench_function("in_memory_mt_2", |b| bench_in_memory_mt(b, 1000, 1000, 2));
+    g.bench_function("atomic_in_memory_mt_2", |b| {
+        bench_atomic_in_memory_mt(b, 1000, 1000, 2)
+    });
     #[cfg(feature = "redis-impl")]
     g.bench_function("redis_mt_2", |b| bench_redis_mt(b, 1000, 1000, 2));
 
     // 4 threads
     g.bench_function("in_memory_mt_4", |b| bench_in_memory_mt(b, 1000, 1000, 4));
+    g.bench_function("atomic_in_memory_mt_4", |b| {
+        bench_atomic_in_memory_mt(b, 1000, 1000, 4)
+    });
     #[cfg(feature = "redis-impl")]
     g.bench_function("redis_onl_mt_4", |b| bench_redis_mt(b, 1000, 1000, 4));
 
     // 8 threads
     g.bench_function("in_memory_mt_8", |b| bench_in_memory_mt(b, 1000, 1000, 8));
+    g.bench_function("atomic_in_memory_mt_8", |b| {
+        bench_atomic_in_memory_mt(b, 1000, 1000, 8)
+    });
     #[cfg(feature = "redis-impl")]
     g.bench_function("redis_mt_8", |b| bench_redis_mt(b, 1000, 1000, 8));
 
     // 16 threads
     g.bench_function("in_memory_mt_16", |b| bench_in_memory_mt(b, 1000, 1000, 16));
+    g.bench_function("atomic_in_memory_mt_16", |b| {
+        bench_atomic_in_memory_mt(b, 1000, 1000, 16)
+    });
     #[cfg(feature = "redis-impl")]
     g.bench_function("redis_mt_16", |b| bench_redis_mt(b, 1000, 1000, 16));
 
     // 32 threads
     g.bench_function("in_memory_mt_32", |b| bench_in_memory_mt(b, 1000, 1000, 32));
+    g.bench_function("atomic_in_memory_mt_32", |b| {
+        bench_atomic_in_memory_mt(b, 1000, 1000, 32)
+    });
     #[cfg(feature = "redis-impl")]
     g.bench_function("redis_mt_32", |b| bench_redis_mt(b, 1000, 1000, 32));
 
@@ -255,26 +302,41 @@ fn bench_over_limit_mt(c: &mut Criterion) {
 
     // 2 threads
     g.bench_function("in_memory_mt_2", |b| bench_in_memory_mt(b, 1000, 1500, 2));
+    g.bench_function("atomic_in_memory_mt_2", |b| {
+        bench_atomic_in_memory_mt(b, 1000, 1500, 2)
+    });
     #[cfg(feature = "redis-impl")]
     g.bench_function("redis_mt_2", |b| bench_redis_mt(b, 1000, 1500, 2));
 
     // 4 threads
     g.bench_function("in_memory_mt_4", |b| bench_in_memory_mt(b, 1000, 1500, 4));
+    g.bench_function("atomic_in_memory_mt_4", |b| {
+        bench_atomic_in_memory_mt(b, 1000, 1500, 4)
+    });
     #[cfg(feature = "redis-impl")]
     g.bench_function("redis_mt_4", |b| bench_redis_mt(b, 1000, 1500, 4));
 
     // 8 threads
     g.bench_function("in_memory_mt_8", |b| bench_in_memory_mt(b, 1000, 1500, 8));
+    g.bench_function("atomic_in_memory_mt_8", |b| {
+        bench_atomic_in_memory_mt(b, 1000, 1500, 8)
+    });
     #[cfg(feature = "redis-impl")]
     g.bench_function("redis_mt_8", |b| bench_redis_mt(b, 1000, 1500, 8));
 
     // 16 threads
     g.bench_function("in_memory_mt_16", |b| bench_in_memory_mt(b, 1000, 1500, 16));
+    g.bench_function("atomic_in_memory_mt_16", |b| {
+        bench_atomic_in_memory_mt(b, 1000, 1500, 16)
+    });
     #[cfg(feature = "redis-impl")]
     g.bench_function("redis_mt_16", |b| bench_redis_mt(b, 1000, 1500, 16));
 
     // 32 threads
     g.bench_function("in_memory_mt_32", |b| bench_in_memory_mt(b, 1000, 1500, 32));
+    g.bench_function("atomic_in_memory_mt_32", |b| {
+        bench_atomic_in_memory_mt(b, 1000, 1500, 32)
+    });
     #[cfg(feature = "redis-impl")]
     g.bench_function("redis_mt_32", |b| bench_redis_mt(b, 1000, 1500, 32));
 
@@ -0,0 +1,249 @@
+use crate::{
+    balance, validate_capacity, validate_tick, ConfigError, InitialFill, Mode,
+    RateLimitExceededError, State, TokenBucketAlgorithm,
+};
+
+use std::time::Duration;
+
+/// Limit of a single bucket: `cap` tokens refilled evenly during `period`.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub struct Limit {
+    cap: u64,
+    period: Duration,
+}
+
+impl Limit {
+    /// Creates a limit of `cap` permits per `period`.
+    ///
+    /// A limit of zero permits never gives tokens, see [`try_new`](Self::try_new).
+    ///
+    /// # Panics
+    ///
+    /// Panics if `period` is zero or shorter than a nanosecond per permit.
+    pub fn new(cap: u64, period: Duration) -> Self {
+        let limit = Self { cap, period };
+        if let Err(err) = validate_tick(limit.refill_tick()) {
+            panic!("invalid limit of {} per {:?}: {}", cap, period, err);
+        }
+        limit
+    }
+
+    /// Creates a limit of `cap` permits per `period`.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if `cap` is zero, if `period` is zero or shorter
+    /// than a nanosecond per permit.
+    pub fn try_new(cap: u64, period: Duration) -> Result<Self, ConfigError> {
+        let limit = Self {
+            cap: validate_capacity(cap)?,
+            period,
+        };
+        validate_tick(limit.refill_tick())?;
+        Ok(limit)
+    }
+
+    /// Creates a limit of `cap` permits per second.
+    pub fn per_second(cap: u64) -> Self {
+        Self::new(cap, Duration::from_secs(1))
+    }
+
+    /// Creates a limit of `cap` permits per minute.
+    pub fn per_minute(cap: u64) -> Self {
+        Self::new(cap, Duration::from_secs(60))
+    }
+
+    /// Creates a limit of `cap` permits per hour.
+    pub fn per_hour(cap: u64) -> Self {
+        Self::new(cap, Duration::from_secs(60 * 60))
+    }
+
+    /// Creates a limit of `cap` permits per day.
+    pub fn per_day(cap: u64) -> Self {
+        Self::new(cap, Duration::from_secs(24 * 60 * 60))
+    }
+
+    pub fn cap(&self) -> u64 {
+        self.cap
+    }
+
+    pub fn period(&self) -> Duration {
+        self.period
+    }
+
+    pub(crate) fn refill_tick(&self) -> time::Duration {
+        let period = time::Duration::try_from(self.period).unwrap_or(time::Duration::MAX);
+        time::Duration::nanoseconds_i128(period.whole_nanoseconds() / i128::from(self.cap.max(1)))
+    }
+
+    /// State of a new bucket with `initial_fill` tokens.
+    pub(crate) fn new_state(&self, initial_fill: InitialFill) -> State {
+        State {
+            cap: self.cap,
+            available_tokens: balance(initial_fill.tokens(self.cap)),
+            last_refill: time::OffsetDateTime::now_utc(),
+            refill_tick: self.refill_tick(),
+            warm_up: None,
+            warm_since: None,
+        }
+    }
+}
+
+/// Trait that provides function for acquiring tokens from several buckets at once.
+///
+/// Object that implements this trait should load states of all limits,
+/// acquire tokens from all of them or from none and save updated states atomically.
+///
+/// A request above the capacity of any limit never succeeds, so it should fail
+/// with [`RateLimitExceededError::ExceedsCapacity`] without touching states.
+pub trait CompositeStorage {
+    type Error: From<CompositeLimitExceededError> + From<RateLimitExceededError>;
+
+    fn try_acquire_all(&self, limits: &[Limit], permits: u64) -> Result<(), Self::Error>;
+
+    /// Takes `cost` tokens from all limits regardless of their balances,
+    /// which may become negative.
+    fn charge_all(&self, limits: &[Limit], cost: u64) -> Result<(), Self::Error>;
+}
+
+/// Rate limiter that checks several limits at once, e.g. "10/s AND 300/min AND 5000/day".
+///
+/// Tokens are acquired from all limits or from none of them, so a denied request
+/// doesn't consume other limits.
+///
+/// # Example
+/// ```
+/// use tocket::{CompositeLimiter, InMemoryCompositeStorage, InMemoryCompositeStorageError, Limit};
+///
+/// let limiter = CompositeLimiter::new(
+///     InMemoryCompositeStorage::new(),
+///     vec![Limit::per_second(10), Limit::per_minute(15)],
+/// );
+/// assert!(limiter.try_acquire(10).is_ok());
+///
+/// match limiter.try_acquire(1) {
+///     Err(InMemoryCompositeStorageError::CompositeLimitExceeded(err)) => {
+///         assert_eq!(err.tripped, vec![Limit::per_second(10)]);
+///     }
+///     res => panic!("unexpected result: {:?}", res),
+/// }
+/// ```
+pub struct CompositeLimiter<S> {
+    storage: S,
+    limits: Vec<Limit>,
+}
+
+impl<S> CompositeLimiter<S>
+where
+    S: CompositeStorage,
+{
+    /// Creates new rate limiter with provided storage and limits. Repeated limits are ignored.
+    pub fn new<I>(storage: S, limits: I) -> Self
+    where
+        I: IntoIterator<Item = Limit>,
+    {
+        let mut unique = Vec::new();
+        for limit in limits {
+            if !unique.contains(&limit) {
+                unique.push(limit);
+            }
+        }
+
+        Self {
+            storage,
+            limits: unique,
+        }
+    }
+
+    pub fn limits(&self) -> &[Limit] {
+        &self.limits
+    }
+
+    /// Tries to acquire N tokens from every limit.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if any limit doesn't have enough tokens, if `permits` exceed
+    /// the capacity of any limit or if the storage could not save/load state.
+    pub fn try_acquire(&self, permits: u64) -> Result<(), S::Error> {
+        self.storage.try_acquire_all(&self.limits, permits)
+    }
+
+    /// Tries to acquire 1 token from every limit.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if any limit doesn't have enough tokens or if the storage could not save/load state.
+    pub fn try_acquire_one(&self) -> Result<(), S::Error> {
+        self.try_acquire(1)
+    }
+
+    /// Takes `cost` tokens from every limit regardless of their balances.
+    /// Next requests are denied until debts of all limits are repaid by refill.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the storage could not save/load state.
+    pub fn charge(&self, cost: u64) -> Result<(), S::Error> {
+        self.storage.charge_all(&self.limits, cost)
+    }
+}
+
+/// Fails with [`RateLimitExceededError::ExceedsCapacity`] of the smallest limit
+/// if `permits` exceed it.
+pub(crate) fn check_capacity(limits: &[Limit], permits: u64) -> Result<(), RateLimitExceededError> {
+    match limits.iter().map(Limit::cap).min() {
+        Some(cap) if permits > cap => Err(RateLimitExceededError::ExceedsCapacity { permits, cap }),
+        _ => Ok(()),
+    }
+}
+
+/// Acquires permits from all states or from none of them.
+pub(crate) fn try_acquire_all<'a, I>(
+    states: I,
+    permits: u64,
+) -> Result<(), CompositeLimitExceededError>
+where
+    I: IntoIterator<Item = (&'a Limit, &'a mut State)>,
+{
+    let alg = TokenBucketAlgorithm { mode: Mode::N };
+    let mut acquired = Vec::new();
+    let mut denied = Vec::new();
+    for (limit, state) in states {
+        let mut new_state = state.clone();
+        match alg.try_acquire(&mut new_state, permits) {
+            Ok(()) => acquired.push((state, new_state)),
+            Err(_) => denied.push((*limit, new_state.retry_after(permits))),
+        }
+    }
+
+    if !denied.is_empty() {
+        return Err(CompositeLimitExceededError::new(denied));
+    }
+    for (state, new_state) in acquired {
+        *state = new_state;
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, thiserror::Error)]
+#[error("rate limits {tripped:?} exceeded, retry after {retry_after:?}")]
+pub struct CompositeLimitExceededError {
+    /// Limits that don't have enough tokens.
+    pub tripped: Vec<Limit>,
+    /// Time until all tripped limits have enough tokens.
+    pub retry_after: Duration,
+}
+
+impl CompositeLimitExceededError {
+    pub(crate) fn new<I>(denied: I) -> Self
+    where
+        I: IntoIterator<Item = (Limit, Duration)>,
+    {
+        let (tripped, retry_after): (Vec<_>, Vec<_>) = denied.into_iter().unzip();
+        Self {
+            tripped,
+            retry_after: retry_after.into_iter().max().unwrap_or_default(),
+        }
+    }
+}
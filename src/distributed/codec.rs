@@ -1,4 +1,4 @@
-use crate::distributed::message::Message;
+use crate::distributed::message::{Message, PROTOCOL_VERSION};
 use crate::error::DistributedStorageError;
 
 use borsh::BorshDeserialize;
@@ -27,6 +27,14 @@ impl Decoder for Codec {
         if !src.is_empty() {
             let len = src.len();
             let buf = src.split_to(len);
+            // The version goes first, so messages of other versions are rejected before decoding
+            let version = <String as BorshDeserialize>::deserialize(&mut &buf[..])?;
+            if version != PROTOCOL_VERSION {
+                return Err(DistributedStorageError::ProtocolVersionMismatch {
+                    act: version,
+                    exp: PROTOCOL_VERSION,
+                });
+            }
             let item = <Message as BorshDeserialize>::try_from_slice(&buf)?;
             item.check_checksum()?;
             Ok(Some(item))
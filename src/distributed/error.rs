@@ -1,5 +1,5 @@
 use crate::distributed::message::ContentKind;
-use crate::RateLimitExceededError;
+use crate::{ConfigError, RateLimitExceededError, StorageError};
 use std::net::SocketAddr;
 
 #[derive(Debug, thiserror::Error)]
@@ -8,9 +8,13 @@ pub enum DistributedStorageError {
     RateLimitExceededError(#[from] RateLimitExceededError),
     #[error(transparent)]
     IoError(#[from] std::io::Error),
+    #[error(transparent)]
+    ConfigError(#[from] ConfigError),
 
     #[error("checksum does not match: actual = {act:#x} expected = {exp:#x}")]
     ChecksumMismatch { act: u32, exp: u32 },
+    #[error("protocol version does not match: actual = '{act}' expected = '{exp}'")]
+    ProtocolVersionMismatch { act: String, exp: &'static str },
     #[error("peer {peer} not whitelisted")]
     PeerNotWhitelisted { peer: SocketAddr },
     #[error("message content mismatch: expected '{exp:?}', but actual is '{act:?}'")]
@@ -18,3 +22,13 @@ pub enum DistributedStorageError {
     #[error("peer address not resolved")]
     PeerAddrNotResolved,
 }
+
+impl StorageError for DistributedStorageError {
+    fn is_rate_limit_exceeded(&self) -> bool {
+        matches!(self, DistributedStorageError::RateLimitExceededError(_))
+    }
+
+    fn is_capacity_exceeded(&self) -> bool {
+        matches!(self, DistributedStorageError::RateLimitExceededError(err) if err.is_capacity_exceeded())
+    }
+}
@@ -5,8 +5,13 @@ use std::borrow::Cow;
 use std::hash::Hash;
 use std::io::Write;
 
+/// Version of the wire format, peers of other versions are rejected.
+/// Version 2 has permits in 64 bits and the time of the change in reconfigurations.
+pub const PROTOCOL_VERSION: &str = "2";
+
 #[derive(Debug, Clone, Eq, PartialEq, BorshSerialize, BorshDeserialize)]
 pub struct Message {
+    /// [`PROTOCOL_VERSION`] of the sender, older versions sent the version of the crate.
     pub version: Cow<'static, str>,
     pub content: Content,
     pub checksum: u32,
@@ -14,7 +19,7 @@ pub struct Message {
 
 impl Message {
     pub fn new(content: Content) -> Self {
-        let version: Cow<'static, str> = std::env!("CARGO_PKG_VERSION").into();
+        let version: Cow<'static, str> = PROTOCOL_VERSION.into();
         let checksum = calculate_checksum(&version, &content);
 
         Self {
@@ -47,38 +52,70 @@ fn calculate_checksum(version: &str, content: &Content) -> u32 {
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub enum ContentKind {
     Whitelist,
+    Charge,
+    Reconfigure,
 }
 
 #[derive(Debug, Clone, Eq, PartialEq, Hash, BorshSerialize, BorshDeserialize)]
 pub enum Content {
     Whitelist(WhitelistContent),
+    Reconfigure(ReconfigureContent),
+    /// Tokens taken by a peer regardless of the balance.
+    Charge(WhitelistContent),
 }
 
 impl Content {
     pub fn kind(&self) -> ContentKind {
         match self {
             Content::Whitelist(_) => ContentKind::Whitelist,
+            Content::Reconfigure(_) => ContentKind::Reconfigure,
+            Content::Charge(_) => ContentKind::Charge,
         }
     }
 }
 
+fn serialize_ts<W: Write>(ts: &time::OffsetDateTime, writer: &mut W) -> std::io::Result<()> {
+    BorshSerialize::serialize(&ts.year(), writer)?;
+    BorshSerialize::serialize(&ts.ordinal(), writer)?;
+    BorshSerialize::serialize(&ts.hour(), writer)?;
+    BorshSerialize::serialize(&ts.minute(), writer)?;
+    BorshSerialize::serialize(&ts.second(), writer)?;
+    BorshSerialize::serialize(&ts.nanosecond(), writer)?;
+    BorshSerialize::serialize(&ts.offset().whole_hours(), writer)?;
+    BorshSerialize::serialize(&ts.offset().minutes_past_hour(), writer)?;
+    BorshSerialize::serialize(&ts.offset().seconds_past_minute(), writer)?;
+    Ok(())
+}
+
+fn deserialize_ts(buf: &mut &[u8]) -> std::io::Result<time::OffsetDateTime> {
+    let year = <i32 as BorshDeserialize>::deserialize(buf)?;
+    let ordinal = <u16 as BorshDeserialize>::deserialize(buf)?;
+    let hour = <u8 as BorshDeserialize>::deserialize(buf)?;
+    let minute = <u8 as BorshDeserialize>::deserialize(buf)?;
+    let second = <u8 as BorshDeserialize>::deserialize(buf)?;
+    let nanosecond = <u32 as BorshDeserialize>::deserialize(buf)?;
+    let offset_hours = <i8 as BorshDeserialize>::deserialize(buf)?;
+    let offset_minutes = <i8 as BorshDeserialize>::deserialize(buf)?;
+    let offset_seconds = <i8 as BorshDeserialize>::deserialize(buf)?;
+
+    time::Date::from_ordinal_date(year, ordinal)
+        .and_then(|date| date.with_hms_nano(hour, minute, second, nanosecond))
+        .and_then(|datetime| {
+            time::UtcOffset::from_hms(offset_hours, offset_minutes, offset_seconds)
+                .map(|offset| datetime.assume_offset(offset))
+        })
+        .map_err(|err| std::io::Error::other(err.to_string()))
+}
+
 #[derive(Debug, Clone, Eq, PartialEq, Hash)]
 pub struct WhitelistContent {
     pub sent_ts: time::OffsetDateTime,
-    pub permits: u32,
+    pub permits: u64,
 }
 
 impl BorshSerialize for WhitelistContent {
     fn serialize<W: Write>(&self, writer: &mut W) -> std::io::Result<()> {
-        BorshSerialize::serialize(&self.sent_ts.year(), writer)?;
-        BorshSerialize::serialize(&self.sent_ts.ordinal(), writer)?;
-        BorshSerialize::serialize(&self.sent_ts.hour(), writer)?;
-        BorshSerialize::serialize(&self.sent_ts.minute(), writer)?;
-        BorshSerialize::serialize(&self.sent_ts.second(), writer)?;
-        BorshSerialize::serialize(&self.sent_ts.nanosecond(), writer)?;
-        BorshSerialize::serialize(&self.sent_ts.offset().whole_hours(), writer)?;
-        BorshSerialize::serialize(&self.sent_ts.offset().minutes_past_hour(), writer)?;
-        BorshSerialize::serialize(&self.sent_ts.offset().seconds_past_minute(), writer)?;
+        serialize_ts(&self.sent_ts, writer)?;
         BorshSerialize::serialize(&self.permits, writer)?;
 
         Ok(())
@@ -87,25 +124,57 @@ impl BorshSerialize for WhitelistContent {
 
 impl BorshDeserialize for WhitelistContent {
     fn deserialize(buf: &mut &[u8]) -> std::io::Result<Self> {
-        let year = <i32 as BorshDeserialize>::deserialize(buf)?;
-        let ordinal = <u16 as BorshDeserialize>::deserialize(buf)?;
-        let hour = <u8 as BorshDeserialize>::deserialize(buf)?;
-        let minute = <u8 as BorshDeserialize>::deserialize(buf)?;
-        let second = <u8 as BorshDeserialize>::deserialize(buf)?;
-        let nanosecond = <u32 as BorshDeserialize>::deserialize(buf)?;
-        let offset_hours = <i8 as BorshDeserialize>::deserialize(buf)?;
-        let offset_minutes = <i8 as BorshDeserialize>::deserialize(buf)?;
-        let offset_seconds = <i8 as BorshDeserialize>::deserialize(buf)?;
-
-        let sent_ts = time::Date::from_ordinal_date(year, ordinal)
-            .and_then(|date| date.with_hms_nano(hour, minute, second, nanosecond))
-            .and_then(|datetime| {
-                time::UtcOffset::from_hms(offset_hours, offset_minutes, offset_seconds)
-                    .map(|offset| datetime.assume_offset(offset))
-            })
-            .map_err(|err| std::io::Error::new(std::io::ErrorKind::Other, err.to_string()))?;
-        let permits = <u32 as BorshDeserialize>::deserialize(buf)?;
+        let sent_ts = deserialize_ts(buf)?;
+        let permits = <u64 as BorshDeserialize>::deserialize(buf)?;
 
         Ok(Self { sent_ts, permits })
     }
 }
+
+/// Change of the bucket configuration made by a peer.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, BorshSerialize, BorshDeserialize)]
+pub enum Reconfiguration {
+    Rate {
+        rps_limit: u32,
+    },
+    Capacity {
+        cap: u64,
+        scale: bool,
+    },
+    /// Rate of a token per `nanos`, for rates that aren't whole per second.
+    RefillTick {
+        nanos: u64,
+    },
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct ReconfigureContent {
+    pub sent_ts: time::OffsetDateTime,
+    /// Time of the change, the same when the change is sent again.
+    pub changed_ts: time::OffsetDateTime,
+    pub change: Reconfiguration,
+}
+
+impl BorshSerialize for ReconfigureContent {
+    fn serialize<W: Write>(&self, writer: &mut W) -> std::io::Result<()> {
+        serialize_ts(&self.sent_ts, writer)?;
+        serialize_ts(&self.changed_ts, writer)?;
+        BorshSerialize::serialize(&self.change, writer)?;
+
+        Ok(())
+    }
+}
+
+impl BorshDeserialize for ReconfigureContent {
+    fn deserialize(buf: &mut &[u8]) -> std::io::Result<Self> {
+        let sent_ts = deserialize_ts(buf)?;
+        let changed_ts = deserialize_ts(buf)?;
+        let change = <Reconfiguration as BorshDeserialize>::deserialize(buf)?;
+
+        Ok(Self {
+            sent_ts,
+            changed_ts,
+            change,
+        })
+    }
+}
@@ -9,8 +9,11 @@ pub use error::DistributedStorageError;
 pub use whitelist::WhitelistStrategy;
 
 use crate::distributed::codec::Codec;
-use crate::distributed::message::Message;
-use crate::{InMemoryStorage, Storage, TokenBucketAlgorithm};
+use crate::distributed::message::{Message, Reconfiguration};
+use crate::{
+    InMemoryStorage, InitialFill, Mode, ReconfigureError, ResizePolicy, Storage,
+    TokenBucketAlgorithm,
+};
 
 use std::net::{SocketAddr, ToSocketAddrs};
 use std::sync::Arc;
@@ -19,8 +22,20 @@ use tokio::sync::mpsc;
 use tokio_util::udp::UdpFramed;
 use tracing::Instrument;
 
-type AcquireTx = mpsc::UnboundedSender<u32>;
-type AcquireRx = mpsc::UnboundedReceiver<u32>;
+type CommandTx = mpsc::UnboundedSender<Command>;
+type CommandRx = mpsc::UnboundedReceiver<Command>;
+
+/// Local changes of the bucket that are sent to peers by the background task.
+#[derive(Debug)]
+enum Command {
+    Acquire(u64),
+    Charge(u64),
+    /// The change and the time it was applied locally.
+    Reconfigure(Reconfiguration, time::OffsetDateTime),
+}
+
+/// Interval of [`Strategy::on_tick`].
+const TICK_INTERVAL: std::time::Duration = std::time::Duration::from_secs(1);
 
 /// A distributed storage that under the hood stores the state in the local `InMemoryStorage`
 /// and sends messages to the rest of the distributed storages via UDP messages on each tokens acquiring,
@@ -32,12 +47,14 @@ type AcquireRx = mpsc::UnboundedReceiver<u32>;
 /// # Available strategies:
 /// - [`WhitelistStrategy`]
 ///
+/// Charges and changes of the rate and capacity are sent to peers too.
+///
 /// # Example
 /// See usage examples in strategies above.
 ///
 /// [`WhitelistStrategy`]: crate::distributed::whitelist::WhitelistStrategy
 pub struct DistributedStorage {
-    tx: AcquireTx,
+    tx: CommandTx,
     storage: Arc<InMemoryStorage>,
     listen_addr: SocketAddr,
 }
@@ -48,7 +65,7 @@ impl DistributedStorage {
     ///
     /// # Errors
     ///
-    /// Will return `Err` if failed to resolve listen address.
+    /// Will return `Err` if `rps_limit` is zero or if failed to resolve listen address.
     pub async fn serve<A, S>(
         rps_limit: u32,
         listen_addr: A,
@@ -58,38 +75,183 @@ impl DistributedStorage {
         A: ToSocketAddrs,
         S: Strategy + Send + 'static,
     {
-        let listen_addr = listen_addr.to_socket_addrs()?.collect::<Vec<_>>();
+        Self::builder(rps_limit, listen_addr, strategy)
+            .serve()
+            .await
+    }
+
+    /// Creates a builder of storage. Needs for customizing of capacity and initial fill.
+    pub fn builder<A, S>(
+        rps_limit: u32,
+        listen_addr: A,
+        strategy: S,
+    ) -> DistributedStorageBuilder<A, S>
+    where
+        A: ToSocketAddrs,
+        S: Strategy + Send + 'static,
+    {
+        DistributedStorageBuilder {
+            rps_limit,
+            listen_addr,
+            strategy,
+            cap: None,
+            initial_fill: InitialFill::Full,
+            refill_tick: None,
+        }
+    }
+
+    /// Get listen address.
+    pub fn listen_addr(&self) -> SocketAddr {
+        self.listen_addr
+    }
+
+    /// Changes the rate to a token per `refill_tick` locally and sends the change to peers,
+    /// like [`set_rate`](Storage::set_rate) but for any rate.
+    #[cfg(feature = "registry")]
+    pub(crate) fn set_refill_tick(
+        &self,
+        refill_tick: time::Duration,
+    ) -> Result<(), ReconfigureError<DistributedStorageError>> {
+        let changed_ts = time::OffsetDateTime::now_utc();
+        self.storage
+            .set_refill_tick(refill_tick)
+            .map_err(|err| err.map(DistributedStorageError::from))?;
+        self.send(Command::Reconfigure(
+            Reconfiguration::RefillTick {
+                nanos: refill_tick.whole_nanoseconds() as u64,
+            },
+            changed_ts,
+        ));
+        Ok(())
+    }
+
+    fn send(&self, command: Command) {
+        self.tx
+            .send(command)
+            .expect("sending command to background task failed, this is a bug");
+    }
+}
+
+pub struct DistributedStorageBuilder<A, S> {
+    rps_limit: u32,
+    listen_addr: A,
+    strategy: S,
+    cap: Option<u64>,
+    initial_fill: InitialFill,
+    refill_tick: Option<time::Duration>,
+}
+
+impl<A, S> DistributedStorageBuilder<A, S>
+where
+    A: ToSocketAddrs,
+    S: Strategy + Send + 'static,
+{
+    /// Customize capacity of the bucket, i.e. the largest burst. Equal to the rate by default.
+    ///
+    /// Peers should use the same capacity.
+    pub fn with_capacity(mut self, cap: u64) -> Self {
+        self.cap = Some(cap);
+        self
+    }
+
+    /// Customize tokens of the local bucket on start, full by default.
+    pub fn with_initial_fill(mut self, initial_fill: InitialFill) -> Self {
+        self.initial_fill = initial_fill;
+        self
+    }
+
+    /// Refill a token per `refill_tick` instead of the rate, e.g. for a quota per minute.
+    #[cfg(feature = "registry")]
+    pub(crate) fn with_refill_tick(mut self, refill_tick: time::Duration) -> Self {
+        self.refill_tick = Some(refill_tick);
+        self
+    }
+
+    /// Creates a distributed storage and starts a background task that will listen a UDP socket.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the rate or the capacity is zero or if failed to resolve listen address.
+    pub async fn serve(self) -> Result<DistributedStorage, DistributedStorageError> {
+        let mut storage =
+            InMemoryStorage::builder(self.rps_limit).with_initial_fill(self.initial_fill);
+        if let Some(cap) = self.cap {
+            storage = storage.with_capacity(cap);
+        }
+        if let Some(refill_tick) = self.refill_tick {
+            storage = storage.with_refill_tick(refill_tick);
+        }
+        let storage = Arc::new(storage.try_build()?);
+
+        let listen_addr = self.listen_addr.to_socket_addrs()?.collect::<Vec<_>>();
         let socket = UdpSocket::bind(listen_addr.as_slice()).await?;
         let listen_addr = socket.local_addr()?;
 
-        let storage = Arc::new(InMemoryStorage::new(rps_limit));
         let (tx, rx) = mpsc::unbounded_channel();
         tokio::spawn(
-            processing::process(socket, strategy, Arc::clone(&storage), rx)
+            processing::process(socket, self.strategy, Arc::clone(&storage), rx)
                 .instrument(tracing::Span::current()),
         );
 
-        Ok(Self {
+        Ok(DistributedStorage {
             tx,
             storage,
             listen_addr,
         })
     }
-
-    /// Get listen address.
-    pub fn listen_addr(&self) -> SocketAddr {
-        self.listen_addr
-    }
 }
 
 impl Storage for DistributedStorage {
     type Error = DistributedStorageError;
 
-    fn try_acquire(&self, alg: TokenBucketAlgorithm, permits: u32) -> Result<(), Self::Error> {
+    fn try_acquire(&self, alg: TokenBucketAlgorithm, permits: u64) -> Result<(), Self::Error> {
         self.storage.try_acquire(alg, permits)?;
-        self.tx
-            .send(permits)
-            .expect("sending permits to background task failed, this is a bug");
+        // Overdraft may take more tokens than peers have, so they are charged exactly
+        if alg.mode() == Mode::Overdraft {
+            self.send(Command::Charge(permits));
+        } else {
+            self.send(Command::Acquire(permits));
+        }
+        Ok(())
+    }
+
+    /// Charges the bucket locally and sends the charge to peers.
+    fn charge(&self, cost: u64) -> Result<(), Self::Error> {
+        self.storage.charge(cost)?;
+        self.send(Command::Charge(cost));
+        Ok(())
+    }
+
+    /// Changes the rate locally and sends the change to peers.
+    fn set_rate(&self, rps_limit: u32) -> Result<(), ReconfigureError<Self::Error>> {
+        let changed_ts = time::OffsetDateTime::now_utc();
+        self.storage
+            .set_rate(rps_limit)
+            .map_err(|err| err.map(DistributedStorageError::from))?;
+        self.send(Command::Reconfigure(
+            Reconfiguration::Rate { rps_limit },
+            changed_ts,
+        ));
+        Ok(())
+    }
+
+    /// Changes the capacity locally and sends the change to peers.
+    fn set_capacity(
+        &self,
+        cap: u64,
+        policy: ResizePolicy,
+    ) -> Result<(), ReconfigureError<Self::Error>> {
+        let changed_ts = time::OffsetDateTime::now_utc();
+        self.storage
+            .set_capacity(cap, policy)
+            .map_err(|err| err.map(DistributedStorageError::from))?;
+        self.send(Command::Reconfigure(
+            Reconfiguration::Capacity {
+                cap,
+                scale: policy == ResizePolicy::Scale,
+            },
+            changed_ts,
+        ));
         Ok(())
     }
 }
@@ -98,7 +260,28 @@ impl Storage for DistributedStorage {
 pub trait Strategy: private::Sealed {
     async fn on_acquire(
         &mut self,
-        permits: u32,
+        permits: u64,
+        framed: &mut UdpFramed<Codec>,
+    ) -> Result<(), DistributedStorageError>;
+
+    async fn on_charge(
+        &mut self,
+        cost: u64,
+        framed: &mut UdpFramed<Codec>,
+    ) -> Result<(), DistributedStorageError>;
+
+    /// Called with a change applied locally at `changed_ts`.
+    async fn on_reconfigure(
+        &mut self,
+        change: Reconfiguration,
+        changed_ts: time::OffsetDateTime,
+        storage: &InMemoryStorage,
+        framed: &mut UdpFramed<Codec>,
+    ) -> Result<(), DistributedStorageError>;
+
+    /// Called periodically, e.g. to send again messages that peers may have missed.
+    async fn on_tick(
+        &mut self,
         framed: &mut UdpFramed<Codec>,
     ) -> Result<(), DistributedStorageError>;
 
@@ -1,5 +1,5 @@
 use crate::distributed::codec::Codec;
-use crate::distributed::{AcquireRx, Strategy};
+use crate::distributed::{Command, CommandRx, Strategy, TICK_INTERVAL};
 use crate::InMemoryStorage;
 
 use futures::StreamExt;
@@ -11,27 +11,46 @@ pub(super) async fn process<S1>(
     socket: UdpSocket,
     mut strategy: S1,
     storage: Arc<InMemoryStorage>,
-    mut acq_rx: AcquireRx,
+    mut cmd_rx: CommandRx,
 ) where
     S1: Strategy,
 {
     tracing::debug!("start background task");
     let mut framed = UdpFramed::new(socket, Codec::default());
+    let mut ticks = tokio::time::interval(TICK_INTERVAL);
+    ticks.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
 
     loop {
         tokio::select! {
-            res = acq_rx.recv() => {
+            res = cmd_rx.recv() => {
                 match res {
-                    Some(permits) => {
+                    Some(Command::Acquire(permits)) => {
                         tracing::debug!("received acquiring of {} permits", permits);
                         if let Err(err) = strategy.on_acquire(permits, &mut framed).await {
                             tracing::error!("processing of acquiring failed: {}", err);
                         }
                     }
+                    Some(Command::Charge(cost)) => {
+                        tracing::debug!("received charging of {} tokens", cost);
+                        if let Err(err) = strategy.on_charge(cost, &mut framed).await {
+                            tracing::error!("processing of charging failed: {}", err);
+                        }
+                    }
+                    Some(Command::Reconfigure(change, changed_ts)) => {
+                        tracing::debug!("received reconfiguration {:?}", change);
+                        if let Err(err) = strategy.on_reconfigure(change, changed_ts, &storage, &mut framed).await {
+                            tracing::error!("processing of reconfiguration failed: {}", err);
+                        }
+                    }
                     // Channel closed
                     None => break,
                 }
             }
+            _ = ticks.tick() => {
+                if let Err(err) = strategy.on_tick(&mut framed).await {
+                    tracing::error!("processing of tick failed: {}", err);
+                }
+            }
             res = framed.next() => {
                 let res = res.expect("received None from udp, this is a bug");
                 match res {
@@ -1,7 +1,9 @@
 use crate::distributed::codec::Codec;
-use crate::distributed::message::{Content, ContentKind, Message, WhitelistContent};
+use crate::distributed::message::{
+    Content, Message, Reconfiguration, ReconfigureContent, WhitelistContent,
+};
 use crate::error::DistributedStorageError;
-use crate::{InMemoryStorage, Mode, Storage, Strategy, TokenBucketAlgorithm};
+use crate::{InMemoryStorage, Mode, ResizePolicy, Storage, Strategy, TokenBucketAlgorithm};
 
 use futures::SinkExt;
 use std::collections::HashSet;
@@ -12,6 +14,10 @@ const MAX_TS_DIFF: time::Duration = time::Duration::seconds(5);
 
 /// Strategy that receives messages only from whitelisted peers and sends messages only to them.
 ///
+/// Changes of the rate and capacity are applied in order of making them, local ones included,
+/// a change older than the last applied one of the same kind is skipped. The last changes
+/// are sent to peers again every second, so peers that missed them (e.g. restarted ones) catch up.
+///
 /// # Example
 /// ```
 /// use tocket::{TokenBucket, DistributedStorage, WhitelistStrategy};
@@ -53,6 +59,8 @@ const MAX_TS_DIFF: time::Duration = time::Duration::seconds(5);
 /// ```
 pub struct WhitelistStrategy {
     peers: HashSet<SocketAddr>,
+    last_rate: Option<ReconfigureContent>,
+    last_capacity: Option<ReconfigureContent>,
 }
 
 impl WhitelistStrategy {
@@ -77,27 +85,126 @@ impl WhitelistStrategy {
             })
             .collect::<Result<HashSet<_>, _>>()?;
 
-        Ok(Self { peers })
+        Ok(Self {
+            peers,
+            last_rate: None,
+            last_capacity: None,
+        })
+    }
+
+    async fn send(
+        &self,
+        msg: Message,
+        framed: &mut UdpFramed<Codec>,
+    ) -> Result<(), DistributedStorageError> {
+        for peer in &self.peers {
+            framed.send((msg.clone(), *peer)).await?;
+            tracing::debug!("sent message to peer {}: {:?}", peer, msg);
+        }
+
+        Ok(())
+    }
+
+    /// Applies the change unless a later one of the same kind is already applied.
+    fn reconfigure(&mut self, content: ReconfigureContent, storage: &InMemoryStorage) {
+        let last = match content.change {
+            Reconfiguration::Rate { .. } | Reconfiguration::RefillTick { .. } => {
+                &mut self.last_rate
+            }
+            Reconfiguration::Capacity { .. } => &mut self.last_capacity,
+        };
+        match last {
+            // Sent again
+            Some(last) if content.changed_ts == last.changed_ts => return,
+            Some(last) if content.changed_ts < last.changed_ts => {
+                tracing::warn!("received outdated reconfiguration, skip it");
+                return;
+            }
+            _ => {}
+        }
+        *last = Some(content.clone());
+
+        let res = match content.change {
+            Reconfiguration::Rate { rps_limit } => storage.set_rate(rps_limit),
+            Reconfiguration::RefillTick { nanos } => {
+                storage.set_refill_tick(time::Duration::nanoseconds_i128(nanos.into()))
+            }
+            Reconfiguration::Capacity { cap, scale } => {
+                let policy = if scale {
+                    ResizePolicy::Scale
+                } else {
+                    ResizePolicy::Clamp
+                };
+                storage.set_capacity(cap, policy)
+            }
+        };
+        if let Err(err) = res {
+            tracing::error!("reconfiguration {:?} failed: {}", content.change, err);
+        }
     }
 }
 
+fn is_expired(sent_ts: time::OffsetDateTime) -> bool {
+    let now = time::OffsetDateTime::now_utc();
+    sent_ts < now - MAX_TS_DIFF || sent_ts > now
+}
+
 #[async_trait::async_trait]
 impl Strategy for WhitelistStrategy {
     async fn on_acquire(
         &mut self,
-        permits: u32,
+        permits: u64,
         framed: &mut UdpFramed<Codec>,
     ) -> Result<(), DistributedStorageError> {
         let msg = Message::new(Content::Whitelist(WhitelistContent {
             sent_ts: time::OffsetDateTime::now_utc(),
             permits,
         }));
+        self.send(msg, framed).await
+    }
 
-        for peer in &self.peers {
-            framed.send((msg.clone(), *peer)).await?;
-            tracing::debug!("sent message to peer {}: {:?}", peer, msg);
-        }
+    async fn on_charge(
+        &mut self,
+        cost: u64,
+        framed: &mut UdpFramed<Codec>,
+    ) -> Result<(), DistributedStorageError> {
+        let msg = Message::new(Content::Charge(WhitelistContent {
+            sent_ts: time::OffsetDateTime::now_utc(),
+            permits: cost,
+        }));
+        self.send(msg, framed).await
+    }
+
+    async fn on_reconfigure(
+        &mut self,
+        change: Reconfiguration,
+        changed_ts: time::OffsetDateTime,
+        storage: &InMemoryStorage,
+        framed: &mut UdpFramed<Codec>,
+    ) -> Result<(), DistributedStorageError> {
+        let content = ReconfigureContent {
+            sent_ts: time::OffsetDateTime::now_utc(),
+            changed_ts,
+            change,
+        };
+        // Already applied, but an older change of a peer could be applied in the meantime
+        self.reconfigure(content.clone(), storage);
+        self.send(Message::new(Content::Reconfigure(content)), framed)
+            .await
+    }
 
+    async fn on_tick(
+        &mut self,
+        framed: &mut UdpFramed<Codec>,
+    ) -> Result<(), DistributedStorageError> {
+        let last = [self.last_rate.clone(), self.last_capacity.clone()];
+        for content in last.into_iter().flatten() {
+            let msg = Message::new(Content::Reconfigure(ReconfigureContent {
+                sent_ts: time::OffsetDateTime::now_utc(),
+                ..content
+            }));
+            self.send(msg, framed).await?;
+        }
         Ok(())
     }
 
@@ -112,12 +219,9 @@ impl Strategy for WhitelistStrategy {
             return Err(DistributedStorageError::PeerNotWhitelisted { peer: source });
         }
 
-        // TODO: remove allowing when add another one strategy
-        #[allow(unreachable_patterns)]
         match msg.content {
             Content::Whitelist(content) => {
-                let now = time::OffsetDateTime::now_utc();
-                if content.sent_ts < now - MAX_TS_DIFF || content.sent_ts > now {
+                if is_expired(content.sent_ts) {
                     tracing::warn!("received expired message, skip it");
                     return Ok(());
                 }
@@ -125,10 +229,24 @@ impl Strategy for WhitelistStrategy {
                 storage.try_acquire(TokenBucketAlgorithm { mode: Mode::All }, content.permits)?;
                 Ok(())
             }
-            x => Err(DistributedStorageError::MessageContentMismatch {
-                exp: ContentKind::Whitelist,
-                act: x.kind(),
-            }),
+            Content::Charge(content) => {
+                if is_expired(content.sent_ts) {
+                    tracing::warn!("received expired message, skip it");
+                    return Ok(());
+                }
+
+                storage.charge(content.permits)?;
+                Ok(())
+            }
+            Content::Reconfigure(content) => {
+                if is_expired(content.sent_ts) {
+                    tracing::warn!("received expired message, skip it");
+                    return Ok(());
+                }
+
+                self.reconfigure(content, storage);
+                Ok(())
+            }
         }
     }
 }
@@ -136,7 +254,8 @@ impl Strategy for WhitelistStrategy {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::{DistributedStorage, TokenBucket};
+    use crate::distributed::TICK_INTERVAL;
+    use crate::{ConfigError, DistributedStorage, InitialFill, StorageError, TokenBucket};
     use std::time::Duration;
 
     async fn make_token_bucket<I, S>(port: u16, peers: I) -> TokenBucket<DistributedStorage>
@@ -155,6 +274,32 @@ mod tests {
         TokenBucket::new(storage)
     }
 
+    #[test]
+    fn protocol_version() {
+        use tokio_util::codec::{Decoder, Encoder};
+
+        let content = Content::Charge(WhitelistContent {
+            sent_ts: time::OffsetDateTime::now_utc(),
+            permits: 1,
+        });
+        let mut codec = Codec::default();
+        let mut buf = bytes::BytesMut::new();
+        codec
+            .encode(Message::new(content.clone()), &mut buf)
+            .unwrap();
+        let msg = codec.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(msg.content, content);
+
+        // Older versions sent the version of the crate
+        let mut old = Message::new(content);
+        old.version = "0.2.1".into();
+        codec.encode(old, &mut buf).unwrap();
+        assert!(matches!(
+            codec.decode(&mut buf),
+            Err(DistributedStorageError::ProtocolVersionMismatch { act, .. }) if act == "0.2.1"
+        ));
+    }
+
     #[tokio::test]
     async fn try_acquire_single() {
         let tb = make_token_bucket(0, Vec::<String>::new()).await;
@@ -197,4 +342,112 @@ mod tests {
         assert!(tb2.try_acquire_one().is_err());
         assert!(tb3.try_acquire_one().is_err());
     }
+
+    #[tokio::test]
+    async fn initial_fill() {
+        let storage = DistributedStorage::builder(
+            2,
+            "0.0.0.0:0",
+            WhitelistStrategy::new(Vec::<String>::new()).unwrap(),
+        )
+        .with_initial_fill(InitialFill::Empty)
+        .serve()
+        .await
+        .unwrap();
+
+        let tb = TokenBucket::new(storage);
+        assert!(tb.try_acquire_one().is_err());
+    }
+
+    #[tokio::test]
+    async fn validation() {
+        let storage = DistributedStorage::serve(
+            0,
+            "0.0.0.0:0",
+            WhitelistStrategy::new(Vec::<String>::new()).unwrap(),
+        )
+        .await;
+        assert!(matches!(
+            storage,
+            Err(DistributedStorageError::ConfigError(ConfigError::ZeroRate))
+        ));
+
+        let storage = DistributedStorage::builder(
+            2,
+            "0.0.0.0:0",
+            WhitelistStrategy::new(Vec::<String>::new()).unwrap(),
+        )
+        .with_capacity(4)
+        .serve()
+        .await
+        .unwrap();
+        let tb = TokenBucket::new(storage);
+        assert!(tb.try_acquire(5).unwrap_err().is_capacity_exceeded());
+        assert!(tb.try_acquire(4).is_ok());
+    }
+
+    #[tokio::test]
+    async fn reconfigure() {
+        let tb1 = make_token_bucket(49011, vec!["127.0.0.1:49012"]).await;
+        let tb2 = make_token_bucket(49012, vec!["127.0.0.1:49011"]).await;
+
+        assert!(tb1.set_capacity(10, ResizePolicy::Scale).is_ok());
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(tb2.try_acquire(10).is_ok());
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(tb1.try_acquire_one().is_err());
+
+        assert!(tb2.set_rate(1000).is_ok());
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(tb1.try_acquire(10).is_ok());
+    }
+
+    #[test]
+    fn local_change_is_newer() {
+        let mut strategy = WhitelistStrategy::new(Vec::<String>::new()).unwrap();
+        let storage = InMemoryStorage::new(2);
+        let now = time::OffsetDateTime::now_utc();
+        let change = |cap, changed_ts| ReconfigureContent {
+            sent_ts: time::OffsetDateTime::now_utc(),
+            changed_ts,
+            change: Reconfiguration::Capacity { cap, scale: true },
+        };
+
+        // Applied locally, then an older change of a peer arrives
+        assert!(storage.set_capacity(10, ResizePolicy::Scale).is_ok());
+        strategy.reconfigure(change(10, now), &storage);
+        strategy.reconfigure(change(4, now - time::Duration::seconds(1)), &storage);
+
+        let alg = TokenBucketAlgorithm { mode: Mode::N };
+        assert!(storage.try_acquire(alg, 10).is_ok());
+    }
+
+    #[tokio::test]
+    async fn restarted_peer_catches_up() {
+        let tb1 = make_token_bucket(49031, vec!["127.0.0.1:49032"]).await;
+        assert!(tb1.set_capacity(10, ResizePolicy::Scale).is_ok());
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        // Starts after the change was sent
+        let tb2 = make_token_bucket(49032, vec!["127.0.0.1:49031"]).await;
+        assert!(tb2.try_acquire(10).unwrap_err().is_capacity_exceeded());
+        tokio::time::sleep(TICK_INTERVAL + Duration::from_millis(200)).await;
+        assert!(tb2.try_acquire(10).is_ok());
+    }
+
+    #[tokio::test]
+    async fn charge() {
+        let tb1 = make_token_bucket(49021, vec!["127.0.0.1:49022"]).await;
+        let tb2 = make_token_bucket(49022, vec!["127.0.0.1:49021"]).await;
+
+        // Peers are charged exactly, not just emptied
+        assert!(tb1.try_acquire_overdraft(3).is_ok());
+        assert!(tb1.charge(2).is_ok());
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(tb2.try_acquire_overdraft(1).is_err());
+
+        // Debt of 3 tokens is repaid in 1.5 seconds
+        tokio::time::sleep(Duration::from_millis(1000)).await;
+        assert!(tb2.try_acquire_overdraft(1).is_err());
+    }
 }
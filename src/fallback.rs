@@ -0,0 +1,438 @@
+use crate::{
+    InMemoryStorage, RateLimitExceededError, ReconfigureError, ResizePolicy, Storage,
+    TokenBucketAlgorithm,
+};
+
+use std::time::{Duration, Instant};
+
+/// Default number of consecutive failures of the primary storage that opens the circuit.
+pub const DEFAULT_FAILURE_THRESHOLD: u32 = 3;
+/// Default time the primary storage is not called after the circuit is opened.
+pub const DEFAULT_OPEN_DURATION: Duration = Duration::from_secs(5);
+
+/// Error of a storage that tells denial of the rate limiter from failures of the storage itself.
+pub trait StorageError {
+    /// Returns `true` if the error means there are not enough tokens.
+    fn is_rate_limit_exceeded(&self) -> bool;
+
+    /// Returns `true` if the request needs more tokens than the bucket can hold,
+    /// so retrying it never succeeds.
+    fn is_capacity_exceeded(&self) -> bool {
+        false
+    }
+}
+
+impl StorageError for RateLimitExceededError {
+    fn is_rate_limit_exceeded(&self) -> bool {
+        true
+    }
+
+    fn is_capacity_exceeded(&self) -> bool {
+        matches!(self, RateLimitExceededError::ExceedsCapacity { .. })
+    }
+}
+
+/// Which storage serves requests at the moment.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum FallbackMode {
+    /// The primary storage is healthy.
+    Primary,
+    /// The primary storage failed, requests are served by the fallback.
+    Fallback,
+    /// A request is checking if the primary storage is restored,
+    /// others are served by the fallback.
+    Probing,
+}
+
+enum Fallback<S> {
+    Storage(S),
+    Open,
+    Closed,
+}
+
+#[derive(Default)]
+struct Circuit {
+    failures: u32,
+    opened_at: Option<Instant>,
+    probing: bool,
+}
+
+/// A storage that falls back to another storage or policy while the primary one fails.
+///
+/// The primary storage is tracked by a circuit breaker: after
+/// [`with_failure_threshold`](FallbackStorage::with_failure_threshold) consecutive failures
+/// it is not called for [`with_open_duration`](FallbackStorage::with_open_duration),
+/// then a single request checks if it's restored. Denials of the primary storage are not failures.
+///
+/// While the primary storage fails, requests are served by the secondary storage
+/// (e.g. [`InMemoryStorage`] with the rate divided by the number of application instances),
+/// allowed ([`fail_open`](FallbackStorage::fail_open))
+/// or denied ([`fail_closed`](FallbackStorage::fail_closed)).
+///
+/// # Example
+/// ```no_run
+/// # #[cfg(feature = "redis-impl")]
+/// # {
+/// use tocket::{FallbackStorage, InMemoryStorage, RedisStorage, TokenBucket};
+///
+/// let instances = 4;
+/// let storage = FallbackStorage::new(
+///     RedisStorage::new(100, "redis://127.0.0.1:6379").unwrap(),
+///     InMemoryStorage::new(100 / instances),
+/// );
+///
+/// let tb = TokenBucket::new(storage);
+/// assert!(tb.try_acquire_one().is_ok());
+/// # }
+/// ```
+pub struct FallbackStorage<P, S = InMemoryStorage> {
+    primary: P,
+    fallback: Fallback<S>,
+    circuit: parking_lot::Mutex<Circuit>,
+    failure_threshold: u32,
+    open_duration: Duration,
+}
+
+impl<P, S> FallbackStorage<P, S> {
+    /// Creates a storage that uses `secondary` while `primary` fails.
+    pub fn new(primary: P, secondary: S) -> Self {
+        Self::with_fallback(primary, Fallback::Storage(secondary))
+    }
+
+    fn with_fallback(primary: P, fallback: Fallback<S>) -> Self {
+        Self {
+            primary,
+            fallback,
+            circuit: Default::default(),
+            failure_threshold: DEFAULT_FAILURE_THRESHOLD,
+            open_duration: DEFAULT_OPEN_DURATION,
+        }
+    }
+
+    /// Customize number of consecutive failures that switches to the fallback.
+    pub fn with_failure_threshold(mut self, threshold: u32) -> Self {
+        self.failure_threshold = threshold.max(1);
+        self
+    }
+
+    /// Customize time until the primary storage is checked again after switching to the fallback.
+    pub fn with_open_duration(mut self, duration: Duration) -> Self {
+        self.open_duration = duration;
+        self
+    }
+
+    /// Returns which storage serves requests at the moment.
+    pub fn mode(&self) -> FallbackMode {
+        let circuit = self.circuit.lock();
+        match circuit.opened_at {
+            None => FallbackMode::Primary,
+            Some(_) if circuit.probing => FallbackMode::Probing,
+            Some(_) => FallbackMode::Fallback,
+        }
+    }
+
+    /// Returns `true` if the request should be sent to the primary storage.
+    fn use_primary(&self) -> bool {
+        let mut circuit = self.circuit.lock();
+        match circuit.opened_at {
+            None => true,
+            Some(opened_at) if !circuit.probing && opened_at.elapsed() >= self.open_duration => {
+                circuit.probing = true;
+                true
+            }
+            Some(_) => false,
+        }
+    }
+
+    fn on_success(&self) {
+        let mut circuit = self.circuit.lock();
+        if circuit.opened_at.is_some() {
+            tracing::info!("primary storage restored");
+        }
+        *circuit = Circuit::default();
+    }
+
+    fn on_failure<E: std::fmt::Display>(&self, err: &E) {
+        let mut circuit = self.circuit.lock();
+        circuit.failures = circuit.failures.saturating_add(1);
+        if circuit.probing || circuit.failures == self.failure_threshold {
+            tracing::warn!(
+                "primary storage failed, switching to fallback for {:?}: {}",
+                self.open_duration,
+                err
+            );
+            circuit.opened_at = Some(Instant::now());
+            circuit.probing = false;
+        }
+    }
+}
+
+impl<P> FallbackStorage<P> {
+    /// Creates a storage that allows all requests while `primary` fails.
+    pub fn fail_open(primary: P) -> Self {
+        Self::with_fallback(primary, Fallback::Open)
+    }
+
+    /// Creates a storage that denies all requests while `primary` fails.
+    pub fn fail_closed(primary: P) -> Self {
+        Self::with_fallback(primary, Fallback::Closed)
+    }
+}
+
+impl<P, S> Storage for FallbackStorage<P, S>
+where
+    P: Storage,
+    P::Error: StorageError + std::fmt::Display,
+    S: Storage,
+{
+    type Error = FallbackStorageError<P::Error, S::Error>;
+
+    fn try_acquire(&self, alg: TokenBucketAlgorithm, permits: u64) -> Result<(), Self::Error> {
+        let primary_err = if self.use_primary() {
+            match self.primary.try_acquire(alg, permits) {
+                Ok(()) => {
+                    self.on_success();
+                    return Ok(());
+                }
+                Err(err) if err.is_rate_limit_exceeded() => {
+                    self.on_success();
+                    return Err(FallbackStorageError::Primary(err));
+                }
+                Err(err) => {
+                    self.on_failure(&err);
+                    Some(err)
+                }
+            }
+        } else {
+            None
+        };
+
+        match &self.fallback {
+            Fallback::Storage(secondary) => secondary
+                .try_acquire(alg, permits)
+                .map_err(FallbackStorageError::Secondary),
+            Fallback::Open => Ok(()),
+            Fallback::Closed => Err(primary_err.map_or(
+                FallbackStorageError::Unavailable,
+                FallbackStorageError::Primary,
+            )),
+        }
+    }
+
+    fn release(&self, permits: u64) -> Result<(), Self::Error> {
+        match (&self.fallback, self.mode()) {
+            (_, FallbackMode::Primary) => self
+                .primary
+                .release(permits)
+                .map_err(FallbackStorageError::Primary),
+            (Fallback::Storage(secondary), _) => secondary
+                .release(permits)
+                .map_err(FallbackStorageError::Secondary),
+            _ => Ok(()),
+        }
+    }
+
+    /// Charges the storage in use, like [`release`](Storage::release).
+    fn charge(&self, cost: u64) -> Result<(), Self::Error> {
+        match (&self.fallback, self.mode()) {
+            (_, FallbackMode::Primary) => self
+                .primary
+                .charge(cost)
+                .map_err(FallbackStorageError::Primary),
+            (Fallback::Storage(secondary), _) => secondary
+                .charge(cost)
+                .map_err(FallbackStorageError::Secondary),
+            _ => Ok(()),
+        }
+    }
+
+    /// Reconfigures the primary storage only, the secondary one keeps its own limit.
+    fn set_rate(&self, rps_limit: u32) -> Result<(), ReconfigureError<Self::Error>> {
+        self.primary
+            .set_rate(rps_limit)
+            .map_err(|err| err.map(FallbackStorageError::Primary))
+    }
+
+    /// Reconfigures the primary storage only, the secondary one keeps its own limit.
+    fn set_capacity(
+        &self,
+        cap: u64,
+        policy: ResizePolicy,
+    ) -> Result<(), ReconfigureError<Self::Error>> {
+        self.primary
+            .set_capacity(cap, policy)
+            .map_err(|err| err.map(FallbackStorageError::Primary))
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum FallbackStorageError<P, S> {
+    #[error(transparent)]
+    Primary(P),
+    #[error(transparent)]
+    Secondary(S),
+    #[error(transparent)]
+    RateLimitExceededError(#[from] RateLimitExceededError),
+    #[error("primary storage is unavailable")]
+    Unavailable,
+}
+
+impl<P, S> StorageError for FallbackStorageError<P, S>
+where
+    P: StorageError,
+    S: StorageError,
+{
+    fn is_rate_limit_exceeded(&self) -> bool {
+        match self {
+            FallbackStorageError::Primary(err) => err.is_rate_limit_exceeded(),
+            FallbackStorageError::Secondary(err) => err.is_rate_limit_exceeded(),
+            FallbackStorageError::RateLimitExceededError(_) => true,
+            FallbackStorageError::Unavailable => false,
+        }
+    }
+
+    fn is_capacity_exceeded(&self) -> bool {
+        match self {
+            FallbackStorageError::Primary(err) => err.is_capacity_exceeded(),
+            FallbackStorageError::Secondary(err) => err.is_capacity_exceeded(),
+            FallbackStorageError::RateLimitExceededError(err) => err.is_capacity_exceeded(),
+            FallbackStorageError::Unavailable => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::TokenBucket;
+
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    #[derive(Debug, thiserror::Error)]
+    enum FlakyError {
+        #[error(transparent)]
+        RateLimitExceeded(#[from] RateLimitExceededError),
+        #[error("storage is down")]
+        Down,
+    }
+
+    impl StorageError for FlakyError {
+        fn is_rate_limit_exceeded(&self) -> bool {
+            matches!(self, FlakyError::RateLimitExceeded(_))
+        }
+    }
+
+    struct Flaky {
+        down: Arc<AtomicBool>,
+        storage: InMemoryStorage,
+    }
+
+    impl Storage for Flaky {
+        type Error = FlakyError;
+
+        fn try_acquire(&self, alg: TokenBucketAlgorithm, permits: u64) -> Result<(), Self::Error> {
+            if self.down.load(Ordering::Relaxed) {
+                return Err(FlakyError::Down);
+            }
+            Ok(self.storage.try_acquire(alg, permits)?)
+        }
+
+        fn charge(&self, cost: u64) -> Result<(), Self::Error> {
+            if self.down.load(Ordering::Relaxed) {
+                return Err(FlakyError::Down);
+            }
+            Ok(self.storage.charge(cost)?)
+        }
+    }
+
+    fn flaky(rps_limit: u32) -> (Flaky, Arc<AtomicBool>) {
+        let down = Arc::new(AtomicBool::new(false));
+        let storage = Flaky {
+            down: Arc::clone(&down),
+            storage: InMemoryStorage::new(rps_limit),
+        };
+        (storage, down)
+    }
+
+    #[test]
+    fn fallback_to_secondary() {
+        let (primary, down) = flaky(100);
+        let storage = FallbackStorage::new(primary, InMemoryStorage::new(2))
+            .with_failure_threshold(2)
+            .with_open_duration(Duration::from_millis(200));
+        let tb = TokenBucket::new(storage);
+
+        // Denials don't open the circuit
+        assert!(tb.try_acquire(100).is_ok());
+        assert!(matches!(
+            tb.try_acquire_one(),
+            Err(FallbackStorageError::Primary(
+                FlakyError::RateLimitExceeded(_)
+            ))
+        ));
+
+        down.store(true, Ordering::Relaxed);
+        assert!(tb.try_acquire_one().is_ok());
+        assert!(tb.try_acquire_one().is_ok());
+        assert!(matches!(
+            tb.try_acquire_one(),
+            Err(FallbackStorageError::Secondary(_))
+        ));
+
+        std::thread::sleep(Duration::from_millis(1100));
+        down.store(false, Ordering::Relaxed);
+        assert!(tb.try_acquire_one().is_ok());
+        assert!(tb.try_acquire_one().is_ok());
+    }
+
+    #[test]
+    fn circuit_breaker() {
+        let (primary, down) = flaky(100);
+        let storage = FallbackStorage::fail_closed(primary)
+            .with_failure_threshold(2)
+            .with_open_duration(Duration::from_millis(100));
+        let alg = || TokenBucketAlgorithm {
+            mode: crate::Mode::N,
+        };
+
+        down.store(true, Ordering::Relaxed);
+        assert!(matches!(
+            storage.try_acquire(alg(), 1),
+            Err(FallbackStorageError::Primary(FlakyError::Down))
+        ));
+        assert_eq!(storage.mode(), FallbackMode::Primary);
+        assert!(storage.try_acquire(alg(), 1).is_err());
+        assert_eq!(storage.mode(), FallbackMode::Fallback);
+
+        // The primary storage is not called while the circuit is open
+        down.store(false, Ordering::Relaxed);
+        assert!(matches!(
+            storage.try_acquire(alg(), 1),
+            Err(FallbackStorageError::Unavailable)
+        ));
+
+        // Failed probe opens the circuit again
+        std::thread::sleep(Duration::from_millis(150));
+        down.store(true, Ordering::Relaxed);
+        assert!(storage.try_acquire(alg(), 1).is_err());
+        assert_eq!(storage.mode(), FallbackMode::Fallback);
+
+        std::thread::sleep(Duration::from_millis(150));
+        down.store(false, Ordering::Relaxed);
+        assert!(storage.try_acquire(alg(), 1).is_ok());
+        assert_eq!(storage.mode(), FallbackMode::Primary);
+    }
+
+    #[test]
+    fn fail_open() {
+        let (primary, down) = flaky(1);
+        let storage = FallbackStorage::fail_open(primary).with_failure_threshold(1);
+        let tb = TokenBucket::new(storage);
+
+        down.store(true, Ordering::Relaxed);
+        for _ in 0..10 {
+            assert!(tb.try_acquire_one().is_ok());
+        }
+    }
+}
@@ -1,9 +1,19 @@
-use crate::{RateLimitExceededError, State, Storage, TokenBucketAlgorithm};
+use crate::composite::{self, CompositeLimitExceededError, CompositeStorage, Limit};
+use crate::{
+    refill_tick, validate_capacity, validate_tick, BucketConfig, ConfigError, InitialFill,
+    KeyedStorage, Mode, RateLimitExceededError, ReconfigureError, ResizePolicy, State, Storage,
+    StorageError, TokenBucketAlgorithm, WarmUp,
+};
+
+use std::collections::HashMap;
 
 /// A storage that stores state in memory.
 ///
 /// Useful for single application instance or for tests.
 ///
+/// The bucket holds as many tokens as are refilled per second, unless the capacity is
+/// customized by [`with_capacity`](InMemoryStorageBuilder::with_capacity).
+///
 /// # Example
 /// ```
 /// # fn main() {
@@ -22,35 +32,432 @@ pub struct InMemoryStorage {
 
 impl InMemoryStorage {
     /// Creates a storage.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `rps_limit` is zero, see [`try_new`](Self::try_new).
     pub fn new(rps_limit: u32) -> Self {
-        Self {
-            state: parking_lot::Mutex::new(State {
-                cap: rps_limit,
-                available_tokens: rps_limit,
-                last_refill: time::OffsetDateTime::now_utc(),
-                refill_tick: time::Duration::seconds(1) / rps_limit,
-            }),
+        Self::builder(rps_limit).build()
+    }
+
+    /// Creates a storage.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if `rps_limit` is zero.
+    pub fn try_new(rps_limit: u32) -> Result<Self, ConfigError> {
+        Self::builder(rps_limit).try_build()
+    }
+
+    /// Creates a builder of storage. Needs for customizing of capacity, initial fill and warm-up.
+    pub fn builder(rps_limit: u32) -> InMemoryStorageBuilder {
+        InMemoryStorageBuilder {
+            rps_limit,
+            cap: None,
+            initial_fill: InitialFill::Full,
+            warm_up: None,
+            refill_tick: None,
+        }
+    }
+
+    /// Returns a copy of the current state, e.g. to save it on shutdown.
+    pub fn snapshot(&self) -> State {
+        self.state.lock().clone()
+    }
+
+    /// Changes the rate to a token per `refill_tick`, like [`set_rate`](Storage::set_rate)
+    /// but for any rate.
+    pub(crate) fn set_refill_tick(
+        &self,
+        refill_tick: time::Duration,
+    ) -> Result<(), ReconfigureError<RateLimitExceededError>> {
+        let refill_tick = validate_tick(refill_tick)?;
+        self.state.lock().set_rate(refill_tick);
+        Ok(())
+    }
+
+    /// Restores tokens from a snapshot taken earlier, e.g. by another process before restart.
+    ///
+    /// Capacity and rate of the storage are kept, tokens are refilled for the time
+    /// since the snapshot was taken.
+    pub fn restore(&self, snapshot: &State) {
+        self.state.lock().restore(snapshot);
+    }
+}
+
+pub struct InMemoryStorageBuilder {
+    rps_limit: u32,
+    cap: Option<u64>,
+    initial_fill: InitialFill,
+    warm_up: Option<WarmUp>,
+    refill_tick: Option<time::Duration>,
+}
+
+impl InMemoryStorageBuilder {
+    /// Customize capacity of the bucket, i.e. the largest burst. Equal to the rate by default.
+    pub fn with_capacity(mut self, cap: u64) -> Self {
+        self.cap = Some(cap);
+        self
+    }
+
+    /// Customize tokens of the new bucket, full by default.
+    pub fn with_initial_fill(mut self, initial_fill: InitialFill) -> Self {
+        self.initial_fill = initial_fill;
+        self
+    }
+
+    /// Start the bucket cold and warm it up after idleness, see [`WarmUp`].
+    pub fn with_warm_up(mut self, warm_up: WarmUp) -> Self {
+        self.warm_up = Some(warm_up);
+        self
+    }
+
+    /// Refill a token per `refill_tick` instead of the rate, e.g. for a quota per minute.
+    #[cfg(any(feature = "registry", feature = "distributed-impl"))]
+    pub(crate) fn with_refill_tick(mut self, refill_tick: time::Duration) -> Self {
+        self.refill_tick = Some(refill_tick);
+        self
+    }
+
+    /// # Panics
+    ///
+    /// Panics if the configuration is invalid, see [`try_build`](Self::try_build).
+    pub fn build(self) -> InMemoryStorage {
+        match self.try_build() {
+            Ok(storage) => storage,
+            Err(err) => panic!("invalid configuration of in-memory storage: {}", err),
         }
     }
+
+    /// # Errors
+    ///
+    /// Will return `Err` if the rate or the capacity is zero.
+    pub fn try_build(self) -> Result<InMemoryStorage, ConfigError> {
+        let cap = self.cap.unwrap_or(self.rps_limit.into());
+        let config = BucketConfig::try_new(self.rps_limit, cap)?
+            .with_refill_tick(self.refill_tick)?
+            .with_initial_fill(self.initial_fill)
+            .with_warm_up(self.warm_up);
+        Ok(InMemoryStorage {
+            state: parking_lot::Mutex::new(config.new_state()),
+        })
+    }
 }
 
 impl Storage for InMemoryStorage {
     type Error = RateLimitExceededError;
 
-    fn try_acquire(&self, alg: TokenBucketAlgorithm, permits: u32) -> Result<(), Self::Error> {
+    fn try_acquire(&self, alg: TokenBucketAlgorithm, permits: u64) -> Result<(), Self::Error> {
         let mut state = self.state.lock();
         alg.try_acquire(&mut state, permits)?;
         Ok(())
     }
+
+    fn release(&self, permits: u64) -> Result<(), Self::Error> {
+        self.state.lock().release(permits);
+        Ok(())
+    }
+
+    fn charge(&self, cost: u64) -> Result<(), Self::Error> {
+        TokenBucketAlgorithm::new(Mode::N).charge(&mut self.state.lock(), cost);
+        Ok(())
+    }
+
+    fn set_rate(&self, rps_limit: u32) -> Result<(), ReconfigureError<Self::Error>> {
+        self.set_refill_tick(refill_tick(rps_limit)?)
+    }
+
+    fn set_capacity(
+        &self,
+        cap: u64,
+        policy: ResizePolicy,
+    ) -> Result<(), ReconfigureError<Self::Error>> {
+        let cap = validate_capacity(cap)?;
+        self.state.lock().set_capacity(cap, policy);
+        Ok(())
+    }
+}
+
+/// A storage that stores states of buckets of every key in memory.
+///
+/// Buckets are created on first use of the key, full unless the initial fill is customized.
+///
+/// # Example
+/// ```
+/// use tocket::{KeyedInMemoryStorage, KeyedTokenBucket};
+///
+/// let storage = KeyedInMemoryStorage::new(10);
+/// let tb = KeyedTokenBucket::new(storage);
+/// assert!(tb.try_acquire("alice", 5).is_ok());
+///
+/// // Restore buckets after restart
+/// let snapshot = tb.storage().snapshot();
+/// let restored = KeyedInMemoryStorage::new(10);
+/// restored.restore(snapshot);
+/// assert!(KeyedTokenBucket::new(restored).try_acquire("alice", 10).is_err());
+/// ```
+pub struct KeyedInMemoryStorage {
+    states: parking_lot::Mutex<HashMap<String, State>>,
+    /// Locked after `states`.
+    config: parking_lot::RwLock<BucketConfig>,
+}
+
+impl KeyedInMemoryStorage {
+    /// Creates a storage with the same limit for every key.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `rps_limit` is zero, see [`try_new`](Self::try_new).
+    pub fn new(rps_limit: u32) -> Self {
+        Self::builder(rps_limit).build()
+    }
+
+    /// Creates a storage with the same limit for every key.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if `rps_limit` is zero.
+    pub fn try_new(rps_limit: u32) -> Result<Self, ConfigError> {
+        Self::builder(rps_limit).try_build()
+    }
+
+    /// Creates a builder of storage. Needs for customizing of capacity, initial fill and warm-up.
+    pub fn builder(rps_limit: u32) -> KeyedInMemoryStorageBuilder {
+        KeyedInMemoryStorageBuilder {
+            rps_limit,
+            cap: None,
+            initial_fill: InitialFill::Full,
+            warm_up: None,
+        }
+    }
+
+    /// Returns copies of states of all buckets, e.g. to save them on shutdown.
+    pub fn snapshot(&self) -> HashMap<String, State> {
+        self.states.lock().clone()
+    }
+
+    /// Restores buckets from a snapshot taken earlier, other buckets are kept.
+    ///
+    /// Capacity and rate of the storage are kept, tokens are refilled for the time
+    /// since the snapshot was taken.
+    pub fn restore<I>(&self, snapshot: I)
+    where
+        I: IntoIterator<Item = (String, State)>,
+    {
+        let mut states = self.states.lock();
+        for (key, saved) in snapshot {
+            let mut state = self.config.read().new_state();
+            state.restore(&saved);
+            states.insert(key, state);
+        }
+    }
+}
+
+pub struct KeyedInMemoryStorageBuilder {
+    rps_limit: u32,
+    cap: Option<u64>,
+    initial_fill: InitialFill,
+    warm_up: Option<WarmUp>,
+}
+
+impl KeyedInMemoryStorageBuilder {
+    /// Customize capacity of buckets, i.e. the largest burst. Equal to the rate by default.
+    pub fn with_capacity(mut self, cap: u64) -> Self {
+        self.cap = Some(cap);
+        self
+    }
+
+    /// Customize tokens of buckets of new keys, full by default.
+    pub fn with_initial_fill(mut self, initial_fill: InitialFill) -> Self {
+        self.initial_fill = initial_fill;
+        self
+    }
+
+    /// Start buckets cold and warm them up after idleness, see [`WarmUp`].
+    pub fn with_warm_up(mut self, warm_up: WarmUp) -> Self {
+        self.warm_up = Some(warm_up);
+        self
+    }
+
+    /// # Panics
+    ///
+    /// Panics if the configuration is invalid, see [`try_build`](Self::try_build).
+    pub fn build(self) -> KeyedInMemoryStorage {
+        match self.try_build() {
+            Ok(storage) => storage,
+            Err(err) => panic!("invalid configuration of keyed in-memory storage: {}", err),
+        }
+    }
+
+    /// # Errors
+    ///
+    /// Will return `Err` if the rate or the capacity is zero.
+    pub fn try_build(self) -> Result<KeyedInMemoryStorage, ConfigError> {
+        let cap = self.cap.unwrap_or(self.rps_limit.into());
+        let config = BucketConfig::try_new(self.rps_limit, cap)?
+            .with_initial_fill(self.initial_fill)
+            .with_warm_up(self.warm_up);
+        Ok(KeyedInMemoryStorage {
+            states: Default::default(),
+            config: parking_lot::RwLock::new(config),
+        })
+    }
+}
+
+impl KeyedStorage for KeyedInMemoryStorage {
+    type Key = str;
+    type Error = RateLimitExceededError;
+
+    fn try_acquire(
+        &self,
+        key: &str,
+        alg: TokenBucketAlgorithm,
+        permits: u64,
+    ) -> Result<(), Self::Error> {
+        let mut states = self.states.lock();
+        match states.get_mut(key) {
+            Some(state) => alg.try_acquire(state, permits),
+            None => {
+                let mut state = self.config.read().new_state();
+                let res = alg.try_acquire(&mut state, permits);
+                states.insert(key.to_owned(), state);
+                res
+            }
+        }
+    }
+
+    fn release(&self, key: &str, permits: u64) -> Result<(), Self::Error> {
+        if let Some(state) = self.states.lock().get_mut(key) {
+            state.release(permits);
+        }
+        Ok(())
+    }
+
+    fn charge(&self, key: &str, cost: u64) -> Result<(), Self::Error> {
+        let mut states = self.states.lock();
+        let state = match states.get_mut(key) {
+            Some(state) => state,
+            None => states
+                .entry(key.to_owned())
+                .or_insert_with(|| self.config.read().new_state()),
+        };
+        TokenBucketAlgorithm::new(Mode::N).charge(state, cost);
+        Ok(())
+    }
+
+    fn set_rate(&self, rps_limit: u32) -> Result<(), ReconfigureError<Self::Error>> {
+        let refill_tick = refill_tick(rps_limit)?;
+        let mut states = self.states.lock();
+        for state in states.values_mut() {
+            state.set_rate(refill_tick);
+        }
+        self.config.write().refill_tick = refill_tick;
+        Ok(())
+    }
+
+    fn set_capacity(
+        &self,
+        cap: u64,
+        policy: ResizePolicy,
+    ) -> Result<(), ReconfigureError<Self::Error>> {
+        let cap = validate_capacity(cap)?;
+        let mut states = self.states.lock();
+        for state in states.values_mut() {
+            state.set_capacity(cap, policy);
+        }
+        self.config.write().cap = cap;
+        Ok(())
+    }
+}
+
+/// A storage that stores states of several limits in memory.
+///
+/// All states are guarded by a single lock, so tokens are acquired from all limits atomically.
+/// See [`CompositeLimiter`](crate::CompositeLimiter).
+#[derive(Default)]
+pub struct InMemoryCompositeStorage {
+    states: parking_lot::Mutex<HashMap<Limit, State>>,
+    initial_fill: InitialFill,
+}
+
+impl InMemoryCompositeStorage {
+    /// Creates a storage. States of limits are created full on first use.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Customize tokens of limits that are used for the first time, full by default.
+    pub fn with_initial_fill(mut self, initial_fill: InitialFill) -> Self {
+        self.initial_fill = initial_fill;
+        self
+    }
+}
+
+impl CompositeStorage for InMemoryCompositeStorage {
+    type Error = InMemoryCompositeStorageError;
+
+    fn try_acquire_all(&self, limits: &[Limit], permits: u64) -> Result<(), Self::Error> {
+        composite::check_capacity(limits, permits)?;
+        let mut states = self.states.lock();
+        let mut current: Vec<State> = limits
+            .iter()
+            .map(|limit| {
+                states
+                    .get(limit)
+                    .cloned()
+                    .unwrap_or_else(|| limit.new_state(self.initial_fill))
+            })
+            .collect();
+
+        composite::try_acquire_all(limits.iter().zip(current.iter_mut()), permits)?;
+        states.extend(limits.iter().copied().zip(current));
+        Ok(())
+    }
+
+    fn charge_all(&self, limits: &[Limit], cost: u64) -> Result<(), Self::Error> {
+        let mut states = self.states.lock();
+        for limit in limits {
+            let state = states
+                .entry(*limit)
+                .or_insert_with(|| limit.new_state(self.initial_fill));
+            TokenBucketAlgorithm::new(Mode::N).charge(state, cost);
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, thiserror::Error)]
+pub enum InMemoryCompositeStorageError {
+    #[error(transparent)]
+    RateLimitExceededError(#[from] RateLimitExceededError),
+    #[error(transparent)]
+    CompositeLimitExceeded(#[from] CompositeLimitExceededError),
+}
+
+impl StorageError for InMemoryCompositeStorageError {
+    fn is_rate_limit_exceeded(&self) -> bool {
+        true
+    }
+
+    fn is_capacity_exceeded(&self) -> bool {
+        matches!(self, InMemoryCompositeStorageError::RateLimitExceededError(err) if err.is_capacity_exceeded())
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::TokenBucket;
+    use crate::{CompositeLimiter, KeyedTokenBucket, TokenBucket};
 
     use std::time::Duration;
 
+    fn tripped(res: Result<(), InMemoryCompositeStorageError>) -> CompositeLimitExceededError {
+        match res {
+            Err(InMemoryCompositeStorageError::CompositeLimitExceeded(err)) => err,
+            res => panic!("unexpected result: {:?}", res),
+        }
+    }
+
     #[test]
     fn try_acquire() {
         let tb = TokenBucket::new(InMemoryStorage::new(2));
@@ -65,4 +472,300 @@ mod tests {
         assert!(tb.try_acquire(2).is_ok());
         assert!(tb.try_acquire_one().is_err());
     }
+
+    #[test]
+    fn composite_all_or_nothing() {
+        let limiter = CompositeLimiter::new(
+            InMemoryCompositeStorage::new(),
+            vec![Limit::per_second(2), Limit::per_minute(3)],
+        );
+
+        assert!(limiter.try_acquire(2).is_ok());
+        let err = tripped(limiter.try_acquire_one());
+        assert_eq!(err.tripped, vec![Limit::per_second(2)]);
+        assert!(err.retry_after <= Duration::from_millis(500));
+
+        // Denied request doesn't consume the minute limit
+        std::thread::sleep(Duration::from_secs(1));
+        assert!(limiter.try_acquire_one().is_ok());
+
+        std::thread::sleep(Duration::from_secs(1));
+        let err = tripped(limiter.try_acquire(2));
+        assert_eq!(err.tripped, vec![Limit::per_minute(3)]);
+        assert!(err.retry_after > Duration::from_secs(37));
+        assert!(err.retry_after <= Duration::from_secs(38));
+    }
+
+    #[test]
+    fn restore_refills_offline_time() {
+        let storage = InMemoryStorage::new(10);
+        assert!(storage
+            .try_acquire(
+                TokenBucketAlgorithm {
+                    mode: crate::Mode::N
+                },
+                10
+            )
+            .is_ok());
+
+        // Taken 500ms ago
+        let mut snapshot = storage.snapshot();
+        snapshot.last_refill -= time::Duration::milliseconds(500);
+        // Config of the new storage wins
+        snapshot.cap = 100;
+
+        let restored = InMemoryStorage::new(10);
+        restored.restore(&snapshot);
+        let state = restored.snapshot();
+        assert_eq!(state.cap, 10);
+        assert!((4..=5).contains(&state.available_tokens));
+
+        // Long break refills the bucket
+        snapshot.last_refill -= time::Duration::days(365);
+        restored.restore(&snapshot);
+        assert_eq!(restored.snapshot().available_tokens, 10);
+    }
+
+    #[test]
+    fn keyed() {
+        let tb = KeyedTokenBucket::new(KeyedInMemoryStorage::new(2));
+        assert!(tb.try_acquire("a", 2).is_ok());
+        assert!(tb.try_acquire_one("a").is_err());
+        assert!(tb.try_acquire(&"b".to_owned(), 2).is_ok());
+
+        let restored = KeyedInMemoryStorage::new(2);
+        restored.restore(tb.storage().snapshot());
+        let tb = KeyedTokenBucket::new(restored);
+        assert!(tb.try_acquire_one("a").is_err());
+        assert!(tb.try_acquire_one("b").is_err());
+        assert!(tb.try_acquire_one("c").is_ok());
+    }
+
+    #[test]
+    fn initial_fill() {
+        let tb = TokenBucket::new(
+            InMemoryStorage::builder(100)
+                .with_initial_fill(InitialFill::Empty)
+                .build(),
+        );
+        assert!(tb.try_acquire_one().is_err());
+
+        let storage = KeyedInMemoryStorage::builder(100)
+            .with_initial_fill(InitialFill::Tokens(10))
+            .build();
+        let tb = KeyedTokenBucket::new(storage);
+        assert!(tb.try_acquire("a", 11).is_err());
+        assert!(tb.try_acquire("a", 10).is_ok());
+        assert!(tb.try_acquire("b", 10).is_ok());
+    }
+
+    #[test]
+    fn composite_initial_fill() {
+        let limiter = CompositeLimiter::new(
+            InMemoryCompositeStorage::new().with_initial_fill(InitialFill::Tokens(1)),
+            vec![Limit::per_second(10), Limit::per_minute(100)],
+        );
+        assert!(limiter.try_acquire(2).is_err());
+        assert!(limiter.try_acquire_one().is_ok());
+        assert!(limiter.try_acquire_one().is_err());
+    }
+
+    #[test]
+    fn composite_exceeds_capacity() {
+        let limiter = CompositeLimiter::new(
+            InMemoryCompositeStorage::new(),
+            vec![Limit::per_second(10), Limit::per_minute(5)],
+        );
+        let err = limiter.try_acquire(6).unwrap_err();
+        assert!(err.is_capacity_exceeded());
+        assert_eq!(
+            err,
+            RateLimitExceededError::ExceedsCapacity { permits: 6, cap: 5 }.into()
+        );
+
+        // The denied request doesn't touch states
+        assert!(limiter.try_acquire(5).is_ok());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn snapshot_serde() {
+        let tb = KeyedTokenBucket::new(KeyedInMemoryStorage::new(10));
+        assert!(tb.try_acquire("a", 3).is_ok());
+
+        let json = serde_json::to_string(&tb.storage().snapshot()).unwrap();
+        let snapshot: HashMap<String, State> = serde_json::from_str(&json).unwrap();
+        assert_eq!(snapshot, tb.storage().snapshot());
+    }
+
+    #[test]
+    fn reconfigure() {
+        let tb = TokenBucket::new(InMemoryStorage::new(10));
+        assert!(tb.try_acquire(5).is_ok());
+
+        assert!(tb.set_capacity(20, ResizePolicy::Scale).is_ok());
+        assert!(tb.try_acquire(10).is_ok());
+        assert!(tb.try_acquire_one().is_err());
+
+        assert!(tb.set_rate(1000).is_ok());
+        std::thread::sleep(Duration::from_millis(50));
+        assert!(tb.try_acquire(20).is_ok());
+
+        assert!(tb.set_capacity(5, ResizePolicy::Clamp).is_ok());
+        std::thread::sleep(Duration::from_millis(50));
+        assert!(tb.try_acquire(6).is_err());
+        assert!(tb.try_acquire(5).is_ok());
+    }
+
+    #[test]
+    fn reconfigure_keyed() {
+        let tb = KeyedTokenBucket::new(KeyedInMemoryStorage::new(10));
+        assert!(tb.try_acquire("a", 5).is_ok());
+
+        assert!(tb.set_capacity(20, ResizePolicy::Scale).is_ok());
+        assert!(tb.try_acquire("a", 11).is_err());
+        assert!(tb.try_acquire("a", 10).is_ok());
+        assert!(tb.try_acquire("b", 20).is_ok());
+
+        assert!(tb.set_rate(1000).is_ok());
+        std::thread::sleep(Duration::from_millis(50));
+        assert!(tb.try_acquire("a", 20).is_ok());
+        assert!(tb.try_acquire("b", 20).is_ok());
+    }
+
+    #[test]
+    #[should_panic(expected = "invalid limit")]
+    fn zero_period_limit() {
+        Limit::new(1, Duration::ZERO);
+    }
+
+    #[test]
+    fn validation() {
+        assert_eq!(
+            InMemoryStorage::try_new(0).err(),
+            Some(ConfigError::ZeroRate)
+        );
+        let storage = InMemoryStorage::builder(10).with_capacity(0).try_build();
+        assert_eq!(storage.err(), Some(ConfigError::ZeroCapacity));
+        assert_eq!(
+            InMemoryStorage::try_new(1_000_000_001).err(),
+            Some(ConfigError::RateTooHigh)
+        );
+        assert!(InMemoryStorage::try_new(1_000_000_000).is_ok());
+
+        let second = Duration::from_secs(1);
+        assert_eq!(
+            Limit::try_new(0, second).err(),
+            Some(ConfigError::ZeroCapacity)
+        );
+        assert_eq!(
+            Limit::try_new(1, Duration::ZERO).err(),
+            Some(ConfigError::RateTooHigh)
+        );
+        assert_eq!(
+            Limit::try_new(1_001, Duration::from_micros(1)).err(),
+            Some(ConfigError::RateTooHigh)
+        );
+        assert!(Limit::try_new(1_000, Duration::from_micros(1)).is_ok());
+        assert_eq!(
+            KeyedInMemoryStorage::try_new(0).err(),
+            Some(ConfigError::ZeroRate)
+        );
+        let storage = KeyedInMemoryStorage::builder(10)
+            .with_capacity(0)
+            .try_build();
+        assert_eq!(storage.err(), Some(ConfigError::ZeroCapacity));
+
+        let tb = TokenBucket::new(InMemoryStorage::builder(10).with_capacity(20).build());
+        assert!(matches!(
+            tb.set_rate(0),
+            Err(ReconfigureError::Config(ConfigError::ZeroRate))
+        ));
+        assert!(matches!(
+            tb.set_rate(u32::MAX),
+            Err(ReconfigureError::Config(ConfigError::RateTooHigh))
+        ));
+        assert!(matches!(
+            tb.set_capacity(0, ResizePolicy::Clamp),
+            Err(ReconfigureError::Config(ConfigError::ZeroCapacity))
+        ));
+
+        // Never succeeds, unlike a request that waits for refill
+        let err = tb.try_acquire(21).unwrap_err();
+        assert_eq!(
+            err,
+            RateLimitExceededError::ExceedsCapacity {
+                permits: 21,
+                cap: 20
+            }
+        );
+        assert!(err.is_capacity_exceeded());
+        assert!(tb.try_acquire(20).is_ok());
+        let err = tb.try_acquire(20).unwrap_err();
+        assert_eq!(err, RateLimitExceededError::Exhausted);
+        assert!(!err.is_capacity_exceeded());
+    }
+
+    #[test]
+    fn warm_up() {
+        let storage = InMemoryStorage::builder(300)
+            .with_warm_up(WarmUp::new(Duration::from_secs(1)))
+            .build();
+        let tb = TokenBucket::new(storage);
+        assert!(tb.try_acquire(100).is_ok());
+        assert!(tb.try_acquire_one().is_err());
+
+        // ~11 tokens instead of 30 of a warm bucket
+        std::thread::sleep(Duration::from_millis(100));
+        assert!(tb.try_acquire(30).is_err());
+        assert!(tb.try_acquire(5).is_ok());
+    }
+
+    #[test]
+    fn overdraft() {
+        let tb = TokenBucket::new(InMemoryStorage::new(100));
+        assert!(tb.try_acquire_overdraft(150).is_ok());
+        assert!(tb.try_acquire_overdraft(1).is_err());
+        assert!(tb.try_acquire_n_or_all(1).is_ok());
+
+        // The debt of 50 tokens is repaid in 0.5 seconds
+        std::thread::sleep(Duration::from_millis(400));
+        assert!(tb.try_acquire_overdraft(1).is_err());
+        std::thread::sleep(Duration::from_millis(200));
+        assert!(tb.try_acquire_overdraft(1).is_ok());
+
+        assert!(tb.charge(100).is_ok());
+        assert!(tb.try_acquire_overdraft(1).is_err());
+
+        let tb = KeyedTokenBucket::new(KeyedInMemoryStorage::new(10));
+        assert!(tb.charge("a", 20).is_ok());
+        assert!(tb.try_acquire_overdraft("a", 1).is_err());
+        assert!(tb.try_acquire_overdraft("b", 20).is_ok());
+        assert!(tb.try_acquire_one("b").is_err());
+
+        let limiter = CompositeLimiter::new(
+            InMemoryCompositeStorage::new(),
+            vec![Limit::per_second(10), Limit::per_minute(100)],
+        );
+        assert!(limiter.charge(15).is_ok());
+        let err = tripped(limiter.try_acquire_one());
+        assert_eq!(err.tripped, vec![Limit::per_second(10)]);
+        assert!(err.retry_after > Duration::from_millis(500));
+    }
+
+    #[test]
+    fn large_quantities() {
+        // 10 GB bucket refilled with 1 GB per second
+        let tb = TokenBucket::new(InMemoryStorage::new(1_000_000_000));
+        assert!(tb.set_capacity(10_000_000_000, ResizePolicy::Scale).is_ok());
+        assert!(tb.try_acquire(6_000_000_000).is_ok());
+        assert!(tb.try_acquire(6_000_000_000).is_err());
+        assert!(tb.try_acquire_n_or_all(u64::MAX).is_ok());
+
+        // Quantities above the balance range saturate instead of overflowing
+        assert!(tb.try_acquire(u64::MAX).is_err());
+        assert!(tb.charge(u64::MAX).is_ok());
+        assert!(tb.charge(u64::MAX).is_ok());
+        assert!(tb.try_acquire_overdraft(1).is_err());
+    }
 }
@@ -0,0 +1,612 @@
+use crate::{
+    refill_tick, validate_capacity, ConfigError, InitialFill, Mode, RateLimitExceededError,
+    ReconfigureError, ResizePolicy, Storage, TokenBucketAlgorithm,
+};
+
+#[cfg(loom)]
+use loom::sync::atomic::{AtomicU64, Ordering};
+#[cfg(not(loom))]
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
+
+/// A storage that stores state in memory without locks.
+///
+/// The state is a single `AtomicU64` updated by compare-and-swap, so acquiring threads
+/// don't block each other. Prefer it to [`InMemoryStorage`](crate::InMemoryStorage)
+/// when many threads share the bucket.
+///
+/// Instead of the balance and the time of the last refill, the state is the time when the bucket
+/// becomes full, in nanoseconds since creation of the storage. Acquiring moves it forward
+/// by a refill tick per token, the balance is the number of ticks left until it. The time
+/// doesn't wrap around for centuries, so a bucket is refilled however long it isn't used.
+///
+/// Rate and capacity can be changed in place. The new ones are stored aside and the state
+/// is rebuilt for them by compare-and-swap, so acquiring threads are not blocked either.
+/// Refill of the whole capacity must take less than about 292 years, the range of the state,
+/// otherwise the change fails with [`ConfigError::CapacityOverflow`].
+///
+/// # Example
+/// ```
+/// use tocket::{AtomicInMemoryStorage, TokenBucket};
+///
+/// let tb = TokenBucket::new(AtomicInMemoryStorage::new(2));
+/// assert!(tb.try_acquire(2).is_ok());
+/// assert!(tb.try_acquire_one().is_err());
+/// ```
+pub struct AtomicInMemoryStorage {
+    /// Time when the bucket becomes full, tagged with the generation of the rate it's counted in.
+    state: AtomicU64,
+    /// Capacities of both generations.
+    caps: [AtomicU64; 2],
+    /// Refill ticks in nanoseconds of both generations.
+    ticks: [AtomicU64; 2],
+    /// Serializes reconfigurations.
+    reconfiguring: parking_lot::Mutex<()>,
+    created_at: Instant,
+}
+
+/// Bit of a state that tells which of two generations of the rate the state is counted in.
+/// A reconfiguration writes the other generation and then moves the state to it,
+/// so the rate of a state is never changed under an acquiring thread.
+const GENERATION_BIT: u64 = 1 << 63;
+/// Latest time when a bucket becomes full, states saturate at it.
+/// Leaves room for a special state of [`SharedMemoryStorage`](crate::SharedMemoryStorage).
+pub(crate) const MAX_FULL_AT: u64 = GENERATION_BIT - 2;
+
+/// Capacity and refill tick in nanoseconds a state is counted in.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub(crate) struct Rate {
+    pub(crate) cap: u64,
+    pub(crate) tick: u64,
+}
+
+/// Refill tick of `rps_limit` in whole nanoseconds.
+pub(crate) fn tick_nanos(rps_limit: u32) -> Result<u64, ConfigError> {
+    Ok(refill_tick(rps_limit)?.whole_nanoseconds() as u64)
+}
+
+/// Fails with [`ConfigError::CapacityOverflow`] if refill of the whole capacity
+/// doesn't fit in a state.
+pub(crate) fn validate_rate(rate: Rate) -> Result<Rate, ConfigError> {
+    match rate.cap.checked_mul(rate.tick) {
+        Some(nanos) if nanos <= MAX_FULL_AT => Ok(rate),
+        _ => Err(ConfigError::CapacityOverflow),
+    }
+}
+
+/// Splits a state into its generation and the time when the bucket becomes full.
+pub(crate) fn split(state: u64) -> (usize, u64) {
+    ((state >> 63) as usize, state & !GENERATION_BIT)
+}
+
+/// Tags the time when the bucket becomes full with the generation of its rate.
+pub(crate) fn join(generation: usize, full_at: u64) -> u64 {
+    full_at.min(MAX_FULL_AT) | (generation as u64) << 63
+}
+
+/// Tokens of a bucket that becomes full at `full_at`, negative if the bucket is in debt.
+/// Only whole ticks are refilled, so tokens are added when their ticks end.
+pub(crate) fn available(full_at: u64, now: u64, cap: u64, tick: u64) -> i128 {
+    let missing = u128::from(full_at.saturating_sub(now)).div_ceil(u128::from(tick));
+    i128::from(cap) - missing as i128
+}
+
+/// Returns the time when the bucket becomes full after acquiring
+/// or `None` if there are not enough tokens.
+pub(crate) fn take(
+    full_at: u64,
+    now: u64,
+    cap: u64,
+    tick: u64,
+    alg: TokenBucketAlgorithm,
+    permits: u64,
+) -> Option<u64> {
+    let tokens = available(full_at, now, cap, tick);
+    let taken = match alg.mode() {
+        Mode::N if tokens >= i128::from(permits) => permits,
+        Mode::N => return None,
+        Mode::All => permits.min(tokens.max(0) as u64),
+        Mode::Overdraft if tokens > 0 => permits,
+        Mode::Overdraft => return None,
+    };
+    Some(charge(full_at, now, tick, taken))
+}
+
+/// Fails if `permits` can never be acquired at once from a bucket of `cap` tokens.
+pub(crate) fn check_capacity(
+    alg: TokenBucketAlgorithm,
+    permits: u64,
+    cap: u64,
+) -> Result<(), RateLimitExceededError> {
+    if alg.mode() == Mode::N && permits > cap {
+        return Err(RateLimitExceededError::ExceedsCapacity { permits, cap });
+    }
+    Ok(())
+}
+
+/// Returns the time when the bucket becomes full after charging `cost` regardless of the balance.
+pub(crate) fn charge(full_at: u64, now: u64, tick: u64, cost: u64) -> u64 {
+    full_at.max(now).saturating_add(cost.saturating_mul(tick))
+}
+
+/// Returns the time when the bucket becomes full after releasing `permits`,
+/// but not more tokens than the capacity.
+pub(crate) fn release(full_at: u64, now: u64, tick: u64, permits: u64) -> u64 {
+    full_at
+        .saturating_sub(permits.saturating_mul(tick))
+        .max(now.min(full_at))
+}
+
+/// Returns the time when the bucket becomes full after its rate `from` is changed to `to`.
+///
+/// The bucket is refilled with the old rate until `now`: the debt and the progress of
+/// the current tick are scaled to the new tick, then tokens are adjusted to the new capacity
+/// by `policy`.
+pub(crate) fn reconfigure(
+    full_at: u64,
+    now: u64,
+    from: Rate,
+    to: Rate,
+    policy: ResizePolicy,
+) -> u64 {
+    let missing = u128::from(full_at.saturating_sub(now)) * u128::from(to.tick);
+    let missing = missing.div_ceil(u128::from(from.tick)) as i128;
+    let (old_cap, new_cap, tick) = (
+        i128::from(from.cap),
+        i128::from(to.cap),
+        i128::from(to.tick),
+    );
+    let missing = match policy {
+        ResizePolicy::Clamp => missing + (new_cap - old_cap) * tick,
+        ResizePolicy::Scale => {
+            let tokens = old_cap * tick - missing;
+            new_cap * tick - tokens.saturating_mul(new_cap) / old_cap
+        }
+    };
+    now.saturating_add(u64::try_from(missing.max(0)).unwrap_or(u64::MAX))
+}
+
+impl AtomicInMemoryStorage {
+    /// Creates a storage.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `rps_limit` is zero, see [`try_new`](Self::try_new).
+    pub fn new(rps_limit: u32) -> Self {
+        Self::builder(rps_limit).build()
+    }
+
+    /// Creates a storage.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if `rps_limit` is zero.
+    pub fn try_new(rps_limit: u32) -> Result<Self, ConfigError> {
+        Self::builder(rps_limit).try_build()
+    }
+
+    /// Creates a builder of storage. Needs for customizing of initial fill.
+    pub fn builder(rps_limit: u32) -> AtomicInMemoryStorageBuilder {
+        AtomicInMemoryStorageBuilder {
+            rps_limit,
+            initial_fill: InitialFill::Full,
+        }
+    }
+
+    /// Current time in nanoseconds since creation.
+    fn now(&self) -> u64 {
+        u64::try_from(self.created_at.elapsed().as_nanos()).unwrap_or(u64::MAX)
+    }
+
+    /// Rate of the generation.
+    fn rate(&self, generation: usize) -> Rate {
+        Rate {
+            cap: self.caps[generation].load(Ordering::Acquire),
+            tick: self.ticks[generation].load(Ordering::Acquire),
+        }
+    }
+
+    /// Applies `f` to the time when the bucket becomes full, the current time and the rate
+    /// until the update succeeds. The state is not updated if `f` fails.
+    ///
+    /// The time is taken after loading the state, otherwise a thread that took it earlier
+    /// would see tokens acquired by a thread that took it later as not refilled yet.
+    fn update<F>(&self, f: F) -> Result<(), RateLimitExceededError>
+    where
+        F: Fn(u64, u64, Rate) -> Result<u64, RateLimitExceededError>,
+    {
+        let mut current = self.state.load(Ordering::Acquire);
+        loop {
+            let (generation, full_at) = split(current);
+            let new = join(generation, f(full_at, self.now(), self.rate(generation))?);
+            if new == current {
+                return Ok(());
+            }
+            match self.state.compare_exchange_weak(
+                current,
+                new,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => return Ok(()),
+                Err(actual) => current = actual,
+            }
+        }
+    }
+
+    /// Writes the rate returned by `f` to the other generation
+    /// and moves the state to it, adjusting tokens by `policy`.
+    fn reconfigure<F>(&self, f: F, policy: ResizePolicy) -> Result<(), ConfigError>
+    where
+        F: Fn(Rate) -> Result<Rate, ConfigError>,
+    {
+        let _reconfiguring = self.reconfiguring.lock();
+        let mut current = self.state.load(Ordering::Acquire);
+        loop {
+            let (generation, full_at) = split(current);
+            let (from, next) = (self.rate(generation), 1 - generation);
+            let to = f(from)?;
+            // No state is counted in the other generation, so no thread reads it
+            self.caps[next].store(to.cap, Ordering::Release);
+            self.ticks[next].store(to.tick, Ordering::Release);
+
+            let full_at = reconfigure(full_at, self.now(), from, to, policy);
+            match self.state.compare_exchange_weak(
+                current,
+                join(next, full_at),
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => return Ok(()),
+                Err(actual) => current = actual,
+            }
+        }
+    }
+}
+
+pub struct AtomicInMemoryStorageBuilder {
+    rps_limit: u32,
+    initial_fill: InitialFill,
+}
+
+impl AtomicInMemoryStorageBuilder {
+    /// Customize tokens of the new bucket, full by default.
+    pub fn with_initial_fill(mut self, initial_fill: InitialFill) -> Self {
+        self.initial_fill = initial_fill;
+        self
+    }
+
+    /// # Panics
+    ///
+    /// Panics if the configuration is invalid, see [`try_build`](Self::try_build).
+    pub fn build(self) -> AtomicInMemoryStorage {
+        match self.try_build() {
+            Ok(storage) => storage,
+            Err(err) => panic!("invalid configuration of atomic in-memory storage: {}", err),
+        }
+    }
+
+    /// # Errors
+    ///
+    /// Will return `Err` if the rate is zero.
+    pub fn try_build(self) -> Result<AtomicInMemoryStorage, ConfigError> {
+        let Rate { cap, tick } = validate_rate(Rate {
+            cap: self.rps_limit.into(),
+            tick: tick_nanos(self.rps_limit)?,
+        })?;
+        let missing = cap - self.initial_fill.tokens(cap);
+        Ok(AtomicInMemoryStorage {
+            state: AtomicU64::new(join(0, missing.saturating_mul(tick))),
+            caps: [AtomicU64::new(cap), AtomicU64::new(cap)],
+            ticks: [AtomicU64::new(tick), AtomicU64::new(tick)],
+            reconfiguring: Default::default(),
+            created_at: Instant::now(),
+        })
+    }
+}
+
+impl Storage for AtomicInMemoryStorage {
+    type Error = RateLimitExceededError;
+
+    fn try_acquire(&self, alg: TokenBucketAlgorithm, permits: u64) -> Result<(), Self::Error> {
+        self.update(|full_at, now, rate| {
+            check_capacity(alg, permits, rate.cap)?;
+            take(full_at, now, rate.cap, rate.tick, alg, permits)
+                .ok_or(RateLimitExceededError::Exhausted)
+        })
+    }
+
+    fn release(&self, permits: u64) -> Result<(), Self::Error> {
+        self.update(|full_at, now, rate| Ok(release(full_at, now, rate.tick, permits)))
+    }
+
+    fn charge(&self, cost: u64) -> Result<(), Self::Error> {
+        self.update(|full_at, now, rate| Ok(charge(full_at, now, rate.tick, cost)))
+    }
+
+    fn set_rate(&self, rps_limit: u32) -> Result<(), ReconfigureError<Self::Error>> {
+        let tick = tick_nanos(rps_limit)?;
+        self.reconfigure(
+            |rate| validate_rate(Rate { tick, ..rate }),
+            ResizePolicy::Clamp,
+        )?;
+        Ok(())
+    }
+
+    fn set_capacity(
+        &self,
+        cap: u64,
+        policy: ResizePolicy,
+    ) -> Result<(), ReconfigureError<Self::Error>> {
+        let cap = validate_capacity(cap)?;
+        self.reconfigure(|rate| validate_rate(Rate { cap, ..rate }), policy)?;
+        Ok(())
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use super::*;
+    use crate::TokenBucket;
+
+    use std::sync::atomic::AtomicU32;
+    use std::time::Duration;
+
+    #[test]
+    fn try_acquire() {
+        let tb = TokenBucket::new(AtomicInMemoryStorage::new(2));
+        assert!(tb.try_acquire(2).is_ok());
+        assert!(tb.try_acquire_one().is_err());
+
+        std::thread::sleep(Duration::from_secs(1));
+        assert!(tb.try_acquire(2).is_ok());
+        assert!(tb.try_acquire_one().is_err());
+
+        assert!(tb.try_acquire_n_or_all(5).is_ok());
+        std::thread::sleep(Duration::from_millis(600));
+        assert!(tb.try_acquire_one().is_ok());
+        assert!(tb.try_acquire_one().is_err());
+    }
+
+    #[test]
+    fn release() {
+        let storage = AtomicInMemoryStorage::new(10);
+        let alg = TokenBucketAlgorithm::new(Mode::N);
+        assert!(storage.try_acquire(alg, 10).is_ok());
+        assert!(storage.release(20).is_ok());
+        assert!(storage.try_acquire(alg, 10).is_ok());
+        assert!(storage.try_acquire(alg, 1).is_err());
+    }
+
+    #[test]
+    fn multithread() {
+        let tb = TokenBucket::new(AtomicInMemoryStorage::new(1000));
+        let acquired = AtomicU32::new(0);
+        let started_at = Instant::now();
+
+        std::thread::scope(|s| {
+            for _ in 0..8 {
+                s.spawn(|| {
+                    for _ in 0..500 {
+                        if tb.try_acquire_one().is_ok() {
+                            acquired.fetch_add(1, Ordering::Relaxed);
+                        }
+                    }
+                });
+            }
+        });
+
+        let refilled = started_at.elapsed().as_millis() as u32 + 1;
+        let acquired = acquired.into_inner();
+        assert!(acquired >= 1000);
+        assert!(acquired <= 1000 + refilled);
+    }
+
+    #[test]
+    fn charge() {
+        let tb = TokenBucket::new(AtomicInMemoryStorage::new(100));
+        assert!(tb.try_acquire_overdraft(150).is_ok());
+        assert!(tb.try_acquire_overdraft(1).is_err());
+
+        std::thread::sleep(Duration::from_millis(600));
+        assert!(tb.try_acquire_overdraft(1).is_ok());
+        assert!(tb.charge(100).is_ok());
+        assert!(tb.try_acquire_n_or_all(1).is_ok());
+        assert!(tb.try_acquire_overdraft(1).is_err());
+    }
+
+    #[test]
+    fn idle_past_wrap() {
+        // A tick of a nanosecond, so ticks in 32 bits wrapped around after a few seconds
+        let mut storage = AtomicInMemoryStorage::new(1_000_000_000);
+        let alg = TokenBucketAlgorithm::new(Mode::N);
+        assert!(storage.try_acquire(alg, 1_000_000_000).is_ok());
+        assert!(storage.try_acquire(alg, 1_000_000).is_err());
+
+        // Idle for 5 seconds
+        storage.created_at = storage
+            .created_at
+            .checked_sub(Duration::from_secs(5))
+            .unwrap();
+        assert!(storage.try_acquire(alg, 1_000_000_000).is_ok());
+    }
+
+    #[test]
+    fn whole_ticks() {
+        assert_eq!(available(0, 10, 5, 100), 5);
+        assert_eq!(available(250, 0, 5, 100), 2);
+        assert_eq!(available(250, 50, 5, 100), 3);
+        assert_eq!(available(900, 0, 5, 100), -4);
+        // Centuries later
+        assert_eq!(available(900, u64::MAX / 2, 5, 1), 5);
+
+        let alg = TokenBucketAlgorithm::new(Mode::All);
+        assert_eq!(take(250, 50, 5, 100, alg, 10), Some(550));
+        assert_eq!(super::release(550, 50, 100, 10), 50);
+        assert_eq!(super::release(0, 50, 100, 10), 0);
+    }
+
+    #[test]
+    fn reconfigure_state() {
+        let rate = Rate { cap: 10, tick: 100 };
+        // 2 tokens and a half of a tick are missing
+        let full_at = 1250;
+        let faster = Rate { tick: 10, ..rate };
+        assert_eq!(
+            super::reconfigure(full_at, 1000, rate, faster, ResizePolicy::Clamp),
+            1025
+        );
+
+        let bigger = Rate { cap: 20, ..rate };
+        assert_eq!(
+            super::reconfigure(full_at, 1000, rate, bigger, ResizePolicy::Clamp),
+            2250
+        );
+        assert_eq!(
+            super::reconfigure(full_at, 1000, rate, bigger, ResizePolicy::Scale),
+            1500
+        );
+        let smaller = Rate { cap: 2, ..rate };
+        assert_eq!(
+            super::reconfigure(full_at, 1000, rate, smaller, ResizePolicy::Clamp),
+            1000
+        );
+        // Debt is scaled too
+        assert_eq!(
+            super::reconfigure(3000, 1000, rate, bigger, ResizePolicy::Scale),
+            1000 + 2000 + 2 * 1000
+        );
+        assert_eq!(split(join(1, full_at)), (1, full_at));
+    }
+
+    #[test]
+    fn set_rate_and_capacity() {
+        assert!(matches!(
+            AtomicInMemoryStorage::try_new(0),
+            Err(ConfigError::ZeroRate)
+        ));
+        assert!(matches!(
+            AtomicInMemoryStorage::try_new(u32::MAX),
+            Err(ConfigError::RateTooHigh)
+        ));
+        let storage = AtomicInMemoryStorage::new(10);
+        let alg = TokenBucketAlgorithm::new(Mode::N);
+        assert!(storage.try_acquire(alg, 10).is_ok());
+
+        assert!(storage.set_rate(1000).is_ok());
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(storage.try_acquire(alg, 10).is_ok());
+
+        assert!(storage.set_capacity(100, ResizePolicy::Scale).is_ok());
+        assert!(matches!(
+            storage.try_acquire(alg, 101),
+            Err(RateLimitExceededError::ExceedsCapacity { cap: 100, .. })
+        ));
+        std::thread::sleep(Duration::from_millis(150));
+        assert!(storage.try_acquire(alg, 100).is_ok());
+        assert!(storage.try_acquire(alg, 10).is_err());
+
+        assert!(matches!(
+            storage.set_rate(0),
+            Err(ReconfigureError::Config(ConfigError::ZeroRate))
+        ));
+        assert!(matches!(
+            storage.set_capacity(0, ResizePolicy::Clamp),
+            Err(ReconfigureError::Config(ConfigError::ZeroCapacity))
+        ));
+
+        // A millisecond per token for centuries
+        assert!(matches!(
+            storage.set_capacity(u64::MAX / 1000, ResizePolicy::Clamp),
+            Err(ReconfigureError::Config(ConfigError::CapacityOverflow))
+        ));
+        assert!(storage
+            .set_capacity(1_000_000_000_000, ResizePolicy::Clamp)
+            .is_ok());
+        assert!(matches!(
+            storage.set_rate(1),
+            Err(ReconfigureError::Config(ConfigError::CapacityOverflow))
+        ));
+    }
+}
+
+#[cfg(all(test, loom))]
+mod loom_tests {
+    use super::*;
+
+    use loom::sync::Arc;
+
+    fn tokens(storage: &AtomicInMemoryStorage) -> i128 {
+        let (generation, full_at) = split(storage.state.load(Ordering::Acquire));
+        let rate = storage.rate(generation);
+        available(full_at, storage.now(), rate.cap, rate.tick)
+    }
+
+    // Run with `RUSTFLAGS="--cfg loom" cargo test --release --lib loom_tests`
+    #[test]
+    fn concurrent_acquire() {
+        loom::model(|| {
+            // The bucket isn't refilled during the model run
+            let storage = Arc::new(AtomicInMemoryStorage::new(2));
+            let alg = TokenBucketAlgorithm::new(Mode::N);
+
+            let threads: Vec<_> = (0..3)
+                .map(|_| {
+                    let storage = Arc::clone(&storage);
+                    loom::thread::spawn(move || storage.try_acquire(alg, 1).is_ok())
+                })
+                .collect();
+            let acquired = threads
+                .into_iter()
+                .map(|t| t.join().unwrap())
+                .filter(|ok| *ok)
+                .count();
+
+            assert_eq!(acquired, 2);
+            assert_eq!(tokens(&storage), 0);
+        });
+    }
+
+    #[test]
+    fn concurrent_release() {
+        loom::model(|| {
+            let storage = Arc::new(AtomicInMemoryStorage::new(2));
+            let alg = TokenBucketAlgorithm::new(Mode::N);
+            assert!(storage.try_acquire(alg, 2).is_ok());
+
+            let released = {
+                let storage = Arc::clone(&storage);
+                loom::thread::spawn(move || storage.release(1).is_ok())
+            };
+            let acquired = storage.try_acquire(alg, 1).is_ok();
+            assert!(released.join().unwrap());
+
+            // Released token is either acquired or left in the bucket
+            assert_eq!(tokens(&storage) + i128::from(acquired), 1);
+        });
+    }
+
+    #[test]
+    fn concurrent_reconfigure() {
+        loom::model(|| {
+            let storage = Arc::new(AtomicInMemoryStorage::new(2));
+            let alg = TokenBucketAlgorithm::new(Mode::N);
+
+            let acquired = {
+                let storage = Arc::clone(&storage);
+                loom::thread::spawn(move || storage.try_acquire(alg, 2).is_ok())
+            };
+            assert!(storage.set_capacity(4, ResizePolicy::Clamp).is_ok());
+            assert!(acquired.join().unwrap());
+
+            // Tokens are kept whether they are acquired before or after the change
+            assert_eq!(tokens(&storage), 0);
+            assert_eq!(
+                storage
+                    .rate(split(storage.state.load(Ordering::Acquire)).0)
+                    .cap,
+                4
+            );
+        });
+    }
+}
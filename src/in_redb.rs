@@ -0,0 +1,607 @@
+use crate::{
+    refill_tick, validate_capacity, BucketConfig, ConfigError, InitialFill, KeyedStorage, Mode,
+    RateLimitExceededError, ReconfigureError, ResizePolicy, State, Storage, StorageError,
+    TokenBucketAlgorithm,
+};
+
+use redb::{Database, Durability, ReadableTable, Table, TableDefinition};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, LazyLock, Weak};
+use std::time::{Duration, Instant};
+
+/// Default key of the bucket.
+pub const DEFAULT_REDB_KEY: &str = "tocket";
+/// Default name of the table with buckets.
+pub const DEFAULT_REDB_TABLE: &str = "tocket_buckets";
+/// Default time to wait for other processes to release the database.
+pub const DEFAULT_REDB_BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+const BUSY_RETRY_INTERVAL: Duration = Duration::from_millis(1);
+
+/// Databases kept open by storages of this process by their canonical paths,
+/// redb fails to open a file that is already open.
+static DATABASES: LazyLock<parking_lot::Mutex<HashMap<PathBuf, Weak<Database>>>> =
+    LazyLock::new(Default::default);
+
+/// When updated states are flushed to disk with `fsync`.
+///
+/// States are written to the file on every acquisition anyway, so they survive a restart
+/// or a crash of the process. The policy only decides what survives a crash of the host.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum FsyncPolicy {
+    /// Flush every update. The slowest, nothing is lost.
+    Always,
+    /// Flush at most once per interval, updates since the last flush may be lost.
+    Interval(Duration),
+    /// Leave flushing to the OS.
+    Never,
+}
+
+enum Db {
+    /// The database is kept open by this process and shared by its storages of the file.
+    Exclusive(Arc<Database>),
+    /// The database is opened for every operation, so other processes can use it in between.
+    Shared {
+        path: PathBuf,
+        busy_timeout: Duration,
+    },
+}
+
+/// A storage that stores state in an embedded [redb](https://docs.rs/redb) database file.
+///
+/// Every bucket is an entry of the table. [`Storage`] uses the bucket of
+/// [`with_key`](RedbStorageBuilder::with_key), [`KeyedStorage`] a bucket per key,
+/// e.g. per client, so quotas of clients survive restarts too. State is loaded, refilled,
+/// debited and saved inside a single write transaction and survives restarts of the process. Entries are
+/// `(i64, i64)` tuples of the balance and the last refill in Unix nanoseconds; tables written
+/// by older versions with unsigned balances fail to open with a type mismatch.
+///
+/// redb allows only one process to open the database. By default the storage keeps it open
+/// and storages of the same file in the process share it,
+/// use [`with_multi_process`](RedbStorageBuilder::with_multi_process) to share the file
+/// between processes of the host at the cost of opening it for every acquisition.
+///
+/// Rate and capacity are changed for all entries of the table with [`KeyedStorage::set_rate`]
+/// and [`KeyedStorage::set_capacity`], [`Storage`] methods do the same.
+///
+/// # Example
+/// ```
+/// use tocket::{FsyncPolicy, RedbStorage, TokenBucket};
+/// use std::time::Duration;
+///
+/// let path = std::env::temp_dir().join("tocket_redb_doc_example.redb");
+/// # let _ = std::fs::remove_file(&path);
+/// let storage = RedbStorage::builder(2, &path)
+///     .with_key("api")
+///     .with_fsync(FsyncPolicy::Interval(Duration::from_secs(1)))
+///     .build()
+///     .unwrap();
+///
+/// let tb = TokenBucket::new(storage);
+/// assert!(tb.try_acquire(2).is_ok());
+/// assert!(tb.try_acquire_one().is_err());
+/// # drop(tb);
+/// # let _ = std::fs::remove_file(&path);
+/// ```
+pub struct RedbStorage {
+    db: Db,
+    table: String,
+    key: String,
+    config: parking_lot::RwLock<BucketConfig>,
+    fsync: FsyncPolicy,
+    last_fsync: parking_lot::Mutex<Option<Instant>>,
+}
+
+impl RedbStorage {
+    /// Creates a storage with default key of the bucket, kept open by this process.
+    pub fn new<P>(rps_limit: u32, path: P) -> Result<Self, RedbStorageError>
+    where
+        P: AsRef<Path>,
+    {
+        Self::builder(rps_limit, path).build()
+    }
+
+    /// Creates a builder of storage. Needs for customizing of key, fsync policy
+    /// and sharing between processes.
+    pub fn builder<P>(rps_limit: u32, path: P) -> RedbStorageBuilder
+    where
+        P: AsRef<Path>,
+    {
+        RedbStorageBuilder {
+            rps_limit,
+            path: path.as_ref().to_owned(),
+            key: DEFAULT_REDB_KEY.to_owned(),
+            table: DEFAULT_REDB_TABLE.to_owned(),
+            fsync: FsyncPolicy::Always,
+            busy_timeout: None,
+            initial_fill: InitialFill::Full,
+        }
+    }
+
+    fn durability(&self) -> Durability {
+        let now = Instant::now();
+        let mut last_fsync = self.last_fsync.lock();
+        let due = match self.fsync {
+            FsyncPolicy::Always => true,
+            FsyncPolicy::Interval(interval) => {
+                last_fsync.is_none_or(|last| now.duration_since(last) >= interval)
+            }
+            FsyncPolicy::Never => false,
+        };
+
+        if due {
+            *last_fsync = Some(now);
+            Durability::Immediate
+        } else {
+            Durability::Eventual
+        }
+    }
+
+    /// Runs `f` with the table inside a write transaction, which is committed only if `f` succeeds.
+    fn with_table<F>(&self, f: F) -> Result<(), RedbStorageError>
+    where
+        F: FnOnce(&mut Table<'_, &'static str, (i64, i64)>) -> Result<(), RedbStorageError>,
+    {
+        match &self.db {
+            Db::Exclusive(db) => self.with_table_in(db, f),
+            Db::Shared { path, busy_timeout } => {
+                let db = open_shared(path, *busy_timeout)?;
+                self.with_table_in(&db, f)
+            }
+        }
+    }
+
+    fn with_table_in<F>(&self, db: &Database, f: F) -> Result<(), RedbStorageError>
+    where
+        F: FnOnce(&mut Table<'_, &'static str, (i64, i64)>) -> Result<(), RedbStorageError>,
+    {
+        let definition: TableDefinition<&str, (i64, i64)> = TableDefinition::new(&self.table);
+        let mut tx = db.begin_write().map_err(redb::Error::from)?;
+        tx.set_durability(self.durability());
+        {
+            let mut table = tx.open_table(definition).map_err(redb::Error::from)?;
+            f(&mut table)?;
+        }
+        tx.commit().map_err(redb::Error::from)?;
+        Ok(())
+    }
+
+    /// Runs `f` with the state of the bucket of `key` inside a write transaction.
+    /// The state is saved only if `f` succeeds.
+    fn with_state<F>(&self, key: &str, config: &BucketConfig, f: F) -> Result<(), RedbStorageError>
+    where
+        F: FnOnce(&mut State) -> Result<(), RedbStorageError>,
+    {
+        self.with_table(|table| {
+            let entry = table
+                .get(key)
+                .map_err(redb::Error::from)?
+                .map(|value| value.value());
+            let mut state = match entry {
+                Some(entry) => decode(config, entry)?,
+                None => config.new_state(),
+            };
+
+            f(&mut state)?;
+            table
+                .insert(key, encode(&state)?)
+                .map_err(redb::Error::from)?;
+            Ok(())
+        })
+    }
+
+    /// Applies `f` to states of all buckets of the table inside a write transaction.
+    fn with_all_states<F>(&self, config: &BucketConfig, f: F) -> Result<(), RedbStorageError>
+    where
+        F: Fn(&mut State),
+    {
+        self.with_table(|table| {
+            let mut entries = Vec::new();
+            for entry in table.iter().map_err(redb::Error::from)? {
+                let (key, value) = entry.map_err(redb::Error::from)?;
+                entries.push((key.value().to_owned(), value.value()));
+            }
+            for (key, entry) in entries {
+                let mut state = decode(config, entry)?;
+                f(&mut state);
+                table
+                    .insert(key.as_str(), encode(&state)?)
+                    .map_err(redb::Error::from)?;
+            }
+            Ok(())
+        })
+    }
+}
+
+/// State of a stored `(balance, last refill in Unix nanoseconds)` entry.
+fn decode(
+    config: &BucketConfig,
+    (available_tokens, last_refill): (i64, i64),
+) -> Result<State, RedbStorageError> {
+    let last_refill = time::OffsetDateTime::from_unix_timestamp_nanos(last_refill.into())
+        .map_err(|_| RedbStorageError::InvalidTimestamp(last_refill))?;
+    Ok(config.state(available_tokens, last_refill))
+}
+
+/// Entry of a state, see [`decode`].
+fn encode(state: &State) -> Result<(i64, i64), RedbStorageError> {
+    let last_refill = i64::try_from(state.last_refill.unix_timestamp_nanos())
+        .map_err(|_| RedbStorageError::InvalidTimestamp(i64::MAX))?;
+    Ok((state.available_tokens, last_refill))
+}
+
+/// Opens the database or returns the one this process keeps open already.
+fn open_exclusive(path: &Path) -> Result<Arc<Database>, RedbStorageError> {
+    let mut databases = DATABASES.lock();
+    databases.retain(|_, db| db.strong_count() > 0);
+    let open = std::fs::canonicalize(path)
+        .ok()
+        .and_then(|path| databases.get(&path).and_then(Weak::upgrade));
+    if let Some(db) = open {
+        return Ok(db);
+    }
+
+    let db = Arc::new(Database::create(path).map_err(redb::Error::from)?);
+    let path = std::fs::canonicalize(path).map_err(redb::Error::Io)?;
+    databases.insert(path, Arc::downgrade(&db));
+    Ok(db)
+}
+
+/// Opens the database, waiting while it's open by another process.
+fn open_shared(path: &Path, busy_timeout: Duration) -> Result<Database, RedbStorageError> {
+    let started_at = Instant::now();
+    loop {
+        match Database::create(path) {
+            Ok(db) => return Ok(db),
+            Err(redb::DatabaseError::DatabaseAlreadyOpen) => {
+                if started_at.elapsed() >= busy_timeout {
+                    return Err(RedbStorageError::Busy {
+                        timeout: busy_timeout,
+                    });
+                }
+                std::thread::sleep(BUSY_RETRY_INTERVAL);
+            }
+            Err(err) => return Err(redb::Error::from(err).into()),
+        }
+    }
+}
+
+pub struct RedbStorageBuilder {
+    rps_limit: u32,
+    path: PathBuf,
+    key: String,
+    table: String,
+    fsync: FsyncPolicy,
+    busy_timeout: Option<Duration>,
+    initial_fill: InitialFill,
+}
+
+impl RedbStorageBuilder {
+    /// Customize key of the bucket.
+    pub fn with_key<K>(mut self, key: K) -> Self
+    where
+        K: Into<String>,
+    {
+        self.key = key.into();
+        self
+    }
+
+    /// Customize name of the table.
+    pub fn with_table<T>(mut self, table: T) -> Self
+    where
+        T: Into<String>,
+    {
+        self.table = table.into();
+        self
+    }
+
+    /// Customize when updates are flushed to disk. [`FsyncPolicy::Always`] by default.
+    pub fn with_fsync(mut self, policy: FsyncPolicy) -> Self {
+        self.fsync = policy;
+        self
+    }
+
+    /// Share the database file with other processes. An acquisition waits up to `busy_timeout`
+    /// while the database is used by another process.
+    pub fn with_multi_process(mut self, busy_timeout: Duration) -> Self {
+        self.busy_timeout = Some(busy_timeout);
+        self
+    }
+
+    /// Customize tokens of the bucket that isn't stored yet, full by default.
+    pub fn with_initial_fill(mut self, initial_fill: InitialFill) -> Self {
+        self.initial_fill = initial_fill;
+        self
+    }
+
+    /// Creates the database file if it doesn't exist and opens it.
+    pub fn build(self) -> Result<RedbStorage, RedbStorageError> {
+        let config = BucketConfig::try_new(self.rps_limit, self.rps_limit.into())?
+            .with_initial_fill(self.initial_fill);
+        let db = match self.busy_timeout {
+            Some(busy_timeout) => {
+                // Check the file can be opened
+                drop(open_shared(&self.path, busy_timeout)?);
+                Db::Shared {
+                    path: self.path,
+                    busy_timeout,
+                }
+            }
+            None => Db::Exclusive(open_exclusive(&self.path)?),
+        };
+
+        Ok(RedbStorage {
+            db,
+            table: self.table,
+            key: self.key,
+            config: parking_lot::RwLock::new(config),
+            fsync: self.fsync,
+            last_fsync: Default::default(),
+        })
+    }
+}
+
+impl Storage for RedbStorage {
+    type Error = RedbStorageError;
+
+    fn try_acquire(&self, alg: TokenBucketAlgorithm, permits: u64) -> Result<(), Self::Error> {
+        KeyedStorage::try_acquire(self, &self.key, alg, permits)
+    }
+
+    fn release(&self, permits: u64) -> Result<(), Self::Error> {
+        KeyedStorage::release(self, &self.key, permits)
+    }
+
+    fn charge(&self, cost: u64) -> Result<(), Self::Error> {
+        KeyedStorage::charge(self, &self.key, cost)
+    }
+
+    /// Changes the rate of all buckets of the table, see [`KeyedStorage::set_rate`].
+    fn set_rate(&self, rps_limit: u32) -> Result<(), ReconfigureError<Self::Error>> {
+        KeyedStorage::set_rate(self, rps_limit)
+    }
+
+    /// Changes the capacity of all buckets of the table, see [`KeyedStorage::set_capacity`].
+    fn set_capacity(
+        &self,
+        cap: u64,
+        policy: ResizePolicy,
+    ) -> Result<(), ReconfigureError<Self::Error>> {
+        KeyedStorage::set_capacity(self, cap, policy)
+    }
+}
+
+impl KeyedStorage for RedbStorage {
+    type Key = str;
+    type Error = RedbStorageError;
+
+    fn try_acquire(
+        &self,
+        key: &str,
+        alg: TokenBucketAlgorithm,
+        permits: u64,
+    ) -> Result<(), Self::Error> {
+        self.with_state(key, &self.config.read(), |state| {
+            Ok(alg.try_acquire(state, permits)?)
+        })
+    }
+
+    fn release(&self, key: &str, permits: u64) -> Result<(), Self::Error> {
+        self.with_state(key, &self.config.read(), |state| {
+            state.release(permits);
+            Ok(())
+        })
+    }
+
+    fn charge(&self, key: &str, cost: u64) -> Result<(), Self::Error> {
+        self.with_state(key, &self.config.read(), |state| {
+            TokenBucketAlgorithm::new(Mode::N).charge(state, cost);
+            Ok(())
+        })
+    }
+
+    /// Changes the rate used by this storage and refills all stored states with the old one.
+    /// Other processes sharing the database should be reconfigured too.
+    fn set_rate(&self, rps_limit: u32) -> Result<(), ReconfigureError<Self::Error>> {
+        let refill_tick = refill_tick(rps_limit)?;
+        let mut config = self.config.write();
+        self.with_all_states(&config, |state| state.set_rate(refill_tick))
+            .map_err(ReconfigureError::Storage)?;
+        config.refill_tick = refill_tick;
+        Ok(())
+    }
+
+    /// Changes the capacity used by this storage and adjusts all stored tokens.
+    /// Other processes sharing the database should be reconfigured too,
+    /// with [`ResizePolicy::Clamp`] if the tokens are already scaled.
+    fn set_capacity(
+        &self,
+        cap: u64,
+        policy: ResizePolicy,
+    ) -> Result<(), ReconfigureError<Self::Error>> {
+        let cap = validate_capacity(cap)?;
+        let mut config = self.config.write();
+        self.with_all_states(&config, |state| state.set_capacity(cap, policy))
+            .map_err(ReconfigureError::Storage)?;
+        config.cap = cap;
+        Ok(())
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum RedbStorageError {
+    #[error(transparent)]
+    RedbError(Box<redb::Error>),
+    #[error(transparent)]
+    RateLimitExceededError(#[from] RateLimitExceededError),
+    #[error(transparent)]
+    ConfigError(#[from] ConfigError),
+    #[error("timestamp {0} is out of range")]
+    InvalidTimestamp(i64),
+    #[error("database is used by another process longer than {timeout:?}")]
+    Busy { timeout: Duration },
+}
+
+impl From<redb::Error> for RedbStorageError {
+    fn from(err: redb::Error) -> Self {
+        RedbStorageError::RedbError(Box::new(err))
+    }
+}
+
+impl StorageError for RedbStorageError {
+    fn is_rate_limit_exceeded(&self) -> bool {
+        matches!(self, RedbStorageError::RateLimitExceededError(_))
+    }
+
+    fn is_capacity_exceeded(&self) -> bool {
+        matches!(self, RedbStorageError::RateLimitExceededError(err) if err.is_capacity_exceeded())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{KeyedTokenBucket, Mode, TokenBucket};
+
+    use uuid::Uuid;
+
+    struct TempDb(PathBuf);
+
+    impl TempDb {
+        fn new() -> Self {
+            Self(std::env::temp_dir().join(format!("tocket_{}.redb", Uuid::new_v4())))
+        }
+    }
+
+    impl Drop for TempDb {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    #[test]
+    fn survives_restart() {
+        let db = TempDb::new();
+        let storage = |key: &str| {
+            RedbStorage::builder(10, &db.0)
+                .with_key(key)
+                .with_fsync(FsyncPolicy::Never)
+                .build()
+                .unwrap()
+        };
+
+        let a = TokenBucket::new(storage("a"));
+        assert!(a.try_acquire(10).is_ok());
+        assert!(a.try_acquire_one().is_err());
+        drop(a);
+
+        let b = TokenBucket::new(storage("b"));
+        assert!(b.try_acquire(10).is_ok());
+        drop(b);
+
+        // Reopened bucket keeps its state
+        let a = TokenBucket::new(storage("a"));
+        assert!(a.try_acquire(5).is_err());
+    }
+
+    #[test]
+    fn keyed_survives_restart() {
+        let db = TempDb::new();
+        let storage = || {
+            RedbStorage::builder(10, &db.0)
+                .with_fsync(FsyncPolicy::Never)
+                .build()
+                .unwrap()
+        };
+
+        // Storages of the same file share the database
+        let clients = KeyedTokenBucket::new(storage());
+        let default = TokenBucket::new(storage());
+        assert!(clients.try_acquire("alice", 10).is_ok());
+        assert!(clients.try_acquire_one("alice").is_err());
+        assert!(clients.try_acquire_one("bob").is_ok());
+        assert!(default.try_acquire(10).is_ok());
+        drop((clients, default));
+
+        let clients = KeyedTokenBucket::new(storage());
+        assert!(clients.try_acquire("alice", 5).is_err());
+        assert!(clients.try_acquire(DEFAULT_REDB_KEY, 5).is_err());
+
+        // Stored buckets of all keys are reconfigured
+        assert!(clients.set_capacity(3, ResizePolicy::Clamp).is_ok());
+        assert!(clients.try_acquire("bob", 3).is_ok());
+        assert!(clients.try_acquire_one("bob").is_err());
+        assert!(clients.try_acquire("carol", 4).is_err());
+        assert!(clients.try_acquire("carol", 3).is_ok());
+    }
+
+    #[test]
+    fn invalid_config() {
+        let db = TempDb::new();
+        assert!(matches!(
+            RedbStorage::new(0, &db.0),
+            Err(RedbStorageError::ConfigError(ConfigError::ZeroRate))
+        ));
+
+        let tb = TokenBucket::new(RedbStorage::new(10, &db.0).unwrap());
+        assert!(matches!(
+            tb.set_rate(0),
+            Err(ReconfigureError::Config(ConfigError::ZeroRate))
+        ));
+        assert!(matches!(
+            tb.set_capacity(0, ResizePolicy::Clamp),
+            Err(ReconfigureError::Config(ConfigError::ZeroCapacity))
+        ));
+        assert!(tb.try_acquire(10).is_ok());
+    }
+
+    #[test]
+    fn exclusive_by_default() {
+        let db = TempDb::new();
+        let _storage = RedbStorage::new(10, &db.0).unwrap();
+        assert!(matches!(
+            RedbStorage::builder(10, &db.0)
+                .with_multi_process(Duration::from_millis(50))
+                .build(),
+            Err(RedbStorageError::Busy { .. })
+        ));
+    }
+
+    #[test]
+    fn multi_process() {
+        let db = TempDb::new();
+        let storages: Vec<_> = (0..4)
+            .map(|_| {
+                RedbStorage::builder(5, &db.0)
+                    .with_fsync(FsyncPolicy::Interval(Duration::from_millis(100)))
+                    .with_multi_process(DEFAULT_REDB_BUSY_TIMEOUT)
+                    .build()
+                    .unwrap()
+            })
+            .collect();
+
+        let started_at = Instant::now();
+        let acquired: u128 = std::thread::scope(|s| {
+            let handles: Vec<_> = storages
+                .iter()
+                .map(|storage| {
+                    s.spawn(move || {
+                        (0..5)
+                            .filter(|_| {
+                                Storage::try_acquire(storage, TokenBucketAlgorithm::new(Mode::N), 1)
+                                    .is_ok()
+                            })
+                            .count() as u128
+                    })
+                })
+                .collect();
+            handles.into_iter().map(|h| h.join().unwrap()).sum()
+        });
+
+        // 5 tokens and ones refilled during the test
+        let refilled = started_at.elapsed().as_millis() / 200;
+        assert!(acquired >= 5);
+        assert!(acquired <= 5 + refilled);
+    }
+}
@@ -0,0 +1,282 @@
+use crate::composite;
+use crate::composite::{CompositeLimitExceededError, CompositeStorage, Limit};
+use crate::in_redis::layout::{BatchBucket, BatchMode, Layout};
+use crate::in_redis::{
+    check_same_slot, last_refill_key, state_key, tokens_key, validate_redis_capacity, RedisStorage,
+    RedisStorageError, MAX_REDIS_CAPACITY,
+};
+use crate::{balance, ticks, BucketConfig, ConfigError, InitialFill, RateLimitExceededError};
+
+use std::time::Duration;
+
+/// A bucket stored in Redis, used for acquiring several buckets at once.
+///
+/// Created by [`RedisStorage::bucket`] and stored the same way as the bucket of the storage.
+#[derive(Debug, Clone)]
+pub struct RedisBucket {
+    pub(crate) layout: Layout,
+    pub(crate) cap: u64,
+    pub(crate) refill_tick: time::Duration,
+    pub(crate) initial_fill: InitialFill,
+}
+
+impl RedisBucket {
+    /// The bucket taking `permits` in a batch script.
+    fn batch(&self, permits: u64) -> BatchBucket<'_> {
+        BatchBucket {
+            layout: &self.layout,
+            cap: self.cap,
+            refill_tick: self.refill_tick,
+            initial_tokens: balance(self.initial_fill.tokens(self.cap)),
+            permits,
+        }
+    }
+}
+
+/// Result of a single bucket in a batch.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct BucketResult {
+    /// `true` if the bucket has enough tokens for the request.
+    pub allowed: bool,
+    /// Tokens left in the bucket. If the batch is denied, nothing is acquired
+    /// and this is the number of tokens available at the moment, negative if the bucket is in debt.
+    pub available_tokens: i64,
+    /// Time until the bucket has enough tokens, zero if it's allowed.
+    pub retry_after: Duration,
+}
+
+impl RedisStorage {
+    /// Creates a bucket named like with [`RedisStorageBuilder::with_bucket`] with its own limit,
+    /// `cap` tokens refilled with `rps_limit` tokens per second.
+    ///
+    /// The bucket uses the same layout and initial fill as the storage. In Redis Cluster all buckets of a batch
+    /// must be in the same slot, so give them a common hash tag, e.g. `{tenant:1}:user:42`
+    /// and `{tenant:1}:endpoint:search`.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the rate or the capacity is zero or if the capacity is above
+    /// [`MAX_REDIS_CAPACITY`].
+    ///
+    /// [`RedisStorageBuilder::with_bucket`]: crate::RedisStorageBuilder::with_bucket
+    pub fn bucket<B>(&self, bucket: B, rps_limit: u32, cap: u64) -> Result<RedisBucket, ConfigError>
+    where
+        B: AsRef<str>,
+    {
+        let config = BucketConfig::try_new(rps_limit, cap)?;
+        validate_redis_capacity(config.cap)?;
+        let bucket = bucket.as_ref();
+        let storage_bucket = self.bucket.read();
+        let layout = match &storage_bucket.layout {
+            Layout::Keys { .. } => Layout::keys(tokens_key(bucket), last_refill_key(bucket), None),
+            Layout::Hash {
+                store_config,
+                expires,
+                ..
+            } => Layout::hash(state_key(bucket), *store_config, *expires),
+        };
+
+        Ok(RedisBucket {
+            layout,
+            cap: config.cap,
+            refill_tick: config.refill_tick,
+            initial_fill: storage_bucket.initial_fill,
+        })
+    }
+
+    /// Acquires permits from all buckets or from none of them.
+    ///
+    /// All buckets are refilled, checked and updated by a single script, so the batch takes
+    /// one round trip whatever the number of buckets is and is never repeated. If the same
+    /// bucket is given several times, its permits are summed up.
+    ///
+    /// # Example
+    /// ```no_run
+    /// use tocket::{RedisStorage, RedisStorageError};
+    ///
+    /// let storage = RedisStorage::new(100, "redis://127.0.0.1:6379").unwrap();
+    /// let user = storage.bucket("user:42", 10, 20).unwrap();
+    /// let tenant = storage.bucket("tenant:1", 1000, 1000).unwrap();
+    ///
+    /// match storage.try_acquire_batch(&[(&user, 1), (&tenant, 1)]) {
+    ///     Ok(_) => println!("acquired"),
+    ///     Err(RedisStorageError::BatchRateLimitExceeded { results }) => {
+    ///         println!("user allowed: {}", results[0].allowed);
+    ///     }
+    ///     Err(err) => eprintln!("{}", err),
+    /// }
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// Will return [`RedisStorageError::BatchRateLimitExceeded`] with results of all buckets
+    /// if any of them doesn't have enough tokens, [`RateLimitExceededError::ExceedsCapacity`]
+    /// if permits of a bucket exceed its capacity, [`RedisStorageError::MixedLayouts`]
+    /// if buckets are stored differently, [`RedisStorageError::WarmUpInBatch`] if the batch
+    /// has the bucket of the storage that warms up, [`RedisStorageError::CrossSlotKeys`] if buckets
+    /// are in different slots of Redis Cluster or if the storage could not save/load state.
+    pub fn try_acquire_batch(
+        &self,
+        requests: &[(&RedisBucket, u64)],
+    ) -> Result<Vec<BucketResult>, RedisStorageError> {
+        let first = match requests.first() {
+            Some((bucket, _)) => &bucket.layout,
+            None => return Ok(Vec::new()),
+        };
+        if requests.iter().any(|(bucket, _)| {
+            std::mem::discriminant(&bucket.layout) != std::mem::discriminant(first)
+        }) {
+            return Err(RedisStorageError::MixedLayouts);
+        }
+        // The script refills buckets linearly, a cold bucket would take the full rate
+        if self.warm_up.is_some() {
+            let bucket = self.bucket.read();
+            let key = bucket.layout.last_refill_key();
+            if requests
+                .iter()
+                .any(|(b, _)| b.layout.last_refill_key() == key)
+            {
+                return Err(RedisStorageError::WarmUpInBatch {
+                    key: key.to_owned(),
+                });
+            }
+        }
+        if self.cluster {
+            let keys: Vec<&str> = requests
+                .iter()
+                .flat_map(|(bucket, _)| bucket.layout.accessed_keys())
+                .collect();
+            check_same_slot(&keys)?;
+        }
+
+        // Requests of the same bucket share its state, so their permits are summed up
+        let mut buckets: Vec<BatchBucket<'_>> = Vec::new();
+        let mut owners = Vec::with_capacity(requests.len());
+        for (bucket, permits) in requests {
+            let key = bucket.layout.last_refill_key();
+            match buckets
+                .iter()
+                .position(|b| b.layout.last_refill_key() == key)
+            {
+                Some(owner) => {
+                    buckets[owner].permits = buckets[owner].permits.saturating_add(*permits);
+                    owners.push(owner);
+                }
+                None => {
+                    owners.push(buckets.len());
+                    buckets.push(bucket.batch(*permits));
+                }
+            }
+        }
+
+        // A request above the capacity never succeeds, so Redis isn't asked
+        if let Some(b) = buckets.iter().find(|b| b.permits > b.cap) {
+            return Err(RateLimitExceededError::ExceedsCapacity {
+                permits: b.permits,
+                cap: b.cap,
+            }
+            .into());
+        }
+        let (acquired, refilled) = self.pool.with_conn(|conn| {
+            Layout::update_many(&buckets, BatchMode::Acquire, self.encoding, conn)
+        })?;
+
+        // Later requests of the same bucket see fewer tokens
+        let mut tokens: Vec<i64> = refilled.iter().map(|r| r.available_tokens).collect();
+        let mut results = Vec::with_capacity(requests.len());
+        for ((bucket, permits), &owner) in requests.iter().zip(&owners) {
+            let available = &mut tokens[owner];
+            let allowed = i128::from(*available) >= i128::from(*permits);
+            if allowed {
+                *available -= balance(*permits);
+            }
+            let missing = (i128::from(*permits) - i128::from(*available)).max(0);
+            results.push(BucketResult {
+                allowed,
+                available_tokens: *available,
+                retry_after: if allowed {
+                    Duration::ZERO
+                } else {
+                    ticks(bucket.refill_tick, missing)
+                        .saturating_sub(refilled[owner].since_refill)
+                        .try_into()
+                        .unwrap_or_default()
+                },
+            });
+        }
+        if !acquired {
+            return Err(RedisStorageError::BatchRateLimitExceeded { results });
+        }
+
+        // Report the final number of tokens of every bucket
+        for (result, &owner) in results.iter_mut().zip(&owners) {
+            result.available_tokens = tokens[owner];
+        }
+        Ok(results)
+    }
+}
+
+/// Limits are stored next to the bucket of the storage: its keys are suffixed
+/// with `:<cap>:<period in nanoseconds>`, e.g. `{tocket}:tokens:10:1000000000`.
+/// So in Redis Cluster all limits are in the slot of the storage bucket.
+impl CompositeStorage for RedisStorage {
+    type Error = RedisStorageError;
+
+    fn try_acquire_all(&self, limits: &[Limit], permits: u64) -> Result<(), Self::Error> {
+        let buckets = self.limit_buckets(limits)?;
+        composite::check_capacity(limits, permits)?;
+        let requests: Vec<_> = buckets.iter().map(|bucket| (bucket, permits)).collect();
+
+        match self.try_acquire_batch(&requests) {
+            Ok(_) => Ok(()),
+            Err(RedisStorageError::BatchRateLimitExceeded { results }) => {
+                Err(CompositeLimitExceededError::new(
+                    limits
+                        .iter()
+                        .zip(results)
+                        .filter(|(_, result)| !result.allowed)
+                        .map(|(limit, result)| (*limit, result.retry_after)),
+                )
+                .into())
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Charges all limits by the same script as acquiring, a charge is never denied.
+    /// The cost saturates at [`MAX_REDIS_CAPACITY`].
+    fn charge_all(&self, limits: &[Limit], cost: u64) -> Result<(), Self::Error> {
+        let buckets = self.limit_buckets(limits)?;
+        let cost = cost.min(MAX_REDIS_CAPACITY);
+        let buckets: Vec<_> = buckets.iter().map(|bucket| bucket.batch(cost)).collect();
+
+        self.pool.with_conn(|conn| {
+            Layout::update_many(&buckets, BatchMode::Charge, self.encoding, conn)
+        })?;
+        Ok(())
+    }
+}
+
+impl RedisStorage {
+    /// Buckets of limits next to the bucket of the storage, new ones are filled
+    /// like the bucket of the storage. Fails if a limit is above [`MAX_REDIS_CAPACITY`].
+    fn limit_buckets(&self, limits: &[Limit]) -> Result<Vec<RedisBucket>, ConfigError> {
+        for limit in limits {
+            validate_redis_capacity(limit.cap())?;
+        }
+        let storage_bucket = self.bucket.read();
+        Ok(limits
+            .iter()
+            .map(|limit| RedisBucket {
+                layout: storage_bucket.layout.with_suffix(&format!(
+                    ":{}:{}",
+                    limit.cap(),
+                    limit.period().as_nanos()
+                )),
+                cap: limit.cap(),
+                refill_tick: limit.refill_tick(),
+                initial_fill: storage_bucket.initial_fill,
+            })
+            .collect())
+    }
+}
@@ -0,0 +1,185 @@
+use crate::in_redis::pool::Connection;
+
+use redis::{ConnectionAddr, ConnectionInfo, ErrorKind, IntoConnectionInfo, RedisResult};
+
+/// Where the storage connects to.
+pub(crate) enum Target {
+    Single(String),
+    Cluster(Vec<String>),
+    Sentinel {
+        master_name: String,
+        sentinels: Vec<String>,
+    },
+}
+
+/// Connection settings that override the ones from URLs.
+#[derive(Clone, Default)]
+pub(crate) struct ConnectionOptions {
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub db: Option<i64>,
+    pub tls: bool,
+    #[cfg(feature = "redis-tls-impl")]
+    pub certs: Option<redis::TlsCertificates>,
+}
+
+impl ConnectionOptions {
+    /// Applies credentials, database and TLS to the info of a data node.
+    fn apply(&self, mut info: ConnectionInfo) -> ConnectionInfo {
+        if self.username.is_some() {
+            info.redis.username = self.username.clone();
+        }
+        if self.password.is_some() {
+            info.redis.password = self.password.clone();
+        }
+        if let Some(db) = self.db {
+            info.redis.db = db;
+        }
+        if self.uses_tls() {
+            if let ConnectionAddr::Tcp(host, port) = info.addr {
+                info.addr = ConnectionAddr::TcpTls {
+                    host,
+                    port,
+                    insecure: false,
+                    tls_params: None,
+                };
+            }
+        }
+        info
+    }
+
+    fn uses_tls(&self) -> bool {
+        #[cfg(feature = "redis-tls-impl")]
+        if self.certs.is_some() {
+            return true;
+        }
+        self.tls
+    }
+
+    fn open_client(&self, info: ConnectionInfo) -> RedisResult<redis::Client> {
+        #[cfg(feature = "redis-tls-impl")]
+        if let Some(certs) = &self.certs {
+            return redis::Client::build_with_tls(info, certs.clone());
+        }
+        redis::Client::open(info)
+    }
+}
+
+/// Opens connections to a single node, to a cluster or to the master monitored by sentinels.
+pub(crate) enum Connector {
+    Single(redis::Client),
+    Cluster(redis::cluster::ClusterClient),
+    Sentinel(SentinelConnector),
+}
+
+impl Connector {
+    pub fn new(target: &Target, options: &ConnectionOptions) -> RedisResult<Self> {
+        match target {
+            Target::Single(conn_info) => {
+                let info = options.apply(conn_info.as_str().into_connection_info()?);
+                options.open_client(info).map(Connector::Single)
+            }
+            Target::Cluster(nodes) => {
+                if options.db.is_some_and(|db| db != 0) {
+                    return Err((
+                        ErrorKind::InvalidClientConfig,
+                        "Redis Cluster supports only database 0",
+                    )
+                        .into());
+                }
+                let nodes = nodes
+                    .iter()
+                    .map(|n| n.as_str().into_connection_info().map(|i| options.apply(i)))
+                    .collect::<RedisResult<Vec<_>>>()?;
+                #[allow(unused_mut)]
+                let mut builder = redis::cluster::ClusterClient::builder(nodes);
+                #[cfg(feature = "redis-tls-impl")]
+                if let Some(certs) = &options.certs {
+                    builder = builder.certs(certs.clone());
+                }
+                builder.build().map(Connector::Cluster)
+            }
+            Target::Sentinel {
+                master_name,
+                sentinels,
+            } => {
+                let sentinels = sentinels
+                    .iter()
+                    .map(|s| options.open_client(s.as_str().into_connection_info()?))
+                    .collect::<RedisResult<Vec<_>>>()?;
+                if sentinels.is_empty() {
+                    return Err((ErrorKind::InvalidClientConfig, "no sentinels given").into());
+                }
+                Ok(Connector::Sentinel(SentinelConnector {
+                    master_name: master_name.clone(),
+                    sentinels,
+                    options: options.clone(),
+                }))
+            }
+        }
+    }
+
+    pub fn connect(&self) -> RedisResult<Connection> {
+        match self {
+            Connector::Single(client) => client.get_connection().map(Connection::Single),
+            Connector::Cluster(client) => client
+                .get_connection()
+                .map(|conn| Connection::Cluster(Box::new(conn))),
+            Connector::Sentinel(sentinel) => sentinel.connect().map(Connection::Single),
+        }
+    }
+}
+
+/// Asks sentinels for the current master on every connect, so reconnects follow failovers.
+pub(crate) struct SentinelConnector {
+    master_name: String,
+    sentinels: Vec<redis::Client>,
+    options: ConnectionOptions,
+}
+
+impl SentinelConnector {
+    /// Tries sentinels in order until one of them points to a reachable master.
+    fn connect(&self) -> RedisResult<redis::Connection> {
+        let mut last_err = None;
+        for sentinel in &self.sentinels {
+            match self.connect_via(sentinel) {
+                Ok(conn) => return Ok(conn),
+                Err(err) => {
+                    tracing::debug!(
+                        "getting master '{}' from sentinel {} failed: {}",
+                        self.master_name,
+                        sentinel.get_connection_info().addr,
+                        err
+                    );
+                    last_err = Some(err);
+                }
+            }
+        }
+        Err(last_err.expect("sentinels are never empty"))
+    }
+
+    fn connect_via(&self, sentinel: &redis::Client) -> RedisResult<redis::Connection> {
+        let mut sentinel = sentinel.get_connection()?;
+        let (host, port): (String, u16) = redis::cmd("SENTINEL")
+            .arg("get-master-addr-by-name")
+            .arg(&self.master_name)
+            .query(&mut sentinel)?;
+
+        let info = self.options.apply(ConnectionInfo {
+            addr: ConnectionAddr::Tcp(host, port),
+            redis: Default::default(),
+        });
+        let mut conn = self.options.open_client(info)?.get_connection()?;
+
+        // Sentinel could still point to the old master during failover
+        let role: Vec<redis::Value> = redis::cmd("ROLE").query(&mut conn)?;
+        match role.first() {
+            Some(redis::Value::Data(role)) if role == b"master" => Ok(conn),
+            _ => Err((
+                ErrorKind::ReadOnly,
+                "node given by sentinel is not a master",
+            )
+                .into()),
+        }
+    }
+}
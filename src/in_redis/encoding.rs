@@ -0,0 +1,101 @@
+/// Encoding of the last refill time in redis.
+///
+/// Available tokens are always stored as a decimal string. Last refill is the time
+/// until which tokens were added to the bucket. Missing values mean a full bucket
+/// refilled right now.
+///
+/// Decimal strings are decoded in the unit of the selected encoding (nanoseconds for [`Binary`]),
+/// any other 16 bytes value is decoded as [`Binary`]. So buckets written by older versions
+/// stay readable after switching the encoding.
+///
+/// [`Binary`]: TimestampEncoding::Binary
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq)]
+pub enum TimestampEncoding {
+    /// Unix timestamp in nanoseconds as 16 bytes of little-endian `i128`.
+    #[default]
+    Binary,
+    /// Unix timestamp in microseconds as a decimal string, e.g. `1650000000123456`.
+    UnixMicros,
+    /// Unix timestamp in nanoseconds as a decimal string, e.g. `1650000000123456789`.
+    UnixNanos,
+}
+
+const I128_SIZE: usize = std::mem::size_of::<i128>();
+
+impl TimestampEncoding {
+    pub(crate) fn encode(&self, ts: time::OffsetDateTime) -> Vec<u8> {
+        let nanos = ts.unix_timestamp_nanos();
+        match self {
+            TimestampEncoding::Binary => nanos.to_le_bytes().to_vec(),
+            TimestampEncoding::UnixMicros => nanos.div_euclid(1_000).to_string().into_bytes(),
+            TimestampEncoding::UnixNanos => nanos.to_string().into_bytes(),
+        }
+    }
+
+    /// Returns `None` if the value is neither decimal nor 16 bytes long.
+    pub(crate) fn decode_nanos(&self, value: &[u8]) -> Option<i128> {
+        if let Some(decimal) = parse_decimal(value) {
+            return match self {
+                TimestampEncoding::UnixMicros => decimal.checked_mul(1_000),
+                TimestampEncoding::Binary | TimestampEncoding::UnixNanos => Some(decimal),
+            };
+        }
+
+        let arr: [u8; I128_SIZE] = value.try_into().ok()?;
+        Some(i128::from_le_bytes(arr))
+    }
+}
+
+fn parse_decimal(value: &[u8]) -> Option<i128> {
+    let digits = value.strip_prefix(b"-").unwrap_or(value);
+    if digits.is_empty() || !digits.iter().all(u8::is_ascii_digit) {
+        return None;
+    }
+    std::str::from_utf8(value).ok()?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let ts =
+            time::OffsetDateTime::from_unix_timestamp_nanos(1_650_000_000_123_456_789).unwrap();
+
+        for (enc, exp_nanos) in [
+            (TimestampEncoding::Binary, 1_650_000_000_123_456_789),
+            (TimestampEncoding::UnixMicros, 1_650_000_000_123_456_000),
+            (TimestampEncoding::UnixNanos, 1_650_000_000_123_456_789),
+        ] {
+            assert_eq!(enc.decode_nanos(&enc.encode(ts)), Some(exp_nanos));
+        }
+
+        assert_eq!(
+            TimestampEncoding::UnixMicros.encode(ts),
+            b"1650000000123456".to_vec()
+        );
+        assert_eq!(
+            TimestampEncoding::UnixNanos.encode(ts),
+            b"1650000000123456789".to_vec()
+        );
+    }
+
+    #[test]
+    fn decode_legacy_binary() {
+        let binary = TimestampEncoding::Binary.encode(
+            time::OffsetDateTime::from_unix_timestamp_nanos(1_650_000_000_123_456_789).unwrap(),
+        );
+
+        for enc in [
+            TimestampEncoding::Binary,
+            TimestampEncoding::UnixMicros,
+            TimestampEncoding::UnixNanos,
+        ] {
+            assert_eq!(enc.decode_nanos(&binary), Some(1_650_000_000_123_456_789));
+        }
+
+        assert_eq!(TimestampEncoding::UnixMicros.decode_nanos(b"12ab"), None);
+        assert_eq!(TimestampEncoding::UnixMicros.decode_nanos(b""), None);
+    }
+}
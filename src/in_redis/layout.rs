@@ -0,0 +1,867 @@
+use crate::in_redis::pool::Connection;
+use crate::in_redis::{RedisStorageError, TimestampEncoding};
+use crate::State;
+
+use std::sync::LazyLock;
+
+/// Sets `KEYS` to the second half of `ARGV` if their current values
+/// are equal to the first half. Empty string means a missing key.
+pub(crate) const COMPARE_AND_SET_SCRIPT: &str = r"
+local n = #KEYS
+for i = 1, n do
+    if (redis.call('GET', KEYS[i]) or '') ~= ARGV[i] then
+        return 0
+    end
+end
+for i = 1, n do
+    redis.call('SET', KEYS[i], ARGV[n + i])
+end
+return 1
+";
+
+/// Sets fields of hashes `KEYS` if current values of their `tokens` and `last_refill`
+/// are equal to pairs in the first part of `ARGV`. Empty string means a missing field.
+/// The rest of `ARGV` is expiration in milliseconds (`0` means the hash never expires),
+/// number of values and field-value pairs for every hash.
+pub(crate) const HASH_COMPARE_AND_SET_SCRIPT: &str = r"
+local n = #KEYS
+for i = 1, n do
+    local cur = redis.call('HMGET', KEYS[i], 'tokens', 'last_refill')
+    if (cur[1] or '') ~= ARGV[2 * i - 1] or (cur[2] or '') ~= ARGV[2 * i] then
+        return 0
+    end
+end
+local pos = 2 * n + 1
+for i = 1, n do
+    local ttl, len = ARGV[pos], tonumber(ARGV[pos + 1])
+    redis.call('HSET', KEYS[i], unpack(ARGV, pos + 2, pos + 1 + len))
+    if tonumber(ttl) > 0 then
+        redis.call('PEXPIRE', KEYS[i], ttl)
+    else
+        redis.call('PERSIST', KEYS[i])
+    end
+    pos = pos + 2 + len
+end
+return 1
+";
+
+/// Refills buckets of `KEYS`, checks that all of them have enough tokens and takes them.
+///
+/// `ARGV` starts with the current time as seconds and nanoseconds, encoding of timestamps
+/// (`binary`, `micros` or `nanos`), mode (`acquire` or `charge` that takes tokens regardless
+/// of the balance) and layout (`keys` with two keys per bucket or `hash`). Then every bucket
+/// has capacity, refill tick in nanoseconds, tokens of a new bucket, permits, `1` if the hash
+/// expires and `1` if the hash stores the config.
+///
+/// Timestamps are kept as pairs of seconds and nanoseconds, so they fit into numbers of Lua.
+/// Capacities and permits are at most 2^53, so they are exact, and a debt of charges stops
+/// at -2^53.
+/// Returns `1` if tokens are taken or `0` if not, followed by available tokens and nanoseconds
+/// since the last refill of every bucket before taking tokens. Returns `-1`, index of the bucket
+/// and the value if its last refill can't be decoded.
+pub(crate) const BATCH_SCRIPT: &str = r"
+local NS = 1000000000
+local now_sec, now_nsec = tonumber(ARGV[1]), tonumber(ARGV[2])
+local encoding, mode, hash = ARGV[3], ARGV[4], ARGV[5] == 'hash'
+
+local function decode(v)
+    if v:match('^%d+$') then
+        local digits = encoding == 'micros' and 6 or 9
+        local scale = 10 ^ (9 - digits)
+        if #v <= digits then
+            return 0, tonumber(v) * scale
+        end
+        return tonumber(v:sub(1, -digits - 1)), tonumber(v:sub(-digits)) * scale
+    end
+    if #v ~= 16 or v:byte(16) >= 128 then
+        return nil
+    end
+    local sec, rem = 0, 0
+    for i = 16, 1, -1 do
+        rem = rem * 256 + v:byte(i)
+        local q = math.floor(rem / NS)
+        sec, rem = sec * 256 + q, rem - q * NS
+    end
+    return sec, rem
+end
+
+local function encode(sec, nsec)
+    if encoding == 'binary' then
+        local bytes = {}
+        for i = 1, 16 do
+            local high = math.floor(sec / 256)
+            local rest = (sec - high * 256) * NS + nsec
+            local low = math.floor(rest / 256)
+            bytes[i] = rest - low * 256
+            sec, nsec = high, low
+        end
+        return string.char(unpack(bytes))
+    end
+    local width = 9
+    if encoding == 'micros' then
+        nsec, width = math.floor(nsec / 1000), 6
+    end
+    if sec > 0 then
+        return string.format('%d%0' .. width .. 'd', sec, nsec)
+    end
+    return string.format('%d', nsec)
+end
+
+local per_bucket = hash and 1 or 2
+local buckets, res, allowed = {}, {}, true
+for i = 1, (#ARGV - 5) / 6 do
+    local a = 5 + (i - 1) * 6
+    local b = {
+        key = KEYS[(i - 1) * per_bucket + 1],
+        cap = tonumber(ARGV[a + 1]),
+        tick = tonumber(ARGV[a + 2]),
+        permits = tonumber(ARGV[a + 4]),
+        expires = ARGV[a + 5] == '1',
+        store_config = ARGV[a + 6] == '1',
+        cap_arg = ARGV[a + 1],
+        tick_arg = ARGV[a + 2],
+    }
+    local tokens, last
+    if hash then
+        local cur = redis.call('HMGET', b.key, 'tokens', 'last_refill')
+        tokens, last = cur[1], cur[2]
+    else
+        tokens, last = redis.call('GET', b.key), redis.call('GET', KEYS[2 * i])
+    end
+
+    if tokens then
+        b.tokens = tonumber(tokens)
+        if not b.tokens then
+            return redis.error_reply('ERR available tokens of ' .. b.key .. ' is not a number')
+        end
+    else
+        b.tokens = tonumber(ARGV[a + 3])
+    end
+    b.sec, b.nsec = now_sec, now_nsec
+    if last then
+        b.sec, b.nsec = decode(last)
+        if not b.sec then
+            return {-1, i, last}
+        end
+    end
+
+    -- Whole ticks that ended strictly before now
+    local since = (now_sec - b.sec) * NS + (now_nsec - b.nsec)
+    if since > b.tick then
+        local ticks = math.floor((since - 1) / b.tick)
+        local add = ticks * b.tick
+        local add_sec = math.floor(add / NS)
+        b.tokens = math.min(b.tokens + ticks, b.cap)
+        b.sec, b.nsec = b.sec + add_sec, b.nsec + add - add_sec * NS
+        if b.nsec >= NS then
+            b.sec, b.nsec = b.sec + 1, b.nsec - NS
+        end
+        since = since - add
+    end
+
+    allowed = allowed and b.tokens >= b.permits
+    buckets[i] = b
+    res[2 * i] = b.tokens
+    res[2 * i + 1] = since
+end
+
+if mode == 'acquire' and not allowed then
+    res[1] = 0
+    return res
+end
+
+for i, b in ipairs(buckets) do
+    local tokens = string.format('%d', math.max(b.tokens - b.permits, -2 ^ 53))
+    local last = encode(b.sec, b.nsec)
+    if hash then
+        local fields = {'tokens', tokens, 'last_refill', last}
+        if b.store_config then
+            fields[5], fields[6], fields[7], fields[8] = 'cap', b.cap_arg, 'tick', b.tick_arg
+        end
+        redis.call('HSET', b.key, unpack(fields))
+        if b.expires then
+            local missing = math.max(b.cap - b.tokens + b.permits, 0)
+            local ttl = math.max(math.ceil(missing * b.tick / 1000000), 1)
+            redis.call('PEXPIRE', b.key, string.format('%d', math.min(ttl, 2 ^ 53)))
+        else
+            redis.call('PERSIST', b.key)
+        end
+    else
+        redis.call('SET', b.key, tokens)
+        redis.call('SET', KEYS[2 * i], last)
+    end
+end
+res[1] = 1
+return res
+";
+
+static COMPARE_AND_SET: LazyLock<redis::Script> =
+    LazyLock::new(|| redis::Script::new(COMPARE_AND_SET_SCRIPT));
+static HASH_COMPARE_AND_SET: LazyLock<redis::Script> =
+    LazyLock::new(|| redis::Script::new(HASH_COMPARE_AND_SET_SCRIPT));
+static BATCH: LazyLock<redis::Script> = LazyLock::new(|| redis::Script::new(BATCH_SCRIPT));
+
+const TOKENS_FIELD: &str = "tokens";
+const LAST_REFILL_FIELD: &str = "last_refill";
+const WARM_SINCE_FIELD: &str = "warm_since";
+const CAP_FIELD: &str = "cap";
+const TICK_FIELD: &str = "tick";
+
+/// Values of the state as they are stored in redis, `None` means missing value.
+#[derive(Debug, Default)]
+pub(crate) struct RawState {
+    pub available_tokens: Option<Vec<u8>>,
+    pub last_refill: Option<Vec<u8>>,
+    pub warm_since: Option<Vec<u8>>,
+}
+
+impl RawState {
+    /// Takes values in order of [`Layout::accessed_keys`] or hash fields.
+    fn from_values(values: &[Option<Vec<u8>>]) -> Self {
+        Self {
+            available_tokens: values.first().cloned().flatten(),
+            last_refill: values.get(1).cloned().flatten(),
+            warm_since: values.get(2).cloned().flatten(),
+        }
+    }
+}
+
+/// Bucket of a batch updated by [`Layout::update_many`].
+pub(crate) struct BatchBucket<'a> {
+    pub layout: &'a Layout,
+    pub cap: u64,
+    pub refill_tick: time::Duration,
+    /// Tokens of a bucket that isn't stored yet.
+    pub initial_tokens: i64,
+    /// Sum of permits of all requests of the bucket.
+    pub permits: u64,
+}
+
+/// How [`Layout::update_many`] changes buckets.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub(crate) enum BatchMode {
+    /// Takes permits from all buckets if all of them have enough tokens.
+    Acquire,
+    /// Takes permits regardless of the balance, which may become negative.
+    Charge,
+}
+
+/// State of a bucket refilled by [`Layout::update_many`] before taking permits.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Refilled {
+    pub available_tokens: i64,
+    /// Time since the last refill, so the next token is refilled in a tick minus this.
+    pub since_refill: time::Duration,
+}
+
+/// Arguments of a compare-and-set script that updates one or several buckets at once.
+#[derive(Default)]
+pub(crate) struct CompareAndSet {
+    keys: Vec<String>,
+    expected: Vec<Vec<u8>>,
+    updates: Vec<Vec<u8>>,
+}
+
+impl CompareAndSet {
+    /// Returns `false` if somebody changed any of the states in the meantime.
+    pub fn invoke(
+        &self,
+        script: &redis::Script,
+        conn: &mut Connection,
+    ) -> redis::RedisResult<bool> {
+        script
+            .key(&self.keys)
+            .arg(&self.expected)
+            .arg(&self.updates)
+            .invoke(conn)
+    }
+}
+
+/// How the state is placed in redis.
+#[derive(Debug, Clone)]
+pub(crate) enum Layout {
+    /// Every value is stored in its own key that never expires.
+    /// Start of the warm-up is stored only if the bucket warms up.
+    Keys {
+        available_tokens_key: String,
+        last_refill_key: String,
+        warm_since_key: Option<String>,
+    },
+    /// All values are stored in a single hash that expires when the bucket becomes full,
+    /// unless a missing hash means a bucket that isn't full.
+    Hash {
+        key: String,
+        store_config: bool,
+        expires: bool,
+    },
+}
+
+impl Layout {
+    pub fn keys(
+        available_tokens_key: String,
+        last_refill_key: String,
+        warm_since_key: Option<String>,
+    ) -> Self {
+        Layout::Keys {
+            available_tokens_key,
+            last_refill_key,
+            warm_since_key,
+        }
+    }
+
+    pub fn hash(key: String, store_config: bool, expires: bool) -> Self {
+        Layout::Hash {
+            key,
+            store_config,
+            expires,
+        }
+    }
+
+    /// Same layout with all keys suffixed.
+    pub fn with_suffix(&self, suffix: &str) -> Self {
+        match self {
+            Layout::Keys {
+                available_tokens_key,
+                last_refill_key,
+                warm_since_key,
+            } => Layout::keys(
+                format!("{}{}", available_tokens_key, suffix),
+                format!("{}{}", last_refill_key, suffix),
+                warm_since_key
+                    .as_ref()
+                    .map(|key| format!("{}{}", key, suffix)),
+            ),
+            Layout::Hash {
+                key,
+                store_config,
+                expires,
+            } => Layout::hash(format!("{}{}", key, suffix), *store_config, *expires),
+        }
+    }
+
+    /// Key that is reported in decoding errors of the last refill.
+    pub fn last_refill_key(&self) -> &str {
+        match self {
+            Layout::Keys {
+                last_refill_key, ..
+            } => last_refill_key,
+            Layout::Hash { key, .. } => key,
+        }
+    }
+
+    /// Key that is reported in decoding errors of the start of the warm-up.
+    pub fn warm_since_key(&self) -> &str {
+        match self {
+            Layout::Keys {
+                warm_since_key: Some(warm_since_key),
+                ..
+            } => warm_since_key,
+            Layout::Keys {
+                last_refill_key, ..
+            } => last_refill_key,
+            Layout::Hash { key, .. } => key,
+        }
+    }
+
+    /// Keys that are accessed by the script.
+    pub fn accessed_keys(&self) -> Vec<&str> {
+        match self {
+            Layout::Keys {
+                available_tokens_key,
+                last_refill_key,
+                warm_since_key,
+            } => {
+                let mut keys = vec![available_tokens_key.as_str(), last_refill_key];
+                keys.extend(warm_since_key.as_deref());
+                keys
+            }
+            Layout::Hash { key, .. } => vec![key],
+        }
+    }
+
+    pub fn script(&self) -> &'static redis::Script {
+        match self {
+            Layout::Keys { .. } => &COMPARE_AND_SET,
+            Layout::Hash { .. } => &HASH_COMPARE_AND_SET,
+        }
+    }
+
+    pub fn load(&self, conn: &mut Connection) -> Result<RawState, RedisStorageError> {
+        let values: Vec<Option<Vec<u8>>> = match self {
+            Layout::Keys { .. } => redis::cmd("MGET").arg(self.accessed_keys()).query(conn)?,
+            Layout::Hash { key, .. } => redis::cmd("HMGET")
+                .arg(key)
+                .arg(TOKENS_FIELD)
+                .arg(LAST_REFILL_FIELD)
+                .arg(WARM_SINCE_FIELD)
+                .query(conn)?,
+        };
+
+        Ok(RawState::from_values(&values))
+    }
+
+    /// Refills, checks and updates buckets of the same kind by a single script.
+    ///
+    /// Returns `true` if the buckets are updated and their states before taking permits.
+    pub fn update_many(
+        buckets: &[BatchBucket<'_>],
+        mode: BatchMode,
+        encoding: TimestampEncoding,
+        conn: &mut Connection,
+    ) -> Result<(bool, Vec<Refilled>), RedisStorageError> {
+        let hash = matches!(buckets.first(), Some(b) if matches!(b.layout, Layout::Hash { .. }));
+        let now = time::OffsetDateTime::now_utc().unix_timestamp_nanos();
+        let mut invocation = BATCH.prepare_invoke();
+        invocation
+            .arg(now.div_euclid(1_000_000_000) as i64)
+            .arg(now.rem_euclid(1_000_000_000) as i64)
+            .arg(match encoding {
+                TimestampEncoding::Binary => "binary",
+                TimestampEncoding::UnixMicros => "micros",
+                TimestampEncoding::UnixNanos => "nanos",
+            })
+            .arg(match mode {
+                BatchMode::Acquire => "acquire",
+                BatchMode::Charge => "charge",
+            })
+            .arg(if hash { "hash" } else { "keys" });
+
+        for bucket in buckets {
+            let (store_config, expires) = match bucket.layout {
+                Layout::Keys {
+                    available_tokens_key,
+                    last_refill_key,
+                    ..
+                } => {
+                    invocation.key(available_tokens_key).key(last_refill_key);
+                    (false, false)
+                }
+                Layout::Hash {
+                    key,
+                    store_config,
+                    expires,
+                } => {
+                    invocation.key(key);
+                    (*store_config, *expires)
+                }
+            };
+            invocation
+                .arg(bucket.cap)
+                .arg(bucket.refill_tick.whole_nanoseconds().max(1) as u64)
+                .arg(bucket.initial_tokens)
+                .arg(bucket.permits)
+                .arg(u8::from(expires))
+                .arg(u8::from(store_config));
+        }
+
+        let values: Vec<redis::Value> = invocation.invoke(conn)?;
+        let status: i64 = redis::from_redis_value(values.first().unwrap_or(&redis::Value::Nil))?;
+        if status < 0 {
+            let index: usize = redis::from_redis_value(&values[1])?;
+            return Err(RedisStorageError::ConvertingBytesToI128Error {
+                key: buckets[index - 1].layout.last_refill_key().to_owned(),
+                value: redis::from_redis_value(&values[2])?,
+            });
+        }
+
+        let refilled = values[1..]
+            .chunks(2)
+            .map(|pair| {
+                Ok(Refilled {
+                    available_tokens: redis::from_redis_value(&pair[0])?,
+                    since_refill: time::Duration::nanoseconds(redis::from_redis_value(&pair[1])?),
+                })
+            })
+            .collect::<redis::RedisResult<_>>()?;
+        Ok((status == 1, refilled))
+    }
+
+    /// Writes `new` values if the current ones are still equal to `old`.
+    ///
+    /// Returns `false` if somebody changed the state in the meantime.
+    pub fn store(
+        &self,
+        conn: &mut Connection,
+        old: &RawState,
+        new: &RawState,
+        state: &State,
+    ) -> Result<bool, RedisStorageError> {
+        let mut cas = CompareAndSet::default();
+        self.push_store(&mut cas, old, new, state);
+        Ok(cas.invoke(self.script(), conn)?)
+    }
+
+    /// Adds writing of `new` values to the script arguments.
+    ///
+    /// All layouts of the same script must be of the same kind.
+    pub fn push_store(
+        &self,
+        cas: &mut CompareAndSet,
+        old: &RawState,
+        new: &RawState,
+        state: &State,
+    ) {
+        let old_tokens = old.available_tokens.clone().unwrap_or_default();
+        let old_last_refill = old.last_refill.clone().unwrap_or_default();
+        let new_tokens = new.available_tokens.clone().unwrap_or_default();
+        let new_last_refill = new.last_refill.clone().unwrap_or_default();
+
+        match self {
+            Layout::Keys {
+                available_tokens_key,
+                last_refill_key,
+                warm_since_key,
+            } => {
+                cas.keys.push(available_tokens_key.clone());
+                cas.keys.push(last_refill_key.clone());
+                cas.expected.extend([old_tokens, old_last_refill]);
+                cas.updates.extend([new_tokens, new_last_refill]);
+                // Buckets without warm-up don't touch the key
+                let warms_up = old.warm_since.is_some() || new.warm_since.is_some();
+                if let Some(warm_since_key) = warm_since_key.as_ref().filter(|_| warms_up) {
+                    cas.keys.push(warm_since_key.clone());
+                    cas.expected
+                        .push(old.warm_since.clone().unwrap_or_default());
+                    cas.updates.push(new.warm_since.clone().unwrap_or_default());
+                }
+            }
+            Layout::Hash {
+                key,
+                store_config,
+                expires,
+            } => {
+                let mut fields = vec![
+                    TOKENS_FIELD.into(),
+                    new_tokens,
+                    LAST_REFILL_FIELD.into(),
+                    new_last_refill,
+                ];
+                if let Some(warm_since) = &new.warm_since {
+                    fields.extend([WARM_SINCE_FIELD.into(), warm_since.clone()]);
+                }
+                if *store_config {
+                    fields.extend([
+                        CAP_FIELD.into(),
+                        state.cap.to_string().into_bytes(),
+                        TICK_FIELD.into(),
+                        (state.refill_tick.whole_nanoseconds() as u64)
+                            .to_string()
+                            .into_bytes(),
+                    ]);
+                }
+
+                cas.keys.push(key.clone());
+                cas.expected.extend([old_tokens, old_last_refill]);
+                let ttl = if *expires { time_to_full_ms(state) } else { 0 };
+                cas.updates.push(ttl.to_string().into_bytes());
+                cas.updates.push(fields.len().to_string().into_bytes());
+                cas.updates.extend(fields);
+            }
+        }
+    }
+}
+
+/// Upper bound of time until the bucket becomes full, when its state is equal to the missing one.
+/// A bucket with warm-up is refilled slower and kept for the warm-up period more,
+/// until it becomes cold.
+fn time_to_full_ms(state: &State) -> u64 {
+    let missing = (i128::from(state.cap) - i128::from(state.available_tokens)).max(0);
+    let (slowdown, cooling) = state.warm_up.map_or((1, 0), |warm_up| {
+        (warm_up.cold_factor(), warm_up.period().as_nanos())
+    });
+    let tick = state.refill_tick.whole_nanoseconds().max(0) as u128 * slowdown as u128;
+    let nanos = tick.saturating_mul(missing as u128).saturating_add(cooling);
+    let millis = nanos.div_ceil(1_000_000);
+    u64::try_from(millis).unwrap_or(u64::MAX).max(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::in_redis::tests::redis_host;
+    use crate::{balance, BucketConfig};
+
+    use std::time::Duration;
+    use uuid::Uuid;
+
+    fn connect(url: &str) -> Connection {
+        Connection::Single(redis::Client::open(url).unwrap().get_connection().unwrap())
+    }
+
+    fn raw(tokens: &str, last_refill: &str, warm_since: Option<&str>) -> RawState {
+        RawState {
+            available_tokens: Some(tokens.into()),
+            last_refill: Some(last_refill.into()),
+            warm_since: warm_since.map(Into::into),
+        }
+    }
+
+    fn get(conn: &mut Connection, key: &str) -> Option<String> {
+        redis::cmd("GET").arg(key).query(conn).unwrap()
+    }
+
+    fn hget(conn: &mut Connection, key: &str, field: &str) -> Option<String> {
+        redis::cmd("HGET").arg(key).arg(field).query(conn).unwrap()
+    }
+
+    fn pttl(conn: &mut Connection, key: &str) -> i64 {
+        redis::cmd("PTTL").arg(key).query(conn).unwrap()
+    }
+
+    #[test]
+    #[ignore = "needs a Redis server at REDIS_HOST"]
+    fn compare_and_set_script() {
+        let url = redis_host();
+        let mut conn = connect(&url);
+        let id = Uuid::new_v4();
+        let layout = Layout::keys(
+            format!("tokens:{}", id),
+            format!("last_refill:{}", id),
+            Some(format!("warm_since:{}", id)),
+        );
+        let state = BucketConfig::try_new(10, 10).unwrap().new_state();
+
+        let missing = layout.load(&mut conn).unwrap();
+        assert!(missing.available_tokens.is_none() && missing.last_refill.is_none());
+        let first = raw("5", "100", None);
+        assert!(layout.store(&mut conn, &missing, &first, &state).unwrap());
+        assert_eq!(
+            get(&mut conn, &format!("tokens:{}", id)).as_deref(),
+            Some("5")
+        );
+        assert_eq!(
+            get(&mut conn, &format!("last_refill:{}", id)).as_deref(),
+            Some("100")
+        );
+        // Buckets without warm-up don't touch the key
+        assert_eq!(get(&mut conn, &format!("warm_since:{}", id)), None);
+
+        // The state was changed since it was loaded
+        assert!(!layout
+            .store(&mut conn, &missing, &raw("4", "200", None), &state)
+            .unwrap());
+        assert_eq!(
+            get(&mut conn, &format!("tokens:{}", id)).as_deref(),
+            Some("5")
+        );
+
+        let loaded = layout.load(&mut conn).unwrap();
+        let second = raw("-3", "300", Some("250"));
+        assert!(layout.store(&mut conn, &loaded, &second, &state).unwrap());
+        let loaded = layout.load(&mut conn).unwrap();
+        assert_eq!(loaded.available_tokens.as_deref(), Some(&b"-3"[..]));
+        assert_eq!(loaded.last_refill.as_deref(), Some(&b"300"[..]));
+        assert_eq!(loaded.warm_since.as_deref(), Some(&b"250"[..]));
+        // Keys never expire
+        assert_eq!(pttl(&mut conn, &format!("tokens:{}", id)), -1);
+
+        // Several layouts are updated all-or-nothing
+        let other = layout.with_suffix(":other");
+        let mut cas = CompareAndSet::default();
+        other.push_store(&mut cas, &RawState::default(), &first, &state);
+        layout.push_store(&mut cas, &first, &first, &state);
+        assert!(!cas.invoke(layout.script(), &mut conn).unwrap());
+        assert_eq!(get(&mut conn, &format!("tokens:{}:other", id)), None);
+    }
+
+    #[test]
+    #[ignore = "needs a Redis server at REDIS_HOST"]
+    fn hash_compare_and_set_script() {
+        let url = redis_host();
+        let mut conn = connect(&url);
+        let key = format!("state:{}", Uuid::new_v4());
+        let expiring = Layout::hash(key.clone(), true, true);
+        let mut state = BucketConfig::try_new(10, 10).unwrap().new_state();
+        state.available_tokens = 5;
+
+        let missing = expiring.load(&mut conn).unwrap();
+        assert!(missing.available_tokens.is_none() && missing.last_refill.is_none());
+        let first = raw("5", "100", Some("50"));
+        assert!(expiring.store(&mut conn, &missing, &first, &state).unwrap());
+        assert_eq!(hget(&mut conn, &key, "tokens").as_deref(), Some("5"));
+        assert_eq!(hget(&mut conn, &key, "last_refill").as_deref(), Some("100"));
+        assert_eq!(hget(&mut conn, &key, "warm_since").as_deref(), Some("50"));
+        assert_eq!(hget(&mut conn, &key, "cap").as_deref(), Some("10"));
+        assert_eq!(hget(&mut conn, &key, "tick").as_deref(), Some("100000000"));
+        // Expires when 5 missing tokens are refilled
+        let ttl = pttl(&mut conn, &key);
+        assert!(ttl > 400 && ttl <= 500, "{}", ttl);
+
+        assert!(!expiring
+            .store(&mut conn, &missing, &raw("4", "200", None), &state)
+            .unwrap());
+        assert_eq!(hget(&mut conn, &key, "tokens").as_deref(), Some("5"));
+
+        // The same hash without expiration is persisted
+        let persistent = Layout::hash(key.clone(), false, false);
+        let loaded = persistent.load(&mut conn).unwrap();
+        assert_eq!(loaded.warm_since.as_deref(), Some(&b"50"[..]));
+        assert!(persistent
+            .store(&mut conn, &loaded, &raw("-2", "300", None), &state)
+            .unwrap());
+        assert_eq!(hget(&mut conn, &key, "tokens").as_deref(), Some("-2"));
+        assert_eq!(pttl(&mut conn, &key), -1);
+    }
+
+    fn batch_bucket(layout: &Layout, cap: u64, permits: u64) -> BatchBucket<'_> {
+        BatchBucket {
+            layout,
+            cap,
+            refill_tick: time::Duration::milliseconds(100),
+            initial_tokens: balance(cap),
+            permits,
+        }
+    }
+
+    #[test]
+    #[ignore = "needs a Redis server at REDIS_HOST"]
+    fn batch_script() {
+        let url = redis_host();
+        let mut conn = connect(&url);
+        let id = Uuid::new_v4();
+        let keys = Layout::keys(
+            format!("tokens:{}", id),
+            format!("last_refill:{}", id),
+            None,
+        );
+        let other = keys.with_suffix(":other");
+        let enc = TimestampEncoding::Binary;
+
+        let (acquired, refilled) = Layout::update_many(
+            &[batch_bucket(&keys, 10, 4), batch_bucket(&other, 3, 3)],
+            BatchMode::Acquire,
+            enc,
+            &mut conn,
+        )
+        .unwrap();
+        assert!(acquired);
+        assert_eq!(refilled[0].available_tokens, 10);
+        assert_eq!(refilled[1].available_tokens, 3);
+        assert_eq!(
+            get(&mut conn, &format!("tokens:{}", id)).as_deref(),
+            Some("6")
+        );
+        assert_eq!(
+            get(&mut conn, &format!("tokens:{}:other", id)).as_deref(),
+            Some("0")
+        );
+        // Stored in the same encoding as by a single bucket
+        let loaded = keys.load(&mut conn).unwrap();
+        let last_refill = enc.decode_nanos(&loaded.last_refill.unwrap()).unwrap();
+        let now = time::OffsetDateTime::now_utc().unix_timestamp_nanos();
+        assert!(last_refill <= now && last_refill > now - 1_000_000_000);
+
+        // Nothing is taken if any bucket doesn't have enough tokens
+        let (acquired, refilled) = Layout::update_many(
+            &[batch_bucket(&keys, 10, 1), batch_bucket(&other, 3, 1)],
+            BatchMode::Acquire,
+            enc,
+            &mut conn,
+        )
+        .unwrap();
+        assert!(!acquired);
+        assert_eq!(refilled[0].available_tokens, 6);
+        assert_eq!(refilled[1].available_tokens, 0);
+        assert!(refilled[1].since_refill < time::Duration::milliseconds(100));
+        assert_eq!(
+            get(&mut conn, &format!("tokens:{}", id)).as_deref(),
+            Some("6")
+        );
+
+        // Refilled by whole ticks
+        std::thread::sleep(Duration::from_millis(250));
+        let (acquired, refilled) = Layout::update_many(
+            &[batch_bucket(&other, 3, 2)],
+            BatchMode::Acquire,
+            enc,
+            &mut conn,
+        )
+        .unwrap();
+        assert!(acquired);
+        assert_eq!(refilled[0].available_tokens, 2);
+        assert!(refilled[0].since_refill >= time::Duration::milliseconds(50));
+    }
+
+    #[test]
+    #[ignore = "needs a Redis server at REDIS_HOST"]
+    fn batch_script_encodings() {
+        let url = redis_host();
+        let mut conn = connect(&url);
+        let stored = time::OffsetDateTime::now_utc() - time::Duration::milliseconds(250);
+
+        for enc in [
+            TimestampEncoding::Binary,
+            TimestampEncoding::UnixMicros,
+            TimestampEncoding::UnixNanos,
+        ] {
+            let layout = Layout::hash(format!("state:{}", Uuid::new_v4()), true, true);
+            let state = BucketConfig::try_new(10, 10).unwrap().new_state();
+            let raw = RawState {
+                available_tokens: Some(b"0".to_vec()),
+                last_refill: Some(enc.encode(stored)),
+                warm_since: None,
+            };
+            assert!(layout
+                .store(&mut conn, &RawState::default(), &raw, &state)
+                .unwrap());
+
+            let (acquired, refilled) = Layout::update_many(
+                &[batch_bucket(&layout, 10, 1)],
+                BatchMode::Acquire,
+                enc,
+                &mut conn,
+            )
+            .unwrap();
+            assert!(acquired);
+            assert_eq!(refilled[0].available_tokens, 2, "{:?}", enc);
+
+            // The last refill is moved by whole ticks
+            let key = layout.last_refill_key();
+            let loaded = layout.load(&mut conn).unwrap();
+            let last_refill = enc.decode_nanos(&loaded.last_refill.unwrap()).unwrap();
+            let stored = enc.decode_nanos(&enc.encode(stored)).unwrap();
+            assert_eq!(last_refill - stored, 200_000_000, "{:?}", enc);
+            assert_eq!(hget(&mut conn, key, "tokens").as_deref(), Some("1"));
+            assert_eq!(hget(&mut conn, key, "cap").as_deref(), Some("10"));
+            assert_eq!(hget(&mut conn, key, "tick").as_deref(), Some("100000000"));
+            let ttl = pttl(&mut conn, key);
+            assert!(ttl > 800 && ttl <= 900, "{}", ttl);
+        }
+
+        let layout = Layout::hash(format!("state:{}", Uuid::new_v4()), false, false);
+        redis::cmd("HSET")
+            .arg(layout.last_refill_key())
+            .arg("last_refill")
+            .arg("garbage")
+            .query::<()>(&mut conn)
+            .unwrap();
+        assert!(matches!(
+            Layout::update_many(
+                &[batch_bucket(&layout, 10, 1)],
+                BatchMode::Acquire,
+                TimestampEncoding::Binary,
+                &mut conn,
+            ),
+            Err(RedisStorageError::ConvertingBytesToI128Error { value, .. }) if value == b"garbage"
+        ));
+    }
+
+    #[test]
+    fn time_to_full() {
+        let mut state = State {
+            cap: 10,
+            available_tokens: 10,
+            last_refill: time::OffsetDateTime::now_utc(),
+            refill_tick: time::Duration::seconds(1) / 3,
+            warm_up: None,
+            warm_since: None,
+        };
+        assert_eq!(time_to_full_ms(&state), 1);
+
+        state.available_tokens = 7;
+        assert_eq!(time_to_full_ms(&state), 1000);
+
+        state.available_tokens = 0;
+        assert_eq!(time_to_full_ms(&state), 3334);
+    }
+}
@@ -0,0 +1,1521 @@
+mod batch;
+mod connect;
+mod encoding;
+mod layout;
+mod pool;
+#[cfg(test)]
+pub(crate) mod stub;
+
+pub use batch::{BucketResult, RedisBucket};
+pub use encoding::TimestampEncoding;
+pub use pool::{RedisHealth, DEFAULT_MAX_BACKOFF, DEFAULT_MIN_BACKOFF, DEFAULT_POOL_SIZE};
+
+use crate::in_redis::connect::{ConnectionOptions, Connector, Target};
+use crate::in_redis::layout::{Layout, RawState};
+use crate::in_redis::pool::{Backoff, Pool};
+use crate::{
+    balance, refill_tick, validate_capacity, BucketConfig, CompositeLimitExceededError,
+    ConfigError, InitialFill, Mode, RateLimitExceededError, ReconfigureError, ResizePolicy, State,
+    Storage, StorageError, TokenBucketAlgorithm, WarmUp,
+};
+
+use std::time::Duration;
+
+/// Default key of available tokens in redis
+pub const AVAILABLE_TOKENS_KEY: &str = "tocket::available_tokens";
+/// Default key of last refill in redis
+pub const LAST_REFILL_KEY: &str = "tocket::last_refill";
+/// Default key of start of the warm-up in redis, used only if the bucket warms up
+pub const WARM_SINCE_KEY: &str = "tocket::warm_since";
+/// Default key of the whole state in redis, when it's stored in a hash
+pub const STATE_KEY: &str = "tocket::state";
+/// Default bucket name in cluster mode
+pub const DEFAULT_BUCKET: &str = "tocket";
+
+/// Number of hash slots in Redis Cluster.
+const CLUSTER_SLOTS: u16 = 16384;
+
+/// A storage that stores state in Redis.
+///
+/// Useful when you have multiple application instances with shared state
+/// and Redis already running.
+///
+/// Connections are kept in a pool, so concurrent acquiring doesn't wait for a single connection.
+/// Broken connections (e.g. after Redis restart) are reopened with exponential backoff,
+/// their state can be checked with [`RedisStorage::health`].
+///
+/// The state is read, updated locally and written back by a script only if nobody
+/// changed it in the meantime, otherwise the acquiring is repeated. So all keys of the bucket
+/// are accessed together and must be in the same slot in Redis Cluster (see [`RedisStorage::cluster_builder`]).
+///
+/// Several buckets can be acquired all-or-nothing with [`RedisStorage::try_acquire_batch`],
+/// several limits of the bucket with [`CompositeLimiter`](crate::CompositeLimiter).
+/// They are refilled, checked and updated by a single script without repeating.
+///
+/// # Example
+/// ```
+/// # fn main() {
+/// use tocket::{TokenBucket, RedisStorage};
+///
+/// fn main() {
+///      let storage = RedisStorage::new(2, "redis://127.0.0.1:6379").unwrap();
+///
+///     let tb = TokenBucket::new(storage);
+///     assert!(tb.try_acquire(2).is_ok());
+///     assert!(tb.try_acquire_one().is_err());
+/// }
+/// # }
+/// ```
+pub struct RedisStorage {
+    pool: Pool,
+    bucket: parking_lot::RwLock<RedisBucket>,
+    warm_up: Option<WarmUp>,
+    encoding: TimestampEncoding,
+    cluster: bool,
+}
+
+impl RedisStorage {
+    /// Creates a storage.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if `rps_limit` is zero or if failed to connect to the Redis.
+    pub fn new<I>(rps_limit: u32, conn_info: I) -> Result<Self, RedisStorageError>
+    where
+        I: AsRef<str>,
+    {
+        Self::builder(rps_limit, conn_info).build()
+    }
+
+    /// Creates a builder of storage. Needs for customizing of capacity, redis keys and connection pool.
+    pub fn builder<I>(rps_limit: u32, conn_info: I) -> RedisStorageBuilder
+    where
+        I: AsRef<str>,
+    {
+        RedisStorageBuilder::new(
+            rps_limit,
+            Target::Single(conn_info.as_ref().to_owned()),
+            AVAILABLE_TOKENS_KEY.to_owned(),
+            LAST_REFILL_KEY.to_owned(),
+            WARM_SINCE_KEY.to_owned(),
+            STATE_KEY.to_owned(),
+        )
+    }
+
+    /// Creates a builder of storage that works with Redis Cluster.
+    ///
+    /// Keys are named with hash tag of the bucket (`{tocket}:tokens`, `{tocket}:last_refill`
+    /// and `{tocket}:state` by default), so the whole state of the bucket is stored in a single slot.
+    ///
+    /// # Example
+    /// ```no_run
+    /// use tocket::{TokenBucket, RedisStorage};
+    ///
+    /// let storage = RedisStorage::cluster_builder(
+    ///     2,
+    ///     vec!["redis://127.0.0.1:7000", "redis://127.0.0.1:7001"],
+    /// )
+    /// .with_bucket("user:42")
+    /// .build()
+    /// .unwrap();
+    ///
+    /// let tb = TokenBucket::new(storage);
+    /// assert!(tb.try_acquire_one().is_ok());
+    /// ```
+    pub fn cluster_builder<I, N>(rps_limit: u32, nodes: I) -> RedisStorageBuilder
+    where
+        I: IntoIterator<Item = N>,
+        N: AsRef<str>,
+    {
+        RedisStorageBuilder::new(
+            rps_limit,
+            Target::Cluster(nodes.into_iter().map(|n| n.as_ref().to_owned()).collect()),
+            tokens_key(DEFAULT_BUCKET),
+            last_refill_key(DEFAULT_BUCKET),
+            warm_since_key(DEFAULT_BUCKET),
+            state_key(DEFAULT_BUCKET),
+        )
+    }
+
+    /// Creates a builder of storage that connects to the master monitored by Redis Sentinel.
+    ///
+    /// The master address is requested from sentinels (in the given order) on every connect,
+    /// so after a failover broken connections are reopened to the new master.
+    /// Credentials, database and TLS set on the builder apply to the master,
+    /// sentinels use the ones from their URLs.
+    ///
+    /// # Example
+    /// ```no_run
+    /// use tocket::{TokenBucket, RedisStorage};
+    ///
+    /// let storage = RedisStorage::sentinel_builder(
+    ///     2,
+    ///     "mymaster",
+    ///     vec!["redis://127.0.0.1:26379", "redis://127.0.0.1:26380"],
+    /// )
+    /// .with_password("secret")
+    /// .with_db(1)
+    /// .build()
+    /// .unwrap();
+    ///
+    /// let tb = TokenBucket::new(storage);
+    /// assert!(tb.try_acquire_one().is_ok());
+    /// ```
+    pub fn sentinel_builder<M, I, N>(
+        rps_limit: u32,
+        master_name: M,
+        sentinels: I,
+    ) -> RedisStorageBuilder
+    where
+        M: Into<String>,
+        I: IntoIterator<Item = N>,
+        N: AsRef<str>,
+    {
+        RedisStorageBuilder::new(
+            rps_limit,
+            Target::Sentinel {
+                master_name: master_name.into(),
+                sentinels: sentinels
+                    .into_iter()
+                    .map(|s| s.as_ref().to_owned())
+                    .collect(),
+            },
+            AVAILABLE_TOKENS_KEY.to_owned(),
+            LAST_REFILL_KEY.to_owned(),
+            WARM_SINCE_KEY.to_owned(),
+            STATE_KEY.to_owned(),
+        )
+    }
+
+    /// Returns health of the connections to the Redis.
+    pub fn health(&self) -> RedisHealth {
+        self.pool.health()
+    }
+
+    /// Moves the state stored in separate keys into the hash of this storage.
+    ///
+    /// The state is moved only if the hash doesn't exist yet, then the old keys are deleted.
+    /// Returns `true` if the state was moved.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the storage doesn't use hash layout, if the old state is malformed
+    /// or if the storage could not save/load state.
+    pub fn migrate_from_keys<K1, K2>(
+        &self,
+        available_tokens_key: K1,
+        last_refill_key: K2,
+    ) -> Result<bool, RedisStorageError>
+    where
+        K1: Into<String>,
+        K2: Into<String>,
+    {
+        let bucket = self.bucket.read();
+        if !matches!(bucket.layout, Layout::Hash { .. }) {
+            return Err(RedisStorageError::NotHashLayout);
+        }
+
+        let available_tokens_key = available_tokens_key.into();
+        let last_refill_key = last_refill_key.into();
+        let old_bucket = RedisBucket {
+            layout: Layout::keys(available_tokens_key.clone(), last_refill_key.clone(), None),
+            ..bucket.clone()
+        };
+
+        self.pool.with_conn(|conn| {
+            let old = old_bucket.layout.load(conn)?;
+            if old.available_tokens.is_none() && old.last_refill.is_none() {
+                return Ok(false);
+            }
+
+            let state = self.decode(&old, &old_bucket)?;
+            let migrated =
+                bucket
+                    .layout
+                    .store(conn, &RawState::default(), &self.encode(&state), &state)?;
+            if migrated {
+                // Keys could be in different slots, so they're deleted one by one
+                redis::cmd("DEL")
+                    .arg(&available_tokens_key)
+                    .query::<()>(conn)?;
+                redis::cmd("DEL").arg(&last_refill_key).query::<()>(conn)?;
+            }
+
+            Ok(migrated)
+        })
+    }
+
+    fn decode(&self, raw: &RawState, bucket: &RedisBucket) -> Result<State, RedisStorageError> {
+        let available_tokens = match &raw.available_tokens {
+            Some(v) => redis::from_redis_value(&redis::Value::Data(v.clone()))?,
+            None => balance(bucket.initial_fill.tokens(bucket.cap)),
+        };
+
+        let last_refill = match &raw.last_refill {
+            Some(last_refill_ts) => {
+                let nanos_ts = self.encoding.decode_nanos(last_refill_ts).ok_or_else(|| {
+                    RedisStorageError::ConvertingBytesToI128Error {
+                        key: bucket.layout.last_refill_key().to_owned(),
+                        value: last_refill_ts.clone(),
+                    }
+                })?;
+                time::OffsetDateTime::from_unix_timestamp_nanos(nanos_ts)?
+            }
+            None => time::OffsetDateTime::now_utc(),
+        };
+
+        // Missing or empty start means a cold bucket
+        let warm_since = match raw.warm_since.as_deref().filter(|ts| !ts.is_empty()) {
+            Some(warm_since_ts) => {
+                let nanos_ts = self.encoding.decode_nanos(warm_since_ts).ok_or_else(|| {
+                    RedisStorageError::ConvertingBytesToI128Error {
+                        key: bucket.layout.warm_since_key().to_owned(),
+                        value: warm_since_ts.to_vec(),
+                    }
+                })?;
+                Some(time::OffsetDateTime::from_unix_timestamp_nanos(nanos_ts)?)
+            }
+            None => None,
+        };
+
+        Ok(State {
+            cap: bucket.cap,
+            available_tokens,
+            refill_tick: bucket.refill_tick,
+            last_refill,
+            warm_up: self.warm_up,
+            warm_since,
+        })
+    }
+
+    /// Applies `f` to the state of the bucket until nobody changes it in the meantime.
+    fn update<F>(&self, bucket: &RedisBucket, f: F) -> Result<(), RedisStorageError>
+    where
+        F: Fn(&mut State),
+    {
+        self.pool.with_conn(|conn| loop {
+            let raw = bucket.layout.load(conn)?;
+            let mut state = self.decode(&raw, bucket)?;
+            f(&mut state);
+
+            if bucket
+                .layout
+                .store(conn, &raw, &self.encode(&state), &state)?
+            {
+                return Ok(());
+            }
+        })
+    }
+
+    fn encode(&self, state: &State) -> RawState {
+        RawState {
+            available_tokens: Some(state.available_tokens.to_string().into_bytes()),
+            last_refill: Some(self.encoding.encode(state.last_refill)),
+            warm_since: state.warm_since.map(|ts| self.encoding.encode(ts)),
+        }
+    }
+}
+
+pub struct RedisStorageBuilder {
+    rps_limit: u32,
+    cap: Option<u64>,
+    target: Target,
+    available_tokens_key: String,
+    last_refill_key: String,
+    warm_since_key: String,
+    state_key: String,
+    warm_up: Option<WarmUp>,
+    refill_tick: Option<time::Duration>,
+    initial_fill: InitialFill,
+    hash_layout: bool,
+    store_config: bool,
+    encoding: TimestampEncoding,
+    pool_size: usize,
+    backoff: Backoff,
+    options: ConnectionOptions,
+}
+
+impl RedisStorageBuilder {
+    fn new(
+        rps_limit: u32,
+        target: Target,
+        available_tokens_key: String,
+        last_refill_key: String,
+        warm_since_key: String,
+        state_key: String,
+    ) -> Self {
+        Self {
+            rps_limit,
+            cap: None,
+            target,
+            available_tokens_key,
+            last_refill_key,
+            warm_since_key,
+            state_key,
+            warm_up: None,
+            refill_tick: None,
+            initial_fill: InitialFill::Full,
+            hash_layout: false,
+            store_config: false,
+            encoding: TimestampEncoding::default(),
+            pool_size: DEFAULT_POOL_SIZE,
+            backoff: Backoff::default(),
+            options: ConnectionOptions::default(),
+        }
+    }
+
+    /// Customize key for value in redis.
+    pub fn with_available_tokens_key<K>(mut self, key: K) -> Self
+    where
+        K: Into<String>,
+    {
+        self.available_tokens_key = key.into();
+        self
+    }
+
+    /// Customize key for value in redis.
+    pub fn with_last_refill_key<K>(mut self, key: K) -> Self
+    where
+        K: Into<String>,
+    {
+        self.last_refill_key = key.into();
+        self
+    }
+
+    /// Customize key for value in redis. The key is used only with [`with_warm_up`].
+    ///
+    /// [`with_warm_up`]: RedisStorageBuilder::with_warm_up
+    pub fn with_warm_since_key<K>(mut self, key: K) -> Self
+    where
+        K: Into<String>,
+    {
+        self.warm_since_key = key.into();
+        self
+    }
+
+    /// Customize key for the hash in redis.
+    pub fn with_state_key<K>(mut self, key: K) -> Self
+    where
+        K: Into<String>,
+    {
+        self.state_key = key.into();
+        self
+    }
+
+    /// Customize all keys by the bucket name.
+    /// Keys are `{<bucket>}:tokens`, `{<bucket>}:last_refill`, `{<bucket>}:warm_since`
+    /// and `{<bucket>}:state`. Names that already have a hash tag (e.g. `{tenant:1}:user:42`)
+    /// are used as is: `<bucket>:tokens`, `<bucket>:last_refill`, `<bucket>:warm_since`
+    /// and `<bucket>:state`.
+    pub fn with_bucket<B>(self, bucket: B) -> Self
+    where
+        B: AsRef<str>,
+    {
+        let bucket = bucket.as_ref();
+        self.with_available_tokens_key(tokens_key(bucket))
+            .with_last_refill_key(last_refill_key(bucket))
+            .with_warm_since_key(warm_since_key(bucket))
+            .with_state_key(state_key(bucket))
+    }
+
+    /// Customize capacity of the bucket, i.e. the largest burst. Equal to the rate by default.
+    ///
+    /// Other application instances sharing the bucket should use the same capacity.
+    /// The capacity is limited by [`MAX_REDIS_CAPACITY`].
+    pub fn with_capacity(mut self, cap: u64) -> Self {
+        self.cap = Some(cap);
+        self
+    }
+
+    /// Customize tokens of the bucket that isn't stored yet, full by default.
+    ///
+    /// With the hash layout a bucket that isn't full initially is never expired,
+    /// otherwise an abandoned bucket would be created again with the initial tokens.
+    pub fn with_initial_fill(mut self, initial_fill: InitialFill) -> Self {
+        self.initial_fill = initial_fill;
+        self
+    }
+
+    /// Start the bucket cold and warm it up after idleness, see [`WarmUp`].
+    ///
+    /// Start of the warm-up is stored in a separate key (see [`with_warm_since_key`])
+    /// or in the `warm_since` field of the hash. Other application instances sharing
+    /// the bucket should use the same warm-up.
+    ///
+    /// [`with_warm_since_key`]: RedisStorageBuilder::with_warm_since_key
+    pub fn with_warm_up(mut self, warm_up: WarmUp) -> Self {
+        self.warm_up = Some(warm_up);
+        self
+    }
+
+    /// Refill a token per `refill_tick` instead of the rate, e.g. for a quota per minute.
+    #[cfg(feature = "registry")]
+    pub(crate) fn with_refill_tick(mut self, refill_tick: time::Duration) -> Self {
+        self.refill_tick = Some(refill_tick);
+        self
+    }
+
+    /// Store the whole state in a single hash instead of separate keys.
+    ///
+    /// The hash has fields `tokens` and `last_refill` with the same values as separate keys
+    /// and expires when the bucket becomes full, so abandoned buckets don't stay in redis
+    /// (see [`with_initial_fill`](RedisStorageBuilder::with_initial_fill) for exceptions).
+    /// Use [`RedisStorage::migrate_from_keys`] to move already stored state.
+    pub fn with_hash_layout(mut self) -> Self {
+        self.hash_layout = true;
+        self
+    }
+
+    /// Also store `cap` and `tick` (refill tick in nanoseconds) fields in the hash.
+    ///
+    /// Useful for inspecting buckets, the storage itself doesn't read them.
+    pub fn with_stored_config(mut self) -> Self {
+        self.store_config = true;
+        self
+    }
+
+    /// Customize encoding of the last refill time.
+    ///
+    /// Use decimal encodings to share buckets with services written in other languages,
+    /// see [`TimestampEncoding`] for details.
+    pub fn with_timestamp_encoding(mut self, encoding: TimestampEncoding) -> Self {
+        self.encoding = encoding;
+        self
+    }
+
+    /// Customize maximum number of connections to the Redis.
+    ///
+    /// Connections are opened lazily, when all opened ones are busy.
+    pub fn with_pool_size(mut self, size: usize) -> Self {
+        self.pool_size = size;
+        self
+    }
+
+    /// Customize delays between reconnect attempts.
+    ///
+    /// The delay starts from `min` and doubles after each failed attempt up to `max`.
+    pub fn with_reconnect_backoff(mut self, min: Duration, max: Duration) -> Self {
+        self.backoff = Backoff { min, max };
+        self
+    }
+
+    /// Authenticate with the username (Redis 6 ACL), overrides the one from URLs.
+    pub fn with_username<U>(mut self, username: U) -> Self
+    where
+        U: Into<String>,
+    {
+        self.options.username = Some(username.into());
+        self
+    }
+
+    /// Authenticate with the password, overrides the one from URLs.
+    pub fn with_password<P>(mut self, password: P) -> Self
+    where
+        P: Into<String>,
+    {
+        self.options.password = Some(password.into());
+        self
+    }
+
+    /// Select the database, overrides the one from URLs.
+    ///
+    /// Redis Cluster supports only database `0`.
+    pub fn with_db(mut self, db: i64) -> Self {
+        self.options.db = Some(db);
+        self
+    }
+
+    /// Connect to Redis over TLS, verifying it with system root certificates.
+    ///
+    /// Not needed for `rediss://` URLs, but required for the master found by sentinels.
+    #[cfg(feature = "redis-tls-impl")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "redis-tls-impl")))]
+    pub fn with_tls(mut self) -> Self {
+        self.options.tls = true;
+        self
+    }
+
+    /// Connect to Redis over TLS, verifying it with the given root certificates in PEM format.
+    #[cfg(feature = "redis-tls-impl")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "redis-tls-impl")))]
+    pub fn with_root_cert<C>(mut self, pem: C) -> Self
+    where
+        C: Into<Vec<u8>>,
+    {
+        self.certs().root_cert = Some(pem.into());
+        self
+    }
+
+    /// Connect to Redis over TLS, authenticating with the client certificate and private key in PEM format.
+    #[cfg(feature = "redis-tls-impl")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "redis-tls-impl")))]
+    pub fn with_client_cert<C, K>(mut self, cert_pem: C, key_pem: K) -> Self
+    where
+        C: Into<Vec<u8>>,
+        K: Into<Vec<u8>>,
+    {
+        self.certs().client_tls = Some(redis::ClientTlsConfig {
+            client_cert: cert_pem.into(),
+            client_key: key_pem.into(),
+        });
+        self
+    }
+
+    #[cfg(feature = "redis-tls-impl")]
+    fn certs(&mut self) -> &mut redis::TlsCertificates {
+        self.options.certs.get_or_insert(redis::TlsCertificates {
+            client_tls: None,
+            root_cert: None,
+        })
+    }
+
+    /// Creates a storage and opens the first connection.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the rate or the capacity is zero, if the capacity is above
+    /// [`MAX_REDIS_CAPACITY`], if failed to connect to the Redis
+    /// or if keys are mapped to different slots in cluster mode.
+    pub fn build(self) -> Result<RedisStorage, RedisStorageError> {
+        let config =
+            BucketConfig::try_new(self.rps_limit, self.cap.unwrap_or(self.rps_limit.into()))?
+                .with_refill_tick(self.refill_tick)?;
+        validate_redis_capacity(config.cap)?;
+        let cluster = matches!(self.target, Target::Cluster(_));
+        if cluster && !self.hash_layout {
+            let mut keys = vec![self.available_tokens_key.as_str(), &self.last_refill_key];
+            if self.warm_up.is_some() {
+                keys.push(&self.warm_since_key);
+            }
+            check_same_slot(&keys)?;
+        }
+        let connector = Connector::new(&self.target, &self.options)?;
+        let pool = Pool::new(connector, self.pool_size, self.backoff);
+        pool.connect()?;
+
+        Ok(RedisStorage {
+            pool,
+            bucket: parking_lot::RwLock::new(RedisBucket {
+                layout: if self.hash_layout {
+                    Layout::hash(
+                        self.state_key,
+                        self.store_config,
+                        self.initial_fill == InitialFill::Full,
+                    )
+                } else {
+                    Layout::keys(
+                        self.available_tokens_key,
+                        self.last_refill_key,
+                        self.warm_up.map(|_| self.warm_since_key),
+                    )
+                },
+                cap: config.cap,
+                refill_tick: config.refill_tick,
+                initial_fill: self.initial_fill,
+            }),
+            warm_up: self.warm_up,
+            encoding: self.encoding,
+            cluster,
+        })
+    }
+}
+
+impl Storage for RedisStorage {
+    type Error = RedisStorageError;
+
+    fn try_acquire(&self, alg: TokenBucketAlgorithm, permits: u64) -> Result<(), Self::Error> {
+        let bucket = &*self.bucket.read();
+        self.pool.with_conn(|conn| loop {
+            let raw = bucket.layout.load(conn)?;
+            let mut state = self.decode(&raw, bucket)?;
+            let result = alg
+                .try_acquire(&mut state, permits)
+                .map_err(RedisStorageError::from);
+
+            // Somebody changed the state in the meantime, so try again with the fresh one
+            if bucket
+                .layout
+                .store(conn, &raw, &self.encode(&state), &state)?
+            {
+                return result;
+            }
+        })
+    }
+
+    fn release(&self, permits: u64) -> Result<(), Self::Error> {
+        self.update(&self.bucket.read(), |state| state.release(permits))
+    }
+
+    fn charge(&self, cost: u64) -> Result<(), Self::Error> {
+        self.update(&self.bucket.read(), |state| {
+            TokenBucketAlgorithm::new(Mode::N).charge(state, cost)
+        })
+    }
+
+    /// Changes the rate used by this instance and refills the stored state with the old one.
+    /// Other application instances sharing the bucket should be reconfigured too.
+    fn set_rate(&self, rps_limit: u32) -> Result<(), ReconfigureError<Self::Error>> {
+        let refill_tick = refill_tick(rps_limit)?;
+        // Requests aren't blocked during the round trip, the config is swapped after it
+        let bucket = self.bucket.read().clone();
+        self.update(&bucket, |state| state.set_rate(refill_tick))
+            .map_err(ReconfigureError::Storage)?;
+        self.bucket.write().refill_tick = refill_tick;
+        Ok(())
+    }
+
+    /// Changes the capacity used by this instance and adjusts the stored tokens.
+    /// Other application instances sharing the bucket should be reconfigured too,
+    /// with [`ResizePolicy::Clamp`] if the tokens are already scaled.
+    /// The capacity is limited by [`MAX_REDIS_CAPACITY`].
+    fn set_capacity(
+        &self,
+        cap: u64,
+        policy: ResizePolicy,
+    ) -> Result<(), ReconfigureError<Self::Error>> {
+        let cap = validate_redis_capacity(cap)?;
+        let bucket = self.bucket.read().clone();
+        self.update(&bucket, |state| state.set_capacity(cap, policy))
+            .map_err(ReconfigureError::Storage)?;
+        self.bucket.write().cap = cap;
+        Ok(())
+    }
+}
+
+/// Largest capacity of a bucket. Batch scripts count tokens in numbers of Lua, which are
+/// doubles, so larger ones would be rounded.
+pub const MAX_REDIS_CAPACITY: u64 = 1 << 53;
+
+/// Returns `cap` if tokens of such capacity are counted exactly by scripts.
+pub(crate) fn validate_redis_capacity(cap: u64) -> Result<u64, ConfigError> {
+    match validate_capacity(cap)? {
+        cap if cap > MAX_REDIS_CAPACITY => Err(ConfigError::CapacityOverflow),
+        cap => Ok(cap),
+    }
+}
+
+fn tokens_key(bucket: &str) -> String {
+    bucket_key(bucket, "tokens")
+}
+
+fn last_refill_key(bucket: &str) -> String {
+    bucket_key(bucket, "last_refill")
+}
+
+fn warm_since_key(bucket: &str) -> String {
+    bucket_key(bucket, "warm_since")
+}
+
+fn state_key(bucket: &str) -> String {
+    bucket_key(bucket, "state")
+}
+
+/// Wraps the bucket name into a hash tag, unless it already has one.
+fn bucket_key(bucket: &str, suffix: &str) -> String {
+    if hash_tag(bucket.as_bytes()).is_some() {
+        format!("{}:{}", bucket, suffix)
+    } else {
+        format!("{{{}}}:{}", bucket, suffix)
+    }
+}
+
+/// Returns the part of the key between the first `{` and the next `}` if it isn't empty.
+fn hash_tag(key: &[u8]) -> Option<&[u8]> {
+    let open = key.iter().position(|b| *b == b'{')?;
+    let tag = &key[open + 1..];
+    let close = tag.iter().position(|b| *b == b'}')?;
+    Some(&tag[..close]).filter(|tag| !tag.is_empty())
+}
+
+/// Calculates the cluster slot of the key, taking hash tag into account.
+fn key_slot(key: &str) -> u16 {
+    let key = key.as_bytes();
+    let hashed = hash_tag(key).unwrap_or(key);
+    crc16::State::<crc16::XMODEM>::calculate(hashed) % CLUSTER_SLOTS
+}
+
+fn check_same_slot(keys: &[&str]) -> Result<(), RedisStorageError> {
+    let first = keys.first().map(|k| key_slot(k));
+    if keys.iter().any(|k| Some(key_slot(k)) != first) {
+        return Err(RedisStorageError::CrossSlotKeys {
+            keys: keys.iter().map(|k| k.to_string()).collect(),
+        });
+    }
+    Ok(())
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum RedisStorageError {
+    #[error(transparent)]
+    RedisError(#[from] redis::RedisError),
+    #[error(transparent)]
+    TimeComponentRangeError(#[from] time::error::ComponentRange),
+    #[error(transparent)]
+    RateLimitExceededError(#[from] RateLimitExceededError),
+    #[error(transparent)]
+    ConfigError(#[from] ConfigError),
+    #[error("converting '{key}' ({value:?}) to timestamp failed")]
+    ConvertingBytesToI128Error { key: String, value: Vec<u8> },
+    #[error("redis is unavailable, next reconnect attempt in {retry_in:?}")]
+    Unavailable { retry_in: Duration },
+    #[error("keys {keys:?} are mapped to different cluster slots")]
+    CrossSlotKeys { keys: Vec<String> },
+    #[error("storage doesn't use hash layout")]
+    NotHashLayout,
+    #[error("rate limit exceeded for some buckets of the batch")]
+    BatchRateLimitExceeded { results: Vec<BucketResult> },
+    #[error("buckets of the batch are stored with different layouts")]
+    MixedLayouts,
+    #[error("bucket '{key}' warms up, which batches don't support")]
+    WarmUpInBatch { key: String },
+    #[error(transparent)]
+    CompositeLimitExceeded(#[from] CompositeLimitExceededError),
+}
+
+impl StorageError for RedisStorageError {
+    fn is_rate_limit_exceeded(&self) -> bool {
+        matches!(
+            self,
+            RedisStorageError::RateLimitExceededError(_)
+                | RedisStorageError::BatchRateLimitExceeded { .. }
+                | RedisStorageError::CompositeLimitExceeded(_)
+        )
+    }
+
+    fn is_capacity_exceeded(&self) -> bool {
+        matches!(self, RedisStorageError::RateLimitExceededError(err) if err.is_capacity_exceeded())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::in_redis::stub::RespStub;
+    use crate::{CompositeLimiter, LeasingStorage, Limit, Mode, TokenBucket};
+
+    use uuid::Uuid;
+
+    /// Url of the Redis server from `REDIS_HOST`. Tests of scripts run only against
+    /// a real server, so they are ignored unless run with `--ignored` and `REDIS_HOST`,
+    /// the stub is used for connection tests.
+    pub(crate) fn redis_host() -> String {
+        std::env::var("REDIS_HOST").expect("REDIS_HOST must be set to url of a Redis server")
+    }
+
+    /// Bucket name unique for the test run, so runs don't share state.
+    fn unique(name: &str) -> String {
+        format!("{}:{}", name, Uuid::new_v4())
+    }
+
+    fn connect(url: &str) -> redis::Connection {
+        redis::Client::open(url).unwrap().get_connection().unwrap()
+    }
+
+    fn get(conn: &mut redis::Connection, key: &str) -> Option<String> {
+        redis::cmd("GET").arg(key).query(conn).unwrap()
+    }
+
+    fn hget(conn: &mut redis::Connection, key: &str, field: &str) -> Option<String> {
+        redis::cmd("HGET").arg(key).arg(field).query(conn).unwrap()
+    }
+
+    fn exists(conn: &mut redis::Connection, key: &str) -> bool {
+        redis::cmd("EXISTS").arg(key).query(conn).unwrap()
+    }
+
+    fn tokens(conn: &mut redis::Connection, key: &str) -> i64 {
+        get(conn, key).unwrap().parse().unwrap()
+    }
+
+    #[test]
+    fn try_acquire() {
+        let storage = RedisStorage::builder(
+            2,
+            std::env::var("REDIS_HOST").unwrap_or_else(|_| "redis://127.0.0.1:6379".to_owned()),
+        )
+        .with_last_refill_key(format!("last_refill_{}", Uuid::new_v4()))
+        .with_available_tokens_key(format!("available_tokens{}", Uuid::new_v4()))
+        .build()
+        .unwrap();
+
+        let tb = TokenBucket::new(storage);
+
+        assert!(tb.try_acquire(2).is_ok());
+        assert!(tb.try_acquire_one().is_err());
+
+        std::thread::sleep(Duration::from_millis(1500));
+        assert!(tb.try_acquire(2).is_ok());
+        assert!(tb.try_acquire_one().is_err());
+
+        std::thread::sleep(Duration::from_millis(1500));
+        assert!(tb.try_acquire(2).is_ok());
+        assert!(tb.try_acquire_one().is_err());
+    }
+
+    #[test]
+    fn reconnect_after_restart() {
+        let stub = RespStub::start(([127, 0, 0, 1], 0));
+        let addr = stub.addr();
+        let storage = RedisStorage::builder(10, stub.url())
+            .with_pool_size(2)
+            .with_reconnect_backoff(Duration::from_millis(50), Duration::from_millis(200))
+            .build()
+            .unwrap();
+        let alg = || TokenBucketAlgorithm { mode: Mode::N };
+
+        assert!(storage.try_acquire(alg(), 1).is_ok());
+        assert_eq!(storage.health().connected, 1);
+
+        drop(stub);
+        assert!(storage.try_acquire(alg(), 1).is_err());
+        assert!(storage.try_acquire(alg(), 1).is_err());
+        assert!(!storage.health().is_healthy());
+        assert_eq!(storage.health().connected, 0);
+
+        let _stub = RespStub::start(addr);
+        std::thread::sleep(Duration::from_millis(300));
+        assert!(storage.try_acquire(alg(), 1).is_ok());
+        assert!(storage.health().is_healthy());
+        assert_eq!(storage.health().connected, 1);
+    }
+
+    #[test]
+    fn cluster_try_acquire() {
+        let stub = RespStub::start(([127, 0, 0, 1], 0));
+        let storage = RedisStorage::cluster_builder(2, vec![stub.url()])
+            .with_bucket("user:42")
+            .build()
+            .unwrap();
+        let tb = TokenBucket::new(storage);
+
+        assert!(tb.try_acquire_one().is_ok());
+        assert!(stub.exists("{user:42}:tokens"));
+        assert!(stub.exists("{user:42}:last_refill"));
+    }
+
+    #[test]
+    fn cluster_keys_validation() {
+        let res = RedisStorage::cluster_builder(2, vec!["redis://127.0.0.1:1"])
+            .with_available_tokens_key("tokens")
+            .with_last_refill_key("last_refill")
+            .build();
+        assert!(matches!(res, Err(RedisStorageError::CrossSlotKeys { .. })));
+    }
+
+    #[test]
+    fn hash_tagged_key_slot() {
+        assert_eq!(key_slot("123456789"), 12739);
+        assert_eq!(key_slot("{user1000}.following"), key_slot("user1000"));
+        assert_eq!(key_slot(&tokens_key("a")), key_slot(&last_refill_key("a")));
+        assert_ne!(key_slot("{}a"), key_slot("a"));
+    }
+
+    #[test]
+    #[ignore = "needs a Redis server at REDIS_HOST"]
+    fn hash_layout_expires() {
+        let url = redis_host();
+        let mut conn = connect(&url);
+        let bucket = unique("hash");
+        let storage = RedisStorage::builder(100, &url)
+            .with_bucket(&bucket)
+            .with_hash_layout()
+            .with_stored_config()
+            .build()
+            .unwrap();
+        let tb = TokenBucket::new(storage);
+        let key = state_key(&bucket);
+
+        assert!(tb.try_acquire(100).is_ok());
+        assert!(tb.try_acquire_one().is_err());
+        assert_eq!(hget(&mut conn, &key, "tokens").as_deref(), Some("0"));
+        assert_eq!(hget(&mut conn, &key, "cap").as_deref(), Some("100"));
+        assert_eq!(hget(&mut conn, &key, "tick").as_deref(), Some("10000000"));
+
+        std::thread::sleep(Duration::from_millis(1100));
+        assert!(!exists(&mut conn, &key));
+        assert!(tb.try_acquire(100).is_ok());
+    }
+
+    #[test]
+    #[ignore = "needs a Redis server at REDIS_HOST"]
+    fn migrate_from_keys() {
+        let url = redis_host();
+        let mut conn = connect(&url);
+        let bucket = unique("migrated");
+        let (old_tokens, old_last_refill) = (tokens_key(&bucket), last_refill_key(&bucket));
+        let last_refill = time::OffsetDateTime::now_utc().unix_timestamp_nanos();
+        redis::cmd("SET")
+            .arg(&old_tokens)
+            .arg("0")
+            .query::<()>(&mut conn)
+            .unwrap();
+        redis::cmd("SET")
+            .arg(&old_last_refill)
+            .arg(&last_refill.to_le_bytes()[..])
+            .query::<()>(&mut conn)
+            .unwrap();
+
+        let storage = RedisStorage::builder(2, &url)
+            .with_bucket(&bucket)
+            .with_hash_layout()
+            .build()
+            .unwrap();
+
+        assert!(storage
+            .migrate_from_keys(&old_tokens, &old_last_refill)
+            .unwrap());
+        assert!(!exists(&mut conn, &old_tokens));
+        assert!(!exists(&mut conn, &old_last_refill));
+        assert_eq!(
+            hget(&mut conn, &state_key(&bucket), "tokens").as_deref(),
+            Some("0")
+        );
+        assert!(!storage
+            .migrate_from_keys(&old_tokens, &old_last_refill)
+            .unwrap());
+
+        let tb = TokenBucket::new(storage);
+        assert!(tb.try_acquire_one().is_err());
+    }
+
+    #[test]
+    #[ignore = "needs a Redis server at REDIS_HOST"]
+    fn decimal_timestamp_encoding() {
+        let url = redis_host();
+        let mut conn = connect(&url);
+        let bucket = unique("decimal");
+        let last_refill = time::OffsetDateTime::now_utc().unix_timestamp_nanos();
+        redis::cmd("SET")
+            .arg(tokens_key(&bucket))
+            .arg("0")
+            .query::<()>(&mut conn)
+            .unwrap();
+        redis::cmd("SET")
+            .arg(last_refill_key(&bucket))
+            .arg(&last_refill.to_le_bytes()[..])
+            .query::<()>(&mut conn)
+            .unwrap();
+
+        let storage = RedisStorage::builder(2, &url)
+            .with_bucket(&bucket)
+            .with_timestamp_encoding(TimestampEncoding::UnixMicros)
+            .build()
+            .unwrap();
+        let tb = TokenBucket::new(storage);
+
+        // Legacy binary value is still readable
+        assert!(tb.try_acquire_one().is_err());
+        std::thread::sleep(Duration::from_millis(600));
+        assert!(tb.try_acquire_one().is_ok());
+
+        let stored = get(&mut conn, &last_refill_key(&bucket)).unwrap();
+        let micros: i128 = stored.parse().unwrap();
+        assert!(micros > last_refill / 1000);
+
+        redis::cmd("SET")
+            .arg(last_refill_key(&bucket))
+            .arg("garbage")
+            .query::<()>(&mut conn)
+            .unwrap();
+        assert!(matches!(
+            tb.try_acquire_one(),
+            Err(RedisStorageError::ConvertingBytesToI128Error { .. })
+        ));
+    }
+
+    #[test]
+    fn connection_options() {
+        let stub = RespStub::start(([127, 0, 0, 1], 0));
+        let storage = RedisStorage::builder(2, stub.url())
+            .with_username("user")
+            .with_password("secret")
+            .with_db(3)
+            .build()
+            .unwrap();
+        assert!(TokenBucket::new(storage).try_acquire_one().is_ok());
+
+        assert_eq!(stub.received("AUTH"), vec![vec!["AUTH", "user", "secret"]]);
+        assert_eq!(stub.received("SELECT"), vec![vec!["SELECT", "3"]]);
+
+        let res = RedisStorage::cluster_builder(2, vec![stub.url()])
+            .with_db(3)
+            .build();
+        assert!(matches!(res, Err(RedisStorageError::RedisError(_))));
+    }
+
+    #[test]
+    fn sentinel_failover() {
+        let sentinel = RespStub::start(([127, 0, 0, 1], 0));
+        let old_master = RespStub::start(([127, 0, 0, 1], 0));
+        let new_master = RespStub::start(([127, 0, 0, 1], 0));
+        sentinel.set_master("mymaster", old_master.addr());
+
+        let unreachable = "redis://127.0.0.1:1";
+        let storage =
+            RedisStorage::sentinel_builder(10, "mymaster", vec![unreachable, &sentinel.url()])
+                .with_pool_size(1)
+                .with_password("secret")
+                .build()
+                .unwrap();
+        let alg = || TokenBucketAlgorithm { mode: Mode::N };
+
+        assert!(storage.try_acquire(alg(), 1).is_ok());
+        assert!(old_master.exists(AVAILABLE_TOKENS_KEY));
+        assert_eq!(old_master.received("AUTH"), vec![vec!["AUTH", "secret"]]);
+        assert!(sentinel.received("AUTH").is_empty());
+
+        // Failover: the old master is demoted and sentinel points to the new one
+        old_master.set_replica(true);
+        sentinel.set_master("mymaster", new_master.addr());
+
+        assert!(matches!(
+            storage.try_acquire(alg(), 1),
+            Err(RedisStorageError::RedisError(err)) if err.kind() == redis::ErrorKind::ReadOnly
+        ));
+        assert!(storage.try_acquire(alg(), 1).is_ok());
+        assert!(new_master.exists(AVAILABLE_TOKENS_KEY));
+    }
+
+    #[test]
+    #[ignore = "needs a Redis server at REDIS_HOST"]
+    fn batch_all_or_nothing() {
+        let url = redis_host();
+        let mut conn = connect(&url);
+        let storage = RedisStorage::new(100, &url).unwrap();
+        let (user, tenant) = (unique("user"), unique("tenant"));
+        let user_bucket = storage.bucket(&user, 2, 2).unwrap();
+        let tenant_bucket = storage.bucket(&tenant, 10, 10).unwrap();
+
+        for _ in 0..2 {
+            storage
+                .try_acquire_batch(&[(&user_bucket, 1), (&tenant_bucket, 1)])
+                .unwrap();
+        }
+        match storage.try_acquire_batch(&[(&user_bucket, 1), (&tenant_bucket, 1)]) {
+            Err(RedisStorageError::BatchRateLimitExceeded { results }) => {
+                assert!(!results[0].allowed);
+                assert_eq!(results[0].available_tokens, 0);
+                assert!(results[0].retry_after > Duration::ZERO);
+                assert!(results[0].retry_after <= Duration::from_millis(500));
+                assert_eq!(
+                    results[1],
+                    BucketResult {
+                        allowed: true,
+                        available_tokens: 7,
+                        retry_after: Duration::ZERO,
+                    }
+                );
+            }
+            res => panic!("unexpected result: {:?}", res),
+        }
+        assert_eq!(tokens(&mut conn, &tokens_key(&tenant)), 8);
+
+        // Permits of the same bucket are summed up
+        let burst = unique("burst");
+        let burst_bucket = storage.bucket(&burst, 1, 10).unwrap();
+        assert!(matches!(
+            storage.try_acquire_batch(&[(&burst_bucket, 6), (&burst_bucket, 6)]),
+            Err(RedisStorageError::RateLimitExceededError(
+                RateLimitExceededError::ExceedsCapacity {
+                    permits: 12,
+                    cap: 10
+                }
+            ))
+        ));
+        assert_eq!(
+            storage.bucket(&burst, 0, 10).unwrap_err(),
+            ConfigError::ZeroRate
+        );
+        assert_eq!(
+            storage.bucket(&burst, 1, 0).unwrap_err(),
+            ConfigError::ZeroCapacity
+        );
+        assert_eq!(
+            storage
+                .bucket(&burst, 1, MAX_REDIS_CAPACITY + 1)
+                .unwrap_err(),
+            ConfigError::CapacityOverflow
+        );
+        assert_eq!(
+            storage
+                .try_acquire_batch(&[(&burst_bucket, 5), (&burst_bucket, 5)])
+                .unwrap(),
+            vec![
+                BucketResult {
+                    allowed: true,
+                    available_tokens: 0,
+                    retry_after: Duration::ZERO,
+                };
+                2
+            ]
+        );
+        assert_eq!(tokens(&mut conn, &tokens_key(&burst)), 0);
+
+        // Buckets are filled like the bucket of the storage
+        let storage = RedisStorage::builder(100, &url)
+            .with_initial_fill(InitialFill::Tokens(1))
+            .build()
+            .unwrap();
+        let bucket = storage.bucket(unique("cold"), 10, 10).unwrap();
+        assert!(storage.try_acquire_batch(&[(&bucket, 2)]).is_err());
+        assert!(storage.try_acquire_batch(&[(&bucket, 1)]).is_ok());
+    }
+
+    #[test]
+    #[ignore = "needs a Redis server at REDIS_HOST"]
+    fn batch_hash_layout() {
+        let url = redis_host();
+        let mut conn = connect(&url);
+        let storage = RedisStorage::builder(100, &url)
+            .with_hash_layout()
+            .build()
+            .unwrap();
+        let tenant = unique("tenant");
+        let user = storage
+            .bucket(format!("{{{}}}:user:42", tenant), 1, 1)
+            .unwrap();
+        let endpoint = storage
+            .bucket(format!("{{{}}}:endpoint:search", tenant), 5, 5)
+            .unwrap();
+        let endpoint_key = format!("{{{}}}:endpoint:search:state", tenant);
+
+        storage
+            .try_acquire_batch(&[(&user, 1), (&endpoint, 1)])
+            .unwrap();
+        assert_eq!(
+            hget(&mut conn, &endpoint_key, "tokens").as_deref(),
+            Some("4")
+        );
+        assert!(matches!(
+            storage.try_acquire_batch(&[(&user, 1), (&endpoint, 1)]),
+            Err(RedisStorageError::BatchRateLimitExceeded { .. })
+        ));
+        assert_eq!(
+            hget(&mut conn, &endpoint_key, "tokens").as_deref(),
+            Some("4")
+        );
+    }
+
+    #[test]
+    fn cluster_batch() {
+        let stub = RespStub::start(([127, 0, 0, 1], 0));
+        let storage = RedisStorage::cluster_builder(100, vec![stub.url()])
+            .with_hash_layout()
+            .build()
+            .unwrap();
+        let user = storage.bucket("{tenant:1}:user:42", 1, 1).unwrap();
+
+        let other = storage.bucket("tenant:2", 5, 5).unwrap();
+        assert!(matches!(
+            storage.try_acquire_batch(&[(&user, 1), (&other, 1)]),
+            Err(RedisStorageError::CrossSlotKeys { .. })
+        ));
+
+        let keys_storage = RedisStorage::new(100, stub.url()).unwrap();
+        assert!(matches!(
+            storage.try_acquire_batch(&[
+                (&user, 1),
+                (&keys_storage.bucket("{tenant:1}", 5, 5).unwrap(), 1)
+            ]),
+            Err(RedisStorageError::MixedLayouts)
+        ));
+    }
+
+    #[test]
+    #[ignore = "needs a Redis server at REDIS_HOST"]
+    fn composite_limits() {
+        let url = redis_host();
+        let mut conn = connect(&url);
+        let bucket = unique("user");
+        let storage = RedisStorage::builder(100, &url)
+            .with_bucket(&bucket)
+            .build()
+            .unwrap();
+        let limiter =
+            CompositeLimiter::new(storage, vec![Limit::per_second(2), Limit::per_minute(3)]);
+        let second = format!("{}:2:1000000000", tokens_key(&bucket));
+        let minute = format!("{}:3:60000000000", tokens_key(&bucket));
+
+        assert!(limiter.try_acquire(2).is_ok());
+        assert_eq!(tokens(&mut conn, &second), 0);
+        assert_eq!(tokens(&mut conn, &minute), 1);
+
+        match limiter.try_acquire_one() {
+            Err(RedisStorageError::CompositeLimitExceeded(err)) => {
+                assert_eq!(err.tripped, vec![Limit::per_second(2)]);
+                assert!(err.retry_after <= Duration::from_millis(500));
+            }
+            res => panic!("unexpected result: {:?}", res),
+        }
+        assert_eq!(tokens(&mut conn, &minute), 1);
+        assert!(limiter.try_acquire(3).unwrap_err().is_capacity_exceeded());
+
+        assert!(limiter.charge(3).is_ok());
+        assert_eq!(tokens(&mut conn, &second), -3);
+        assert_eq!(tokens(&mut conn, &minute), -2);
+
+        // Periods shorter than a millisecond get their own buckets
+        let storage = RedisStorage::builder(100, &url)
+            .with_bucket(&bucket)
+            .build()
+            .unwrap();
+        let limiter = CompositeLimiter::new(
+            storage,
+            vec![
+                Limit::new(2, Duration::from_micros(1500)),
+                Limit::new(2, Duration::from_micros(1800)),
+            ],
+        );
+        assert!(limiter.try_acquire(2).is_ok());
+        for key in ["2:1500000", "2:1800000"] {
+            assert_eq!(
+                tokens(&mut conn, &format!("{}:{}", tokens_key(&bucket), key)),
+                0
+            );
+        }
+
+        // New limits are filled like the bucket of the storage
+        let storage = RedisStorage::builder(100, &url)
+            .with_bucket(unique("empty"))
+            .with_initial_fill(InitialFill::Empty)
+            .build()
+            .unwrap();
+        let limiter = CompositeLimiter::new(storage, vec![Limit::per_second(2)]);
+        assert!(limiter.try_acquire_one().is_err());
+    }
+
+    #[test]
+    #[ignore = "needs a Redis server at REDIS_HOST"]
+    fn leasing() {
+        let url = redis_host();
+        let mut conn = connect(&url);
+        let bucket = unique("leased");
+        let storage = RedisStorage::builder(100, &url)
+            .with_bucket(&bucket)
+            .build()
+            .unwrap();
+        let storage = LeasingStorage::new(storage, 10);
+
+        assert!(storage
+            .try_acquire(TokenBucketAlgorithm { mode: Mode::N }, 1)
+            .is_ok());
+        assert_eq!(tokens(&mut conn, &tokens_key(&bucket)), 89);
+
+        // Unused tokens are returned on drop
+        drop(storage);
+        assert!(tokens(&mut conn, &tokens_key(&bucket)) >= 99);
+    }
+
+    #[test]
+    #[ignore = "needs a Redis server at REDIS_HOST"]
+    fn reconfigure() {
+        let url = redis_host();
+        let mut conn = connect(&url);
+        let bucket = unique("reconfigured");
+        let storage = RedisStorage::builder(2, &url)
+            .with_bucket(&bucket)
+            .build()
+            .unwrap();
+        let tb = TokenBucket::new(storage);
+
+        assert!(tb.try_acquire_one().is_ok());
+        assert!(tb.set_capacity(4, ResizePolicy::Scale).is_ok());
+        assert_eq!(tokens(&mut conn, &tokens_key(&bucket)), 2);
+        assert!(tb.try_acquire(3).is_err());
+
+        assert!(tb.set_rate(1000).is_ok());
+        std::thread::sleep(Duration::from_millis(50));
+        assert!(tb.try_acquire(4).is_ok());
+        assert_eq!(tokens(&mut conn, &tokens_key(&bucket)), 0);
+    }
+
+    #[test]
+    #[ignore = "needs a Redis server at REDIS_HOST"]
+    fn warm_up() {
+        let url = redis_host();
+        let mut conn = connect(&url);
+        let (keys, hash) = (unique("keys"), unique("hash"));
+        let storage = |hash_layout: bool| {
+            let builder = RedisStorage::builder(300, &url)
+                .with_bucket(if hash_layout { &hash } else { &keys })
+                .with_warm_up(WarmUp::new(Duration::from_secs(1)));
+            let builder = if hash_layout {
+                builder.with_hash_layout()
+            } else {
+                builder
+            };
+            TokenBucket::new(builder.build().unwrap())
+        };
+
+        for hash_layout in [false, true] {
+            let tb = storage(hash_layout);
+            assert!(tb.try_acquire(100).is_ok());
+            assert!(tb.try_acquire_one().is_err());
+
+            // Other instances see the same warm-up
+            std::thread::sleep(Duration::from_millis(100));
+            let tb = storage(hash_layout);
+            assert!(tb.try_acquire(30).is_err());
+            assert!(tb.try_acquire(5).is_ok());
+        }
+        assert!(exists(&mut conn, &warm_since_key(&keys)));
+        assert!(redis::cmd("HGET")
+            .arg(state_key(&hash))
+            .arg("warm_since")
+            .query::<Option<Vec<u8>>>(&mut conn)
+            .unwrap()
+            .is_some());
+
+        // The batch script refills linearly, so the warming bucket isn't batched
+        let warming = RedisStorage::builder(300, &url)
+            .with_bucket(&keys)
+            .with_warm_up(WarmUp::new(Duration::from_secs(1)))
+            .build()
+            .unwrap();
+        let own = warming.bucket(&keys, 300, 300).unwrap();
+        let other = warming.bucket(unique("other"), 300, 300).unwrap();
+        assert!(matches!(
+            warming.try_acquire_batch(&[(&other, 1), (&own, 1)]),
+            Err(RedisStorageError::WarmUpInBatch { key }) if key == last_refill_key(&keys)
+        ));
+        assert!(warming.try_acquire_batch(&[(&other, 1)]).is_ok());
+
+        redis::cmd("SET")
+            .arg(warm_since_key(&keys))
+            .arg("garbage")
+            .query::<()>(&mut conn)
+            .unwrap();
+        match storage(false).try_acquire_one() {
+            Err(RedisStorageError::ConvertingBytesToI128Error { key, .. }) => {
+                assert_eq!(key, warm_since_key(&keys));
+            }
+            res => panic!("unexpected result: {:?}", res),
+        }
+    }
+
+    #[test]
+    #[ignore = "needs a Redis server at REDIS_HOST"]
+    fn initial_fill() {
+        let url = redis_host();
+        let mut conn = connect(&url);
+        let storage = RedisStorage::builder(100, &url)
+            .with_bucket(unique("partial"))
+            .with_initial_fill(InitialFill::Tokens(10))
+            .build()
+            .unwrap();
+        let tb = TokenBucket::new(storage);
+        assert!(tb.try_acquire(11).is_err());
+        assert!(tb.try_acquire(10).is_ok());
+
+        let hash = unique("hash");
+        let storage = RedisStorage::builder(1000, &url)
+            .with_bucket(&hash)
+            .with_hash_layout()
+            .with_initial_fill(InitialFill::Empty)
+            .build()
+            .unwrap();
+        let tb = TokenBucket::new(storage);
+        assert!(tb.try_acquire_one().is_err());
+
+        // The full bucket isn't expired, so it isn't created empty again
+        std::thread::sleep(Duration::from_millis(1100));
+        assert!(exists(&mut conn, &state_key(&hash)));
+        assert!(tb.try_acquire(1000).is_ok());
+    }
+
+    #[test]
+    #[ignore = "needs a Redis server at REDIS_HOST"]
+    fn charge() {
+        let url = redis_host();
+        let mut conn = connect(&url);
+        let bucket = unique("debt");
+        let storage = RedisStorage::builder(10, &url)
+            .with_bucket(&bucket)
+            .build()
+            .unwrap();
+        let tb = TokenBucket::new(storage);
+        assert!(tb.try_acquire_overdraft(15).is_ok());
+        assert!(tb.charge(5).is_ok());
+        assert!(tb.try_acquire_overdraft(1).is_err());
+
+        // The negative balance is stored as a signed decimal
+        assert_eq!(get(&mut conn, &tokens_key(&bucket)).as_deref(), Some("-10"));
+    }
+
+    #[test]
+    fn validation() {
+        // Checked before connecting
+        let storage = RedisStorage::new(0, "redis://127.0.0.1:1");
+        assert!(matches!(
+            storage,
+            Err(RedisStorageError::ConfigError(ConfigError::ZeroRate))
+        ));
+        let storage = RedisStorage::builder(10, "redis://127.0.0.1:1")
+            .with_capacity(MAX_REDIS_CAPACITY + 1)
+            .build();
+        assert!(matches!(
+            storage,
+            Err(RedisStorageError::ConfigError(
+                ConfigError::CapacityOverflow
+            ))
+        ));
+    }
+
+    #[test]
+    #[ignore = "needs a Redis server at REDIS_HOST"]
+    fn validation_of_reconfiguration() {
+        let url = redis_host();
+        let storage = RedisStorage::builder(10, &url)
+            .with_bucket(unique("burst"))
+            .with_capacity(30)
+            .build()
+            .unwrap();
+        let tb = TokenBucket::new(storage);
+        assert!(matches!(
+            tb.set_capacity(0, ResizePolicy::Clamp),
+            Err(ReconfigureError::Config(ConfigError::ZeroCapacity))
+        ));
+        assert!(matches!(
+            tb.set_capacity(MAX_REDIS_CAPACITY + 1, ResizePolicy::Clamp),
+            Err(ReconfigureError::Config(ConfigError::CapacityOverflow))
+        ));
+        assert!(tb.try_acquire(31).unwrap_err().is_capacity_exceeded());
+        assert!(tb.try_acquire(30).is_ok());
+        let err = tb.try_acquire(1).unwrap_err();
+        assert!(err.is_rate_limit_exceeded() && !err.is_capacity_exceeded());
+
+        let storage = RedisStorage::builder(10, &url)
+            .with_bucket(unique("huge"))
+            .build()
+            .unwrap();
+        let year = Duration::from_secs(365 * 24 * 60 * 60);
+        let limiter =
+            CompositeLimiter::new(storage, vec![Limit::new(MAX_REDIS_CAPACITY + 1, year)]);
+        assert!(matches!(
+            limiter.try_acquire_one(),
+            Err(RedisStorageError::ConfigError(
+                ConfigError::CapacityOverflow
+            ))
+        ));
+    }
+
+    #[test]
+    #[ignore = "needs a Redis server at REDIS_HOST"]
+    fn large_quantities() {
+        let url = redis_host();
+        let mut conn = connect(&url);
+        let bucket = unique("bytes");
+        let storage = RedisStorage::builder(1_000_000_000, &url)
+            .with_bucket(&bucket)
+            .build()
+            .unwrap();
+        let tb = TokenBucket::new(storage);
+        assert!(tb.set_capacity(10_000_000_000, ResizePolicy::Clamp).is_ok());
+        assert!(tb.try_acquire(1_000_000_000).is_ok());
+        assert!(tb.try_acquire(1_000_000_000).is_err());
+
+        std::thread::sleep(Duration::from_secs(2));
+        assert!(tb.try_acquire(1_500_000_000).is_ok());
+        assert!(tokens(&mut conn, &tokens_key(&bucket)) >= 500_000_000);
+    }
+}
@@ -0,0 +1,271 @@
+use crate::in_redis::connect::Connector;
+use crate::in_redis::RedisStorageError;
+
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+/// Default number of connections in the pool.
+pub const DEFAULT_POOL_SIZE: usize = 4;
+/// Default delay before the first reconnect attempt.
+pub const DEFAULT_MIN_BACKOFF: Duration = Duration::from_millis(100);
+/// Default upper bound of the delay between reconnect attempts.
+pub const DEFAULT_MAX_BACKOFF: Duration = Duration::from_secs(10);
+
+/// Health of the connections to the Redis.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct RedisHealth {
+    /// Maximum number of connections.
+    pub pool_size: usize,
+    /// Number of currently opened connections.
+    pub connected: usize,
+    /// Number of failed connection attempts since the last successful one.
+    pub consecutive_failures: u32,
+}
+
+impl RedisHealth {
+    /// Returns `true` if the last connection attempt succeeded.
+    pub fn is_healthy(&self) -> bool {
+        self.consecutive_failures == 0
+    }
+}
+
+/// Exponential backoff between reconnect attempts.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Backoff {
+    pub min: Duration,
+    pub max: Duration,
+}
+
+impl Backoff {
+    fn delay(&self, failures: u32) -> Duration {
+        let factor = 1u32 << failures.saturating_sub(1).min(16);
+        self.min
+            .checked_mul(factor)
+            .map_or(self.max, |d| d.min(self.max))
+    }
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self {
+            min: DEFAULT_MIN_BACKOFF,
+            max: DEFAULT_MAX_BACKOFF,
+        }
+    }
+}
+
+pub(crate) enum Connection {
+    Single(redis::Connection),
+    Cluster(Box<redis::cluster::ClusterConnection>),
+}
+
+impl redis::ConnectionLike for Connection {
+    fn req_packed_command(&mut self, cmd: &[u8]) -> redis::RedisResult<redis::Value> {
+        match self {
+            Connection::Single(conn) => conn.req_packed_command(cmd),
+            Connection::Cluster(conn) => conn.req_packed_command(cmd),
+        }
+    }
+
+    fn req_packed_commands(
+        &mut self,
+        cmd: &[u8],
+        offset: usize,
+        count: usize,
+    ) -> redis::RedisResult<Vec<redis::Value>> {
+        match self {
+            Connection::Single(conn) => conn.req_packed_commands(cmd, offset, count),
+            Connection::Cluster(conn) => conn.req_packed_commands(cmd, offset, count),
+        }
+    }
+
+    fn req_command(&mut self, cmd: &redis::Cmd) -> redis::RedisResult<redis::Value> {
+        match self {
+            Connection::Single(conn) => conn.req_command(cmd),
+            Connection::Cluster(conn) => conn.req_command(cmd),
+        }
+    }
+
+    fn get_db(&self) -> i64 {
+        match self {
+            Connection::Single(conn) => conn.get_db(),
+            Connection::Cluster(conn) => conn.get_db(),
+        }
+    }
+
+    fn supports_pipelining(&self) -> bool {
+        match self {
+            Connection::Single(conn) => conn.supports_pipelining(),
+            Connection::Cluster(conn) => conn.supports_pipelining(),
+        }
+    }
+
+    fn check_connection(&mut self) -> bool {
+        match self {
+            Connection::Single(conn) => conn.check_connection(),
+            Connection::Cluster(conn) => conn.check_connection(),
+        }
+    }
+
+    fn is_open(&self) -> bool {
+        match self {
+            Connection::Single(conn) => conn.is_open(),
+            Connection::Cluster(conn) => conn.is_open(),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Slot {
+    conn: Option<Connection>,
+    retry_at: Option<Instant>,
+}
+
+/// Fixed size pool of lazily opened connections.
+///
+/// Broken connections are dropped and reopened on next use. Failed reconnect attempts
+/// are retried no earlier than the backoff allows, until then calls fail immediately.
+pub(crate) struct Pool {
+    connector: Connector,
+    slots: Vec<parking_lot::Mutex<Slot>>,
+    next: AtomicUsize,
+    backoff: Backoff,
+    connected: AtomicUsize,
+    failures: AtomicU32,
+}
+
+impl Pool {
+    pub fn new(connector: Connector, size: usize, backoff: Backoff) -> Self {
+        Self {
+            connector,
+            slots: (0..size.max(1))
+                .map(|_| parking_lot::Mutex::new(Slot::default()))
+                .collect(),
+            next: AtomicUsize::new(0),
+            backoff,
+            connected: AtomicUsize::new(0),
+            failures: AtomicU32::new(0),
+        }
+    }
+
+    /// Opens the first connection of the pool.
+    pub fn connect(&self) -> Result<(), RedisStorageError> {
+        let mut slot = self.slots[0].lock();
+        self.ensure_connected(&mut slot)?;
+        Ok(())
+    }
+
+    /// Runs `f` on one of the pooled connections.
+    ///
+    /// The operation is never retried: the connection could break after the command
+    /// was applied, so retrying could acquire tokens twice.
+    pub fn with_conn<T, F>(&self, f: F) -> Result<T, RedisStorageError>
+    where
+        F: FnOnce(&mut Connection) -> Result<T, RedisStorageError>,
+    {
+        let mut slot = self.pick_slot();
+        let conn = self.ensure_connected(&mut slot)?;
+
+        let res = f(conn);
+        if let Err(RedisStorageError::RedisError(err)) = &res {
+            if is_connection_error(err) {
+                tracing::warn!("redis connection broken: {}", err);
+                slot.conn = None;
+                self.connected.fetch_sub(1, Ordering::Relaxed);
+            }
+        }
+        res
+    }
+
+    pub fn health(&self) -> RedisHealth {
+        RedisHealth {
+            pool_size: self.slots.len(),
+            connected: self.connected.load(Ordering::Relaxed),
+            consecutive_failures: self.failures.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Prefers a free connected slot, then any free slot, then waits for the next one in turn.
+    fn pick_slot(&self) -> parking_lot::MutexGuard<'_, Slot> {
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        let mut free = None;
+        for i in 0..self.slots.len() {
+            if let Some(slot) = self.slots[(start + i) % self.slots.len()].try_lock() {
+                if slot.conn.is_some() {
+                    return slot;
+                }
+                if free.is_none() {
+                    free = Some(slot);
+                }
+            }
+        }
+
+        free.unwrap_or_else(|| self.slots[start % self.slots.len()].lock())
+    }
+
+    fn ensure_connected<'a>(
+        &self,
+        slot: &'a mut Slot,
+    ) -> Result<&'a mut Connection, RedisStorageError> {
+        if slot.conn.is_none() {
+            if let Some(retry_at) = slot.retry_at {
+                let now = Instant::now();
+                if retry_at > now {
+                    return Err(RedisStorageError::Unavailable {
+                        retry_in: retry_at - now,
+                    });
+                }
+            }
+
+            match self.connector.connect() {
+                Ok(conn) => {
+                    slot.conn = Some(conn);
+                    slot.retry_at = None;
+                    self.connected.fetch_add(1, Ordering::Relaxed);
+                    if self.failures.swap(0, Ordering::Relaxed) > 0 {
+                        tracing::info!("redis connection restored");
+                    }
+                }
+                Err(err) => {
+                    let failures = self.failures.fetch_add(1, Ordering::Relaxed) + 1;
+                    let delay = self.backoff.delay(failures);
+                    tracing::warn!("connecting to redis failed, retry in {:?}: {}", delay, err);
+                    slot.retry_at = Some(Instant::now() + delay);
+                    return Err(err.into());
+                }
+            }
+        }
+
+        Ok(slot.conn.as_mut().expect("connection must be opened above"))
+    }
+}
+
+/// Errors after which the connection is reopened.
+///
+/// A master demoted by failover rejects writes, so the connection is reopened
+/// to find the new master.
+fn is_connection_error(err: &redis::RedisError) -> bool {
+    err.is_io_error()
+        || err.is_connection_dropped()
+        || err.is_connection_refusal()
+        || err.kind() == redis::ErrorKind::ReadOnly
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_delay() {
+        let backoff = Backoff {
+            min: Duration::from_millis(100),
+            max: Duration::from_secs(1),
+        };
+
+        assert_eq!(backoff.delay(1), Duration::from_millis(100));
+        assert_eq!(backoff.delay(2), Duration::from_millis(200));
+        assert_eq!(backoff.delay(4), Duration::from_millis(800));
+        assert_eq!(backoff.delay(5), Duration::from_secs(1));
+        assert_eq!(backoff.delay(u32::MAX), Duration::from_secs(1));
+    }
+}
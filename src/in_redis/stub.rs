@@ -0,0 +1,391 @@
+//! Minimal in-process stand-in of the Redis server that speaks RESP.
+//!
+//! Used only to test connection handling (pool, reconnects, authentication, sentinel
+//! and cluster routing), scripts are tested against a real server given by `REDIS_HOST`.
+//! Supports only commands used by the storage. Scripts are not interpreted, known scripts
+//! of the storage are emulated instead. The stub pretends to be a cluster of a single node
+//! owning all slots and a sentinel monitoring masters given by [`RespStub::set_master`].
+
+use crate::in_redis::layout::{COMPARE_AND_SET_SCRIPT, HASH_COMPARE_AND_SET_SCRIPT};
+
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+type Data = Arc<parking_lot::Mutex<Db>>;
+
+#[derive(Default)]
+struct Db {
+    entries: HashMap<Vec<u8>, Entry>,
+    masters: HashMap<Vec<u8>, SocketAddr>,
+    replica: bool,
+    received: Vec<Vec<Vec<u8>>>,
+}
+
+struct Entry {
+    value: Value,
+    expires_at: Option<Instant>,
+}
+
+enum Value {
+    Str(Vec<u8>),
+    Hash(HashMap<Vec<u8>, Vec<u8>>),
+}
+
+impl Db {
+    fn get(&mut self, key: &[u8]) -> Option<&mut Value> {
+        let expired = matches!(
+            self.entries.get(key),
+            Some(Entry { expires_at: Some(at), .. }) if *at <= Instant::now()
+        );
+        if expired {
+            self.entries.remove(key);
+        }
+        self.entries.get_mut(key).map(|e| &mut e.value)
+    }
+
+    fn get_str(&mut self, key: &[u8]) -> Option<Vec<u8>> {
+        match self.get(key) {
+            Some(Value::Str(v)) => Some(v.clone()),
+            _ => None,
+        }
+    }
+
+    fn set(&mut self, key: &[u8], value: &[u8]) {
+        self.entries.insert(
+            key.to_vec(),
+            Entry {
+                value: Value::Str(value.to_vec()),
+                expires_at: None,
+            },
+        );
+    }
+
+    fn hget(&mut self, key: &[u8], field: &[u8]) -> Option<Vec<u8>> {
+        match self.get(key) {
+            Some(Value::Hash(h)) => h.get(field).cloned(),
+            _ => None,
+        }
+    }
+
+    fn hset(&mut self, key: &[u8], field: &[u8], value: &[u8]) {
+        if !matches!(self.get(key), Some(Value::Hash(_))) {
+            self.entries.insert(
+                key.to_vec(),
+                Entry {
+                    value: Value::Hash(HashMap::new()),
+                    expires_at: None,
+                },
+            );
+        }
+        if let Some(Value::Hash(h)) = self.get(key) {
+            h.insert(field.to_vec(), value.to_vec());
+        }
+    }
+
+    fn persist(&mut self, key: &[u8]) {
+        if let Some(entry) = self.entries.get_mut(key) {
+            entry.expires_at = None;
+        }
+    }
+
+    fn pexpire(&mut self, key: &[u8], millis: u64) {
+        if let Some(entry) = self.entries.get_mut(key) {
+            entry.expires_at = Some(Instant::now() + Duration::from_millis(millis));
+        }
+    }
+}
+
+pub(crate) struct RespStub {
+    addr: SocketAddr,
+    data: Data,
+    stopped: Arc<AtomicBool>,
+    streams: Arc<parking_lot::Mutex<Vec<TcpStream>>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl RespStub {
+    /// Starts a server. Pass `127.0.0.1:0` to use any free port.
+    pub fn start<A: Into<SocketAddr>>(addr: A) -> Self {
+        let listener = TcpListener::bind(addr.into()).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let data = Data::default();
+        let stopped = Arc::new(AtomicBool::new(false));
+        let streams = Arc::new(parking_lot::Mutex::new(Vec::new()));
+
+        let handle = std::thread::spawn({
+            let data = Arc::clone(&data);
+            let stopped = Arc::clone(&stopped);
+            let streams = Arc::clone(&streams);
+            move || {
+                for stream in listener.incoming() {
+                    if stopped.load(Ordering::Acquire) {
+                        break;
+                    }
+                    let stream = match stream {
+                        Ok(s) => s,
+                        Err(_) => continue,
+                    };
+                    streams.lock().push(stream.try_clone().unwrap());
+                    std::thread::spawn({
+                        let data = Arc::clone(&data);
+                        move || serve(stream, data)
+                    });
+                }
+            }
+        });
+
+        Self {
+            addr,
+            data,
+            stopped,
+            streams,
+            handle: Some(handle),
+        }
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn url(&self) -> String {
+        format!("redis://{}", self.addr)
+    }
+
+    pub fn exists(&self, key: &str) -> bool {
+        self.data.lock().get(key.as_bytes()).is_some()
+    }
+
+    /// Makes the stub answer sentinel requests of the master address.
+    pub fn set_master(&self, name: &str, addr: SocketAddr) {
+        self.data
+            .lock()
+            .masters
+            .insert(name.as_bytes().to_vec(), addr);
+    }
+
+    /// Demotes the stub to a replica that rejects scripts.
+    pub fn set_replica(&self, replica: bool) {
+        self.data.lock().replica = replica;
+    }
+
+    /// Returns all received commands with the given name.
+    pub fn received(&self, name: &str) -> Vec<Vec<String>> {
+        self.data
+            .lock()
+            .received
+            .iter()
+            .filter(|args| args[0].eq_ignore_ascii_case(name.as_bytes()))
+            .map(|args| {
+                args.iter()
+                    .map(|a| String::from_utf8_lossy(a).into_owned())
+                    .collect()
+            })
+            .collect()
+    }
+}
+
+impl Drop for RespStub {
+    /// Kills the server: stops accepting and drops all client connections.
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::Release);
+        let _ = TcpStream::connect(self.addr);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+        for stream in self.streams.lock().drain(..) {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+    }
+}
+
+enum Reply {
+    Status(&'static str),
+    Integer(i64),
+    Bulk(Option<Vec<u8>>),
+    Array(Vec<Reply>),
+    Error(String),
+}
+
+impl Reply {
+    fn write_to(&self, out: &mut Vec<u8>) {
+        match self {
+            Reply::Status(s) => out.extend_from_slice(format!("+{}\r\n", s).as_bytes()),
+            Reply::Integer(i) => out.extend_from_slice(format!(":{}\r\n", i).as_bytes()),
+            Reply::Bulk(None) => out.extend_from_slice(b"$-1\r\n"),
+            Reply::Bulk(Some(v)) => {
+                out.extend_from_slice(format!("${}\r\n", v.len()).as_bytes());
+                out.extend_from_slice(v);
+                out.extend_from_slice(b"\r\n");
+            }
+            Reply::Array(items) => {
+                out.extend_from_slice(format!("*{}\r\n", items.len()).as_bytes());
+                for item in items {
+                    item.write_to(out);
+                }
+            }
+            Reply::Error(e) => out.extend_from_slice(format!("-{}\r\n", e).as_bytes()),
+        }
+    }
+}
+
+fn serve(stream: TcpStream, data: Data) {
+    let mut writer = match stream.try_clone() {
+        Ok(w) => w,
+        Err(_) => return,
+    };
+    let mut reader = BufReader::new(stream);
+
+    while let Some(args) = read_command(&mut reader) {
+        let reply = if args[0].eq_ignore_ascii_case(b"CLUSTER") {
+            let addr = writer.local_addr().unwrap();
+            Reply::Array(vec![Reply::Array(vec![
+                Reply::Integer(0),
+                Reply::Integer(16383),
+                Reply::Array(vec![
+                    Reply::Bulk(Some(addr.ip().to_string().into_bytes())),
+                    Reply::Integer(addr.port() as i64),
+                ]),
+            ])])
+        } else {
+            execute(&args, &data)
+        };
+
+        let mut out = Vec::new();
+        reply.write_to(&mut out);
+        if writer.write_all(&out).is_err() {
+            return;
+        }
+    }
+}
+
+fn execute(args: &[Vec<u8>], data: &Data) -> Reply {
+    let name = String::from_utf8_lossy(&args[0]).to_ascii_uppercase();
+    let mut db = data.lock();
+    db.received.push(args.to_vec());
+    match name.as_str() {
+        "PING" => Reply::Status("PONG"),
+        "AUTH" | "SELECT" | "CLIENT" => Reply::Status("OK"),
+        "ROLE" => Reply::Array(vec![
+            Reply::Bulk(Some(if db.replica { "slave" } else { "master" }.into())),
+            Reply::Integer(0),
+            Reply::Array(vec![]),
+        ]),
+        "SENTINEL" if args[1].eq_ignore_ascii_case(b"get-master-addr-by-name") => {
+            match db.masters.get(&args[2]) {
+                Some(addr) => Reply::Array(vec![
+                    Reply::Bulk(Some(addr.ip().to_string().into_bytes())),
+                    Reply::Bulk(Some(addr.port().to_string().into_bytes())),
+                ]),
+                None => Reply::Bulk(None),
+            }
+        }
+        "EVAL" | "EVALSHA" if db.replica => {
+            Reply::Error("READONLY You can't write against a read only replica.".to_owned())
+        }
+        "GET" => Reply::Bulk(db.get_str(&args[1])),
+        "SET" => {
+            db.set(&args[1], &args[2]);
+            Reply::Status("OK")
+        }
+        "MGET" => Reply::Array(
+            args[1..]
+                .iter()
+                .map(|k| Reply::Bulk(db.get_str(k)))
+                .collect(),
+        ),
+        "HMGET" => Reply::Array(
+            args[2..]
+                .iter()
+                .map(|f| Reply::Bulk(db.hget(&args[1], f)))
+                .collect(),
+        ),
+        "DEL" => Reply::Integer(
+            args[1..]
+                .iter()
+                .filter(|k| db.entries.remove(*k).is_some())
+                .count() as i64,
+        ),
+        "EVAL" | "EVALSHA" => {
+            let is = |code: &str| {
+                args[1] == code.as_bytes()
+                    || args[1] == redis::Script::new(code).get_hash().as_bytes()
+            };
+            let n: usize = String::from_utf8_lossy(&args[2]).parse().unwrap();
+            let (keys, argv) = args[3..].split_at(n);
+            if is(COMPARE_AND_SET_SCRIPT) {
+                compare_and_set(keys, argv, &mut db)
+            } else if is(HASH_COMPARE_AND_SET_SCRIPT) {
+                hash_compare_and_set(keys, argv, &mut db)
+            } else {
+                Reply::Error("NOSCRIPT unknown script".to_owned())
+            }
+        }
+        _ => Reply::Error(format!("ERR unknown command '{}'", name)),
+    }
+}
+
+fn compare_and_set(keys: &[Vec<u8>], argv: &[Vec<u8>], db: &mut Db) -> Reply {
+    let (expected, new) = argv.split_at(keys.len());
+    let matches = keys
+        .iter()
+        .zip(expected)
+        .all(|(k, exp)| db.get_str(k).unwrap_or_default() == *exp);
+    if !matches {
+        return Reply::Integer(0);
+    }
+    for (k, v) in keys.iter().zip(new) {
+        db.set(k, v);
+    }
+    Reply::Integer(1)
+}
+
+fn hash_compare_and_set(keys: &[Vec<u8>], argv: &[Vec<u8>], db: &mut Db) -> Reply {
+    let (expected, mut updates) = argv.split_at(keys.len() * 2);
+    let matches = keys.iter().zip(expected.chunks(2)).all(|(k, exp)| {
+        db.hget(k, b"tokens").unwrap_or_default() == exp[0]
+            && db.hget(k, b"last_refill").unwrap_or_default() == exp[1]
+    });
+    if !matches {
+        return Reply::Integer(0);
+    }
+    for key in keys {
+        let ttl: u64 = String::from_utf8_lossy(&updates[0]).parse().unwrap();
+        let len: usize = String::from_utf8_lossy(&updates[1]).parse().unwrap();
+        for pair in updates[2..2 + len].chunks(2) {
+            db.hset(key, &pair[0], &pair[1]);
+        }
+        if ttl > 0 {
+            db.pexpire(key, ttl);
+        } else {
+            db.persist(key);
+        }
+        updates = &updates[2 + len..];
+    }
+    Reply::Integer(1)
+}
+
+fn read_command<R: BufRead>(reader: &mut R) -> Option<Vec<Vec<u8>>> {
+    let count = read_header(reader, b'*')?;
+    (0..count)
+        .map(|_| {
+            let len = read_header(reader, b'$')?;
+            let mut buf = vec![0; len + 2];
+            reader.read_exact(&mut buf).ok()?;
+            buf.truncate(len);
+            Some(buf)
+        })
+        .collect()
+}
+
+fn read_header<R: BufRead>(reader: &mut R, prefix: u8) -> Option<usize> {
+    let mut line = String::new();
+    if reader.read_line(&mut line).ok()? == 0 || line.as_bytes()[0] != prefix {
+        return None;
+    }
+    line[1..].trim_end().parse().ok()
+}
@@ -0,0 +1,877 @@
+use crate::in_memory_atomic::{
+    charge, check_capacity, join, reconfigure, release, split, take, tick_nanos, validate_rate,
+    Rate, MAX_FULL_AT,
+};
+use crate::{
+    validate_capacity, ConfigError, InitialFill, KeyedStorage, RateLimitExceededError,
+    ReconfigureError, ResizePolicy, Storage, StorageError, TokenBucketAlgorithm,
+};
+
+use memmap2::MmapRaw;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Default directory of shared memory files.
+pub const DEFAULT_SHM_DIR: &str = "/dev/shm";
+/// Default number of keyed slots in the file.
+pub const DEFAULT_SHM_SLOTS: u32 = 1024;
+/// Default key of the bucket used by [`Storage`] implementation.
+pub const DEFAULT_SHM_KEY: &str = "tocket";
+
+const MAGIC: u64 = u64::from_ne_bytes(*b"tocketSM");
+/// Version 5 stores rates of two generations after the header fields.
+const VERSION: u32 = 5;
+const HEADER_LEN: usize = 128;
+/// Offset of [`Rates`] in the header.
+const RATES_OFFSET: usize = 40;
+const SLOT_LEN: usize = std::mem::size_of::<Slot>();
+/// State of a slot that wasn't used yet, means a bucket with initial tokens.
+/// Other states are times when the bucket becomes full tagged with the generation of the rate,
+/// as in [`AtomicInMemoryStorage`](crate::AtomicInMemoryStorage), and never reach it.
+const UNUSED: u64 = u64::MAX;
+/// A process that holds the lock of reconfiguration longer is considered crashed.
+const STALE_LOCK_NANOS: u64 = 1_000_000_000;
+
+/// Header of the file, written once before the file becomes visible to other processes.
+/// Rate and capacity the file is created with, reconfigured ones are in [`Rates`].
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+struct Header {
+    slots: u32,
+    cap: u32,
+    initial_tokens: u32,
+    refill_tick_nanos: u64,
+    /// Unix time in nanoseconds, times of slots are counted from it.
+    epoch_nanos: u64,
+}
+
+impl Header {
+    fn encode(&self) -> [u8; HEADER_LEN] {
+        let mut buf = [0; HEADER_LEN];
+        buf[0..8].copy_from_slice(&MAGIC.to_ne_bytes());
+        buf[8..12].copy_from_slice(&VERSION.to_ne_bytes());
+        buf[12..16].copy_from_slice(&self.slots.to_ne_bytes());
+        buf[16..20].copy_from_slice(&self.cap.to_ne_bytes());
+        buf[20..24].copy_from_slice(&self.initial_tokens.to_ne_bytes());
+        buf[24..32].copy_from_slice(&self.refill_tick_nanos.to_ne_bytes());
+        buf[32..40].copy_from_slice(&self.epoch_nanos.to_ne_bytes());
+        // Both generations of `Rates` start with the rate of the file
+        for generation in 0..2 {
+            let offset = RATES_OFFSET + generation * 16;
+            buf[offset..offset + 8].copy_from_slice(&u64::from(self.cap).to_ne_bytes());
+            buf[offset + 8..offset + 16].copy_from_slice(&self.refill_tick_nanos.to_ne_bytes());
+        }
+        buf
+    }
+
+    fn decode(buf: &[u8]) -> Result<Self, SharedMemoryStorageError> {
+        let u32_at = |i: usize| u32::from_ne_bytes(buf[i..i + 4].try_into().unwrap());
+        let u64_at = |i: usize| u64::from_ne_bytes(buf[i..i + 8].try_into().unwrap());
+
+        if buf.len() < HEADER_LEN || u64_at(0) != MAGIC {
+            return Err(SharedMemoryStorageError::Incompatible(
+                "not a shared memory storage file".to_owned(),
+            ));
+        }
+        if u32_at(8) != VERSION {
+            return Err(SharedMemoryStorageError::Incompatible(format!(
+                "unsupported version {}",
+                u32_at(8)
+            )));
+        }
+
+        Ok(Self {
+            slots: u32_at(12),
+            cap: u32_at(16),
+            initial_tokens: u32_at(20),
+            refill_tick_nanos: u64_at(24),
+            epoch_nanos: u64_at(32),
+        })
+    }
+
+    fn file_len(&self) -> usize {
+        HEADER_LEN + SLOT_LEN * self.slots as usize
+    }
+}
+
+/// Rates of the file, follow the fields of the header and are updated in place.
+#[repr(C)]
+struct Rates {
+    /// Capacity and refill tick in nanoseconds of both generations.
+    rates: [[AtomicU64; 2]; 2],
+    /// Number of reconfigurations, its lowest bit is the generation of unused slots.
+    reconfigurations: AtomicU64,
+    /// Time when a process started to reconfigure the file, `0` if none does.
+    lock: AtomicU64,
+}
+
+/// Bucket of a key. Aligned to a cache line, so updates of different buckets don't contend.
+#[repr(C, align(64))]
+struct Slot {
+    /// Hash of the key, `0` if the slot is free.
+    key: AtomicU64,
+    /// Time when the bucket becomes full in nanoseconds since the epoch of the file,
+    /// as in [`AtomicInMemoryStorage`](crate::AtomicInMemoryStorage).
+    state: AtomicU64,
+}
+
+/// A storage that stores buckets in a memory mapped file shared by processes of the host.
+///
+/// Unrelated processes open the file by name (in `/dev/shm` by default) and limit jointly,
+/// e.g. workers of a pre-fork server. The file holds a fixed number of keyed slots,
+/// every slot is updated by compare-and-swap of a single word, so a crashed process
+/// never leaves a bucket half-updated.
+///
+/// The file is initialized under a temporary name and then linked to its name, so other
+/// processes never see a partially initialized file. All processes must use the same limit
+/// and initial fill, otherwise opening fails.
+///
+/// Keys are identified by their 64-bit hashes, buckets of colliding keys are shared.
+/// When all slots are used, a new key takes over the slot of a key whose bucket is full,
+/// since such a key is no different from a new one. A request of the old key made while
+/// its slot is taken over may be counted for the new key. If no bucket is full,
+/// the key fails with [`SharedMemoryStorageError::SlotsExhausted`].
+///
+/// Time is taken from the system clock; buckets are not refilled while it goes backwards.
+///
+/// Rate and capacity are changed for all keys and processes at once with
+/// [`KeyedStorage::set_rate`] and [`KeyedStorage::set_capacity`], the file keeps them.
+/// Processes that open the file later must still use the limit it was created with.
+/// Buckets are rebuilt one by one, a bucket is counted in the old rate until it's rebuilt.
+/// Refill of the whole capacity must take less than about 292 years,
+/// otherwise the change fails with [`ConfigError::CapacityOverflow`].
+///
+/// # Example
+/// ```
+/// use tocket::{KeyedTokenBucket, SharedMemoryStorage};
+///
+/// # let dir = std::env::temp_dir();
+/// let storage = SharedMemoryStorage::builder(2, "tocket_doc_example")
+/// #   .with_dir(&dir)
+///     .build()
+///     .unwrap();
+///
+/// let tb = KeyedTokenBucket::new(storage);
+/// assert!(tb.try_acquire("alice", 2).is_ok());
+/// assert!(tb.try_acquire_one("alice").is_err());
+/// # std::fs::remove_file(tb.storage().path()).unwrap();
+/// ```
+pub struct SharedMemoryStorage {
+    mmap: MmapRaw,
+    path: PathBuf,
+    header: Header,
+    key: String,
+}
+
+impl SharedMemoryStorage {
+    /// Opens or creates a storage with default number of slots.
+    pub fn new<N>(rps_limit: u32, name: N) -> Result<Self, SharedMemoryStorageError>
+    where
+        N: AsRef<Path>,
+    {
+        Self::builder(rps_limit, name).build()
+    }
+
+    /// Creates a builder of storage. Needs for customizing of directory and number of slots.
+    pub fn builder<N>(rps_limit: u32, name: N) -> SharedMemoryStorageBuilder
+    where
+        N: AsRef<Path>,
+    {
+        SharedMemoryStorageBuilder {
+            rps_limit,
+            name: name.as_ref().to_owned(),
+            dir: PathBuf::from(DEFAULT_SHM_DIR),
+            slots: DEFAULT_SHM_SLOTS,
+            key: DEFAULT_SHM_KEY.to_owned(),
+            initial_fill: InitialFill::Full,
+        }
+    }
+
+    /// Returns path of the shared file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn rates(&self) -> &Rates {
+        // SAFETY: rates are within the header, aligned by their offset
+        // and accessed only atomically
+        unsafe { &*(self.mmap.as_ptr().add(RATES_OFFSET) as *const Rates) }
+    }
+
+    fn slots(&self) -> &[Slot] {
+        // SAFETY: the mapping is page aligned, its length is checked on opening,
+        // slots are accessed only atomically and live as long as the mapping
+        unsafe {
+            std::slice::from_raw_parts(
+                self.mmap.as_ptr().add(HEADER_LEN) as *const Slot,
+                self.header.slots as usize,
+            )
+        }
+    }
+
+    /// Finds the slot of the key, claims a free one or takes over a slot of a full bucket.
+    fn slot_index(&self, key: &str) -> Result<usize, SharedMemoryStorageError> {
+        let hash = key_hash(key);
+        let slots = self.slots();
+        let start = (hash % slots.len() as u64) as usize;
+        let probe = || (start..slots.len()).chain(0..start);
+
+        for i in probe() {
+            let slot = &slots[i];
+            match slot.key.load(Ordering::Acquire) {
+                0 => match slot
+                    .key
+                    .compare_exchange(0, hash, Ordering::AcqRel, Ordering::Acquire)
+                {
+                    Ok(_) => return Ok(i),
+                    Err(actual) if actual == hash => return Ok(i),
+                    Err(_) => continue,
+                },
+                actual if actual == hash => return Ok(i),
+                _ => continue,
+            }
+        }
+
+        for i in probe() {
+            let slot = &slots[i];
+            let old_key = slot.key.load(Ordering::Acquire);
+            let state = slot.state.load(Ordering::Acquire);
+            if !self.is_full(state) {
+                continue;
+            }
+            if slot
+                .key
+                .compare_exchange(old_key, hash, Ordering::AcqRel, Ordering::Acquire)
+                .is_ok()
+            {
+                // Unless the old key took tokens in the meantime
+                let _ =
+                    slot.state
+                        .compare_exchange(state, UNUSED, Ordering::AcqRel, Ordering::Acquire);
+                return Ok(i);
+            }
+        }
+        Err(SharedMemoryStorageError::SlotsExhausted)
+    }
+
+    /// Returns `true` if the bucket in the `state` has all tokens.
+    fn is_full(&self, state: u64) -> bool {
+        match state {
+            UNUSED => self.header.initial_tokens == self.header.cap,
+            state => split(state).1 <= self.now(),
+        }
+    }
+
+    /// Rate of the generation.
+    fn rate(&self, generation: usize) -> Rate {
+        let [cap, tick] = &self.rates().rates[generation];
+        Rate {
+            cap: cap.load(Ordering::Acquire),
+            tick: tick.load(Ordering::Acquire),
+        }
+    }
+
+    /// Generation of unused slots and its rate.
+    fn current_rate(&self) -> (usize, Rate) {
+        let reconfigurations = &self.rates().reconfigurations;
+        loop {
+            let before = reconfigurations.load(Ordering::Acquire);
+            let generation = (before & 1) as usize;
+            let rate = self.rate(generation);
+            // Unless the generation was rewritten in the meantime
+            if reconfigurations.load(Ordering::Acquire) == before {
+                return (generation, rate);
+            }
+        }
+    }
+
+    /// Tokens of an unused slot.
+    fn initial_tokens(&self, cap: u64) -> u64 {
+        if self.header.initial_tokens == self.header.cap {
+            cap
+        } else {
+            u64::from(self.header.initial_tokens).min(cap)
+        }
+    }
+
+    /// Current time in nanoseconds since the epoch of the file.
+    fn now(&self) -> u64 {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos();
+        let since_epoch = now.saturating_sub(u128::from(self.header.epoch_nanos));
+        u64::try_from(since_epoch).unwrap_or(MAX_FULL_AT)
+    }
+
+    /// Applies `f` to the time when the bucket of the slot becomes full, the current time
+    /// and the rate until the update succeeds. The state is not updated if `f` fails.
+    fn update<F>(&self, index: usize, f: F) -> Result<(), RateLimitExceededError>
+    where
+        F: Fn(u64, u64, Rate) -> Result<u64, RateLimitExceededError>,
+    {
+        let state = &self.slots()[index].state;
+        let mut current = state.load(Ordering::Acquire);
+        loop {
+            // Taken after loading the state, as in `AtomicInMemoryStorage`
+            let now = self.now();
+            let (generation, rate, full_at) = match current {
+                UNUSED => {
+                    let (generation, rate) = self.current_rate();
+                    let missing = rate.cap - self.initial_tokens(rate.cap);
+                    let full_at = now.saturating_add(missing.saturating_mul(rate.tick));
+                    (generation, rate, full_at)
+                }
+                state => {
+                    let (generation, full_at) = split(state);
+                    (generation, self.rate(generation), full_at)
+                }
+            };
+            let new = join(generation, f(full_at, now, rate)?);
+
+            if new == current {
+                return Ok(());
+            }
+            match state.compare_exchange_weak(current, new, Ordering::AcqRel, Ordering::Acquire) {
+                Ok(_) => return Ok(()),
+                Err(actual) => current = actual,
+            }
+        }
+    }
+
+    fn try_acquire_slot(
+        &self,
+        index: usize,
+        alg: TokenBucketAlgorithm,
+        permits: u64,
+    ) -> Result<(), SharedMemoryStorageError> {
+        self.update(index, |full_at, now, rate| {
+            check_capacity(alg, permits, rate.cap)?;
+            take(full_at, now, rate.cap, rate.tick, alg, permits)
+                .ok_or(RateLimitExceededError::Exhausted)
+        })?;
+        Ok(())
+    }
+
+    fn release_slot(&self, index: usize, permits: u64) -> Result<(), RateLimitExceededError> {
+        self.update(index, |full_at, now, rate| {
+            Ok(release(full_at, now, rate.tick, permits))
+        })
+    }
+
+    fn charge_slot(&self, index: usize, cost: u64) -> Result<(), RateLimitExceededError> {
+        self.update(index, |full_at, now, rate| {
+            Ok(charge(full_at, now, rate.tick, cost))
+        })
+    }
+
+    /// Writes the rate returned by `f` to the other generation, switches unused slots to it
+    /// and moves states of all slots to it, adjusting tokens by `policy`.
+    fn reconfigure<F>(&self, f: F, policy: ResizePolicy) -> Result<(), ConfigError>
+    where
+        F: Fn(Rate) -> Result<Rate, ConfigError>,
+    {
+        let locked_at = self.lock();
+        let res = self.reconfigure_locked(f, policy);
+        // Unless the lock is taken over as stale
+        let _ =
+            self.rates()
+                .lock
+                .compare_exchange(locked_at, 0, Ordering::AcqRel, Ordering::Acquire);
+        res
+    }
+
+    fn reconfigure_locked<F>(&self, f: F, policy: ResizePolicy) -> Result<(), ConfigError>
+    where
+        F: Fn(Rate) -> Result<Rate, ConfigError>,
+    {
+        let rates = self.rates();
+        let reconfigurations = rates.reconfigurations.load(Ordering::Acquire);
+        let current = (reconfigurations & 1) as usize;
+        let next = 1 - current;
+        // Left behind by a process that crashed while reconfiguring
+        self.migrate(next, current, ResizePolicy::Clamp);
+
+        let to = f(self.rate(current))?;
+        let [cap, tick] = &rates.rates[next];
+        cap.store(to.cap, Ordering::Release);
+        tick.store(to.tick, Ordering::Release);
+        rates
+            .reconfigurations
+            .store(reconfigurations + 1, Ordering::Release);
+        self.migrate(current, next, policy);
+        Ok(())
+    }
+
+    /// Takes the lock of reconfiguration, returns the time it's taken at.
+    fn lock(&self) -> u64 {
+        let lock = &self.rates().lock;
+        loop {
+            let now = self.now().max(1);
+            let locked_at = lock.load(Ordering::Acquire);
+            let free = locked_at == 0 || now.saturating_sub(locked_at) > STALE_LOCK_NANOS;
+            if free
+                && lock
+                    .compare_exchange(locked_at, now, Ordering::AcqRel, Ordering::Acquire)
+                    .is_ok()
+            {
+                return now;
+            }
+            std::thread::yield_now();
+        }
+    }
+
+    /// Moves states of all slots counted in generation `from` to generation `to`.
+    fn migrate(&self, from: usize, to: usize, policy: ResizePolicy) {
+        let (from_rate, to_rate) = (self.rate(from), self.rate(to));
+        for slot in self.slots() {
+            let mut current = slot.state.load(Ordering::Acquire);
+            while current != UNUSED && split(current).0 == from {
+                let full_at = reconfigure(split(current).1, self.now(), from_rate, to_rate, policy);
+                match slot.state.compare_exchange_weak(
+                    current,
+                    join(to, full_at),
+                    Ordering::AcqRel,
+                    Ordering::Acquire,
+                ) {
+                    Ok(_) => break,
+                    Err(actual) => current = actual,
+                }
+            }
+        }
+    }
+}
+
+/// FNV-1a, stable across processes and builds unlike hashers of std.
+fn key_hash(key: &str) -> u64 {
+    let hash = key.bytes().fold(0xcbf29ce484222325u64, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x100000001b3)
+    });
+    // 0 marks free slots
+    hash.max(1)
+}
+
+pub struct SharedMemoryStorageBuilder {
+    rps_limit: u32,
+    name: PathBuf,
+    dir: PathBuf,
+    slots: u32,
+    key: String,
+    initial_fill: InitialFill,
+}
+
+impl SharedMemoryStorageBuilder {
+    /// Customize directory of the file, `/dev/shm` by default.
+    pub fn with_dir<D>(mut self, dir: D) -> Self
+    where
+        D: AsRef<Path>,
+    {
+        self.dir = dir.as_ref().to_owned();
+        self
+    }
+
+    /// Customize number of keyed slots. Used only if the file doesn't exist yet.
+    pub fn with_slots(mut self, slots: u32) -> Self {
+        self.slots = slots.max(1);
+        self
+    }
+
+    /// Customize key of the bucket used by [`Storage`] implementation.
+    pub fn with_key<K>(mut self, key: K) -> Self
+    where
+        K: Into<String>,
+    {
+        self.key = key.into();
+        self
+    }
+
+    /// Customize tokens of buckets of new keys, full by default.
+    pub fn with_initial_fill(mut self, initial_fill: InitialFill) -> Self {
+        self.initial_fill = initial_fill;
+        self
+    }
+
+    /// Creates the file if it doesn't exist and maps it.
+    pub fn build(self) -> Result<SharedMemoryStorage, SharedMemoryStorageError> {
+        let path = self.dir.join(&self.name);
+        let refill_tick_nanos = tick_nanos(self.rps_limit)?;
+        // Not more than the limit, so it fits
+        let initial_tokens =
+            u32::try_from(self.initial_fill.tokens(self.rps_limit.into())).unwrap_or(u32::MAX);
+
+        if !path.exists() {
+            let header = Header {
+                slots: self.slots,
+                cap: self.rps_limit,
+                initial_tokens,
+                refill_tick_nanos,
+                epoch_nanos: SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_nanos() as u64,
+            };
+            create_file(&path, &header)?;
+        }
+
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&path)?;
+        let mmap = MmapRaw::map_raw(&file)?;
+        // SAFETY: the fields of the header are immutable after the file is created
+        let header = Header::decode(unsafe {
+            std::slice::from_raw_parts(mmap.as_ptr(), mmap.len().min(HEADER_LEN))
+        })?;
+        if mmap.len() < header.file_len() {
+            return Err(SharedMemoryStorageError::Incompatible(format!(
+                "file is truncated to {} bytes",
+                mmap.len()
+            )));
+        }
+        if header.cap != self.rps_limit || header.refill_tick_nanos != refill_tick_nanos {
+            return Err(SharedMemoryStorageError::Incompatible(format!(
+                "file is created for {} rps",
+                header.cap
+            )));
+        }
+        if header.initial_tokens != initial_tokens {
+            return Err(SharedMemoryStorageError::Incompatible(format!(
+                "file is created with {} initial tokens",
+                header.initial_tokens
+            )));
+        }
+
+        let storage = SharedMemoryStorage {
+            mmap,
+            path,
+            header,
+            key: self.key,
+        };
+        storage.slot_index(&storage.key)?;
+        Ok(storage)
+    }
+}
+
+/// Writes the file under a temporary name and links it to `path` if it doesn't exist yet.
+fn create_file(path: &Path, header: &Header) -> Result<(), SharedMemoryStorageError> {
+    let mut content = Vec::with_capacity(header.file_len());
+    content.extend_from_slice(&header.encode());
+    for _ in 0..header.slots {
+        let mut slot = [0u8; SLOT_LEN];
+        slot[8..16].copy_from_slice(&UNUSED.to_ne_bytes());
+        content.extend_from_slice(&slot);
+    }
+
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(format!(
+        ".{}.{}.tmp",
+        std::process::id(),
+        header.epoch_nanos
+    ));
+    std::fs::write(&tmp, &content)?;
+
+    let res = match std::fs::hard_link(&tmp, path) {
+        // Created by another process in the meantime
+        Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => Ok(()),
+        res => res,
+    };
+    let _ = std::fs::remove_file(&tmp);
+    Ok(res?)
+}
+
+impl Storage for SharedMemoryStorage {
+    type Error = SharedMemoryStorageError;
+
+    fn try_acquire(&self, alg: TokenBucketAlgorithm, permits: u64) -> Result<(), Self::Error> {
+        KeyedStorage::try_acquire(self, &self.key, alg, permits)
+    }
+
+    fn release(&self, permits: u64) -> Result<(), Self::Error> {
+        KeyedStorage::release(self, &self.key, permits)
+    }
+
+    fn charge(&self, cost: u64) -> Result<(), Self::Error> {
+        KeyedStorage::charge(self, &self.key, cost)
+    }
+
+    /// Changes the rate of buckets of all keys, see [`KeyedStorage::set_rate`].
+    fn set_rate(&self, rps_limit: u32) -> Result<(), ReconfigureError<Self::Error>> {
+        KeyedStorage::set_rate(self, rps_limit)
+    }
+
+    /// Changes the capacity of buckets of all keys, see [`KeyedStorage::set_capacity`].
+    fn set_capacity(
+        &self,
+        cap: u64,
+        policy: ResizePolicy,
+    ) -> Result<(), ReconfigureError<Self::Error>> {
+        KeyedStorage::set_capacity(self, cap, policy)
+    }
+}
+
+impl KeyedStorage for SharedMemoryStorage {
+    type Key = str;
+    type Error = SharedMemoryStorageError;
+
+    fn try_acquire(
+        &self,
+        key: &str,
+        alg: TokenBucketAlgorithm,
+        permits: u64,
+    ) -> Result<(), Self::Error> {
+        let index = self.slot_index(key)?;
+        self.try_acquire_slot(index, alg, permits)
+    }
+
+    fn release(&self, key: &str, permits: u64) -> Result<(), Self::Error> {
+        let index = self.slot_index(key)?;
+        self.release_slot(index, permits)?;
+        Ok(())
+    }
+
+    fn charge(&self, key: &str, cost: u64) -> Result<(), Self::Error> {
+        let index = self.slot_index(key)?;
+        self.charge_slot(index, cost)?;
+        Ok(())
+    }
+
+    /// Changes the rate of buckets of all keys in the file, for all processes.
+    fn set_rate(&self, rps_limit: u32) -> Result<(), ReconfigureError<Self::Error>> {
+        let tick = tick_nanos(rps_limit)?;
+        self.reconfigure(
+            |rate| validate_rate(Rate { tick, ..rate }),
+            ResizePolicy::Clamp,
+        )?;
+        Ok(())
+    }
+
+    /// Changes the capacity of buckets of all keys in the file, for all processes.
+    fn set_capacity(
+        &self,
+        cap: u64,
+        policy: ResizePolicy,
+    ) -> Result<(), ReconfigureError<Self::Error>> {
+        let cap = validate_capacity(cap)?;
+        self.reconfigure(|rate| validate_rate(Rate { cap, ..rate }), policy)?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum SharedMemoryStorageError {
+    #[error(transparent)]
+    IoError(#[from] std::io::Error),
+    #[error(transparent)]
+    RateLimitExceededError(#[from] RateLimitExceededError),
+    #[error(transparent)]
+    ConfigError(#[from] ConfigError),
+    #[error("incompatible shared memory file: {0}")]
+    Incompatible(String),
+    #[error("all slots of shared memory file are used")]
+    SlotsExhausted,
+}
+
+impl StorageError for SharedMemoryStorageError {
+    fn is_rate_limit_exceeded(&self) -> bool {
+        matches!(self, SharedMemoryStorageError::RateLimitExceededError(_))
+    }
+
+    fn is_capacity_exceeded(&self) -> bool {
+        matches!(self, SharedMemoryStorageError::RateLimitExceededError(err) if err.is_capacity_exceeded())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{KeyedTokenBucket, TokenBucket};
+
+    use std::time::Duration;
+    use uuid::Uuid;
+
+    struct TempName(String);
+
+    impl TempName {
+        fn new() -> Self {
+            Self(format!("tocket_{}", Uuid::new_v4()))
+        }
+
+        fn builder(&self, rps_limit: u32) -> SharedMemoryStorageBuilder {
+            SharedMemoryStorage::builder(rps_limit, &self.0).with_dir(std::env::temp_dir())
+        }
+    }
+
+    impl Drop for TempName {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(std::env::temp_dir().join(&self.0));
+        }
+    }
+
+    #[test]
+    fn try_acquire() {
+        let name = TempName::new();
+        let tb = TokenBucket::new(name.builder(2).build().unwrap());
+        assert!(tb.try_acquire(2).is_ok());
+        assert!(tb.try_acquire_one().is_err());
+
+        std::thread::sleep(Duration::from_secs(1));
+        assert!(tb.try_acquire(2).is_ok());
+        assert!(tb.try_acquire_one().is_err());
+    }
+
+    #[test]
+    fn shared_keyed_slots() {
+        let name = TempName::new();
+        // Mappings of "different processes", created concurrently
+        let storages: Vec<_> = std::thread::scope(|s| {
+            let handles: Vec<_> = (0..4)
+                .map(|_| s.spawn(|| name.builder(10).with_slots(8).build().unwrap()))
+                .collect();
+            handles.into_iter().map(|h| h.join().unwrap()).collect()
+        });
+        let (first, second) = (&storages[0], &storages[1]);
+        let alg = TokenBucketAlgorithm::new(crate::Mode::N);
+
+        assert!(KeyedStorage::try_acquire(first, "a", alg, 6).is_ok());
+        assert!(KeyedStorage::try_acquire(second, "a", alg, 5).is_err());
+        assert!(KeyedStorage::try_acquire(second, "a", alg, 4).is_ok());
+        assert!(KeyedStorage::try_acquire(second, "b", alg, 10).is_ok());
+
+        // The default key has its own slot
+        assert!(Storage::try_acquire(first, alg, 10).is_ok());
+
+        // 3 keys of 8 slots are used
+        let tb = KeyedTokenBucket::new(name.builder(10).build().unwrap());
+        for i in 0..5 {
+            assert!(tb.try_acquire_one(&format!("key-{}", i)).is_ok());
+        }
+        assert!(matches!(
+            tb.try_acquire_one("one more"),
+            Err(SharedMemoryStorageError::SlotsExhausted)
+        ));
+    }
+
+    #[test]
+    fn reclaim_full_slots() {
+        let name = TempName::new();
+        let tb = KeyedTokenBucket::new(name.builder(10).with_slots(4).build().unwrap());
+        // The default key is full, so its slot is taken over first
+        for i in 0..4 {
+            assert!(tb.try_acquire(&format!("key-{}", i), 5).is_ok());
+        }
+        assert!(matches!(
+            tb.try_acquire_one("one more"),
+            Err(SharedMemoryStorageError::SlotsExhausted)
+        ));
+
+        // Buckets of all keys are full again
+        std::thread::sleep(Duration::from_millis(600));
+        assert!(tb.try_acquire("one more", 10).is_ok());
+        assert!(tb.try_acquire_one("one more").is_err());
+    }
+
+    #[test]
+    fn idle_past_wrap() {
+        let name = TempName::new();
+        let mut storage = name.builder(1_000_000_000).build().unwrap();
+        let alg = TokenBucketAlgorithm::new(crate::Mode::N);
+        assert!(KeyedStorage::try_acquire(&storage, "a", alg, 1_000_000_000).is_ok());
+        assert!(KeyedStorage::try_acquire(&storage, "a", alg, 1_000_000).is_err());
+
+        // Idle for 5 seconds, ticks in 32 bits would have wrapped around
+        storage.header.epoch_nanos -= 5_000_000_000;
+        assert!(KeyedStorage::try_acquire(&storage, "a", alg, 1_000_000_000).is_ok());
+    }
+
+    #[test]
+    fn reconfigure() {
+        let name = TempName::new();
+        let first = KeyedTokenBucket::new(name.builder(10).build().unwrap());
+        let second = KeyedTokenBucket::new(name.builder(10).build().unwrap());
+        assert!(first.try_acquire("a", 10).is_ok());
+        assert!(second.try_acquire("b", 5).is_ok());
+
+        // Applied to buckets of all keys in all mappings
+        assert!(first.set_capacity(20, ResizePolicy::Scale).is_ok());
+        assert!(second.try_acquire("b", 11).is_err());
+        assert!(second.try_acquire("b", 10).is_ok());
+        assert!(second.try_acquire("c", 20).is_ok());
+
+        assert!(second.set_rate(1000).is_ok());
+        std::thread::sleep(Duration::from_millis(30));
+        assert!(first.try_acquire("a", 20).is_ok());
+        assert!(matches!(
+            first.try_acquire("a", 21),
+            Err(SharedMemoryStorageError::RateLimitExceededError(
+                RateLimitExceededError::ExceedsCapacity { cap: 20, .. }
+            ))
+        ));
+
+        // Kept by the file
+        let third = KeyedTokenBucket::new(name.builder(10).build().unwrap());
+        assert!(third.try_acquire("d", 20).is_ok());
+        assert!(matches!(
+            third.set_rate(0),
+            Err(ReconfigureError::Config(ConfigError::ZeroRate))
+        ));
+        assert!(matches!(
+            third.set_capacity(u64::MAX / 1000, ResizePolicy::Clamp),
+            Err(ReconfigureError::Config(ConfigError::CapacityOverflow))
+        ));
+        assert_eq!(third.storage().rates().lock.load(Ordering::Acquire), 0);
+    }
+
+    #[test]
+    fn stale_lock() {
+        let name = TempName::new();
+        let mut storage = name.builder(10).build().unwrap();
+        // A process crashed while reconfiguring
+        storage
+            .rates()
+            .lock
+            .store(storage.now().max(1), Ordering::Release);
+
+        storage.header.epoch_nanos -= 2 * STALE_LOCK_NANOS;
+        assert!(KeyedStorage::set_capacity(&storage, 20, ResizePolicy::Clamp).is_ok());
+        assert_eq!(storage.rates().lock.load(Ordering::Acquire), 0);
+    }
+
+    #[test]
+    fn zero_rate() {
+        let name = TempName::new();
+        assert!(matches!(
+            name.builder(0).build(),
+            Err(SharedMemoryStorageError::ConfigError(ConfigError::ZeroRate))
+        ));
+    }
+
+    #[test]
+    fn incompatible_limit() {
+        let name = TempName::new();
+        let _storage = name.builder(10).build().unwrap();
+        assert!(matches!(
+            name.builder(20).build(),
+            Err(SharedMemoryStorageError::Incompatible(_))
+        ));
+        assert!(matches!(
+            name.builder(10)
+                .with_initial_fill(InitialFill::Empty)
+                .build(),
+            Err(SharedMemoryStorageError::Incompatible(_))
+        ));
+    }
+
+    #[test]
+    fn initial_fill() {
+        let name = TempName::new();
+        let storage = name
+            .builder(10)
+            .with_initial_fill(InitialFill::Tokens(3))
+            .build()
+            .unwrap();
+        let tb = KeyedTokenBucket::new(storage);
+        assert!(tb.try_acquire("a", 4).is_err());
+        assert!(tb.try_acquire("a", 3).is_ok());
+        assert!(tb.try_acquire("b", 3).is_ok());
+    }
+}
@@ -0,0 +1,535 @@
+use crate::{
+    refill_tick, validate_capacity, BucketConfig, ConfigError, InitialFill, KeyedStorage, Mode,
+    RateLimitExceededError, ReconfigureError, ResizePolicy, State, Storage, StorageError,
+    TokenBucketAlgorithm,
+};
+
+use rusqlite::{Connection, OptionalExtension, TransactionBehavior};
+use std::path::Path;
+use std::time::Duration;
+
+/// Default key of the bucket row.
+pub const DEFAULT_KEY: &str = "tocket";
+/// Default name of the table with bucket rows.
+pub const DEFAULT_TABLE: &str = "tocket_buckets";
+/// Default time to wait for other connections (e.g. other processes) to release the database.
+pub const DEFAULT_BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A storage that stores state in rows of SQLite table.
+///
+/// Every bucket is a row of the table. [`Storage`] uses the row of
+/// [`with_key`](SqliteStorageBuilder::with_key), [`KeyedStorage`] a row per key,
+/// so several buckets (and several processes) may share a database file.
+/// State is loaded, refilled, debited and saved inside a single `IMMEDIATE` transaction,
+/// the state survives restarts of the process.
+///
+/// The storage may be used as a reference for other SQL backends:
+/// ```sql
+/// BEGIN IMMEDIATE;
+/// SELECT available_tokens, last_refill FROM tocket_buckets WHERE key = ?;
+/// -- refill and debit the state with `TokenBucketAlgorithm::try_acquire`
+/// INSERT INTO tocket_buckets (key, available_tokens, last_refill) VALUES (?, ?, ?)
+///     ON CONFLICT (key) DO UPDATE SET available_tokens = excluded.available_tokens,
+///                                     last_refill = excluded.last_refill;
+/// COMMIT;
+/// ```
+///
+/// Rate and capacity are changed for all rows of the table with [`KeyedStorage::set_rate`]
+/// and [`KeyedStorage::set_capacity`], [`Storage`] methods do the same.
+///
+/// # Example
+/// ```
+/// use tocket::{SqliteStorage, TokenBucket};
+///
+/// let path = std::env::temp_dir().join("tocket_doc_example.db");
+/// # let _ = std::fs::remove_file(&path);
+/// let storage = SqliteStorage::builder(2, &path)
+///     .with_key("api")
+///     .build()
+///     .unwrap();
+///
+/// let tb = TokenBucket::new(storage);
+/// assert!(tb.try_acquire(2).is_ok());
+/// assert!(tb.try_acquire_one().is_err());
+/// # let _ = std::fs::remove_file(&path);
+/// ```
+pub struct SqliteStorage {
+    conn: parking_lot::Mutex<Connection>,
+    key: String,
+    select_query: String,
+    select_all_query: String,
+    upsert_query: String,
+    config: parking_lot::RwLock<BucketConfig>,
+}
+
+impl SqliteStorage {
+    /// Creates a storage with default key of the bucket row.
+    pub fn new<P>(rps_limit: u32, path: P) -> Result<Self, SqliteStorageError>
+    where
+        P: AsRef<Path>,
+    {
+        Self::builder(rps_limit, path).build()
+    }
+
+    /// Creates a builder of storage. Needs for customizing of key and table.
+    pub fn builder<P>(rps_limit: u32, path: P) -> SqliteStorageBuilder
+    where
+        P: AsRef<Path>,
+    {
+        SqliteStorageBuilder {
+            rps_limit,
+            path: path.as_ref().to_owned(),
+            key: DEFAULT_KEY.to_owned(),
+            table: DEFAULT_TABLE.to_owned(),
+            busy_timeout: DEFAULT_BUSY_TIMEOUT,
+            initial_fill: InitialFill::Full,
+        }
+    }
+
+    /// Runs `f` with the state of the bucket of `key` inside a transaction.
+    /// The state is saved only if `f` succeeds.
+    fn with_state<F>(
+        &self,
+        key: &str,
+        config: &BucketConfig,
+        f: F,
+    ) -> Result<(), SqliteStorageError>
+    where
+        F: FnOnce(&mut State) -> Result<(), SqliteStorageError>,
+    {
+        let mut conn = self.conn.lock();
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+
+        let row: Option<(i64, i64)> = tx
+            .prepare_cached(&self.select_query)?
+            .query_row([key], |row| Ok((row.get(0)?, row.get(1)?)))
+            .optional()?;
+        let mut state = match row {
+            Some(row) => decode(config, row)?,
+            None => config.new_state(),
+        };
+
+        f(&mut state)?;
+
+        let (available_tokens, last_refill) = encode(&state)?;
+        tx.prepare_cached(&self.upsert_query)?
+            .execute((key, available_tokens, last_refill))?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Applies `f` to states of all rows of the table inside a transaction.
+    fn with_all_states<F>(&self, config: &BucketConfig, f: F) -> Result<(), SqliteStorageError>
+    where
+        F: Fn(&mut State),
+    {
+        let mut conn = self.conn.lock();
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+
+        let rows = tx
+            .prepare_cached(&self.select_all_query)?
+            .query_map([], |row| {
+                Ok((row.get::<_, String>(0)?, (row.get(1)?, row.get(2)?)))
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        for (key, row) in rows {
+            let mut state = decode(config, row)?;
+            f(&mut state);
+            let (available_tokens, last_refill) = encode(&state)?;
+            tx.prepare_cached(&self.upsert_query)?
+                .execute((key, available_tokens, last_refill))?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+}
+
+/// State of a stored row of the balance and the last refill in Unix nanoseconds.
+fn decode(
+    config: &BucketConfig,
+    (available_tokens, last_refill): (i64, i64),
+) -> Result<State, SqliteStorageError> {
+    let last_refill = time::OffsetDateTime::from_unix_timestamp_nanos(last_refill.into())
+        .map_err(|_| SqliteStorageError::InvalidTimestamp(last_refill))?;
+    Ok(config.state(available_tokens, last_refill))
+}
+
+/// Row of a state, see [`decode`].
+fn encode(state: &State) -> Result<(i64, i64), SqliteStorageError> {
+    let last_refill = i64::try_from(state.last_refill.unix_timestamp_nanos())
+        .map_err(|_| SqliteStorageError::InvalidTimestamp(i64::MAX))?;
+    Ok((state.available_tokens, last_refill))
+}
+
+pub struct SqliteStorageBuilder {
+    rps_limit: u32,
+    path: std::path::PathBuf,
+    key: String,
+    table: String,
+    busy_timeout: Duration,
+    initial_fill: InitialFill,
+}
+
+impl SqliteStorageBuilder {
+    /// Customize key of the bucket row.
+    pub fn with_key<K>(mut self, key: K) -> Self
+    where
+        K: Into<String>,
+    {
+        self.key = key.into();
+        self
+    }
+
+    /// Customize name of the table. The table is created if it doesn't exist.
+    pub fn with_table<T>(mut self, table: T) -> Self
+    where
+        T: Into<String>,
+    {
+        self.table = table.into();
+        self
+    }
+
+    /// Customize time to wait for the database locked by other connections.
+    pub fn with_busy_timeout(mut self, timeout: Duration) -> Self {
+        self.busy_timeout = timeout;
+        self
+    }
+
+    /// Customize tokens of the bucket that row doesn't exist yet, full by default.
+    pub fn with_initial_fill(mut self, initial_fill: InitialFill) -> Self {
+        self.initial_fill = initial_fill;
+        self
+    }
+
+    /// Opens the database and creates the table.
+    pub fn build(self) -> Result<SqliteStorage, SqliteStorageError> {
+        let config = BucketConfig::try_new(self.rps_limit, self.rps_limit.into())?
+            .with_initial_fill(self.initial_fill);
+        let conn = Connection::open(&self.path)?;
+        conn.busy_timeout(self.busy_timeout)?;
+        // WAL doesn't block readers of other processes while a bucket is updated
+        conn.pragma_update(None, "journal_mode", "WAL")?;
+
+        let table = quote_identifier(&self.table);
+        conn.execute(
+            &format!(
+                "CREATE TABLE IF NOT EXISTS {table} (
+                    key TEXT PRIMARY KEY NOT NULL,
+                    available_tokens INTEGER NOT NULL,
+                    last_refill INTEGER NOT NULL
+                )"
+            ),
+            [],
+        )?;
+
+        Ok(SqliteStorage {
+            conn: parking_lot::Mutex::new(conn),
+            key: self.key,
+            select_query: format!(
+                "SELECT available_tokens, last_refill FROM {table} WHERE key = ?1"
+            ),
+            select_all_query: format!("SELECT key, available_tokens, last_refill FROM {table}"),
+            upsert_query: format!(
+                "INSERT INTO {table} (key, available_tokens, last_refill) VALUES (?1, ?2, ?3)
+                    ON CONFLICT (key) DO UPDATE SET available_tokens = excluded.available_tokens,
+                                                    last_refill = excluded.last_refill"
+            ),
+            config: parking_lot::RwLock::new(config),
+        })
+    }
+}
+
+fn quote_identifier(ident: &str) -> String {
+    format!("\"{}\"", ident.replace('"', "\"\""))
+}
+
+impl Storage for SqliteStorage {
+    type Error = SqliteStorageError;
+
+    fn try_acquire(&self, alg: TokenBucketAlgorithm, permits: u64) -> Result<(), Self::Error> {
+        KeyedStorage::try_acquire(self, &self.key, alg, permits)
+    }
+
+    fn release(&self, permits: u64) -> Result<(), Self::Error> {
+        KeyedStorage::release(self, &self.key, permits)
+    }
+
+    fn charge(&self, cost: u64) -> Result<(), Self::Error> {
+        KeyedStorage::charge(self, &self.key, cost)
+    }
+
+    /// Changes the rate of all rows of the table, see [`KeyedStorage::set_rate`].
+    fn set_rate(&self, rps_limit: u32) -> Result<(), ReconfigureError<Self::Error>> {
+        KeyedStorage::set_rate(self, rps_limit)
+    }
+
+    /// Changes the capacity of all rows of the table, see [`KeyedStorage::set_capacity`].
+    fn set_capacity(
+        &self,
+        cap: u64,
+        policy: ResizePolicy,
+    ) -> Result<(), ReconfigureError<Self::Error>> {
+        KeyedStorage::set_capacity(self, cap, policy)
+    }
+}
+
+impl KeyedStorage for SqliteStorage {
+    type Key = str;
+    type Error = SqliteStorageError;
+
+    fn try_acquire(
+        &self,
+        key: &str,
+        alg: TokenBucketAlgorithm,
+        permits: u64,
+    ) -> Result<(), Self::Error> {
+        self.with_state(key, &self.config.read(), |state| {
+            Ok(alg.try_acquire(state, permits)?)
+        })
+    }
+
+    fn release(&self, key: &str, permits: u64) -> Result<(), Self::Error> {
+        self.with_state(key, &self.config.read(), |state| {
+            state.release(permits);
+            Ok(())
+        })
+    }
+
+    fn charge(&self, key: &str, cost: u64) -> Result<(), Self::Error> {
+        self.with_state(key, &self.config.read(), |state| {
+            TokenBucketAlgorithm::new(Mode::N).charge(state, cost);
+            Ok(())
+        })
+    }
+
+    /// Changes the rate used by this storage and refills all stored rows with the old one.
+    /// Other storages sharing the table should be reconfigured too.
+    fn set_rate(&self, rps_limit: u32) -> Result<(), ReconfigureError<Self::Error>> {
+        let refill_tick = refill_tick(rps_limit)?;
+        let mut config = self.config.write();
+        self.with_all_states(&config, |state| state.set_rate(refill_tick))
+            .map_err(ReconfigureError::Storage)?;
+        config.refill_tick = refill_tick;
+        Ok(())
+    }
+
+    /// Changes the capacity used by this storage and adjusts all stored tokens.
+    /// Other storages sharing the table should be reconfigured too,
+    /// with [`ResizePolicy::Clamp`] if the tokens are already scaled.
+    fn set_capacity(
+        &self,
+        cap: u64,
+        policy: ResizePolicy,
+    ) -> Result<(), ReconfigureError<Self::Error>> {
+        let cap = validate_capacity(cap)?;
+        let mut config = self.config.write();
+        self.with_all_states(&config, |state| state.set_capacity(cap, policy))
+            .map_err(ReconfigureError::Storage)?;
+        config.cap = cap;
+        Ok(())
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum SqliteStorageError {
+    #[error(transparent)]
+    SqliteError(#[from] rusqlite::Error),
+    #[error(transparent)]
+    RateLimitExceededError(#[from] RateLimitExceededError),
+    #[error(transparent)]
+    ConfigError(#[from] ConfigError),
+    #[error("timestamp {0} is out of range")]
+    InvalidTimestamp(i64),
+}
+
+impl StorageError for SqliteStorageError {
+    fn is_rate_limit_exceeded(&self) -> bool {
+        matches!(self, SqliteStorageError::RateLimitExceededError(_))
+    }
+
+    fn is_capacity_exceeded(&self) -> bool {
+        matches!(self, SqliteStorageError::RateLimitExceededError(err) if err.is_capacity_exceeded())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{KeyedTokenBucket, TokenBucket};
+
+    use uuid::Uuid;
+
+    struct TempDb(std::path::PathBuf);
+
+    impl TempDb {
+        fn new() -> Self {
+            Self(std::env::temp_dir().join(format!("tocket_{}.db", Uuid::new_v4())))
+        }
+    }
+
+    impl Drop for TempDb {
+        fn drop(&mut self) {
+            for suffix in ["", "-wal", "-shm"] {
+                let mut path = self.0.clone().into_os_string();
+                path.push(suffix);
+                let _ = std::fs::remove_file(path);
+            }
+        }
+    }
+
+    #[test]
+    fn try_acquire() {
+        let db = TempDb::new();
+        let tb = TokenBucket::new(SqliteStorage::new(2, &db.0).unwrap());
+
+        assert!(tb.try_acquire(2).is_ok());
+        assert!(tb.try_acquire_one().is_err());
+
+        std::thread::sleep(Duration::from_secs(1));
+        assert!(tb.try_acquire(2).is_ok());
+        assert!(tb.try_acquire_one().is_err());
+    }
+
+    #[test]
+    fn keyed_rows_survive_restart() {
+        let db = TempDb::new();
+        let storage = |key: &str| {
+            SqliteStorage::builder(10, &db.0)
+                .with_key(key)
+                .with_table("buckets")
+                .build()
+                .unwrap()
+        };
+
+        let a = TokenBucket::new(storage("a"));
+        assert!(a.try_acquire(10).is_ok());
+        assert!(a.try_acquire_one().is_err());
+        drop(a);
+
+        // Other rows are independent
+        let b = TokenBucket::new(storage("b"));
+        assert!(b.try_acquire(10).is_ok());
+
+        // Reopened bucket keeps its state
+        let a = TokenBucket::new(storage("a"));
+        assert!(a.try_acquire(5).is_err());
+    }
+
+    #[test]
+    fn keyed_rows() {
+        let db = TempDb::new();
+        let tb = KeyedTokenBucket::new(SqliteStorage::new(10, &db.0).unwrap());
+        assert!(tb.try_acquire("alice", 10).is_ok());
+        assert!(tb.try_acquire_one("alice").is_err());
+        assert!(tb.try_acquire_one("bob").is_ok());
+        drop(tb);
+
+        // Rows of keys survive restart, the default row is just another key
+        let storage = SqliteStorage::new(10, &db.0).unwrap();
+        let alg = TokenBucketAlgorithm::new(crate::Mode::N);
+        assert!(Storage::try_acquire(&storage, alg, 10).is_ok());
+        let tb = KeyedTokenBucket::new(storage);
+        assert!(tb.try_acquire("alice", 5).is_err());
+        assert!(tb.try_acquire_one(DEFAULT_KEY).is_err());
+
+        // All stored rows are reconfigured
+        assert!(tb.set_capacity(3, ResizePolicy::Clamp).is_ok());
+        assert!(tb.try_acquire("bob", 3).is_ok());
+        assert!(tb.try_acquire_one("bob").is_err());
+        assert!(tb.try_acquire("carol", 4).is_err());
+        assert!(tb.try_acquire("carol", 3).is_ok());
+    }
+
+    #[test]
+    fn shared_between_connections() {
+        let db = TempDb::new();
+        let storages: Vec<_> = (0..4)
+            .map(|_| SqliteStorage::new(100, &db.0).unwrap())
+            .collect();
+
+        let acquired: u32 = std::thread::scope(|s| {
+            let handles: Vec<_> = storages
+                .iter()
+                .map(|storage| {
+                    s.spawn(move || {
+                        (0..50)
+                            .filter(|_| {
+                                Storage::try_acquire(
+                                    storage,
+                                    TokenBucketAlgorithm::new(crate::Mode::N),
+                                    1,
+                                )
+                                .is_ok()
+                            })
+                            .count() as u32
+                    })
+                })
+                .collect();
+            handles.into_iter().map(|h| h.join().unwrap()).sum()
+        });
+
+        // 100 tokens and a few refilled during the test
+        assert!(acquired >= 100);
+        assert!(acquired < 120);
+    }
+
+    #[test]
+    fn initial_fill() {
+        let db = TempDb::new();
+        let storage = |key: &str| {
+            SqliteStorage::builder(10, &db.0)
+                .with_key(key)
+                .with_initial_fill(InitialFill::Tokens(4))
+                .build()
+                .unwrap()
+        };
+
+        let a = TokenBucket::new(storage("a"));
+        assert!(a.try_acquire(5).is_err());
+        assert!(a.try_acquire(4).is_ok());
+        let b = TokenBucket::new(storage("b"));
+        assert!(b.try_acquire(4).is_ok());
+    }
+
+    #[test]
+    fn reconfigure() {
+        let db = TempDb::new();
+        let tb = TokenBucket::new(SqliteStorage::new(10, &db.0).unwrap());
+        assert!(tb.try_acquire(6).is_ok());
+        assert!(tb.set_capacity(2, ResizePolicy::Clamp).is_ok());
+        assert!(tb.set_rate(1).is_ok());
+        drop(tb);
+
+        // The clamped tokens are stored
+        let storage = SqliteStorage::new(2, &db.0).unwrap();
+        let alg = TokenBucketAlgorithm::new(crate::Mode::N);
+        assert!(Storage::try_acquire(&storage, alg, 2).is_ok());
+        assert!(Storage::try_acquire(&storage, alg, 1).is_err());
+
+        let tb = TokenBucket::new(storage);
+        assert!(matches!(
+            tb.set_rate(0),
+            Err(ReconfigureError::Config(ConfigError::ZeroRate))
+        ));
+        assert!(matches!(
+            tb.set_capacity(0, ResizePolicy::Clamp),
+            Err(ReconfigureError::Config(ConfigError::ZeroCapacity))
+        ));
+        assert!(matches!(
+            SqliteStorage::new(0, &db.0),
+            Err(SqliteStorageError::ConfigError(ConfigError::ZeroRate))
+        ));
+    }
+
+    #[test]
+    fn charge() {
+        let db = TempDb::new();
+        let tb = TokenBucket::new(SqliteStorage::new(10, &db.0).unwrap());
+        assert!(tb.charge(25).is_ok());
+
+        // The debt is stored, so another connection is in debt too
+        let tb = TokenBucket::new(SqliteStorage::new(10, &db.0).unwrap());
+        assert!(tb.try_acquire_overdraft(1).is_err());
+        assert!(tb.try_acquire_n_or_all(1).is_ok());
+    }
+}
@@ -0,0 +1,188 @@
+use crate::{
+    refill_tick, validate_capacity, Mode, RateLimitExceededError, ReconfigureError, ResizePolicy,
+    TokenBucketAlgorithm,
+};
+
+/// Trait that provides function for tokens acquiring from a bucket of the key.
+///
+/// Object that implements this trait should load state of the key's bucket
+/// (or create it on first use), execute provided algorithm and save updated state.
+pub trait KeyedStorage {
+    type Key: ?Sized;
+    type Error: From<RateLimitExceededError>;
+
+    fn try_acquire(
+        &self,
+        key: &Self::Key,
+        alg: TokenBucketAlgorithm,
+        permits: u64,
+    ) -> Result<(), Self::Error>;
+
+    /// Returns unused tokens back to the bucket of the key.
+    ///
+    /// Default implementation drops them.
+    fn release(&self, key: &Self::Key, permits: u64) -> Result<(), Self::Error> {
+        let _ = (key, permits);
+        Ok(())
+    }
+
+    /// Takes `cost` tokens from the bucket of the key regardless of its balance,
+    /// which may become negative.
+    fn charge(&self, key: &Self::Key, cost: u64) -> Result<(), Self::Error>;
+
+    /// Changes number of tokens refilled per second for buckets of all keys in place.
+    /// Tokens for the time before the change are refilled with the old rate.
+    ///
+    /// Default implementation returns [`ReconfigureError::Unsupported`].
+    fn set_rate(&self, rps_limit: u32) -> Result<(), ReconfigureError<Self::Error>> {
+        let _ = rps_limit;
+        Err(ReconfigureError::Unsupported)
+    }
+
+    /// Changes capacity of buckets of all keys in place, available tokens are adjusted
+    /// by `policy`.
+    ///
+    /// Default implementation returns [`ReconfigureError::Unsupported`].
+    fn set_capacity(
+        &self,
+        cap: u64,
+        policy: ResizePolicy,
+    ) -> Result<(), ReconfigureError<Self::Error>> {
+        let _ = (cap, policy);
+        Err(ReconfigureError::Unsupported)
+    }
+}
+
+/// Rate limiter that implements token bucket algorithm with a separate bucket per key,
+/// e.g. per user or per IP address.
+///
+/// # Example
+/// ```
+/// use tocket::{KeyedInMemoryStorage, KeyedTokenBucket};
+///
+/// let tb = KeyedTokenBucket::new(KeyedInMemoryStorage::new(2));
+/// assert!(tb.try_acquire("alice", 2).is_ok());
+/// assert!(tb.try_acquire_one("alice").is_err());
+/// assert!(tb.try_acquire_one("bob").is_ok());
+/// ```
+pub struct KeyedTokenBucket<S> {
+    storage: S,
+}
+
+impl<S> KeyedTokenBucket<S>
+where
+    S: KeyedStorage,
+{
+    /// Creates new keyed token bucket rate limiter with provided storage.
+    pub fn new(storage: S) -> Self {
+        Self { storage }
+    }
+
+    pub fn storage(&self) -> &S {
+        &self.storage
+    }
+
+    /// Tries to acquire N tokens from the bucket of the key.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if there are not enough tokens or if the storage could not save/load state.
+    pub fn try_acquire<Q>(&self, key: &Q, permits: u64) -> Result<(), S::Error>
+    where
+        Q: AsRef<S::Key> + ?Sized,
+    {
+        self.storage.try_acquire(
+            key.as_ref(),
+            TokenBucketAlgorithm { mode: Mode::N },
+            permits,
+        )
+    }
+
+    /// Tries to acquire 1 token from the bucket of the key.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if there are not enough tokens or if the storage could not save/load state.
+    pub fn try_acquire_one<Q>(&self, key: &Q) -> Result<(), S::Error>
+    where
+        Q: AsRef<S::Key> + ?Sized,
+    {
+        self.try_acquire(key, 1)
+    }
+
+    /// Tries to acquire N or all available tokens if `available < N` from the bucket of the key.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the storage could not save/load state.
+    pub fn try_acquire_n_or_all<Q>(&self, key: &Q, permits: u64) -> Result<(), S::Error>
+    where
+        Q: AsRef<S::Key> + ?Sized,
+    {
+        self.storage.try_acquire(
+            key.as_ref(),
+            TokenBucketAlgorithm { mode: Mode::All },
+            permits,
+        )
+    }
+
+    /// Tries to acquire N tokens from the bucket of the key while its balance is positive,
+    /// the balance may become negative.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the bucket is empty or in debt or if the storage could not save/load state.
+    pub fn try_acquire_overdraft<Q>(&self, key: &Q, permits: u64) -> Result<(), S::Error>
+    where
+        Q: AsRef<S::Key> + ?Sized,
+    {
+        self.storage.try_acquire(
+            key.as_ref(),
+            TokenBucketAlgorithm {
+                mode: Mode::Overdraft,
+            },
+            permits,
+        )
+    }
+
+    /// Takes `cost` tokens from the bucket of the key regardless of its balance.
+    /// Next requests of the key are denied until the debt is repaid by refill.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the storage could not save/load state.
+    pub fn charge<Q>(&self, key: &Q, cost: u64) -> Result<(), S::Error>
+    where
+        Q: AsRef<S::Key> + ?Sized,
+    {
+        self.storage.charge(key.as_ref(), cost)
+    }
+
+    /// Changes number of tokens refilled per second for all keys without losing
+    /// states of their buckets.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the rate is zero, if the storage doesn't support reconfiguration
+    /// or could not save/load state.
+    pub fn set_rate(&self, rps_limit: u32) -> Result<(), ReconfigureError<S::Error>> {
+        refill_tick(rps_limit)?;
+        self.storage.set_rate(rps_limit)
+    }
+
+    /// Changes capacity of buckets of all keys without losing their states,
+    /// available tokens are adjusted by `policy`.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the capacity is zero, if the storage doesn't support reconfiguration
+    /// or could not save/load state.
+    pub fn set_capacity(
+        &self,
+        cap: u64,
+        policy: ResizePolicy,
+    ) -> Result<(), ReconfigureError<S::Error>> {
+        validate_capacity(cap)?;
+        self.storage.set_capacity(cap, policy)
+    }
+}
@@ -0,0 +1,477 @@
+use crate::{Mode, ReconfigureError, ResizePolicy, Storage, StorageError, TokenBucketAlgorithm};
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+struct Lease {
+    tokens: u64,
+    last_used: Instant,
+}
+
+struct Inner<S> {
+    storage: S,
+    lease: parking_lot::Mutex<Lease>,
+    /// Halved every time a lease exceeds the capacity of the storage.
+    lease_size: AtomicU64,
+    renew_below: AtomicU64,
+    idle_return: Option<Duration>,
+}
+
+impl<S> Inner<S>
+where
+    S: Storage,
+    S::Error: StorageError + std::fmt::Display,
+{
+    fn renew(&self) {
+        if self.lease.lock().tokens >= self.renew_below.load(Ordering::Relaxed) {
+            return;
+        }
+
+        let lease_size = self.lease_size.load(Ordering::Relaxed);
+        match self
+            .storage
+            .try_acquire(TokenBucketAlgorithm { mode: Mode::N }, lease_size)
+        {
+            Ok(()) => {
+                let mut lease = self.lease.lock();
+                lease.tokens = lease.tokens.saturating_add(lease_size);
+            }
+            Err(err) if err.is_capacity_exceeded() => self.shrink_lease(lease_size),
+            Err(err) => tracing::debug!("renewing lease failed: {}", err),
+        }
+    }
+
+    /// Halves the lease of `lease_size` tokens that exceeds the capacity of the storage,
+    /// unless it's already changed.
+    fn shrink_lease(&self, lease_size: u64) {
+        let halved = lease_size / 2;
+        if self
+            .lease_size
+            .compare_exchange(lease_size, halved, Ordering::Relaxed, Ordering::Relaxed)
+            .is_ok()
+        {
+            self.renew_below.fetch_min(halved / 2, Ordering::Relaxed);
+            tracing::warn!(
+                "lease of {} tokens exceeds capacity of the storage, leasing {} instead",
+                lease_size,
+                halved
+            );
+        }
+    }
+
+    fn return_unused(&self) {
+        let tokens = std::mem::take(&mut self.lease.lock().tokens);
+        if tokens == 0 {
+            return;
+        }
+
+        if let Err(err) = self.storage.release(tokens) {
+            tracing::warn!("returning {} leased tokens failed: {}", tokens, err);
+        }
+    }
+
+    /// Renews the lease on request, returns it after idle period and on shutdown.
+    fn run(&self, rx: mpsc::Receiver<()>) {
+        loop {
+            let received = match self.idle_return {
+                Some(idle) => rx.recv_timeout(idle),
+                None => rx.recv().map_err(|_| mpsc::RecvTimeoutError::Disconnected),
+            };
+
+            match received {
+                Ok(()) => self.renew(),
+                Err(mpsc::RecvTimeoutError::Timeout) => {
+                    let idle = self.lease.lock().last_used.elapsed();
+                    if self.idle_return.is_some_and(|d| idle >= d) {
+                        self.return_unused();
+                    }
+                }
+                Err(mpsc::RecvTimeoutError::Disconnected) => break,
+            }
+        }
+
+        self.return_unused();
+    }
+}
+
+/// A storage that serves acquisitions from a block of tokens leased from another storage.
+///
+/// Useful in front of remote storages (e.g. [`RedisStorage`](crate::RedisStorage)):
+/// most acquisitions don't make a round trip. The lease is renewed by a background thread
+/// when it runs low, if it's exhausted the missing tokens are acquired together with the next
+/// block. Unused tokens are returned when the storage is dropped.
+///
+/// Leasing never lets more requests through than the remote limit, but leased tokens
+/// are unavailable for other instances, so they may be denied earlier. Tune the trade-off with:
+/// - lease size - bigger leases make fewer round trips, but hold more tokens locally,
+///   a lease above the capacity of the remote storage is halved until it fits;
+/// - [`with_renew_below`](LeasingStorageBuilder::with_renew_below) - renewing earlier
+///   makes fewer acquisitions wait for the remote storage;
+/// - [`with_idle_return`](LeasingStorageBuilder::with_idle_return) - returning the lease
+///   of an idle instance makes its tokens available for others.
+///
+/// # Example
+/// ```
+/// use tocket::{InMemoryStorage, LeasingStorage, TokenBucket};
+///
+/// // Lease 5% of capacity at once
+/// let storage = LeasingStorage::new(InMemoryStorage::new(100), 5);
+///
+/// let tb = TokenBucket::new(storage);
+/// assert!(tb.try_acquire_one().is_ok());
+/// ```
+pub struct LeasingStorage<S> {
+    inner: Arc<Inner<S>>,
+    renew_tx: Option<mpsc::SyncSender<()>>,
+    worker: Option<JoinHandle<()>>,
+}
+
+impl<S> LeasingStorage<S>
+where
+    S: Storage + Send + Sync + 'static,
+    S::Error: StorageError + std::fmt::Display,
+{
+    /// Creates a storage that leases `lease_size` tokens at once.
+    pub fn new(storage: S, lease_size: u64) -> Self {
+        Self::builder(storage, lease_size).build()
+    }
+
+    /// Creates a builder of storage. Needs for customizing of renewing and returning of leases.
+    pub fn builder(storage: S, lease_size: u64) -> LeasingStorageBuilder<S> {
+        LeasingStorageBuilder {
+            storage,
+            lease_size,
+            renew_below: lease_size / 2,
+            idle_return: None,
+        }
+    }
+
+    /// Returns number of leased tokens that are not acquired yet.
+    pub fn leased_tokens(&self) -> u64 {
+        self.inner.lease.lock().tokens
+    }
+
+    fn request_renewal(&self) {
+        if let Some(tx) = &self.renew_tx {
+            // Full channel means the renewal is already requested
+            let _ = tx.try_send(());
+        }
+    }
+}
+
+impl<S> Drop for LeasingStorage<S> {
+    /// Stops the background thread and returns unused tokens.
+    fn drop(&mut self) {
+        self.renew_tx.take();
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
+
+pub struct LeasingStorageBuilder<S> {
+    storage: S,
+    lease_size: u64,
+    renew_below: u64,
+    idle_return: Option<Duration>,
+}
+
+impl<S> LeasingStorageBuilder<S>
+where
+    S: Storage + Send + Sync + 'static,
+    S::Error: StorageError + std::fmt::Display,
+{
+    /// Customize number of remaining tokens that triggers renewing of the lease.
+    /// Half of the lease size by default.
+    pub fn with_renew_below(mut self, tokens: u64) -> Self {
+        self.renew_below = tokens;
+        self
+    }
+
+    /// Return unused tokens if there were no acquisitions for `idle`.
+    pub fn with_idle_return(mut self, idle: Duration) -> Self {
+        self.idle_return = Some(idle);
+        self
+    }
+
+    /// Creates a storage and starts its background thread.
+    pub fn build(self) -> LeasingStorage<S> {
+        let inner = Arc::new(Inner {
+            storage: self.storage,
+            lease: parking_lot::Mutex::new(Lease {
+                tokens: 0,
+                last_used: Instant::now(),
+            }),
+            lease_size: AtomicU64::new(self.lease_size),
+            renew_below: AtomicU64::new(self.renew_below),
+            idle_return: self.idle_return,
+        });
+        let (renew_tx, renew_rx) = mpsc::sync_channel(1);
+        let worker = std::thread::spawn({
+            let inner = Arc::clone(&inner);
+            move || inner.run(renew_rx)
+        });
+
+        LeasingStorage {
+            inner,
+            renew_tx: Some(renew_tx),
+            worker: Some(worker),
+        }
+    }
+}
+
+impl<S> Storage for LeasingStorage<S>
+where
+    S: Storage + Send + Sync + 'static,
+    S::Error: StorageError + std::fmt::Display,
+{
+    type Error = S::Error;
+
+    fn try_acquire(&self, alg: TokenBucketAlgorithm, permits: u64) -> Result<(), Self::Error> {
+        let inner = &self.inner;
+        let taken = {
+            let mut lease = inner.lease.lock();
+            lease.last_used = Instant::now();
+            if lease.tokens >= permits {
+                lease.tokens -= permits;
+                let low = lease.tokens < inner.renew_below.load(Ordering::Relaxed);
+                drop(lease);
+                if low {
+                    self.request_renewal();
+                }
+                return Ok(());
+            }
+            std::mem::take(&mut lease.tokens)
+        };
+        let missing = permits - taken;
+
+        let res = match alg.mode {
+            // Missing tokens are acquired together with the next lease to save a round trip
+            Mode::N => {
+                let lease_size = inner.lease_size.load(Ordering::Relaxed);
+                let with_lease = missing.saturating_add(lease_size);
+                match inner.storage.try_acquire(alg, with_lease) {
+                    Ok(()) => {
+                        let mut lease = inner.lease.lock();
+                        lease.tokens = lease.tokens.saturating_add(with_lease - missing);
+                        Ok(())
+                    }
+                    Err(err) if err.is_rate_limit_exceeded() && with_lease > missing => {
+                        let res = inner.storage.try_acquire(alg, missing);
+                        // The lease is too large if the missing tokens alone fit
+                        let fits = !matches!(&res, Err(err) if err.is_capacity_exceeded());
+                        if err.is_capacity_exceeded() && fits {
+                            inner.shrink_lease(lease_size);
+                        }
+                        res
+                    }
+                    Err(err) => Err(err),
+                }
+            }
+            Mode::All | Mode::Overdraft => {
+                self.request_renewal();
+                inner.storage.try_acquire(alg, missing)
+            }
+        };
+
+        if res.is_err() {
+            let mut lease = inner.lease.lock();
+            lease.tokens = lease.tokens.saturating_add(taken);
+        }
+        res
+    }
+
+    /// Returns tokens to the lease up to its size, the rest is released to the remote storage.
+    fn release(&self, permits: u64) -> Result<(), Self::Error> {
+        let excess = {
+            let mut lease = self.inner.lease.lock();
+            let room = self
+                .inner
+                .lease_size
+                .load(Ordering::Relaxed)
+                .saturating_sub(lease.tokens);
+            let kept = permits.min(room);
+            lease.tokens += kept;
+            permits - kept
+        };
+        if excess == 0 {
+            return Ok(());
+        }
+        self.inner.storage.release(excess)
+    }
+
+    /// Spends leased tokens first, the rest is charged to the remote storage.
+    fn charge(&self, cost: u64) -> Result<(), Self::Error> {
+        let missing = {
+            let mut lease = self.inner.lease.lock();
+            let taken = lease.tokens.min(cost);
+            lease.tokens -= taken;
+            cost - taken
+        };
+        if missing == 0 {
+            return Ok(());
+        }
+        self.inner.storage.charge(missing)
+    }
+
+    /// Reconfigures the remote storage, already leased tokens are kept.
+    fn set_rate(&self, rps_limit: u32) -> Result<(), ReconfigureError<Self::Error>> {
+        self.inner.storage.set_rate(rps_limit)
+    }
+
+    /// Reconfigures the remote storage, already leased tokens are kept.
+    fn set_capacity(
+        &self,
+        cap: u64,
+        policy: ResizePolicy,
+    ) -> Result<(), ReconfigureError<Self::Error>> {
+        self.inner.storage.set_capacity(cap, policy)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{InMemoryStorage, RateLimitExceededError, TokenBucket};
+
+    use std::sync::atomic::AtomicU32;
+
+    /// Counts round trips and tokens that went through the storage.
+    #[derive(Default)]
+    struct Counters {
+        calls: AtomicU32,
+        acquired: AtomicU64,
+        released: AtomicU64,
+    }
+
+    struct Remote {
+        storage: InMemoryStorage,
+        counters: Arc<Counters>,
+    }
+
+    impl Storage for Remote {
+        type Error = RateLimitExceededError;
+
+        fn try_acquire(&self, alg: TokenBucketAlgorithm, permits: u64) -> Result<(), Self::Error> {
+            self.counters.calls.fetch_add(1, Ordering::Relaxed);
+            self.storage.try_acquire(alg, permits)?;
+            self.counters.acquired.fetch_add(permits, Ordering::Relaxed);
+            Ok(())
+        }
+
+        fn release(&self, permits: u64) -> Result<(), Self::Error> {
+            self.counters.released.fetch_add(permits, Ordering::Relaxed);
+            self.storage.release(permits)
+        }
+
+        fn charge(&self, cost: u64) -> Result<(), Self::Error> {
+            self.counters.calls.fetch_add(1, Ordering::Relaxed);
+            self.storage.charge(cost)
+        }
+    }
+
+    fn remote(rps_limit: u32) -> (Remote, Arc<Counters>) {
+        let counters = Arc::new(Counters::default());
+        let remote = Remote {
+            storage: InMemoryStorage::new(rps_limit),
+            counters: Arc::clone(&counters),
+        };
+        (remote, counters)
+    }
+
+    #[test]
+    fn serve_from_lease() {
+        let (remote, counters) = remote(100);
+        let storage = LeasingStorage::new(remote, 10);
+        let tb = TokenBucket::new(storage);
+
+        // The first acquisition leases the block
+        assert!(tb.try_acquire_one().is_ok());
+        assert_eq!(counters.calls.load(Ordering::Relaxed), 1);
+        assert_eq!(counters.acquired.load(Ordering::Relaxed), 11);
+
+        for _ in 0..6 {
+            assert!(tb.try_acquire_one().is_ok());
+        }
+        // Renewed in background after going below the half
+        std::thread::sleep(Duration::from_millis(100));
+        assert_eq!(counters.calls.load(Ordering::Relaxed), 2);
+        assert_eq!(counters.acquired.load(Ordering::Relaxed), 21);
+
+        drop(tb);
+        assert_eq!(counters.released.load(Ordering::Relaxed), 14);
+    }
+
+    #[test]
+    fn exhausted_remote() {
+        let (remote, counters) = remote(4);
+        let storage = LeasingStorage::builder(remote, 3)
+            .with_renew_below(0)
+            .build();
+        let tb = TokenBucket::new(storage);
+
+        assert!(tb.try_acquire(1).is_ok());
+        assert!(tb.try_acquire(3).is_ok());
+        assert!(tb.try_acquire_one().is_err());
+        assert_eq!(counters.acquired.load(Ordering::Relaxed), 4);
+    }
+
+    #[test]
+    fn idle_return() {
+        let (remote, counters) = remote(100);
+        let storage = LeasingStorage::builder(remote, 10)
+            .with_idle_return(Duration::from_millis(50))
+            .build();
+
+        assert!(storage
+            .try_acquire(TokenBucketAlgorithm { mode: Mode::N }, 1)
+            .is_ok());
+        assert_eq!(storage.leased_tokens(), 10);
+
+        std::thread::sleep(Duration::from_millis(200));
+        assert_eq!(storage.leased_tokens(), 0);
+        assert_eq!(counters.released.load(Ordering::Relaxed), 10);
+    }
+
+    #[test]
+    fn lease_exceeds_capacity() {
+        let (remote, counters) = remote(4);
+        let storage = LeasingStorage::new(remote, 10);
+        let alg = TokenBucketAlgorithm { mode: Mode::N };
+
+        // The missing token is acquired alone and the lease is halved
+        assert!(storage.try_acquire(alg, 1).is_ok());
+        assert_eq!(storage.inner.lease_size.load(Ordering::Relaxed), 5);
+        assert!(storage.try_acquire(alg, 1).is_ok());
+        assert_eq!(storage.inner.lease_size.load(Ordering::Relaxed), 2);
+        assert_eq!(counters.acquired.load(Ordering::Relaxed), 2);
+
+        // Requests above the capacity don't shrink the lease
+        assert!(storage
+            .try_acquire(alg, 5)
+            .unwrap_err()
+            .is_capacity_exceeded());
+        assert_eq!(storage.inner.lease_size.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn release_excess() {
+        let (remote, counters) = remote(100);
+        let storage = LeasingStorage::new(remote, 10);
+        let alg = TokenBucketAlgorithm { mode: Mode::N };
+
+        assert!(storage.try_acquire(alg, 3).is_ok());
+        assert_eq!(storage.leased_tokens(), 10);
+        assert!(storage.release(5).is_ok());
+        assert_eq!(storage.leased_tokens(), 10);
+        assert_eq!(counters.released.load(Ordering::Relaxed), 5);
+
+        assert!(storage.try_acquire(alg, 3).is_ok());
+        assert!(storage.release(5).is_ok());
+        assert_eq!(storage.leased_tokens(), 10);
+        assert_eq!(counters.released.load(Ordering::Relaxed), 7);
+    }
+}
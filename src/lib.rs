@@ -6,40 +6,143 @@
 //!
 //! ## Available storages:
 //! - [`InMemoryStorage`]
+//! - [`AtomicInMemoryStorage`]
 //! - [`RedisStorage`]
 //! - [`DistributedStorage`]
+//! - [`SqliteStorage`]
+//! - [`RedbStorage`]
+//! - [`SharedMemoryStorage`]
 //!
-//! You can implement your own [storage] (e.g. Postgres).
+//! You can implement your own [storage] (e.g. Postgres), [`SqliteStorage`] is a reference
+//! for SQL backends.
+//!
+//! Rate limiting by a key (e.g. user or IP) is provided by [`KeyedTokenBucket`]. Some keys can be
+//! allowed, denied or given custom quotas by [`KeyRules`] of [`RuledTokenBucket`].
+//!
+//! Several limits (e.g. "10/s AND 300/min") can be checked at once by [`CompositeLimiter`].
+//! Failures of a remote storage can be handled by [`FallbackStorage`], round trips to it
+//! can be reduced by [`LeasingStorage`].
+//!
+//! Rate and capacity of a live bucket can be changed by [`TokenBucket::set_rate`]
+//! and [`TokenBucket::set_capacity`] without losing its state. Services that can't take
+//! the full rate after a cold start can be protected by [`WarmUp`] of in-memory
+//! and redis storages. Tokens of new buckets (e.g. after a restart or for a new key)
+//! are set by [`InitialFill`]. Requests whose cost is known only afterwards can be let
+//! through by [`TokenBucket::try_acquire_overdraft`] and paid by [`TokenBucket::charge`],
+//! the bucket may go into debt. Limits can follow a time-of-day [`RateSchedule`]
+//! with [`ScheduledStorage`]. Limits can be written as strings like `"100/s burst=200"`
+//! or `"10/s; 300/min"` and parsed into [`Quota`] and [`Quotas`], e.g. from env vars.
+//!
+//! Permits, costs and capacities are `u64`, so a bucket can limit large quantities like bytes
+//! rather than requests, as long as a token takes at least a nanosecond to refill. Quantities beyond the range of a storage saturate instead of overflowing,
+//! [`AtomicInMemoryStorage`] and [`SharedMemoryStorage`] keep `i32` balances.
+//!
+//! Invalid configurations (e.g. a zero rate) are reported by [`ConfigError`]. A request of
+//! more permits than the capacity fails with [`RateLimitExceededError::ExceedsCapacity`],
+//! since it can never succeed, see [`StorageError::is_capacity_exceeded`].
 //!
 //! ## Features
 //! - `redis-impl` - redis storage implementation
 //! - `distributed-impl` - distributed storage implementation
+//! - `sqlite-impl` - sqlite storage implementation
+//! - `redb-impl` - redb (embedded key-value database) storage implementation
+//! - `shm-impl` - shared memory storage implementation
+//! - `serde` - serializable [`State`], e.g. for snapshots of in-memory storages
+//! - `registry` - [`LimiterRegistry`] of named limiters configured by TOML, YAML or JSON files,
+//!   reloaded when the file changes
 //!
 //! [`InMemoryStorage`]: crate::in_memory::InMemoryStorage
+//! [`AtomicInMemoryStorage`]: crate::in_memory_atomic::AtomicInMemoryStorage
 //! [`RedisStorage`]: crate::in_redis::RedisStorage
 //! [`DistributedStorage`]: crate::distributed::DistributedStorage
+//! [`SqliteStorage`]: crate::in_sqlite::SqliteStorage
+//! [`RedbStorage`]: crate::in_redb::RedbStorage
+//! [`SharedMemoryStorage`]: crate::in_shm::SharedMemoryStorage
 //! [storage]: crate::Storage
+//! [`KeyedTokenBucket`]: crate::keyed::KeyedTokenBucket
+//! [`KeyRules`]: crate::rules::KeyRules
+//! [`RuledTokenBucket`]: crate::rules::RuledTokenBucket
+//! [`CompositeLimiter`]: crate::composite::CompositeLimiter
+//! [`FallbackStorage`]: crate::fallback::FallbackStorage
+//! [`StorageError::is_capacity_exceeded`]: crate::fallback::StorageError::is_capacity_exceeded
+//! [`LeasingStorage`]: crate::leasing::LeasingStorage
+//! [`WarmUp`]: crate::warm_up::WarmUp
+//! [`RateSchedule`]: crate::schedule::RateSchedule
+//! [`ScheduledStorage`]: crate::schedule::ScheduledStorage
+//! [`Quota`]: crate::quota::Quota
+//! [`Quotas`]: crate::quota::Quotas
+//! [`LimiterRegistry`]: crate::registry::LimiterRegistry
 
+pub mod composite;
+pub mod fallback;
 pub mod in_memory;
+pub mod in_memory_atomic;
+pub mod keyed;
+pub mod leasing;
+pub mod quota;
+pub mod rules;
+pub mod schedule;
+pub mod warm_up;
 
 #[cfg(feature = "distributed-impl")]
 #[cfg_attr(docsrs, doc(cfg(feature = "distributed-impl")))]
 pub mod distributed;
 
+#[cfg(feature = "redb-impl")]
+#[cfg_attr(docsrs, doc(cfg(feature = "redb-impl")))]
+pub mod in_redb;
+
 #[cfg(feature = "redis-impl")]
 #[cfg_attr(docsrs, doc(cfg(feature = "redis-impl")))]
 pub mod in_redis;
 
+#[cfg(feature = "registry")]
+#[cfg_attr(docsrs, doc(cfg(feature = "registry")))]
+pub mod registry;
+
+#[cfg(feature = "shm-impl")]
+#[cfg_attr(docsrs, doc(cfg(feature = "shm-impl")))]
+pub mod in_shm;
+
+#[cfg(feature = "sqlite-impl")]
+#[cfg_attr(docsrs, doc(cfg(feature = "sqlite-impl")))]
+pub mod in_sqlite;
+
+pub use composite::*;
+pub use fallback::*;
 pub use in_memory::*;
+pub use in_memory_atomic::*;
+pub use keyed::*;
+pub use leasing::*;
+pub use quota::*;
+pub use rules::*;
+pub use schedule::*;
+pub use warm_up::*;
 
 #[cfg(feature = "distributed-impl")]
 #[cfg_attr(docsrs, doc(cfg(feature = "distributed-impl")))]
 pub use distributed::*;
 
+#[cfg(feature = "redb-impl")]
+#[cfg_attr(docsrs, doc(cfg(feature = "redb-impl")))]
+pub use in_redb::*;
+
 #[cfg(feature = "redis-impl")]
 #[cfg_attr(docsrs, doc(cfg(feature = "redis-impl")))]
 pub use in_redis::*;
 
+#[cfg(feature = "registry")]
+#[cfg_attr(docsrs, doc(cfg(feature = "registry")))]
+pub use registry::*;
+
+#[cfg(feature = "shm-impl")]
+#[cfg_attr(docsrs, doc(cfg(feature = "shm-impl")))]
+pub use in_shm::*;
+
+#[cfg(feature = "sqlite-impl")]
+#[cfg_attr(docsrs, doc(cfg(feature = "sqlite-impl")))]
+pub use in_sqlite::*;
+
 /// Trait that provides function for tokens acquiring.
 ///
 /// Object that implements this trait should load state, execute provided algorithm
@@ -47,16 +150,262 @@ pub use in_redis::*;
 pub trait Storage {
     type Error: From<RateLimitExceededError>;
 
-    fn try_acquire(&self, alg: TokenBucketAlgorithm, permits: u32) -> Result<(), Self::Error>;
+    fn try_acquire(&self, alg: TokenBucketAlgorithm, permits: u64) -> Result<(), Self::Error>;
+
+    /// Returns unused tokens, e.g. acquired in advance, back to the bucket.
+    ///
+    /// Default implementation drops them.
+    fn release(&self, permits: u64) -> Result<(), Self::Error> {
+        let _ = permits;
+        Ok(())
+    }
+
+    /// Takes `cost` tokens regardless of the balance, which may become negative.
+    /// Acquiring is denied until the debt is repaid by refill.
+    fn charge(&self, cost: u64) -> Result<(), Self::Error>;
+
+    /// Changes number of tokens refilled per second in place.
+    /// Tokens for the time before the change are refilled with the old rate.
+    ///
+    /// Default implementation returns [`ReconfigureError::Unsupported`].
+    fn set_rate(&self, rps_limit: u32) -> Result<(), ReconfigureError<Self::Error>> {
+        let _ = rps_limit;
+        Err(ReconfigureError::Unsupported)
+    }
+
+    /// Changes capacity of the bucket in place, available tokens are adjusted by `policy`.
+    ///
+    /// Default implementation returns [`ReconfigureError::Unsupported`].
+    fn set_capacity(
+        &self,
+        cap: u64,
+        policy: ResizePolicy,
+    ) -> Result<(), ReconfigureError<Self::Error>> {
+        let _ = (cap, policy);
+        Err(ReconfigureError::Unsupported)
+    }
+}
+
+/// Tokens of a bucket that is used for the first time, e.g. after a restart or for a new key.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum InitialFill {
+    /// The whole capacity, so requests may burst right away.
+    #[default]
+    Full,
+    /// No tokens, the bucket is filled with the rate.
+    Empty,
+    /// The given number of tokens, but not more than the capacity.
+    Tokens(u64),
+}
+
+impl InitialFill {
+    /// Tokens of a new bucket with capacity `cap`.
+    pub fn tokens(&self, cap: u64) -> u64 {
+        match self {
+            InitialFill::Full => cap,
+            InitialFill::Empty => 0,
+            InitialFill::Tokens(tokens) => (*tokens).min(cap),
+        }
+    }
+}
+
+/// How available tokens are adjusted when capacity of a bucket is changed.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Default)]
+pub enum ResizePolicy {
+    /// Keep available tokens, but not more than the new capacity.
+    #[default]
+    Clamp,
+    /// Keep the filled fraction of the bucket, e.g. a half full bucket stays half full.
+    Scale,
 }
 
 /// State of token bucket.
+///
+/// With `serde` feature the state is serializable, e.g. for snapshots of
+/// [`InMemoryStorage`].
 #[derive(Debug, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct State {
-    pub cap: u32,
-    pub available_tokens: u32,
+    pub cap: u64,
+    /// Balance of the bucket, negative if it's in debt after [overdraft](Mode::Overdraft)
+    /// or [charge](TokenBucketAlgorithm::charge).
+    pub available_tokens: i64,
+    #[cfg_attr(feature = "serde", serde(with = "time::serde::rfc3339"))]
     pub last_refill: time::OffsetDateTime,
     pub refill_tick: time::Duration,
+    /// Slow start of the bucket, if any.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub warm_up: Option<WarmUp>,
+    /// Start of the last warm-up, `None` if the bucket is cold.
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, with = "time::serde::rfc3339::option")
+    )]
+    pub warm_since: Option<time::OffsetDateTime>,
+}
+
+/// Capacity and refill rate of a bucket.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub(crate) struct BucketConfig {
+    pub(crate) cap: u64,
+    pub(crate) refill_tick: time::Duration,
+    pub(crate) warm_up: Option<WarmUp>,
+    pub(crate) initial_fill: InitialFill,
+}
+
+impl BucketConfig {
+    /// Creates a config of `cap` tokens refilled with `rps_limit` tokens per second.
+    pub(crate) fn try_new(rps_limit: u32, cap: u64) -> Result<Self, ConfigError> {
+        Ok(Self {
+            refill_tick: refill_tick(rps_limit)?,
+            cap: validate_capacity(cap)?,
+            warm_up: None,
+            initial_fill: InitialFill::Full,
+        })
+    }
+
+    pub(crate) fn with_initial_fill(mut self, initial_fill: InitialFill) -> Self {
+        self.initial_fill = initial_fill;
+        self
+    }
+
+    pub(crate) fn with_warm_up(mut self, warm_up: Option<WarmUp>) -> Self {
+        self.warm_up = warm_up;
+        self
+    }
+
+    /// Overrides the refill tick of the rate, e.g. for a rate that isn't a whole number
+    /// of tokens per second.
+    pub(crate) fn with_refill_tick(
+        mut self,
+        refill_tick: Option<time::Duration>,
+    ) -> Result<Self, ConfigError> {
+        if let Some(refill_tick) = refill_tick {
+            self.refill_tick = validate_tick(refill_tick)?;
+        }
+        Ok(self)
+    }
+
+    /// State of a bucket used for the first time.
+    pub(crate) fn new_state(&self) -> State {
+        self.state(
+            balance(self.initial_fill.tokens(self.cap)),
+            time::OffsetDateTime::now_utc(),
+        )
+    }
+
+    /// State of a bucket loaded from a storage, tokens above the capacity are dropped.
+    pub(crate) fn state(&self, available_tokens: i64, last_refill: time::OffsetDateTime) -> State {
+        State {
+            cap: self.cap,
+            available_tokens: available_tokens.min(balance(self.cap)),
+            last_refill,
+            refill_tick: self.refill_tick,
+            warm_up: self.warm_up,
+            warm_since: None,
+        }
+    }
+}
+
+impl State {
+    /// Refills the state and adds `permits` tokens up to the capacity.
+    pub(crate) fn release(&mut self, permits: u64) {
+        TokenBucketAlgorithm { mode: Mode::N }.refill_state(self);
+        self.available_tokens = self
+            .available_tokens
+            .saturating_add(balance(permits))
+            .min(balance(self.cap));
+    }
+
+    /// Refills the state with the current rate and changes the rate, see [`refill_tick`].
+    pub(crate) fn set_rate(&mut self, refill_tick: time::Duration) {
+        TokenBucketAlgorithm { mode: Mode::N }.refill_state(self);
+        self.refill_tick = refill_tick;
+    }
+
+    /// Refills the state and changes the capacity, adjusting available tokens by `policy`.
+    pub(crate) fn set_capacity(&mut self, cap: u64, policy: ResizePolicy) {
+        TokenBucketAlgorithm { mode: Mode::N }.refill_state(self);
+        self.available_tokens = match policy {
+            ResizePolicy::Clamp => self.available_tokens.min(balance(cap)),
+            ResizePolicy::Scale if self.cap == 0 => balance(cap),
+            ResizePolicy::Scale => {
+                let scaled =
+                    i128::from(self.available_tokens) * i128::from(cap) / i128::from(self.cap);
+                i64::try_from(scaled).unwrap_or(if scaled < 0 { i64::MIN } else { i64::MAX })
+            }
+        };
+        self.cap = cap;
+    }
+
+    /// Takes tokens, refill time and warm-up progress of a `snapshot` saved earlier, keeping
+    /// capacity, refill tick and warm-up of this state. Tokens for the time since the snapshot
+    /// are refilled.
+    pub(crate) fn restore(&mut self, snapshot: &State) {
+        let now = time::OffsetDateTime::now_utc();
+        let offline = now - snapshot.last_refill.min(now);
+        if offline >= ticks(self.refill_tick, self.cap.into()) {
+            // Don't count tokens of a long break one by one
+            self.available_tokens = balance(self.cap);
+            self.last_refill = now;
+            self.warm_since = None;
+            return;
+        }
+
+        self.available_tokens = snapshot.available_tokens.min(balance(self.cap));
+        self.last_refill = snapshot.last_refill.min(now);
+        self.warm_since = snapshot.warm_since;
+        TokenBucketAlgorithm { mode: Mode::N }.refill_state(self);
+    }
+
+    /// Time until `permits` tokens are available, assuming the state is just refilled.
+    pub(crate) fn retry_after(&self, permits: u64) -> std::time::Duration {
+        let missing = (i128::from(permits) - i128::from(self.available_tokens)).max(0);
+        let elapsed = time::OffsetDateTime::now_utc() - self.last_refill;
+        ticks(self.refill_tick, missing)
+            .saturating_sub(elapsed)
+            .try_into()
+            .unwrap_or_default()
+    }
+}
+
+/// Interval between refills of single tokens at `rps_limit` tokens per second.
+pub(crate) fn refill_tick(rps_limit: u32) -> Result<time::Duration, ConfigError> {
+    match rps_limit {
+        0 => Err(ConfigError::ZeroRate),
+        rps_limit => validate_tick(time::Duration::seconds(1) / rps_limit),
+    }
+}
+
+/// Returns `tick` if it's at least a nanosecond, a shorter tick would refill nothing
+/// and the bucket would never limit.
+pub(crate) fn validate_tick(tick: time::Duration) -> Result<time::Duration, ConfigError> {
+    if tick.whole_nanoseconds() > 0 {
+        Ok(tick)
+    } else {
+        Err(ConfigError::RateTooHigh)
+    }
+}
+
+/// Returns `cap` if a bucket of such capacity can ever give tokens.
+pub(crate) fn validate_capacity(cap: u64) -> Result<u64, ConfigError> {
+    match cap {
+        0 => Err(ConfigError::ZeroCapacity),
+        cap => Ok(cap),
+    }
+}
+
+/// Balance of `tokens`, saturating at `i64::MAX`.
+pub(crate) fn balance(tokens: u64) -> i64 {
+    i64::try_from(tokens).unwrap_or(i64::MAX)
+}
+
+/// Duration of `n` refill ticks, saturating at bounds of `time::Duration`.
+pub(crate) fn ticks(refill_tick: time::Duration, n: i128) -> time::Duration {
+    let max = time::Duration::MAX.whole_nanoseconds();
+    let nanos = refill_tick.whole_nanoseconds().saturating_mul(n);
+    time::Duration::nanoseconds_i128(nanos.clamp(-max, max))
 }
 
 /// Rate limiter that implements token bucket algorithm.
@@ -78,7 +427,7 @@ where
     /// # Errors
     ///
     /// Will return `Err` if there are not enough tokens or if the storage could not save/load state.
-    pub fn try_acquire(&self, permits: u32) -> Result<(), S::Error> {
+    pub fn try_acquire(&self, permits: u64) -> Result<(), S::Error> {
         self.storage
             .try_acquire(TokenBucketAlgorithm { mode: Mode::N }, permits)
     }
@@ -97,75 +446,211 @@ where
     /// # Errors
     ///
     /// Will return `Err` if the storage could not save/load state.
-    pub fn try_acquire_n_or_all(&self, permits: u32) -> Result<(), S::Error> {
+    pub fn try_acquire_n_or_all(&self, permits: u64) -> Result<(), S::Error> {
         self.storage
             .try_acquire(TokenBucketAlgorithm { mode: Mode::All }, permits)
     }
+
+    /// Tries to acquire N tokens while the balance is positive, the balance may become negative.
+    /// Suits requests whose cost is known only afterwards, see [`charge`](Self::charge).
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the bucket is empty or in debt or if the storage could not save/load state.
+    pub fn try_acquire_overdraft(&self, permits: u64) -> Result<(), S::Error> {
+        self.storage.try_acquire(
+            TokenBucketAlgorithm {
+                mode: Mode::Overdraft,
+            },
+            permits,
+        )
+    }
+
+    /// Takes `cost` tokens regardless of the balance, e.g. the real cost of a request
+    /// known after it's done. Next requests are denied until the debt is repaid by refill.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the storage could not save/load state.
+    pub fn charge(&self, cost: u64) -> Result<(), S::Error> {
+        self.storage.charge(cost)
+    }
+
+    /// Changes number of tokens refilled per second without losing the state of the bucket.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the rate is zero, if the storage doesn't support reconfiguration
+    /// or could not save/load state.
+    pub fn set_rate(&self, rps_limit: u32) -> Result<(), ReconfigureError<S::Error>> {
+        refill_tick(rps_limit)?;
+        self.storage.set_rate(rps_limit)
+    }
+
+    /// Changes capacity of the bucket without losing its state,
+    /// available tokens are adjusted by `policy`.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the capacity is zero, if the storage doesn't support reconfiguration
+    /// or could not save/load state.
+    pub fn set_capacity(
+        &self,
+        cap: u64,
+        policy: ResizePolicy,
+    ) -> Result<(), ReconfigureError<S::Error>> {
+        validate_capacity(cap)?;
+        self.storage.set_capacity(cap, policy)
+    }
 }
 
 /// Struct that implements token bucket algorithm.
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub struct TokenBucketAlgorithm {
     mode: Mode,
 }
 
+/// How many tokens the algorithm acquires.
 #[derive(Debug, Clone, Copy, Eq, PartialEq)]
-enum Mode {
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "lowercase")
+)]
+pub enum Mode {
+    /// Exactly N tokens or none of them.
     N,
+    /// N tokens or all available tokens if there are not enough.
     All,
+    /// N tokens if the balance is positive, the balance may become negative.
+    Overdraft,
 }
 
 impl TokenBucketAlgorithm {
+    /// Creates the algorithm. Needs for storages that wrap or forward to other storages.
+    pub fn new(mode: Mode) -> Self {
+        Self { mode }
+    }
+
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
     pub fn try_acquire(
         &self,
         state: &mut State,
-        permits: u32,
+        permits: u64,
     ) -> Result<(), RateLimitExceededError> {
         self.refill_state(state);
 
         match self.mode {
             Mode::N => {
-                if state.available_tokens >= permits {
-                    state.available_tokens -= permits;
+                if permits > state.cap {
+                    Err(RateLimitExceededError::ExceedsCapacity {
+                        permits,
+                        cap: state.cap,
+                    })
+                } else if i128::from(state.available_tokens) >= i128::from(permits) {
+                    state.available_tokens -= balance(permits);
                     Ok(())
                 } else {
-                    Err(RateLimitExceededError(()))
+                    Err(RateLimitExceededError::Exhausted)
                 }
             }
             Mode::All => {
-                state.available_tokens -= u32::min(permits, state.available_tokens);
+                state.available_tokens -= i64::min(balance(permits), state.available_tokens.max(0));
                 Ok(())
             }
+            Mode::Overdraft => {
+                if state.available_tokens > 0 {
+                    state.available_tokens =
+                        state.available_tokens.saturating_sub(balance(permits));
+                    Ok(())
+                } else {
+                    Err(RateLimitExceededError::Exhausted)
+                }
+            }
         }
     }
 
+    /// Refills the state and takes `cost` tokens regardless of the balance,
+    /// which may become negative.
+    pub fn charge(&self, state: &mut State, cost: u64) {
+        self.refill_state(state);
+        state.available_tokens = state.available_tokens.saturating_sub(balance(cost));
+    }
+
     fn refill_state(&self, state: &mut State) {
         let now = time::OffsetDateTime::now_utc();
+        if let Some(warm_up) = state.warm_up {
+            if warm_up.refill(state, now) {
+                return;
+            }
+        }
+
         let since_last_refill = now - state.last_refill;
 
         if since_last_refill <= state.refill_tick {
             return;
         }
 
-        let tokens_since_last_refill = {
-            let mut tokens_count = 0u32;
-            let mut k = since_last_refill;
-            loop {
-                k -= state.refill_tick;
-                if k <= time::Duration::ZERO {
-                    break;
-                }
-                tokens_count += 1;
-            }
-            tokens_count
-        };
+        // Whole ticks that ended strictly before now
+        let tick_nanos = state.refill_tick.whole_nanoseconds().max(1);
+        let tokens_since_last_refill = (since_last_refill.whole_nanoseconds() - 1) / tick_nanos;
 
-        state.available_tokens =
-            u32::min(state.available_tokens + tokens_since_last_refill, state.cap);
-        state.last_refill += state.refill_tick * tokens_since_last_refill;
+        state.available_tokens = i128::from(state.available_tokens)
+            .saturating_add(tokens_since_last_refill)
+            .min(i128::from(balance(state.cap))) as i64;
+        state.last_refill += ticks(state.refill_tick, tokens_since_last_refill);
     }
 }
 
+#[derive(Debug, Clone, Copy, Eq, PartialEq, thiserror::Error)]
+pub enum RateLimitExceededError {
+    /// There are not enough tokens, the request may succeed after refill.
+    #[error("rate limit exceeded")]
+    Exhausted,
+    /// The request needs more tokens than the bucket can hold, so it never succeeds.
+    #[error("requested {permits} permits exceed capacity {cap} of the bucket")]
+    ExceedsCapacity { permits: u64, cap: u64 },
+}
+
+/// Invalid configuration of a bucket.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, thiserror::Error)]
+pub enum ConfigError {
+    #[error("rate limit must be positive")]
+    ZeroRate,
+    #[error("capacity must be positive")]
+    ZeroCapacity,
+    /// A token is refilled in less than a nanosecond.
+    #[error("rate is too high, a token must take at least a nanosecond")]
+    RateTooHigh,
+    /// The capacity is beyond the range of the storage, e.g. refill of the whole capacity
+    /// doesn't fit in its clock.
+    #[error("capacity is too large for the storage")]
+    CapacityOverflow,
+}
+
 #[derive(Debug, thiserror::Error)]
-#[error("rate limit exceeded")]
-pub struct RateLimitExceededError(());
+pub enum ReconfigureError<E> {
+    #[error("storage doesn't support reconfiguration")]
+    Unsupported,
+    #[error(transparent)]
+    Config(#[from] ConfigError),
+    #[error(transparent)]
+    Storage(E),
+}
+
+impl<E> ReconfigureError<E> {
+    /// Converts the error of the storage, e.g. of a wrapped one.
+    pub fn map<F, T>(self, f: F) -> ReconfigureError<T>
+    where
+        F: FnOnce(E) -> T,
+    {
+        match self {
+            ReconfigureError::Unsupported => ReconfigureError::Unsupported,
+            ReconfigureError::Config(err) => ReconfigureError::Config(err),
+            ReconfigureError::Storage(err) => ReconfigureError::Storage(f(err)),
+        }
+    }
+}
@@ -0,0 +1,484 @@
+use crate::{Limit, RateProfile};
+
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Units of periods, from the largest one. The first name of a unit is used for display.
+const UNITS: [(&[&str], Duration); 7] = [
+    (&["day", "days", "d"], Duration::from_secs(24 * 60 * 60)),
+    (&["h", "hour", "hours", "hr"], Duration::from_secs(60 * 60)),
+    (&["min", "minute", "minutes"], Duration::from_secs(60)),
+    (&["s", "sec", "second", "seconds"], Duration::from_secs(1)),
+    (&["ms"], Duration::from_millis(1)),
+    (&["us"], Duration::from_micros(1)),
+    (&["ns"], Duration::from_nanos(1)),
+];
+
+/// Quota of `count` permits per `period`, optionally with a larger or smaller burst.
+///
+/// Parsed from and displayed as a compact string: `<count>/[<n>]<unit> [burst=<count>]`,
+/// e.g. `100/s burst=200`, `5000/day` or `10/5min`. Units are `ns`, `us`, `ms`,
+/// `s` (`sec`, `second`), `min` (`minute`), `h` (`hour`, `hr`) and `day` (`d`).
+///
+/// With `serde` feature the quota is (de)serialized as such a string.
+///
+/// # Example
+/// ```
+/// use tocket::{InMemoryStorage, Limit, Quota, TokenBucket};
+///
+/// let quota: Quota = "100/s burst=200".parse().unwrap();
+/// assert_eq!(quota.to_string(), "100/s burst=200");
+///
+/// let profile = quota.rate_profile().unwrap();
+/// let storage = InMemoryStorage::builder(profile.rps_limit())
+///     .with_capacity(profile.cap())
+///     .build();
+/// assert!(TokenBucket::new(storage).try_acquire(200).is_ok());
+///
+/// let quota: Quota = "5000/day".parse().unwrap();
+/// assert_eq!(quota.limit(), Limit::per_day(5000));
+/// ```
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub struct Quota {
+    count: u64,
+    period: Duration,
+    burst: Option<u64>,
+}
+
+impl Quota {
+    /// Creates a quota of `count` permits per `period` with burst of `count` permits.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `period` is zero or shorter than a nanosecond per permit,
+    /// see [`try_new`](Self::try_new).
+    pub fn new(count: u64, period: Duration) -> Self {
+        match Self::try_new(count, period) {
+            Ok(quota) => quota,
+            Err(err) => panic!("invalid quota: {}", err),
+        }
+    }
+
+    /// Creates a quota of `count` permits per `period` with burst of `count` permits.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if `period` is zero or shorter than a nanosecond per permit,
+    /// such a quota would never limit.
+    pub fn try_new(count: u64, period: Duration) -> Result<Self, QuotaError> {
+        if period.is_zero() {
+            return Err(QuotaError::ZeroPeriod);
+        }
+        let quota = Self {
+            count,
+            period,
+            burst: None,
+        };
+        if period.as_nanos() < u128::from(count) {
+            return Err(QuotaError::RateTooHigh {
+                quota: quota.to_string(),
+            });
+        }
+        Ok(quota)
+    }
+
+    /// Customize the largest number of permits acquired at once, i.e. capacity of the bucket.
+    pub fn with_burst(mut self, burst: u64) -> Self {
+        self.burst = Some(burst);
+        self
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn period(&self) -> Duration {
+        self.period
+    }
+
+    /// Capacity of the bucket, `count` unless the burst is customized.
+    pub fn burst(&self) -> u64 {
+        self.burst.unwrap_or(self.count)
+    }
+
+    /// Limit of a composite limiter with the same rate and capacity.
+    ///
+    /// A limit refills its capacity during its period, so with a custom burst
+    /// the period is scaled to keep the rate.
+    pub fn limit(&self) -> Limit {
+        let Some(burst) = self.burst.filter(|burst| *burst != self.count) else {
+            return Limit::new(self.count, self.period);
+        };
+        let nanos = self.period.as_nanos() * u128::from(burst) / u128::from(self.count.max(1));
+        Limit::new(
+            burst,
+            Duration::from_nanos(nanos.try_into().unwrap_or(u64::MAX)),
+        )
+    }
+
+    /// Rate and capacity of a bucket, e.g. of [`InMemoryStorage`](crate::InMemoryStorage).
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the quota isn't a whole number of permits per second
+    /// (e.g. `5000/day`) that fits `u32`, use [`limit`](Self::limit) for such quotas.
+    pub fn rate_profile(&self) -> Result<RateProfile, QuotaError> {
+        let per_second = u128::from(self.count) * Duration::from_secs(1).as_nanos();
+        let period = self.period.as_nanos();
+        let rps_limit = match per_second.checked_rem(period) {
+            Some(0) => u32::try_from(per_second / period)
+                .ok()
+                .filter(|rps| *rps > 0),
+            _ => None,
+        };
+        let rps_limit = rps_limit.ok_or_else(|| QuotaError::NotPerSecond {
+            quota: self.to_string(),
+        })?;
+        Ok(RateProfile::new(rps_limit).with_capacity(self.burst()))
+    }
+}
+
+impl From<Limit> for Quota {
+    fn from(limit: Limit) -> Self {
+        Self::new(limit.cap(), limit.period())
+    }
+}
+
+impl fmt::Display for Quota {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let nanos = self.period.as_nanos();
+        let (names, unit) = UNITS
+            .iter()
+            .find(|(_, unit)| nanos.is_multiple_of(unit.as_nanos()))
+            .unwrap_or(&UNITS[UNITS.len() - 1]);
+        match nanos / unit.as_nanos() {
+            1 => write!(f, "{}/{}", self.count, names[0])?,
+            n => write!(f, "{}/{}{}", self.count, n, names[0])?,
+        }
+        if let Some(burst) = self.burst {
+            write!(f, " burst={}", burst)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Quota {
+    type Err = QuotaError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split_whitespace();
+        let rate = parts.next().ok_or(QuotaError::Empty)?;
+        let (count, period) = rate
+            .split_once('/')
+            .ok_or_else(|| QuotaError::MissingPeriod {
+                quota: s.trim().to_string(),
+            })?;
+        let mut quota = Quota::try_new(parse_number(count)?, parse_period(period)?)?;
+
+        for option in parts {
+            match option.split_once('=') {
+                Some(("burst", _)) if quota.burst.is_some() => {
+                    return Err(QuotaError::DuplicateOption {
+                        option: "burst".to_string(),
+                    })
+                }
+                Some(("burst", burst)) => quota.burst = Some(parse_number(burst)?),
+                _ => {
+                    return Err(QuotaError::UnknownOption {
+                        option: option.to_string(),
+                    })
+                }
+            }
+        }
+        Ok(quota)
+    }
+}
+
+/// Parses a positive number.
+fn parse_number(s: &str) -> Result<u64, QuotaError> {
+    match s.parse() {
+        Ok(0) | Err(_) => Err(QuotaError::InvalidNumber {
+            value: s.to_string(),
+        }),
+        Ok(n) => Ok(n),
+    }
+}
+
+/// Parses `[<n>]<unit>`.
+fn parse_period(s: &str) -> Result<Duration, QuotaError> {
+    let digits = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (n, unit) = s.split_at(digits);
+    let n = if n.is_empty() { 1 } else { parse_number(n)? };
+
+    let (_, unit) = UNITS
+        .iter()
+        .find(|(names, _)| names.contains(&unit))
+        .ok_or_else(|| QuotaError::UnknownUnit {
+            unit: unit.to_string(),
+        })?;
+    u32::try_from(n)
+        .ok()
+        .and_then(|n| unit.checked_mul(n))
+        .ok_or_else(|| QuotaError::PeriodTooLong {
+            period: s.to_string(),
+        })
+}
+
+/// Several quotas checked at once, e.g. `10/s; 300/min`, see [`Quota`] for the syntax.
+///
+/// # Example
+/// ```
+/// use tocket::{CompositeLimiter, InMemoryCompositeStorage, Limit, Quotas};
+///
+/// let quotas: Quotas = "10/s; 300/min".parse().unwrap();
+/// assert_eq!(quotas.to_string(), "10/s; 300/min");
+///
+/// let limiter = CompositeLimiter::new(InMemoryCompositeStorage::new(), quotas.limits());
+/// assert_eq!(limiter.limits(), [Limit::per_second(10), Limit::per_minute(300)]);
+/// ```
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct Quotas(Vec<Quota>);
+
+impl Quotas {
+    pub fn iter(&self) -> std::slice::Iter<'_, Quota> {
+        self.0.iter()
+    }
+
+    /// Limits of a composite limiter, one per quota.
+    pub fn limits(&self) -> Vec<Limit> {
+        self.iter().map(Quota::limit).collect()
+    }
+}
+
+impl From<Vec<Quota>> for Quotas {
+    fn from(quotas: Vec<Quota>) -> Self {
+        Self(quotas)
+    }
+}
+
+impl FromIterator<Quota> for Quotas {
+    fn from_iter<I: IntoIterator<Item = Quota>>(iter: I) -> Self {
+        Self(iter.into_iter().collect())
+    }
+}
+
+impl<'a> IntoIterator for &'a Quotas {
+    type Item = &'a Quota;
+    type IntoIter = std::slice::Iter<'a, Quota>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+impl fmt::Display for Quotas {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, quota) in self.iter().enumerate() {
+            if i > 0 {
+                f.write_str("; ")?;
+            }
+            write!(f, "{}", quota)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Quotas {
+    type Err = QuotaError;
+
+    /// Parses quotas separated by `;`, empty ones (e.g. after a trailing `;`) are skipped.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let quotas = s
+            .split(';')
+            .filter(|quota| !quota.trim().is_empty())
+            .map(str::parse)
+            .collect::<Result<Vec<_>, _>>()?;
+        if quotas.is_empty() {
+            return Err(QuotaError::Empty);
+        }
+        Ok(Self(quotas))
+    }
+}
+
+#[cfg(feature = "serde")]
+mod serde_impl {
+    use super::*;
+
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    impl Serialize for Quota {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            serializer.collect_str(self)
+        }
+    }
+
+    impl<'de> Deserialize<'de> for Quota {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            String::deserialize(deserializer)?
+                .parse()
+                .map_err(D::Error::custom)
+        }
+    }
+
+    impl Serialize for Quotas {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            serializer.collect_str(self)
+        }
+    }
+
+    impl<'de> Deserialize<'de> for Quotas {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            String::deserialize(deserializer)?
+                .parse()
+                .map_err(D::Error::custom)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, thiserror::Error)]
+pub enum QuotaError {
+    #[error("quota is empty, expected e.g. '100/s'")]
+    Empty,
+    #[error("quota '{quota}' has no period, expected e.g. '100/s'")]
+    MissingPeriod { quota: String },
+    #[error("'{value}' is not a positive integer")]
+    InvalidNumber { value: String },
+    #[error("unknown time unit '{unit}', expected one of ns, us, ms, s, min, h, day")]
+    UnknownUnit { unit: String },
+    #[error("period '{period}' is too long")]
+    PeriodTooLong { period: String },
+    #[error("period must be positive")]
+    ZeroPeriod,
+    #[error("quota '{quota}' is too high, a permit must take at least a nanosecond")]
+    RateTooHigh { quota: String },
+    #[error("unknown option '{option}', expected 'burst=<count>'")]
+    UnknownOption { option: String },
+    #[error("option '{option}' is given several times")]
+    DuplicateOption { option: String },
+    #[error("quota '{quota}' is not a whole number of permits per second")]
+    NotPerSecond { quota: String },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let quota: Quota = "100/s burst=200".parse().unwrap();
+        assert_eq!(
+            quota,
+            Quota::new(100, Duration::from_secs(1)).with_burst(200)
+        );
+        assert_eq!(
+            "5000/day".parse::<Quota>().unwrap(),
+            Quota::new(5000, Duration::from_secs(24 * 60 * 60))
+        );
+        assert_eq!(
+            "  10/5minutes ".parse::<Quota>().unwrap(),
+            Quota::new(10, Duration::from_secs(5 * 60))
+        );
+
+        let quotas: Quotas = "10/s; 300/min;".parse().unwrap();
+        assert_eq!(
+            quotas.limits(),
+            vec![Limit::per_second(10), Limit::per_minute(300)]
+        );
+    }
+
+    #[test]
+    fn display_round_trip() {
+        for s in [
+            "100/s burst=200",
+            "5000/day",
+            "10/5min",
+            "1/90s",
+            "3/250ms",
+            "7/h burst=1",
+        ] {
+            let quota: Quota = s.parse().unwrap();
+            assert_eq!(quota.to_string(), s);
+        }
+        assert_eq!("60/60s".parse::<Quota>().unwrap().to_string(), "60/min");
+
+        let quota = Quota::new(1, Duration::from_nanos(1_500));
+        assert_eq!(quota.to_string().parse::<Quota>().unwrap(), quota);
+
+        let quotas: Quotas = "10/s;300/min burst=50".parse().unwrap();
+        assert_eq!(quotas.to_string(), "10/s; 300/min burst=50");
+        assert_eq!(quotas.to_string().parse::<Quotas>().unwrap(), quotas);
+    }
+
+    #[test]
+    fn errors() {
+        let err = |s: &str| s.parse::<Quotas>().unwrap_err().to_string();
+        assert_eq!(err(" ; "), "quota is empty, expected e.g. '100/s'");
+        assert_eq!(
+            err("100"),
+            "quota '100' has no period, expected e.g. '100/s'"
+        );
+        assert_eq!(err("0/s"), "'0' is not a positive integer");
+        assert_eq!(err("10/s; -1/min"), "'-1' is not a positive integer");
+        assert_eq!(
+            err("10/week"),
+            "unknown time unit 'week', expected one of ns, us, ms, s, min, h, day"
+        );
+        assert_eq!(
+            err("10/99999999999day"),
+            "period '99999999999day' is too long"
+        );
+        assert_eq!(
+            err("2000000000/s"),
+            "quota '2000000000/s' is too high, a permit must take at least a nanosecond"
+        );
+        assert_eq!(
+            err("10/1ns"),
+            "quota '10/ns' is too high, a permit must take at least a nanosecond"
+        );
+        assert!("1000000000/s".parse::<Quota>().is_ok());
+        assert_eq!(
+            Quota::try_new(1, Duration::ZERO),
+            Err(QuotaError::ZeroPeriod)
+        );
+        assert_eq!(
+            err("10/s burst"),
+            "unknown option 'burst', expected 'burst=<count>'"
+        );
+        assert_eq!(
+            err("10/s burst=1 burst=2"),
+            "option 'burst' is given several times"
+        );
+    }
+
+    #[test]
+    fn conversions() {
+        let quota: Quota = "6000/min burst=300".parse().unwrap();
+        assert_eq!(
+            quota.rate_profile().unwrap(),
+            RateProfile::new(100).with_capacity(300)
+        );
+        // The same rate with a capacity of 300 permits
+        assert_eq!(quota.limit(), Limit::new(300, Duration::from_secs(3)));
+
+        let quota: Quota = "5000/day".parse().unwrap();
+        assert_eq!(
+            quota.rate_profile().unwrap_err().to_string(),
+            "quota '5000/day' is not a whole number of permits per second"
+        );
+        assert_eq!(Quota::from(Limit::per_hour(10)).to_string(), "10/h");
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde() {
+        let quotas: Quotas = serde_json::from_str(r#""10/s; 300/min""#).unwrap();
+        assert_eq!(
+            serde_json::to_string(&quotas).unwrap(),
+            r#""10/s; 300/min""#
+        );
+        let err = serde_json::from_str::<Quota>(r#""10/week""#).unwrap_err();
+        assert!(err.to_string().contains("unknown time unit 'week'"));
+    }
+}
@@ -0,0 +1,1015 @@
+use crate::{
+    validate_capacity, ConfigError, InMemoryStorage, Limit, Mode, Quota, RateLimitExceededError,
+    ReconfigureError, ResizePolicy, Storage, StorageError, TokenBucketAlgorithm,
+};
+#[cfg(feature = "distributed-impl")]
+use crate::{DistributedStorage, DistributedStorageError, WhitelistStrategy};
+#[cfg(feature = "redis-impl")]
+use crate::{RedisStorage, RedisStorageError};
+
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::Instrument;
+
+/// Configuration of named limiters, usually loaded from a file.
+///
+/// # Example
+/// ```toml
+/// [limiters.search]
+/// rate = "100/s"
+/// burst = 200
+///
+/// [limiters.upload]
+/// algorithm = "overdraft"
+/// rate = "1000000/s"
+/// key_prefix = "svc:"
+/// backend = { type = "redis", url = "redis://127.0.0.1:6379" }
+///
+/// [limiters.login]
+/// rate = "10/s"
+/// backend = { type = "distributed", listen_addr = "0.0.0.0:4000", peers = ["10.0.0.2:4000"] }
+/// ```
+#[derive(Debug, Clone, Eq, PartialEq, Default, serde::Serialize, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RegistryConfig {
+    #[serde(default)]
+    pub limiters: BTreeMap<String, LimiterConfig>,
+}
+
+/// Configuration of a single limiter.
+#[derive(Debug, Clone, Eq, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LimiterConfig {
+    /// How many tokens are acquired: `n` (exactly N, default), `all` or `overdraft`.
+    #[serde(default = "default_algorithm")]
+    pub algorithm: Mode,
+    /// Rate of the bucket, a [`Quota`] string, e.g. `100/s` or `5000/day`.
+    pub rate: Quota,
+    /// Capacity of the bucket, the burst of the rate by default.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub burst: Option<u64>,
+    #[serde(default)]
+    pub backend: BackendConfig,
+    /// Prepended to the name of the limiter to get the name of its bucket in a shared backend,
+    /// e.g. `svc:` stores the bucket `upload` in redis keys `{svc:upload}:tokens` etc.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub key_prefix: String,
+}
+
+fn default_algorithm() -> Mode {
+    Mode::N
+}
+
+/// Storage of the bucket of a limiter.
+///
+/// Backends of disabled features (e.g. `redis` without `redis-impl`) fail to deserialize.
+#[derive(Debug, Clone, Eq, PartialEq, Default, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase", deny_unknown_fields)]
+pub enum BackendConfig {
+    /// [`InMemoryStorage`].
+    #[default]
+    Memory,
+    /// [`RedisStorage`].
+    #[cfg(feature = "redis-impl")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "redis-impl")))]
+    Redis {
+        url: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pool_size: Option<usize>,
+    },
+    /// [`DistributedStorage`] with [`WhitelistStrategy`].
+    #[cfg(feature = "distributed-impl")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "distributed-impl")))]
+    Distributed {
+        listen_addr: String,
+        #[serde(default)]
+        peers: Vec<String>,
+    },
+}
+
+/// Format of a config file.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum ConfigFormat {
+    Toml,
+    Yaml,
+    Json,
+}
+
+impl ConfigFormat {
+    /// Detects the format by extension of the file: `toml`, `yaml`, `yml` or `json`.
+    pub fn from_path<P>(path: P) -> Option<Self>
+    where
+        P: AsRef<Path>,
+    {
+        match path.as_ref().extension()?.to_str()? {
+            "toml" => Some(ConfigFormat::Toml),
+            "yaml" | "yml" => Some(ConfigFormat::Yaml),
+            "json" => Some(ConfigFormat::Json),
+            _ => None,
+        }
+    }
+}
+
+impl RegistryConfig {
+    /// Parses a config of the given format.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the config is malformed.
+    pub fn parse(s: &str, format: ConfigFormat) -> Result<Self, RegistryError> {
+        Ok(match format {
+            ConfigFormat::Toml => toml::from_str(s)?,
+            ConfigFormat::Yaml => serde_yaml::from_str(s)?,
+            ConfigFormat::Json => serde_json::from_str(s)?,
+        })
+    }
+
+    /// Reads a config file, the format is detected by [`ConfigFormat::from_path`].
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if failed to read the file, if its format is unknown
+    /// or if the config is malformed.
+    pub fn load<P>(path: P) -> Result<Self, RegistryError>
+    where
+        P: AsRef<Path>,
+    {
+        let path = path.as_ref();
+        let format = ConfigFormat::from_path(path).ok_or_else(|| RegistryError::UnknownFormat {
+            path: path.to_path_buf(),
+        })?;
+        let s = std::fs::read_to_string(path).map_err(|source| RegistryError::Io {
+            path: path.to_path_buf(),
+            source,
+        })?;
+        Self::parse(&s, format)
+    }
+}
+
+/// Storage of any backend of [`BackendConfig`].
+pub enum RegistryStorage {
+    Memory(InMemoryStorage),
+    #[cfg(feature = "redis-impl")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "redis-impl")))]
+    Redis(Box<RedisStorage>),
+    #[cfg(feature = "distributed-impl")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "distributed-impl")))]
+    Distributed(DistributedStorage),
+}
+
+/// Bucket of a limiter, a token is refilled every `refill_tick`.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+struct BucketProfile {
+    refill_tick: time::Duration,
+    cap: u64,
+}
+
+impl LimiterConfig {
+    /// Bucket of the limiter `name`, the quota of `count` permits per `period` refills
+    /// a token every `period / count`, so quotas like `5000/day` are kept exactly.
+    fn profile(&self, name: &str) -> Result<BucketProfile, RegistryError> {
+        let invalid = |source| RegistryError::InvalidConfig {
+            name: name.to_string(),
+            source,
+        };
+        if self.rate.count() == 0 {
+            return Err(invalid(ConfigError::ZeroRate));
+        }
+        let cap = self.burst.unwrap_or(self.rate.burst());
+        Ok(BucketProfile {
+            refill_tick: Limit::new(self.rate.count(), self.rate.period()).refill_tick(),
+            cap: validate_capacity(cap).map_err(invalid)?,
+        })
+    }
+}
+
+impl RegistryStorage {
+    /// Creates the storage of the limiter `name`.
+    ///
+    /// Storages are built with a placeholder rate of a token per second,
+    /// which is replaced by the refill tick of the profile.
+    async fn new(name: &str, config: &LimiterConfig) -> Result<Self, RegistryError> {
+        let profile = config.profile(name)?;
+        let invalid = |source| RegistryError::InvalidConfig {
+            name: name.to_string(),
+            source,
+        };
+
+        match &config.backend {
+            BackendConfig::Memory => InMemoryStorage::builder(1)
+                .with_refill_tick(profile.refill_tick)
+                .with_capacity(profile.cap)
+                .try_build()
+                .map(RegistryStorage::Memory)
+                .map_err(invalid),
+            #[cfg(feature = "redis-impl")]
+            BackendConfig::Redis { url, pool_size } => {
+                let mut builder = RedisStorage::builder(1, url)
+                    .with_bucket(format!("{}{}", config.key_prefix, name))
+                    .with_refill_tick(profile.refill_tick)
+                    .with_capacity(profile.cap);
+                if let Some(size) = pool_size {
+                    builder = builder.with_pool_size(*size);
+                }
+                builder
+                    .build()
+                    .map(|storage| RegistryStorage::Redis(Box::new(storage)))
+                    .map_err(|source| RegistryError::Redis {
+                        name: name.to_string(),
+                        source,
+                    })
+            }
+            #[cfg(feature = "distributed-impl")]
+            BackendConfig::Distributed { listen_addr, peers } => {
+                let distributed = |source| RegistryError::Distributed {
+                    name: name.to_string(),
+                    source,
+                };
+                let strategy = WhitelistStrategy::new(peers).map_err(distributed)?;
+                DistributedStorage::builder(1, listen_addr, strategy)
+                    .with_refill_tick(profile.refill_tick)
+                    .with_capacity(profile.cap)
+                    .serve()
+                    .await
+                    .map(RegistryStorage::Distributed)
+                    .map_err(distributed)
+            }
+        }
+    }
+
+    /// Changes the rate to a token per `refill_tick`, Redis buckets are rebuilt instead.
+    fn set_refill_tick(
+        &self,
+        refill_tick: time::Duration,
+    ) -> Result<(), ReconfigureError<RegistryStorageError>> {
+        match self {
+            RegistryStorage::Memory(storage) => storage
+                .set_refill_tick(refill_tick)
+                .map_err(|err| err.map(Into::into)),
+            #[cfg(feature = "redis-impl")]
+            RegistryStorage::Redis(_) => Err(ReconfigureError::Unsupported),
+            #[cfg(feature = "distributed-impl")]
+            RegistryStorage::Distributed(storage) => storage
+                .set_refill_tick(refill_tick)
+                .map_err(|err| err.map(Into::into)),
+        }
+    }
+}
+
+impl Storage for RegistryStorage {
+    type Error = RegistryStorageError;
+
+    fn try_acquire(&self, alg: TokenBucketAlgorithm, permits: u64) -> Result<(), Self::Error> {
+        match self {
+            RegistryStorage::Memory(storage) => Ok(storage.try_acquire(alg, permits)?),
+            #[cfg(feature = "redis-impl")]
+            RegistryStorage::Redis(storage) => Ok(storage.try_acquire(alg, permits)?),
+            #[cfg(feature = "distributed-impl")]
+            RegistryStorage::Distributed(storage) => Ok(storage.try_acquire(alg, permits)?),
+        }
+    }
+
+    fn release(&self, permits: u64) -> Result<(), Self::Error> {
+        match self {
+            RegistryStorage::Memory(storage) => Ok(storage.release(permits)?),
+            #[cfg(feature = "redis-impl")]
+            RegistryStorage::Redis(storage) => Ok(storage.release(permits)?),
+            #[cfg(feature = "distributed-impl")]
+            RegistryStorage::Distributed(storage) => Ok(storage.release(permits)?),
+        }
+    }
+
+    fn charge(&self, cost: u64) -> Result<(), Self::Error> {
+        match self {
+            RegistryStorage::Memory(storage) => Ok(storage.charge(cost)?),
+            #[cfg(feature = "redis-impl")]
+            RegistryStorage::Redis(storage) => Ok(storage.charge(cost)?),
+            #[cfg(feature = "distributed-impl")]
+            RegistryStorage::Distributed(storage) => Ok(storage.charge(cost)?),
+        }
+    }
+
+    fn set_rate(&self, rps_limit: u32) -> Result<(), ReconfigureError<Self::Error>> {
+        match self {
+            RegistryStorage::Memory(storage) => storage
+                .set_rate(rps_limit)
+                .map_err(|err| err.map(Into::into)),
+            #[cfg(feature = "redis-impl")]
+            RegistryStorage::Redis(storage) => storage
+                .set_rate(rps_limit)
+                .map_err(|err| err.map(Into::into)),
+            #[cfg(feature = "distributed-impl")]
+            RegistryStorage::Distributed(storage) => storage
+                .set_rate(rps_limit)
+                .map_err(|err| err.map(Into::into)),
+        }
+    }
+
+    fn set_capacity(
+        &self,
+        cap: u64,
+        policy: ResizePolicy,
+    ) -> Result<(), ReconfigureError<Self::Error>> {
+        match self {
+            RegistryStorage::Memory(storage) => storage
+                .set_capacity(cap, policy)
+                .map_err(|err| err.map(Into::into)),
+            #[cfg(feature = "redis-impl")]
+            RegistryStorage::Redis(storage) => storage
+                .set_capacity(cap, policy)
+                .map_err(|err| err.map(Into::into)),
+            #[cfg(feature = "distributed-impl")]
+            RegistryStorage::Distributed(storage) => storage
+                .set_capacity(cap, policy)
+                .map_err(|err| err.map(Into::into)),
+        }
+    }
+}
+
+/// A named limiter of a [`LimiterRegistry`].
+pub struct Limiter {
+    name: String,
+    config: parking_lot::RwLock<LimiterConfig>,
+    storage: RegistryStorage,
+}
+
+impl Limiter {
+    async fn new(name: &str, config: &LimiterConfig) -> Result<Self, RegistryError> {
+        Ok(Self {
+            name: name.to_string(),
+            config: parking_lot::RwLock::new(config.clone()),
+            storage: RegistryStorage::new(name, config).await?,
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the current config, it may be changed by [`LimiterRegistry::reload`].
+    pub fn config(&self) -> LimiterConfig {
+        self.config.read().clone()
+    }
+
+    pub fn storage(&self) -> &RegistryStorage {
+        &self.storage
+    }
+
+    /// Tries to acquire N tokens with the configured algorithm.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if there are not enough tokens or if the storage could not save/load state.
+    pub fn try_acquire(&self, permits: u64) -> Result<(), RegistryStorageError> {
+        let alg = TokenBucketAlgorithm::new(self.config.read().algorithm);
+        self.storage.try_acquire(alg, permits)
+    }
+
+    /// Tries to acquire 1 token with the configured algorithm.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if there are not enough tokens or if the storage could not save/load state.
+    pub fn try_acquire_one(&self) -> Result<(), RegistryStorageError> {
+        self.try_acquire(1)
+    }
+
+    /// Takes `cost` tokens regardless of the balance, see [`TokenBucket::charge`](crate::TokenBucket::charge).
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the storage could not save/load state.
+    pub fn charge(&self, cost: u64) -> Result<(), RegistryStorageError> {
+        self.storage.charge(cost)
+    }
+
+    /// Returns `true` if the storage can take `config` in place, keeping its bucket.
+    ///
+    /// Redis buckets are rebuilt instead, their state is kept in redis anyway.
+    fn is_reconfigurable(&self, config: &LimiterConfig) -> bool {
+        if self.config.read().backend != config.backend {
+            return false;
+        }
+        match config.backend {
+            BackendConfig::Memory => true,
+            #[cfg(feature = "redis-impl")]
+            BackendConfig::Redis { .. } => false,
+            #[cfg(feature = "distributed-impl")]
+            BackendConfig::Distributed { .. } => true,
+        }
+    }
+
+    /// Changes rate and capacity of the bucket in place, available tokens are clamped.
+    /// The config of the limiter is left to the caller.
+    fn reconfigure(&self, profile: BucketProfile) -> Result<(), RegistryError> {
+        self.storage
+            .set_refill_tick(profile.refill_tick)
+            .and_then(|_| self.storage.set_capacity(profile.cap, ResizePolicy::Clamp))
+            .map_err(|source| RegistryError::Reconfigure {
+                name: self.name.clone(),
+                source,
+            })
+    }
+}
+
+/// Names of limiters changed by [`LimiterRegistry::reload`].
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct ReloadReport {
+    pub added: Vec<String>,
+    pub changed: Vec<String>,
+    pub removed: Vec<String>,
+}
+
+impl ReloadReport {
+    /// Returns `true` if the config hasn't changed.
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.changed.is_empty() && self.removed.is_empty()
+    }
+}
+
+/// Named limiters built from a [`RegistryConfig`], e.g. from a TOML, YAML or JSON file.
+///
+/// Limiters can be changed at runtime by [`reload`](LimiterRegistry::reload) or by
+/// [`watch`](LimiterRegistry::watch) of the config file, so look them up on every request
+/// rather than keeping them.
+///
+/// # Example
+/// ```
+/// use tocket::{ConfigFormat, LimiterRegistry, RegistryConfig};
+///
+/// # tokio::runtime::Runtime::new().unwrap().block_on(async {
+/// let config = RegistryConfig::parse(
+///     r#"
+///     [limiters.search]
+///     rate = "100/s burst=200"
+///
+///     [limiters.upload]
+///     rate = "10/s"
+///     "#,
+///     ConfigFormat::Toml,
+/// )
+/// .unwrap();
+/// let registry = LimiterRegistry::from_config(&config).await.unwrap();
+///
+/// let search = registry.get("search").unwrap();
+/// assert!(search.try_acquire(200).is_ok());
+/// assert!(search.try_acquire_one().is_err());
+/// # });
+/// ```
+pub struct LimiterRegistry {
+    limiters: parking_lot::RwLock<HashMap<String, Arc<Limiter>>>,
+    reloading: tokio::sync::Mutex<()>,
+}
+
+impl LimiterRegistry {
+    /// Builds all limiters of the config.
+    ///
+    /// Distributed limiters start their background tasks, so they must be built
+    /// within a tokio runtime.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if any limiter is invalid or its storage failed to start.
+    pub async fn from_config(config: &RegistryConfig) -> Result<Self, RegistryError> {
+        let registry = Self {
+            limiters: Default::default(),
+            reloading: Default::default(),
+        };
+        registry.reload(config).await?;
+        Ok(registry)
+    }
+
+    /// Reads the config file and builds all its limiters, see [`RegistryConfig::load`].
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if failed to read the config or to build any limiter.
+    pub async fn load<P>(path: P) -> Result<Self, RegistryError>
+    where
+        P: AsRef<Path>,
+    {
+        Self::from_config(&RegistryConfig::load(path)?).await
+    }
+
+    /// Returns the limiter of the given name.
+    pub fn get(&self, name: &str) -> Option<Arc<Limiter>> {
+        self.limiters.read().get(name).cloned()
+    }
+
+    /// Returns names of all limiters.
+    pub fn names(&self) -> Vec<String> {
+        self.limiters.read().keys().cloned().collect()
+    }
+
+    /// Applies a new config: adds new limiters, changes existing ones and removes missing ones.
+    ///
+    /// Limiters with unchanged config are kept as is. Changed in-memory and distributed limiters
+    /// keep their buckets, only rate and capacity are changed (available tokens are clamped).
+    /// Other changed limiters are built again, redis buckets keep their state in redis.
+    ///
+    /// The config is applied atomically: if any limiter fails to build or to be reconfigured,
+    /// nothing is changed and limiters reconfigured up to the failure get their old rates back.
+    /// A distributed limiter with changed listen address or peers can't be built while
+    /// the old one is running on the same address.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if any limiter is invalid, its storage failed to start
+    /// or failed to be reconfigured.
+    pub async fn reload(&self, config: &RegistryConfig) -> Result<ReloadReport, RegistryError> {
+        let _reloading = self.reloading.lock().await;
+        let current = self.limiters.read().clone();
+
+        let mut report = ReloadReport::default();
+        let mut limiters = HashMap::with_capacity(config.limiters.len());
+        let mut reconfigured = Vec::new();
+        let mut configs = Vec::new();
+        for (name, config) in &config.limiters {
+            let limiter = match current.get(name) {
+                Some(limiter) if *limiter.config.read() == *config => Arc::clone(limiter),
+                Some(limiter) if limiter.is_reconfigurable(config) => {
+                    let old = limiter.config.read().profile(name)?;
+                    reconfigured.push((Arc::clone(limiter), old, config.profile(name)?));
+                    configs.push((Arc::clone(limiter), config.clone()));
+                    report.changed.push(name.clone());
+                    Arc::clone(limiter)
+                }
+                Some(_) => {
+                    report.changed.push(name.clone());
+                    Arc::new(Limiter::new(name, config).await?)
+                }
+                None => {
+                    report.added.push(name.clone());
+                    Arc::new(Limiter::new(name, config).await?)
+                }
+            };
+            limiters.insert(name.clone(), limiter);
+        }
+        report.removed = current
+            .keys()
+            .filter(|name| !limiters.contains_key(*name))
+            .cloned()
+            .collect();
+        report.removed.sort_unstable();
+
+        let mut current = self.limiters.write();
+        reconfigure_all(&reconfigured, |limiter, profile| {
+            limiter.reconfigure(profile)
+        })?;
+        // Configs are changed only when all buckets are reconfigured
+        for (limiter, config) in configs {
+            *limiter.config.write() = config;
+        }
+        *current = limiters;
+        Ok(report)
+    }
+
+    /// Reloads the config file every time its content changes, the file is checked every `interval`.
+    ///
+    /// Results of reloads are reported by `tracing`, a broken config is skipped until
+    /// it's changed again. Watching stops when the returned watcher is dropped.
+    /// Must be called within a tokio runtime.
+    ///
+    /// # Example
+    /// ```no_run
+    /// use std::sync::Arc;
+    /// use std::time::Duration;
+    /// use tocket::LimiterRegistry;
+    ///
+    /// # async fn run() {
+    /// let registry = Arc::new(LimiterRegistry::load("limits.toml").await.unwrap());
+    /// let _watcher = registry.watch("limits.toml", Duration::from_secs(5));
+    /// # }
+    /// ```
+    pub fn watch<P>(self: &Arc<Self>, path: P, interval: Duration) -> RegistryWatcher
+    where
+        P: AsRef<Path>,
+    {
+        let registry = Arc::clone(self);
+        let path = path.as_ref().to_path_buf();
+        let task = tokio::spawn(
+            async move {
+                let mut interval = tokio::time::interval(interval);
+                interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+                let mut last = None;
+                loop {
+                    interval.tick().await;
+                    let content = tokio::fs::read_to_string(&path)
+                        .await
+                        .map_err(|err| err.to_string());
+                    if last.as_ref() == Some(&content) {
+                        continue;
+                    }
+                    last = Some(content.clone());
+
+                    match registry.reload_content(&path, content).await {
+                        Ok(report) if report.is_empty() => {}
+                        Ok(report) => tracing::info!(
+                            "config '{}' reloaded: added {:?}, changed {:?}, removed {:?}",
+                            path.display(),
+                            report.added,
+                            report.changed,
+                            report.removed
+                        ),
+                        Err(err) => {
+                            tracing::error!("reloading config '{}' failed: {}", path.display(), err)
+                        }
+                    }
+                }
+            }
+            .instrument(tracing::Span::current()),
+        );
+        RegistryWatcher { task }
+    }
+
+    async fn reload_content(
+        &self,
+        path: &Path,
+        content: Result<String, String>,
+    ) -> Result<ReloadReport, RegistryError> {
+        let content = content.map_err(|err| RegistryError::Io {
+            path: path.to_path_buf(),
+            source: std::io::Error::other(err),
+        })?;
+        let format = ConfigFormat::from_path(path).ok_or_else(|| RegistryError::UnknownFormat {
+            path: path.to_path_buf(),
+        })?;
+        self.reload(&RegistryConfig::parse(&content, format)?).await
+    }
+}
+
+/// Changes buckets of limiters from old profiles to new ones in order. If any of them fails,
+/// buckets changed so far get their old profiles back, including the failed one, which
+/// may have got its new rate before its capacity failed.
+fn reconfigure_all<L>(
+    changes: &[(L, BucketProfile, BucketProfile)],
+    mut reconfigure: impl FnMut(&L, BucketProfile) -> Result<(), RegistryError>,
+) -> Result<(), RegistryError> {
+    for (i, (limiter, _, new)) in changes.iter().enumerate() {
+        if let Err(err) = reconfigure(limiter, *new) {
+            for (limiter, old, _) in changes[..=i].iter().rev() {
+                if let Err(err) = reconfigure(limiter, *old) {
+                    tracing::error!("rolling back failed: {}", err);
+                }
+            }
+            return Err(err);
+        }
+    }
+    Ok(())
+}
+
+/// Watcher of a config file started by [`LimiterRegistry::watch`], stops when dropped.
+pub struct RegistryWatcher {
+    task: tokio::task::JoinHandle<()>,
+}
+
+impl Drop for RegistryWatcher {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum RegistryStorageError {
+    #[error(transparent)]
+    RateLimitExceededError(#[from] RateLimitExceededError),
+    #[cfg(feature = "redis-impl")]
+    #[error(transparent)]
+    RedisStorageError(#[from] RedisStorageError),
+    #[cfg(feature = "distributed-impl")]
+    #[error(transparent)]
+    DistributedStorageError(#[from] DistributedStorageError),
+}
+
+impl StorageError for RegistryStorageError {
+    fn is_rate_limit_exceeded(&self) -> bool {
+        match self {
+            RegistryStorageError::RateLimitExceededError(_) => true,
+            #[cfg(feature = "redis-impl")]
+            RegistryStorageError::RedisStorageError(err) => err.is_rate_limit_exceeded(),
+            #[cfg(feature = "distributed-impl")]
+            RegistryStorageError::DistributedStorageError(err) => err.is_rate_limit_exceeded(),
+        }
+    }
+
+    fn is_capacity_exceeded(&self) -> bool {
+        match self {
+            RegistryStorageError::RateLimitExceededError(err) => err.is_capacity_exceeded(),
+            #[cfg(feature = "redis-impl")]
+            RegistryStorageError::RedisStorageError(err) => err.is_capacity_exceeded(),
+            #[cfg(feature = "distributed-impl")]
+            RegistryStorageError::DistributedStorageError(err) => err.is_capacity_exceeded(),
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum RegistryError {
+    #[error("reading config '{path}' failed: {source}")]
+    Io {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[error("unknown format of config '{path}', expected .toml, .yaml, .yml or .json")]
+    UnknownFormat { path: PathBuf },
+    #[error(transparent)]
+    TomlError(#[from] toml::de::Error),
+    #[error(transparent)]
+    YamlError(#[from] serde_yaml::Error),
+    #[error(transparent)]
+    JsonError(#[from] serde_json::Error),
+    #[error("invalid config of limiter '{name}': {source}")]
+    InvalidConfig { name: String, source: ConfigError },
+    #[error("reconfiguration of limiter '{name}' failed: {source}")]
+    Reconfigure {
+        name: String,
+        source: ReconfigureError<RegistryStorageError>,
+    },
+    #[cfg(feature = "redis-impl")]
+    #[error("redis storage of limiter '{name}' failed: {source}")]
+    Redis {
+        name: String,
+        source: RedisStorageError,
+    },
+    #[cfg(feature = "distributed-impl")]
+    #[error("distributed storage of limiter '{name}' failed: {source}")]
+    Distributed {
+        name: String,
+        source: DistributedStorageError,
+    },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TOML: &str = r#"
+        [limiters.search]
+        rate = "100/s"
+        burst = 200
+
+        [limiters.upload]
+        algorithm = "overdraft"
+        rate = "10/s"
+    "#;
+
+    #[test]
+    fn parse() {
+        let toml = RegistryConfig::parse(TOML, ConfigFormat::Toml).unwrap();
+        let yaml = RegistryConfig::parse(
+            r#"
+            limiters:
+              search:
+                rate: 100/s
+                burst: 200
+              upload:
+                algorithm: overdraft
+                rate: 10/s
+                backend:
+                  type: memory
+            "#,
+            ConfigFormat::Yaml,
+        )
+        .unwrap();
+        let json = RegistryConfig::parse(
+            r#"{"limiters": {
+                "search": {"rate": "100/s", "burst": 200},
+                "upload": {"rate": "10/s", "algorithm": "overdraft"}
+            }}"#,
+            ConfigFormat::Json,
+        )
+        .unwrap();
+        assert_eq!(toml, yaml);
+        assert_eq!(toml, json);
+
+        let search = &toml.limiters["search"];
+        assert_eq!(search.algorithm, Mode::N);
+        assert_eq!(search.backend, BackendConfig::Memory);
+        assert_eq!(search.burst, Some(200));
+
+        let err = RegistryConfig::parse("[limiters.a]\nrate = \"10/week\"", ConfigFormat::Toml)
+            .unwrap_err();
+        assert!(err.to_string().contains("unknown time unit 'week'"));
+        assert_eq!(
+            ConfigFormat::from_path("limits.yml"),
+            Some(ConfigFormat::Yaml)
+        );
+        assert!(matches!(
+            RegistryConfig::load("limits.ini"),
+            Err(RegistryError::UnknownFormat { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn from_config() {
+        let config = RegistryConfig::parse(TOML, ConfigFormat::Toml).unwrap();
+        let registry = LimiterRegistry::from_config(&config).await.unwrap();
+        let mut names = registry.names();
+        names.sort_unstable();
+        assert_eq!(names, ["search", "upload"]);
+        assert!(registry.get("unknown").is_none());
+
+        let search = registry.get("search").unwrap();
+        assert!(search.try_acquire(200).is_ok());
+        assert!(search.try_acquire_one().is_err());
+
+        let upload = registry.get("upload").unwrap();
+        assert!(upload.try_acquire(100).is_ok());
+        assert!(upload.try_acquire_one().is_err());
+
+        let config = RegistryConfig::parse(
+            "[limiters.empty]\nrate = \"10/s\"\nburst = 0",
+            ConfigFormat::Toml,
+        )
+        .unwrap();
+        let err = LimiterRegistry::from_config(&config).await.err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            "invalid config of limiter 'empty': capacity must be positive"
+        );
+    }
+
+    #[tokio::test]
+    async fn not_per_second() {
+        let refill_tick = |limiter: &Limiter| match limiter.storage() {
+            RegistryStorage::Memory(storage) => storage.snapshot().refill_tick,
+            #[allow(unreachable_patterns)]
+            _ => unreachable!(),
+        };
+        let config =
+            RegistryConfig::parse("[limiters.daily]\nrate = \"5000/day\"", ConfigFormat::Toml)
+                .unwrap();
+        let registry = LimiterRegistry::from_config(&config).await.unwrap();
+        let daily = registry.get("daily").unwrap();
+        assert_eq!(refill_tick(&daily), time::Duration::milliseconds(17_280));
+        assert!(daily.try_acquire(5000).is_ok());
+        assert!(daily.try_acquire_one().is_err());
+
+        // Changed in place
+        let config = RegistryConfig::parse(
+            "[limiters.daily]\nrate = \"60/min\"\nburst = 10",
+            ConfigFormat::Toml,
+        )
+        .unwrap();
+        assert_eq!(registry.reload(&config).await.unwrap().changed, ["daily"]);
+        assert!(Arc::ptr_eq(&daily, &registry.get("daily").unwrap()));
+        assert_eq!(refill_tick(&daily), time::Duration::seconds(1));
+        assert!(daily.try_acquire(11).unwrap_err().is_capacity_exceeded());
+    }
+
+    #[tokio::test]
+    async fn reload() {
+        let config = RegistryConfig::parse(TOML, ConfigFormat::Toml).unwrap();
+        let registry = LimiterRegistry::from_config(&config).await.unwrap();
+        let search = registry.get("search").unwrap();
+        let upload = registry.get("upload").unwrap();
+        assert!(search.try_acquire(150).is_ok());
+
+        let config = RegistryConfig::parse(
+            r#"
+            [limiters.search]
+            algorithm = "all"
+            rate = "1000/s"
+
+            [limiters.upload]
+            algorithm = "overdraft"
+            rate = "10/s"
+
+            [limiters.login]
+            rate = "1/s"
+            "#,
+            ConfigFormat::Toml,
+        )
+        .unwrap();
+        let report = registry.reload(&config).await.unwrap();
+        assert_eq!(report.added, ["login"]);
+        assert_eq!(report.changed, ["search"]);
+        assert!(report.removed.is_empty());
+
+        // The bucket is kept with its tokens, only the rate and the algorithm are changed
+        assert!(Arc::ptr_eq(&search, &registry.get("search").unwrap()));
+        assert!(Arc::ptr_eq(&upload, &registry.get("upload").unwrap()));
+        assert_eq!(search.config().algorithm, Mode::All);
+        let alg = TokenBucketAlgorithm::new(Mode::N);
+        assert!(search.storage().try_acquire(alg, 1000).is_err());
+        assert!(search.try_acquire(1000).is_ok());
+        assert!(search.storage().try_acquire(alg, 100).is_err());
+
+        // Nothing is applied if any limiter is invalid
+        let config = RegistryConfig::parse(
+            "[limiters.search]\nrate = \"5/s\"\n[limiters.empty]\nrate = \"5/s\"\nburst = 0",
+            ConfigFormat::Toml,
+        )
+        .unwrap();
+        assert!(registry.reload(&config).await.is_err());
+        assert_eq!(registry.names().len(), 3);
+        assert_eq!(search.config().rate, "1000/s".parse().unwrap());
+
+        let config =
+            RegistryConfig::parse("[limiters.login]\nrate = \"1/s\"", ConfigFormat::Toml).unwrap();
+        let report = registry.reload(&config).await.unwrap();
+        assert_eq!(report.removed, ["search", "upload"]);
+        assert!(registry.get("search").is_none());
+    }
+
+    #[test]
+    fn rollback() {
+        let profile = |cap| BucketProfile {
+            refill_tick: time::Duration::seconds(1),
+            cap,
+        };
+        let changes = [
+            ("a", profile(1), profile(2)),
+            ("b", profile(1), profile(3)),
+            ("c", profile(1), profile(4)),
+        ];
+        let mut calls = Vec::new();
+        let res = reconfigure_all(&changes, |name, profile| {
+            calls.push((*name, profile.cap));
+            match profile.cap {
+                3 => Err(RegistryError::InvalidConfig {
+                    name: name.to_string(),
+                    source: ConfigError::ZeroCapacity,
+                }),
+                _ => Ok(()),
+            }
+        });
+        assert!(res.is_err());
+        // The failed limiter may have changed its rate, so it's rolled back too
+        assert_eq!(calls, [("a", 2), ("b", 3), ("b", 1), ("a", 1)]);
+    }
+
+    #[tokio::test]
+    async fn watch() {
+        let path = std::env::temp_dir().join(format!("tocket-{}.toml", uuid::Uuid::new_v4()));
+        std::fs::write(&path, TOML).unwrap();
+        let registry = Arc::new(LimiterRegistry::load(&path).await.unwrap());
+        let watcher = registry.watch(&path, Duration::from_millis(20));
+
+        std::fs::write(&path, "[limiters.search]\nrate = \"100/s\"\nburst = 200\n").unwrap();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(registry.names(), ["search"]);
+
+        // A broken config is skipped
+        std::fs::write(&path, "[limiters.search]\nrate = 100").unwrap();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(registry.names(), ["search"]);
+
+        drop(watcher);
+        std::fs::write(&path, TOML).unwrap();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(registry.names(), ["search"]);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(feature = "redis-impl")]
+    #[tokio::test]
+    #[ignore = "needs a Redis server at REDIS_HOST"]
+    async fn redis() {
+        use crate::in_redis::tests::redis_host;
+
+        let url = redis_host();
+        let prefix = format!("svc:{}:", uuid::Uuid::new_v4());
+        let config = format!(
+            r#"
+            [limiters.search]
+            rate = "10/s"
+            key_prefix = "{}"
+            backend = {{ type = "redis", url = "{}", pool_size = 2 }}
+            "#,
+            prefix, url
+        );
+        let config = RegistryConfig::parse(&config, ConfigFormat::Toml).unwrap();
+        let registry = LimiterRegistry::from_config(&config).await.unwrap();
+        let search = registry.get("search").unwrap();
+        assert!(search.try_acquire(4).is_ok());
+
+        let mut conn = redis::Client::open(url).unwrap().get_connection().unwrap();
+        let tokens: String = redis::cmd("GET")
+            .arg(format!("{{{}search}}:tokens", prefix))
+            .query(&mut conn)
+            .unwrap();
+        assert_eq!(tokens, "6");
+    }
+
+    #[cfg(feature = "distributed-impl")]
+    #[tokio::test]
+    async fn distributed() {
+        let config = RegistryConfig::parse(
+            r#"
+            [limiters.login]
+            rate = "2/s"
+            backend = { type = "distributed", listen_addr = "127.0.0.1:0" }
+            "#,
+            ConfigFormat::Toml,
+        )
+        .unwrap();
+        let registry = LimiterRegistry::from_config(&config).await.unwrap();
+        let login = registry.get("login").unwrap();
+        assert!(matches!(login.storage(), RegistryStorage::Distributed(_)));
+        assert!(login.try_acquire(2).is_ok());
+        assert!(login.try_acquire_one().is_err());
+    }
+}
@@ -0,0 +1,422 @@
+use crate::{KeyedStorage, KeyedTokenBucket, RateProfile, StorageError};
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// What is done with requests of keys matching a rule.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub enum KeyAction {
+    /// Requests are never limited, e.g. of internal health checks.
+    Allow,
+    /// Requests are always denied, e.g. of known abusers.
+    Deny,
+    /// Requests are limited by a custom quota instead of the default one.
+    Limit(RateProfile),
+}
+
+/// Rule applied to a request of a key.
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub enum AppliedRule {
+    /// No rule matches the key, the default quota is used.
+    Default,
+    /// The rule of exactly this key.
+    Exact { key: String, action: KeyAction },
+    /// The rule of the longest prefix of the key.
+    Prefix { prefix: String, action: KeyAction },
+}
+
+impl AppliedRule {
+    /// Action of the rule, `None` for the default quota.
+    pub fn action(&self) -> Option<KeyAction> {
+        match self {
+            AppliedRule::Default => None,
+            AppliedRule::Exact { action, .. } | AppliedRule::Prefix { action, .. } => Some(*action),
+        }
+    }
+}
+
+/// Per-key rules: exact keys and key prefixes with their actions.
+///
+/// The most specific rule wins: the rule of the exact key, then the rule of the longest
+/// matching prefix. So an exact rule can deny a single key of an allowed prefix
+/// and vice versa.
+///
+/// # Example
+/// ```
+/// use tocket::{AppliedRule, KeyAction, KeyRules, RateProfile};
+///
+/// let rules = KeyRules::new()
+///     .with_exact("healthcheck", KeyAction::Allow)
+///     .with_exact("user:mallory", KeyAction::Deny)
+///     .with_prefix("tenant:acme:", KeyAction::Limit(RateProfile::new(1000)));
+///
+/// assert_eq!(rules.resolve("user:alice"), AppliedRule::Default);
+/// assert_eq!(
+///     rules.resolve("tenant:acme:bob"),
+///     AppliedRule::Prefix {
+///         prefix: "tenant:acme:".to_string(),
+///         action: KeyAction::Limit(RateProfile::new(1000)),
+///     }
+/// );
+/// ```
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct KeyRules {
+    exact: HashMap<String, KeyAction>,
+    prefixes: Vec<(String, KeyAction)>,
+}
+
+impl KeyRules {
+    /// Creates rules that don't match any key.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds or replaces the rule of exactly this key.
+    pub fn with_exact<K>(mut self, key: K, action: KeyAction) -> Self
+    where
+        K: Into<String>,
+    {
+        self.exact.insert(key.into(), action);
+        self
+    }
+
+    /// Adds or replaces the rule of keys starting with `prefix`.
+    pub fn with_prefix<P>(mut self, prefix: P, action: KeyAction) -> Self
+    where
+        P: Into<String>,
+    {
+        let prefix = prefix.into();
+        match self.prefixes.iter_mut().find(|(p, _)| *p == prefix) {
+            Some((_, current)) => *current = action,
+            None => self.prefixes.push((prefix, action)),
+        }
+        self
+    }
+
+    /// Returns the rule applied to requests of the key.
+    pub fn resolve(&self, key: &str) -> AppliedRule {
+        if let Some((key, action)) = self.exact.get_key_value(key) {
+            return AppliedRule::Exact {
+                key: key.clone(),
+                action: *action,
+            };
+        }
+        self.prefixes
+            .iter()
+            .filter(|(prefix, _)| key.starts_with(prefix.as_str()))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map_or(AppliedRule::Default, |(prefix, action)| {
+                AppliedRule::Prefix {
+                    prefix: prefix.clone(),
+                    action: *action,
+                }
+            })
+    }
+
+    /// Custom quotas of all rules.
+    fn profiles(&self) -> impl Iterator<Item = RateProfile> + '_ {
+        self.exact
+            .values()
+            .chain(self.prefixes.iter().map(|(_, action)| action))
+            .filter_map(|action| match action {
+                KeyAction::Limit(profile) => Some(*profile),
+                KeyAction::Allow | KeyAction::Deny => None,
+            })
+    }
+}
+
+type StorageFactory<S> = dyn Fn(&RateProfile) -> S + Send + Sync;
+
+/// Keyed rate limiter with per-key [`KeyRules`], e.g. to bypass health checks,
+/// block abusers and give custom quotas to some tenants.
+///
+/// Keys without a rule use the default storage. Keys with a custom quota use a separate
+/// storage of that quota, created by the factory when the quota is used first.
+/// Rules can be replaced at runtime by [`set_rules`](RuledTokenBucket::set_rules),
+/// buckets of keys whose quota has changed start anew.
+///
+/// # Example
+/// ```
+/// use tocket::{AppliedRule, KeyAction, KeyRules, KeyedInMemoryStorage, RateProfile, RuledTokenBucket};
+///
+/// let rules = KeyRules::new()
+///     .with_exact("healthcheck", KeyAction::Allow)
+///     .with_prefix("tenant:acme:", KeyAction::Limit(RateProfile::new(100)));
+/// let tb = RuledTokenBucket::new(KeyedInMemoryStorage::new(10), rules, |profile| {
+///     KeyedInMemoryStorage::builder(profile.rps_limit())
+///         .with_capacity(profile.cap())
+///         .build()
+/// });
+///
+/// assert_eq!(tb.try_acquire("user:alice", 10).unwrap(), AppliedRule::Default);
+/// assert!(tb.try_acquire("tenant:acme:bob", 100).is_ok());
+/// assert!(tb.try_acquire("healthcheck", 1_000_000).is_ok());
+///
+/// tb.set_rules(KeyRules::new().with_exact("healthcheck", KeyAction::Deny));
+/// assert!(tb.try_acquire_one("healthcheck").unwrap_err().is_denied());
+/// ```
+pub struct RuledTokenBucket<S> {
+    default: KeyedTokenBucket<S>,
+    rules: parking_lot::RwLock<Arc<KeyRules>>,
+    custom: parking_lot::RwLock<HashMap<RateProfile, Arc<KeyedTokenBucket<S>>>>,
+    factory: Box<StorageFactory<S>>,
+}
+
+impl<S> RuledTokenBucket<S>
+where
+    S: KeyedStorage<Key = str>,
+{
+    /// Creates a rate limiter with the default storage, rules and the factory
+    /// of storages of custom quotas.
+    pub fn new<F>(storage: S, rules: KeyRules, factory: F) -> Self
+    where
+        F: Fn(&RateProfile) -> S + Send + Sync + 'static,
+    {
+        Self {
+            default: KeyedTokenBucket::new(storage),
+            rules: parking_lot::RwLock::new(Arc::new(rules)),
+            custom: Default::default(),
+            factory: Box::new(factory),
+        }
+    }
+
+    /// Returns the default storage.
+    pub fn storage(&self) -> &S {
+        self.default.storage()
+    }
+
+    /// Returns the current rules.
+    pub fn rules(&self) -> Arc<KeyRules> {
+        Arc::clone(&self.rules.read())
+    }
+
+    /// Replaces the rules, storages of quotas that are no longer used are dropped.
+    pub fn set_rules(&self, rules: KeyRules) {
+        let mut current = self.rules.write();
+        self.custom
+            .write()
+            .retain(|profile, _| rules.profiles().any(|p| p == *profile));
+        tracing::info!("key rules replaced");
+        *current = Arc::new(rules);
+    }
+
+    /// Returns the rule applied to requests of the key.
+    pub fn resolve(&self, key: &str) -> AppliedRule {
+        self.rules.read().resolve(key)
+    }
+
+    /// Tries to acquire N tokens from the bucket of the key according to its rule.
+    ///
+    /// Returns the applied rule.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the key is denied, if there are not enough tokens
+    /// or if the storage could not save/load state.
+    pub fn try_acquire(&self, key: &str, permits: u64) -> Result<AppliedRule, RuleError<S::Error>> {
+        self.apply(key, |tb| tb.try_acquire(key, permits))
+    }
+
+    /// Tries to acquire 1 token from the bucket of the key according to its rule.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the key is denied, if there are not enough tokens
+    /// or if the storage could not save/load state.
+    pub fn try_acquire_one(&self, key: &str) -> Result<AppliedRule, RuleError<S::Error>> {
+        self.try_acquire(key, 1)
+    }
+
+    /// Takes `cost` tokens from the bucket of the key regardless of its balance.
+    /// Allowed and denied keys aren't charged.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the key is denied or if the storage could not save/load state.
+    pub fn charge(&self, key: &str, cost: u64) -> Result<AppliedRule, RuleError<S::Error>> {
+        self.apply(key, |tb| tb.charge(key, cost))
+    }
+
+    /// Resolves the rule of the key and calls `f` with the bucket of its quota.
+    fn apply<F>(&self, key: &str, f: F) -> Result<AppliedRule, RuleError<S::Error>>
+    where
+        F: FnOnce(&KeyedTokenBucket<S>) -> Result<(), S::Error>,
+    {
+        let rule = self.resolve(key);
+        let res = match rule.action() {
+            None => f(&self.default),
+            Some(KeyAction::Allow) => Ok(()),
+            Some(KeyAction::Deny) => return Err(RuleError::Denied { rule }),
+            Some(KeyAction::Limit(profile)) => f(&self.custom(profile)),
+        };
+        match res {
+            Ok(()) => Ok(rule),
+            Err(source) => Err(RuleError::Storage { rule, source }),
+        }
+    }
+
+    /// Returns the bucket of the custom quota, creating it on first use.
+    fn custom(&self, profile: RateProfile) -> Arc<KeyedTokenBucket<S>> {
+        if let Some(tb) = self.custom.read().get(&profile) {
+            return Arc::clone(tb);
+        }
+        let mut custom = self.custom.write();
+        let tb = custom
+            .entry(profile)
+            .or_insert_with(|| Arc::new(KeyedTokenBucket::new((self.factory)(&profile))));
+        Arc::clone(tb)
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum RuleError<E> {
+    #[error("key is denied by rule {rule:?}")]
+    Denied { rule: AppliedRule },
+    #[error("{source} (rule {rule:?})")]
+    Storage { rule: AppliedRule, source: E },
+}
+
+impl<E> RuleError<E> {
+    /// Returns the rule applied to the request.
+    pub fn rule(&self) -> &AppliedRule {
+        match self {
+            RuleError::Denied { rule } | RuleError::Storage { rule, .. } => rule,
+        }
+    }
+
+    /// Returns `true` if the key is denied by a rule.
+    pub fn is_denied(&self) -> bool {
+        matches!(self, RuleError::Denied { .. })
+    }
+}
+
+impl<E> StorageError for RuleError<E>
+where
+    E: StorageError,
+{
+    fn is_rate_limit_exceeded(&self) -> bool {
+        match self {
+            RuleError::Denied { .. } => true,
+            RuleError::Storage { source, .. } => source.is_rate_limit_exceeded(),
+        }
+    }
+
+    fn is_capacity_exceeded(&self) -> bool {
+        match self {
+            RuleError::Denied { .. } => false,
+            RuleError::Storage { source, .. } => source.is_capacity_exceeded(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::KeyedInMemoryStorage;
+
+    fn limiter(rules: KeyRules) -> RuledTokenBucket<KeyedInMemoryStorage> {
+        RuledTokenBucket::new(KeyedInMemoryStorage::new(10), rules, |profile| {
+            KeyedInMemoryStorage::builder(profile.rps_limit())
+                .with_capacity(profile.cap())
+                .build()
+        })
+    }
+
+    #[test]
+    fn resolve() {
+        let enterprise = KeyAction::Limit(RateProfile::new(1000));
+        let rules = KeyRules::new()
+            .with_prefix("tenant:", KeyAction::Deny)
+            .with_prefix("tenant:acme:", enterprise)
+            .with_exact("tenant:acme:mallory", KeyAction::Deny)
+            .with_exact("tenant:health", KeyAction::Allow);
+
+        assert_eq!(rules.resolve("user:1"), AppliedRule::Default);
+        assert_eq!(
+            rules.resolve("tenant:other:1").action(),
+            Some(KeyAction::Deny)
+        );
+        assert_eq!(
+            rules.resolve("tenant:acme:bob"),
+            AppliedRule::Prefix {
+                prefix: "tenant:acme:".to_string(),
+                action: enterprise
+            }
+        );
+        assert_eq!(
+            rules.resolve("tenant:acme:mallory"),
+            AppliedRule::Exact {
+                key: "tenant:acme:mallory".to_string(),
+                action: KeyAction::Deny
+            }
+        );
+        assert_eq!(
+            rules.resolve("tenant:health").action(),
+            Some(KeyAction::Allow)
+        );
+
+        // A repeated prefix replaces the rule
+        let rules = rules.with_prefix("tenant:", KeyAction::Allow);
+        assert_eq!(
+            rules.resolve("tenant:other:1").action(),
+            Some(KeyAction::Allow)
+        );
+    }
+
+    #[test]
+    fn try_acquire() {
+        let rules = KeyRules::new()
+            .with_exact("health", KeyAction::Allow)
+            .with_exact("mallory", KeyAction::Deny)
+            .with_prefix(
+                "acme:",
+                KeyAction::Limit(RateProfile::new(10).with_capacity(100)),
+            );
+        let tb = limiter(rules);
+
+        assert!(tb.try_acquire("alice", 10).is_ok());
+        let err = tb.try_acquire_one("alice").unwrap_err();
+        assert_eq!(err.rule(), &AppliedRule::Default);
+        assert!(err.is_rate_limit_exceeded() && !err.is_denied());
+
+        for _ in 0..100 {
+            assert!(tb.try_acquire_one("health").is_ok());
+        }
+        let err = tb.try_acquire_one("mallory").unwrap_err();
+        assert!(err.is_denied());
+        assert!(err.is_rate_limit_exceeded());
+
+        let rule = tb.try_acquire("acme:bob", 100).unwrap();
+        assert_eq!(
+            rule.action(),
+            Some(KeyAction::Limit(RateProfile::new(10).with_capacity(100)))
+        );
+        assert!(tb.try_acquire_one("acme:bob").is_err());
+        assert!(tb.charge("health", 1000).is_ok());
+        assert!(tb.charge("mallory", 1).unwrap_err().is_denied());
+    }
+
+    #[test]
+    fn set_rules() {
+        let acme = KeyAction::Limit(RateProfile::new(100));
+        let tb = limiter(KeyRules::new().with_prefix("acme:", acme));
+        assert!(tb.try_acquire("acme:bob", 50).is_ok());
+        assert!(tb.try_acquire("alice", 10).is_ok());
+        assert_eq!(tb.custom.read().len(), 1);
+
+        tb.set_rules(
+            KeyRules::new()
+                .with_prefix("acme:", acme)
+                .with_exact("alice", KeyAction::Allow),
+        );
+        assert!(tb.try_acquire("alice", 10).is_ok());
+        // The bucket of the unchanged quota is kept
+        assert!(tb.try_acquire("acme:bob", 51).is_err());
+        assert!(tb.try_acquire("acme:bob", 50).is_ok());
+
+        tb.set_rules(KeyRules::new());
+        assert!(tb.custom.read().is_empty());
+        assert_eq!(tb.rules().resolve("alice"), AppliedRule::Default);
+        assert!(tb.try_acquire_one("alice").is_err());
+    }
+}
@@ -0,0 +1,545 @@
+use crate::{
+    refill_tick, validate_capacity, ConfigError, KeyedStorage, RateLimitExceededError,
+    ReconfigureError, ResizePolicy, Storage, StorageError, TokenBucketAlgorithm,
+};
+
+/// Rate and capacity of a bucket while a range of a [`RateSchedule`] is active.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub struct RateProfile {
+    rps_limit: u32,
+    cap: u64,
+}
+
+impl RateProfile {
+    /// Creates a profile with capacity equal to the rate.
+    pub fn new(rps_limit: u32) -> Self {
+        Self {
+            rps_limit,
+            cap: rps_limit.into(),
+        }
+    }
+
+    /// Customize capacity of the bucket.
+    pub fn with_capacity(mut self, cap: u64) -> Self {
+        self.cap = cap;
+        self
+    }
+
+    pub fn rps_limit(&self) -> u32 {
+        self.rps_limit
+    }
+
+    pub fn cap(&self) -> u64 {
+        self.cap
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        refill_tick(self.rps_limit)?;
+        validate_capacity(self.cap)?;
+        Ok(())
+    }
+}
+
+/// Range of time of day, `start` is inclusive and `end` is exclusive.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+struct TimeRange {
+    start: time::Time,
+    end: time::Time,
+    /// Days the range starts at, bit `n` is set for `n` days from Monday.
+    weekdays: u8,
+}
+
+const EVERY_DAY: u8 = 0b111_1111;
+
+impl TimeRange {
+    fn contains(&self, at: time::PrimitiveDateTime) -> bool {
+        let starts_at =
+            |day: time::Weekday| self.weekdays & (1 << day.number_days_from_monday()) != 0;
+        let (day, time) = (at.weekday(), at.time());
+
+        if self.start == self.end {
+            starts_at(day)
+        } else if self.start < self.end {
+            starts_at(day) && self.start <= time && time < self.end
+        } else {
+            // The range wraps midnight, its end belongs to the next day
+            (starts_at(day) && self.start <= time) || (starts_at(day.previous()) && time < self.end)
+        }
+    }
+}
+
+/// Table of time ranges with their rate profiles, e.g. higher limits at night.
+///
+/// Ranges are checked in order of adding, the first one that contains the current time
+/// is active, the default profile is used outside of all ranges. Time of day is taken
+/// in the offset of the schedule, UTC by default.
+///
+/// A range that ends before it starts wraps midnight (e.g. 22:00..06:00),
+/// a range that ends when it starts lasts the whole day.
+///
+/// # Example
+/// ```
+/// use time::{Time, UtcOffset, Weekday};
+/// use tocket::{RateProfile, RateSchedule};
+///
+/// let schedule = RateSchedule::new(RateProfile::new(100))
+///     .with_offset(UtcOffset::from_hms(3, 0, 0).unwrap())
+///     // Nights
+///     .with_range(
+///         Time::from_hms(22, 0, 0).unwrap(),
+///         Time::from_hms(6, 0, 0).unwrap(),
+///         RateProfile::new(1000),
+///     )
+///     // Weekends
+///     .with_weekday_range(
+///         [Weekday::Saturday, Weekday::Sunday],
+///         Time::MIDNIGHT,
+///         Time::MIDNIGHT,
+///         RateProfile::new(500),
+///     );
+/// # let _ = schedule;
+/// ```
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct RateSchedule {
+    default: RateProfile,
+    ranges: Vec<(TimeRange, RateProfile)>,
+    offset: time::UtcOffset,
+}
+
+impl RateSchedule {
+    /// Creates a schedule that uses `default` profile outside of ranges.
+    pub fn new(default: RateProfile) -> Self {
+        Self {
+            default,
+            ranges: Vec::new(),
+            offset: time::UtcOffset::UTC,
+        }
+    }
+
+    /// Customize timezone offset of the time of day.
+    pub fn with_offset(mut self, offset: time::UtcOffset) -> Self {
+        self.offset = offset;
+        self
+    }
+
+    /// Adds a range of every day.
+    pub fn with_range(mut self, start: time::Time, end: time::Time, profile: RateProfile) -> Self {
+        let range = TimeRange {
+            start,
+            end,
+            weekdays: EVERY_DAY,
+        };
+        self.ranges.push((range, profile));
+        self
+    }
+
+    /// Adds a range of the given days. A range that wraps midnight belongs to the day it starts.
+    pub fn with_weekday_range<I>(
+        mut self,
+        weekdays: I,
+        start: time::Time,
+        end: time::Time,
+        profile: RateProfile,
+    ) -> Self
+    where
+        I: IntoIterator<Item = time::Weekday>,
+    {
+        let weekdays = weekdays
+            .into_iter()
+            .fold(0, |days, day| days | 1 << day.number_days_from_monday());
+        let range = TimeRange {
+            start,
+            end,
+            weekdays,
+        };
+        self.ranges.push((range, profile));
+        self
+    }
+
+    /// Returns the profile active at the given moment.
+    pub fn profile_at(&self, at: time::OffsetDateTime) -> RateProfile {
+        let at = at.to_offset(self.offset);
+        let at = time::PrimitiveDateTime::new(at.date(), at.time());
+        self.ranges
+            .iter()
+            .find(|(range, _)| range.contains(at))
+            .map_or(self.default, |(_, profile)| *profile)
+    }
+
+    /// Fails if any profile has zero rate or capacity.
+    fn validate(&self) -> Result<(), ConfigError> {
+        self.default.validate()?;
+        self.ranges
+            .iter()
+            .try_for_each(|(_, profile)| profile.validate())
+    }
+}
+
+/// A storage that changes rate and capacity of another storage according to a [`RateSchedule`].
+///
+/// The active profile is checked on every request. When it changes, the inner storage is
+/// reconfigured with [`Storage::set_rate`] and [`Storage::set_capacity`], so tokens are kept
+/// and adjusted by [`with_resize_policy`](ScheduledStorage::with_resize_policy).
+/// A keyed storage is reconfigured for all keys with [`KeyedStorage::set_rate`] and
+/// [`KeyedStorage::set_capacity`].
+/// A storage that doesn't support reconfiguration fails the first request of every transition
+/// with [`ScheduledStorageError::Unsupported`], later requests use the storage as is.
+///
+/// Every application instance sharing a bucket (e.g. in Redis) applies the transition,
+/// so keep the default [`ResizePolicy::Clamp`] for them.
+///
+/// # Example
+/// ```
+/// use time::Time;
+/// use tocket::{InMemoryStorage, RateProfile, RateSchedule, ScheduledStorage, TokenBucket};
+///
+/// let schedule = RateSchedule::new(RateProfile::new(100)).with_range(
+///     Time::from_hms(22, 0, 0).unwrap(),
+///     Time::from_hms(6, 0, 0).unwrap(),
+///     RateProfile::new(1000),
+/// );
+///
+/// let tb = TokenBucket::new(ScheduledStorage::new(InMemoryStorage::new(100), schedule));
+/// assert!(tb.try_acquire_one().is_ok());
+/// ```
+pub struct ScheduledStorage<S> {
+    storage: S,
+    schedule: RateSchedule,
+    policy: ResizePolicy,
+    active: parking_lot::Mutex<Option<RateProfile>>,
+}
+
+impl<S> ScheduledStorage<S> {
+    /// Creates a storage. The active profile is applied on the first request.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a profile of the schedule has zero rate or capacity, see [`try_new`](Self::try_new).
+    pub fn new(storage: S, schedule: RateSchedule) -> Self {
+        match Self::try_new(storage, schedule) {
+            Ok(storage) => storage,
+            Err(err) => panic!("invalid rate schedule: {}", err),
+        }
+    }
+
+    /// Creates a storage, failing if a profile of the schedule has zero rate or capacity.
+    pub fn try_new(storage: S, schedule: RateSchedule) -> Result<Self, ConfigError> {
+        schedule.validate()?;
+        Ok(Self {
+            storage,
+            schedule,
+            policy: ResizePolicy::Clamp,
+            active: Default::default(),
+        })
+    }
+
+    /// Customize how available tokens are adjusted on transitions.
+    pub fn with_resize_policy(mut self, policy: ResizePolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    pub fn storage(&self) -> &S {
+        &self.storage
+    }
+
+    pub fn schedule(&self) -> &RateSchedule {
+        &self.schedule
+    }
+}
+
+impl<S> ScheduledStorage<S> {
+    /// Reconfigures the inner storage with `reconfigure` if the active profile has changed.
+    ///
+    /// A profile the storage can never take is marked active anyway, so it isn't retried
+    /// on every request. Errors of the storage are retried by the next request.
+    fn apply<E, F>(&self, reconfigure: F) -> Result<(), ScheduledStorageError<E>>
+    where
+        F: Fn(&RateProfile) -> Result<(), ReconfigureError<E>>,
+    {
+        let profile = self.schedule.profile_at(time::OffsetDateTime::now_utc());
+        let mut active = self.active.lock();
+        if *active == Some(profile) {
+            return Ok(());
+        }
+
+        let err = match reconfigure(&profile) {
+            Ok(()) => {
+                tracing::info!("switched to rate profile {:?}", profile);
+                *active = Some(profile);
+                return Ok(());
+            }
+            Err(ReconfigureError::Unsupported) => {
+                tracing::error!("storage doesn't support rate profile {:?}", profile);
+                ScheduledStorageError::Unsupported
+            }
+            Err(ReconfigureError::Config(err)) => {
+                tracing::error!("storage rejected rate profile {:?}: {}", profile, err);
+                ScheduledStorageError::Config(err)
+            }
+            Err(ReconfigureError::Storage(err)) => return Err(ScheduledStorageError::Storage(err)),
+        };
+        *active = Some(profile);
+        Err(err)
+    }
+}
+
+impl<S> Storage for ScheduledStorage<S>
+where
+    S: Storage,
+{
+    type Error = ScheduledStorageError<S::Error>;
+
+    fn try_acquire(&self, alg: TokenBucketAlgorithm, permits: u64) -> Result<(), Self::Error> {
+        self.apply(|profile| self.reconfigure(profile))?;
+        Storage::try_acquire(&self.storage, alg, permits).map_err(ScheduledStorageError::Storage)
+    }
+
+    fn release(&self, permits: u64) -> Result<(), Self::Error> {
+        self.apply(|profile| self.reconfigure(profile))?;
+        Storage::release(&self.storage, permits).map_err(ScheduledStorageError::Storage)
+    }
+
+    fn charge(&self, cost: u64) -> Result<(), Self::Error> {
+        self.apply(|profile| self.reconfigure(profile))?;
+        Storage::charge(&self.storage, cost).map_err(ScheduledStorageError::Storage)
+    }
+}
+
+impl<S> ScheduledStorage<S>
+where
+    S: Storage,
+{
+    fn reconfigure(&self, profile: &RateProfile) -> Result<(), ReconfigureError<S::Error>> {
+        Storage::set_rate(&self.storage, profile.rps_limit)?;
+        Storage::set_capacity(&self.storage, profile.cap, self.policy)
+    }
+}
+
+impl<S> KeyedStorage for ScheduledStorage<S>
+where
+    S: KeyedStorage,
+{
+    type Key = S::Key;
+    type Error = ScheduledStorageError<S::Error>;
+
+    fn try_acquire(
+        &self,
+        key: &Self::Key,
+        alg: TokenBucketAlgorithm,
+        permits: u64,
+    ) -> Result<(), Self::Error> {
+        self.apply(|profile| self.reconfigure_keyed(profile))?;
+        KeyedStorage::try_acquire(&self.storage, key, alg, permits)
+            .map_err(ScheduledStorageError::Storage)
+    }
+
+    fn release(&self, key: &Self::Key, permits: u64) -> Result<(), Self::Error> {
+        self.apply(|profile| self.reconfigure_keyed(profile))?;
+        KeyedStorage::release(&self.storage, key, permits).map_err(ScheduledStorageError::Storage)
+    }
+
+    fn charge(&self, key: &Self::Key, cost: u64) -> Result<(), Self::Error> {
+        self.apply(|profile| self.reconfigure_keyed(profile))?;
+        KeyedStorage::charge(&self.storage, key, cost).map_err(ScheduledStorageError::Storage)
+    }
+}
+
+impl<S> ScheduledStorage<S>
+where
+    S: KeyedStorage,
+{
+    fn reconfigure_keyed(&self, profile: &RateProfile) -> Result<(), ReconfigureError<S::Error>> {
+        KeyedStorage::set_rate(&self.storage, profile.rps_limit)?;
+        KeyedStorage::set_capacity(&self.storage, profile.cap, self.policy)
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ScheduledStorageError<E> {
+    #[error(transparent)]
+    Storage(E),
+    #[error(transparent)]
+    RateLimitExceededError(#[from] RateLimitExceededError),
+    #[error("storage doesn't support reconfiguration")]
+    Unsupported,
+    #[error("invalid rate profile: {0}")]
+    Config(ConfigError),
+}
+
+impl<E> StorageError for ScheduledStorageError<E>
+where
+    E: StorageError,
+{
+    fn is_rate_limit_exceeded(&self) -> bool {
+        match self {
+            ScheduledStorageError::Storage(err) => err.is_rate_limit_exceeded(),
+            ScheduledStorageError::RateLimitExceededError(_) => true,
+            ScheduledStorageError::Unsupported | ScheduledStorageError::Config(_) => false,
+        }
+    }
+
+    fn is_capacity_exceeded(&self) -> bool {
+        match self {
+            ScheduledStorageError::Storage(err) => err.is_capacity_exceeded(),
+            ScheduledStorageError::RateLimitExceededError(err) => err.is_capacity_exceeded(),
+            ScheduledStorageError::Unsupported | ScheduledStorageError::Config(_) => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        AtomicInMemoryStorage, InMemoryStorage, KeyedInMemoryStorage, KeyedTokenBucket, TokenBucket,
+    };
+
+    use time::{Time, UtcOffset, Weekday};
+
+    fn hm(hour: u8, minute: u8) -> Time {
+        Time::from_hms(hour, minute, 0).unwrap()
+    }
+
+    #[test]
+    fn profile_at() {
+        let schedule = RateSchedule::new(RateProfile::new(100))
+            .with_offset(UtcOffset::from_hms(3, 0, 0).unwrap())
+            .with_weekday_range(
+                [Weekday::Saturday, Weekday::Sunday],
+                Time::MIDNIGHT,
+                Time::MIDNIGHT,
+                RateProfile::new(500),
+            )
+            .with_weekday_range(
+                [Weekday::Friday],
+                hm(22, 0),
+                hm(6, 0),
+                RateProfile::new(1000).with_capacity(10),
+            )
+            .with_range(hm(9, 0), hm(18, 0), RateProfile::new(10));
+        // Friday, 2024-03-01 in UTC+3
+        let at = |day: u8, hour: u8, minute: u8| {
+            time::Date::from_calendar_date(2024, time::Month::March, day)
+                .unwrap()
+                .with_time(hm(hour, minute))
+                .assume_offset(UtcOffset::from_hms(3, 0, 0).unwrap())
+        };
+
+        assert_eq!(schedule.profile_at(at(1, 8, 59)), RateProfile::new(100));
+        assert_eq!(schedule.profile_at(at(1, 9, 0)), RateProfile::new(10));
+        assert_eq!(schedule.profile_at(at(1, 18, 0)), RateProfile::new(100));
+        assert_eq!(
+            schedule.profile_at(at(1, 23, 0)),
+            RateProfile::new(1000).with_capacity(10)
+        );
+        // Saturday, the weekend range is added first
+        assert_eq!(schedule.profile_at(at(2, 5, 0)), RateProfile::new(500));
+        // Monday
+        assert_eq!(schedule.profile_at(at(4, 12, 0)), RateProfile::new(10));
+
+        // 19:00 in UTC is 22:00 in UTC+3
+        let utc = at(1, 22, 0).to_offset(UtcOffset::UTC);
+        assert_eq!(utc.hour(), 19);
+        assert_eq!(
+            schedule.profile_at(utc),
+            RateProfile::new(1000).with_capacity(10)
+        );
+    }
+
+    #[test]
+    fn try_acquire() {
+        let schedule = RateSchedule::new(RateProfile::new(2)).with_range(
+            Time::MIDNIGHT,
+            Time::MIDNIGHT,
+            RateProfile::new(10),
+        );
+        let storage = ScheduledStorage::new(InMemoryStorage::new(2), schedule)
+            .with_resize_policy(ResizePolicy::Scale);
+        let tb = TokenBucket::new(storage);
+        assert!(tb.try_acquire(10).is_ok());
+        assert!(tb.try_acquire_one().is_err());
+
+        let schedule = RateSchedule::new(RateProfile::new(2)).with_range(
+            Time::MIDNIGHT,
+            Time::MIDNIGHT,
+            RateProfile::new(10),
+        );
+        let storage = ScheduledStorage::new(AtomicInMemoryStorage::new(2), schedule)
+            .with_resize_policy(ResizePolicy::Scale);
+        let tb = TokenBucket::new(storage);
+        assert!(tb.try_acquire(10).is_ok());
+        assert!(tb.try_acquire_one().is_err());
+    }
+
+    #[test]
+    fn keyed() {
+        let schedule = RateSchedule::new(RateProfile::new(2)).with_range(
+            Time::MIDNIGHT,
+            Time::MIDNIGHT,
+            RateProfile::new(10),
+        );
+        let storage = ScheduledStorage::new(KeyedInMemoryStorage::new(2), schedule)
+            .with_resize_policy(ResizePolicy::Scale);
+        let tb = KeyedTokenBucket::new(storage);
+        assert!(tb.try_acquire("a", 10).is_ok());
+        assert!(tb.try_acquire_one("a").is_err());
+        assert!(tb.try_acquire("b", 10).is_ok());
+    }
+
+    #[test]
+    fn invalid_profile() {
+        let schedule = RateSchedule::new(RateProfile::new(2)).with_range(
+            hm(22, 0),
+            hm(6, 0),
+            RateProfile::new(0),
+        );
+        assert!(matches!(
+            ScheduledStorage::try_new(InMemoryStorage::new(2), schedule),
+            Err(ConfigError::ZeroRate)
+        ));
+
+        let schedule = RateSchedule::new(RateProfile::new(2).with_capacity(0));
+        assert!(matches!(
+            ScheduledStorage::try_new(InMemoryStorage::new(2), schedule),
+            Err(ConfigError::ZeroCapacity)
+        ));
+    }
+
+    /// A storage with a fixed rate.
+    struct Fixed(InMemoryStorage);
+
+    impl Storage for Fixed {
+        type Error = RateLimitExceededError;
+
+        fn try_acquire(&self, alg: TokenBucketAlgorithm, permits: u64) -> Result<(), Self::Error> {
+            self.0.try_acquire(alg, permits)
+        }
+
+        fn charge(&self, cost: u64) -> Result<(), Self::Error> {
+            self.0.charge(cost)
+        }
+    }
+
+    #[test]
+    fn unsupported() {
+        let schedule = RateSchedule::new(RateProfile::new(2));
+        let tb = TokenBucket::new(ScheduledStorage::new(
+            Fixed(InMemoryStorage::new(2)),
+            schedule,
+        ));
+
+        // Only the transition fails, the storage keeps its own rate
+        assert!(matches!(
+            tb.try_acquire_one(),
+            Err(ScheduledStorageError::Unsupported)
+        ));
+        assert!(tb.try_acquire(2).is_ok());
+        assert!(matches!(
+            tb.try_acquire_one(),
+            Err(ScheduledStorageError::Storage(
+                RateLimitExceededError::Exhausted
+            ))
+        ));
+    }
+}
@@ -0,0 +1,204 @@
+use crate::{balance, ticks, State};
+
+use std::time::Duration;
+
+/// Default ratio of the configured rate to the rate of a cold bucket.
+pub const DEFAULT_COLD_FACTOR: u32 = 3;
+
+/// Slow start of a bucket after idleness, like `SmoothWarmingUp` of Guava's `RateLimiter`.
+///
+/// A cold bucket is refilled `cold_factor` times slower than configured and holds
+/// `cap / cold_factor` tokens. Both grow linearly to the configured ones during the warm-up period.
+/// A new bucket is cold, a bucket becomes cold again after it has been full for the warm-up period.
+///
+/// # Example
+/// ```
+/// use std::time::Duration;
+/// use tocket::{InMemoryStorage, TokenBucket, WarmUp};
+///
+/// let storage = InMemoryStorage::builder(90)
+///     .with_warm_up(WarmUp::new(Duration::from_secs(10)))
+///     .build();
+///
+/// // A cold bucket holds only a third of the capacity
+/// let tb = TokenBucket::new(storage);
+/// assert!(tb.try_acquire(30).is_ok());
+/// assert!(tb.try_acquire(30).is_err());
+/// ```
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct WarmUp {
+    period: time::Duration,
+    cold_factor: u32,
+}
+
+impl WarmUp {
+    /// Creates a warm-up of the given period with [`DEFAULT_COLD_FACTOR`].
+    pub fn new(period: Duration) -> Self {
+        Self {
+            period: time::Duration::try_from(period).unwrap_or(time::Duration::MAX),
+            cold_factor: DEFAULT_COLD_FACTOR,
+        }
+    }
+
+    /// Customize how many times a cold bucket is slower than a warm one, at least 1.
+    pub fn with_cold_factor(mut self, cold_factor: u32) -> Self {
+        self.cold_factor = cold_factor.max(1);
+        self
+    }
+
+    pub fn period(&self) -> Duration {
+        self.period.try_into().unwrap_or_default()
+    }
+
+    pub fn cold_factor(&self) -> u32 {
+        self.cold_factor
+    }
+
+    /// Capacity of a cold bucket.
+    fn cold_cap(&self, cap: u64) -> u64 {
+        cap.div_ceil(self.cold_factor.into())
+    }
+
+    /// Warm-up progress `elapsed` after the bucket became cold, from 0 (cold) to 1 (warm).
+    fn progress(&self, elapsed: time::Duration) -> f64 {
+        if elapsed >= self.period {
+            return 1.0;
+        }
+        (elapsed / self.period).max(0.0)
+    }
+
+    /// Refill rate in tokens per second `elapsed` after the bucket became cold.
+    fn rate(&self, refill_tick: time::Duration, elapsed: time::Duration) -> f64 {
+        let rate = 1.0 / refill_tick.as_seconds_f64();
+        let cold_rate = rate / f64::from(self.cold_factor);
+        cold_rate + (rate - cold_rate) * self.progress(elapsed)
+    }
+
+    /// Tokens refilled during `elapsed` after the bucket became cold, i.e. integral of the rate.
+    fn refilled(&self, refill_tick: time::Duration, elapsed: time::Duration) -> f64 {
+        let rate = 1.0 / refill_tick.as_seconds_f64();
+        let cold_rate = rate / f64::from(self.cold_factor);
+        let t = elapsed.as_seconds_f64().max(0.0);
+        let period = self.period.as_seconds_f64();
+        let ramp = if t < period {
+            t * t / (2.0 * period)
+        } else {
+            period / 2.0 + (t - period)
+        };
+        cold_rate * t + (rate - cold_rate) * ramp
+    }
+
+    /// Refills a cold or warming up state.
+    ///
+    /// Returns `false` if the state is already warm and should be refilled as usual.
+    pub(crate) fn refill(&self, state: &mut State, now: time::OffsetDateTime) -> bool {
+        let missing = i128::from(state.cap) - i128::from(state.available_tokens);
+        let full_since =
+            (now - state.last_refill).saturating_sub(ticks(state.refill_tick, missing));
+        let warm_since = match state.warm_since {
+            Some(warm_since) if full_since < self.period => warm_since,
+            // A bucket that wasn't used for the warm-up period
+            Some(_) => {
+                state.available_tokens = balance(self.cold_cap(state.cap));
+                state.last_refill = now;
+                state.warm_since = Some(now);
+                return true;
+            }
+            // A new bucket keeps its initial tokens, if there are less of them
+            None => {
+                state.available_tokens = state
+                    .available_tokens
+                    .min(balance(self.cold_cap(state.cap)));
+                state.last_refill = now;
+                state.warm_since = Some(now);
+                return true;
+            }
+        };
+
+        let since = state.last_refill - warm_since;
+        if since >= self.period {
+            return false;
+        }
+
+        let elapsed = now - warm_since;
+        let refilled =
+            self.refilled(state.refill_tick, elapsed) - self.refilled(state.refill_tick, since);
+        let tokens = refilled.floor();
+        if tokens < 1.0 {
+            return true;
+        }
+
+        let cold_cap = self.cold_cap(state.cap);
+        let cap = cold_cap + ((state.cap - cold_cap) as f64 * self.progress(elapsed)) as u64;
+        state.available_tokens = state
+            .available_tokens
+            .saturating_add(tokens as i64)
+            .min(balance(cap))
+            .max(state.available_tokens);
+        // Keep the part of the next token
+        let rest = (refilled - tokens) / self.rate(state.refill_tick, elapsed);
+        state.last_refill = (now - time::Duration::seconds_f64(rest)).max(state.last_refill);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::BucketConfig;
+
+    #[test]
+    fn ramp() {
+        let warm_up = WarmUp::new(Duration::from_secs(10));
+        let tick = time::Duration::seconds(1) / 100;
+
+        // A third of the rate at start, the whole rate after the period
+        assert_eq!(warm_up.rate(tick, time::Duration::ZERO).round(), 33.0);
+        assert_eq!(warm_up.rate(tick, time::Duration::seconds(5)).round(), 67.0);
+        assert_eq!(
+            warm_up.rate(tick, time::Duration::seconds(20)).round(),
+            100.0
+        );
+
+        // (33 + 100) / 2 tokens per second during the period
+        let refilled = warm_up.refilled(tick, time::Duration::seconds(10));
+        assert_eq!(refilled.round(), 667.0);
+        let refilled = warm_up.refilled(tick, time::Duration::seconds(11)) - refilled;
+        assert_eq!(refilled.round(), 100.0);
+    }
+
+    #[test]
+    fn refill() {
+        let warm_up = WarmUp::new(Duration::from_secs(10));
+        let mut state = BucketConfig::try_new(90, 90)
+            .unwrap()
+            .with_warm_up(Some(warm_up))
+            .new_state();
+        let now = state.last_refill;
+
+        // New bucket is cold
+        assert!(warm_up.refill(&mut state, now));
+        assert_eq!(state.available_tokens, 30);
+        assert_eq!(state.warm_since, Some(now));
+
+        // 30..90 tokens per second ramp during 10 seconds, the capacity grows the same way
+        state.available_tokens = 0;
+        assert!(warm_up.refill(&mut state, now + time::Duration::seconds(1)));
+        assert_eq!(state.available_tokens, 33);
+        state.available_tokens = 0;
+        assert!(warm_up.refill(&mut state, now + time::Duration::seconds(5)));
+        assert_eq!(state.available_tokens, 60);
+
+        // Warm bucket is refilled as usual
+        state.available_tokens = 0;
+        assert!(warm_up.refill(&mut state, now + time::Duration::seconds(10)));
+        assert_eq!(state.available_tokens, 90);
+        state.last_refill = now + time::Duration::seconds(10);
+        assert!(!warm_up.refill(&mut state, now + time::Duration::seconds(11)));
+
+        // Full for the warm-up period
+        assert!(warm_up.refill(&mut state, now + time::Duration::seconds(21)));
+        assert_eq!(state.available_tokens, 30);
+    }
+}